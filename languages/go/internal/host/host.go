@@ -6,6 +6,7 @@ import (
 	"fmt"
 	"math"
 	"reflect"
+	"time"
 
 	"github.com/osohq/go-oso/errors"
 	"github.com/osohq/go-oso/interfaces"
@@ -16,8 +17,6 @@ import (
 
 var CLASSES = make(map[string]reflect.Type)
 
-type None struct{}
-
 type Host struct {
 	ffiPolar     ffi.PolarFfi
 	classes      map[string]reflect.Type
@@ -206,6 +205,9 @@ func (h Host) Isa(value types.Term, classTag string) (bool, error) {
 	if err != nil {
 		return false, err
 	}
+	if instance == nil {
+		return false, nil
+	}
 	instanceType := reflect.TypeOf(instance)
 	res := instanceType.ConvertibleTo(*class)
 	return res, nil
@@ -230,7 +232,7 @@ func (h Host) IsSubspecializer(instanceID int, leftTag string, rightTag string)
 
 func (h Host) ToPolar(v interface{}) (*Value, error) {
 	if v == nil {
-		return h.ToPolar(None{})
+		return &Value{ValueNil{}}, nil
 	}
 	switch v := v.(type) {
 	case bool:
@@ -292,8 +294,7 @@ func (h Host) ToPolar(v interface{}) (*Value, error) {
 	if rt.Kind() == reflect.Ptr {
 		rtDeref := rt.Elem()
 		if rt.IsNil() {
-			// TODO: Is `nil` a reflect.Ptr?
-			return h.ToPolar(None{})
+			return &Value{ValueNil{}}, nil
 		}
 		return h.ToPolar(rtDeref.Interface())
 	}
@@ -355,6 +356,8 @@ func (h Host) ListToGo(v []types.Term) ([]interface{}, error) {
 
 func (h Host) ToGo(v types.Term) (interface{}, error) {
 	switch inner := v.Value.ValueVariant.(type) {
+	case ValueNil:
+		return nil, nil
 	case ValueBoolean:
 		return bool(inner), nil
 	case ValueNumber:
@@ -368,6 +371,12 @@ func (h Host) ToGo(v types.Term) (interface{}, error) {
 		return string(inner), nil
 	case ValueList:
 		return h.ListToGo(inner)
+	case ValueSet:
+		// Go has no set type; a set's elements arrive in canonical order.
+		return h.ListToGo(inner)
+	case ValueDateTime:
+		ms := inner.EpochMs
+		return time.Unix(ms/1000, (ms%1000)*int64(time.Millisecond)).UTC(), nil
 	case ValueDictionary:
 		retMap := make(map[string]interface{})
 		for k, v := range inner.Fields {
@@ -30,11 +30,6 @@ func newPolar() (*Polar, error) {
 		host:     host.NewHost(ffiPolar),
 	}
 
-	err := polar.registerConstant(host.None{}, "nil")
-	if err != nil {
-		return nil, err
-	}
-
 	builtinClasses := map[string]reflect.Type{
 		"Boolean":    reflect.TypeOf(true),
 		"Integer":    reflect.TypeOf(int(1)),
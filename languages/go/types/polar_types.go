@@ -16,6 +16,12 @@ type Call struct {
 	Kwargs *map[Symbol]Term `json:"kwargs"`
 }
 
+// DateTime struct
+type DateTime struct {
+	// EpochMs
+	EpochMs int64 `json:"epoch_ms"`
+}
+
 // Dictionary struct
 type Dictionary struct {
 	// Fields
@@ -2475,6 +2481,43 @@ func (variant *ValueExpression) UnmarshalJSON(b []byte) error {
 
 func (ValueExpression) isValue() {}
 
+// ValueSet newtype
+type ValueSet []Term
+
+func (variant ValueSet) MarshalJSON() ([]byte, error) {
+	return json.Marshal([]Term(variant))
+}
+
+func (variant *ValueSet) UnmarshalJSON(b []byte) error {
+	inner := []Term(*variant)
+	err := json.Unmarshal(b, &inner)
+	*variant = ValueSet(inner)
+	return err
+}
+
+func (ValueSet) isValue() {}
+
+// ValueDateTime newtype
+type ValueDateTime DateTime
+
+func (variant ValueDateTime) MarshalJSON() ([]byte, error) {
+	return json.Marshal(DateTime(variant))
+}
+
+func (variant *ValueDateTime) UnmarshalJSON(b []byte) error {
+	inner := DateTime(*variant)
+	err := json.Unmarshal(b, &inner)
+	*variant = ValueDateTime(inner)
+	return err
+}
+
+func (ValueDateTime) isValue() {}
+
+// ValueNil unit variant
+type ValueNil struct{}
+
+func (ValueNil) isValue() {}
+
 // Value enum
 type ValueVariant interface {
 	isValue()
@@ -2628,6 +2671,32 @@ func (result *Value) UnmarshalJSON(b []byte) error {
 		*result = Value{variant}
 		return nil
 
+	case "Set":
+		var variant ValueSet
+		if variantValue != nil {
+			err := json.Unmarshal(*variantValue, &variant)
+			if err != nil {
+				return err
+			}
+		}
+		*result = Value{variant}
+		return nil
+
+	case "DateTime":
+		var variant ValueDateTime
+		if variantValue != nil {
+			err := json.Unmarshal(*variantValue, &variant)
+			if err != nil {
+				return err
+			}
+		}
+		*result = Value{variant}
+		return nil
+
+	case "Nil":
+		*result = Value{ValueNil{}}
+		return nil
+
 	}
 
 	return fmt.Errorf("Cannot deserialize Value: %s", string(b))
@@ -2690,6 +2759,19 @@ func (variant Value) MarshalJSON() ([]byte, error) {
 		return json.Marshal(map[string]ValueExpression{
 			"Expression": inner,
 		})
+
+	case ValueSet:
+		return json.Marshal(map[string]ValueSet{
+			"Set": inner,
+		})
+
+	case ValueDateTime:
+		return json.Marshal(map[string]ValueDateTime{
+			"DateTime": inner,
+		})
+
+	case ValueNil:
+		return json.Marshal("Nil")
 	}
 
 	return nil, fmt.Errorf("unexpected variant of %v", variant)
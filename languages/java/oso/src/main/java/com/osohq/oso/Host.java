@@ -1,6 +1,7 @@
 package com.osohq.oso;
 
 import java.lang.reflect.Constructor;
+import java.time.Instant;
 import java.util.*;
 import java.util.stream.Collectors;
 import java.util.stream.DoubleStream;
@@ -159,13 +160,29 @@ public class Host implements Cloneable {
   /** Convert Java Objects to Polar (JSON) terms. */
   public JSONObject toPolarTerm(Object value) throws Exceptions.OsoException {
     // Build Polar value
+    Object jVal;
+    if (value == null) {
+      jVal = "Nil";
+    } else {
+      jVal = toPolarValue(value);
+    }
+
+    // Build Polar term
+    JSONObject term = new JSONObject();
+    term.put("id", 0);
+    term.put("offset", 0);
+    term.put("value", jVal);
+    return term;
+  }
+
+  /** Convert a non-null Java Object to a JSONified Polar value. */
+  private JSONObject toPolarValue(Object value) throws Exceptions.OsoException {
     JSONObject jVal = new JSONObject();
-    if (value != null && value.getClass() == Boolean.class) {
+    if (value.getClass() == Boolean.class) {
       jVal.put("Boolean", value);
-    } else if (value != null && value.getClass() == Integer.class) {
+    } else if (value.getClass() == Integer.class) {
       jVal.put("Number", Map.of("Integer", value));
-    } else if (value != null
-        && (value.getClass() == Float.class || value.getClass() == Double.class)) {
+    } else if (value.getClass() == Float.class || value.getClass() == Double.class) {
       if ((Double) value == Double.POSITIVE_INFINITY) {
         jVal.put("Number", Map.of("Float", "Infinity"));
       } else if ((Double) value == Double.NEGATIVE_INFINITY) {
@@ -175,35 +192,29 @@ public class Host implements Cloneable {
       } else {
         jVal.put("Number", Map.of("Float", value));
       }
-    } else if (value != null && value.getClass() == String.class) {
+    } else if (value.getClass() == String.class) {
       jVal.put("String", value);
-    } else if (value != null && value.getClass().isArray()) {
+    } else if (value.getClass().isArray()) {
       jVal.put("List", javaArrayToPolar(value));
-    } else if (value != null && value instanceof List) {
+    } else if (value instanceof List) {
       jVal.put("List", javaListToPolar((List<Object>) value));
-    } else if (value != null && value instanceof Map) {
+    } else if (value instanceof Map) {
       Map<String, JSONObject> jMap = javaMaptoPolar((Map<Object, Object>) value);
       jVal.put("Dictionary", new JSONObject().put("fields", jMap));
-    } else if (value != null && value instanceof Predicate) {
+    } else if (value instanceof Predicate) {
       Predicate pred = (Predicate) value;
       if (pred.args == null) pred.args = new ArrayList<Object>();
       jVal.put(
           "Call", new JSONObject(Map.of("name", pred.name, "args", javaListToPolar(pred.args))));
-    } else if (value != null && value instanceof Variable) {
+    } else if (value instanceof Variable) {
       jVal.put("Variable", value);
     } else {
       JSONObject attrs = new JSONObject();
       attrs.put("instance_id", cacheInstance(value, null));
-      attrs.put("repr", value == null ? "null" : value.toString());
+      attrs.put("repr", value.toString());
       jVal.put("ExternalInstance", attrs);
     }
-
-    // Build Polar term
-    JSONObject term = new JSONObject();
-    term.put("id", 0);
-    term.put("offset", 0);
-    term.put("value", jVal);
-    return term;
+    return jVal;
   }
 
   /** Convert a Java List to a JSONified Polar list. */
@@ -254,6 +265,9 @@ public class Host implements Cloneable {
   public Object toJava(JSONObject term)
       throws Exceptions.UnregisteredInstanceError, Exceptions.UnexpectedPolarTypeError,
           Exceptions.OsoException {
+    if ("Nil".equals(term.opt("value"))) {
+      return null;
+    }
     JSONObject value = term.getJSONObject("value");
     String tag = value.keys().next();
     switch (tag) {
@@ -285,6 +299,10 @@ public class Host implements Cloneable {
         }
       case "List":
         return polarListToJava(value.getJSONArray(tag));
+      case "Set":
+        return new HashSet<Object>(polarListToJava(value.getJSONArray(tag)));
+      case "DateTime":
+        return Instant.ofEpochMilli(value.getJSONObject(tag).getLong("epoch_ms"));
       case "Dictionary":
         return polarDictToJava(value.getJSONObject(tag).getJSONObject("fields"));
       case "ExternalInstance":
@@ -19,9 +19,6 @@ public class Polar {
     ffiPolar = Ffi.get().polarNew();
     host = new Host(ffiPolar);
 
-    // Register global constants.
-    registerConstant(null, "nil");
-
     // Register built-in classes.
     registerClass(Boolean.class, "Boolean");
     registerClass(Integer.class, "Integer");
@@ -440,7 +440,7 @@ public class PolarTest {
     String rule = "1";
     Exceptions.UnrecognizedToken e =
         assertThrows(Exceptions.UnrecognizedToken.class, () -> p.loadStr(rule));
-    assertEquals("did not expect to find the token '1' at line 1, column 1", e.getMessage());
+    assertEquals("[P0005] did not expect to find the token '1' at line 1, column 1", e.getMessage());
   }
 
   /**** TEST LOADING ****/
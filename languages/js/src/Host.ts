@@ -12,10 +12,13 @@ import { Variable } from './Variable';
 import type { Class, EqualityFn, obj, PolarTerm } from './types';
 import { PolarOperator } from './types';
 import {
+  isPolarNil,
   isPolarStr,
   isPolarNum,
   isPolarBool,
   isPolarList,
+  isPolarSet,
+  isPolarDateTime,
   isPolarDict,
   isPolarInstance,
   isPolarPredicate,
@@ -238,6 +241,8 @@ export class Host {
    */
   toPolar(v: any): PolarTerm {
     switch (true) {
+      case v === null:
+        return { value: 'Nil' };
       case typeof v === 'boolean':
         return { value: { Boolean: v } };
       case Number.isInteger(v):
@@ -281,7 +286,9 @@ export class Host {
    */
   async toJs(v: PolarTerm): Promise<any> {
     const t = v.value;
-    if (isPolarStr(t)) {
+    if (isPolarNil(t)) {
+      return null;
+    } else if (isPolarStr(t)) {
       return t.String;
     } else if (isPolarNum(t)) {
       if ('Float' in t.Number) {
@@ -307,6 +314,12 @@ export class Host {
       return t.Boolean;
     } else if (isPolarList(t)) {
       return await Promise.all(t.List.map(async el => await this.toJs(el)));
+    } else if (isPolarDateTime(t)) {
+      return new Date(t.DateTime.epoch_ms);
+    } else if (isPolarSet(t)) {
+      return new Set(
+        await Promise.all(t.Set.map(async el => await this.toJs(el)))
+      );
     } else if (isPolarDict(t)) {
       const { fields } = t.Dictionary;
       let entries =
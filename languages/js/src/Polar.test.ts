@@ -365,7 +365,7 @@ describe('#loadFile', () => {
     const p = new Polar();
     const file = await tempFile(';', 'invalid.polar');
     expect(p.loadFile(file)).rejects.toThrow(
-      `did not expect to find the token ';' at line 1, column 1 in file ${file}`
+      `[P0005] did not expect to find the token ';' at line 1, column 1 in file ${file}`
     );
   });
 
@@ -501,7 +501,7 @@ describe('#makeInstance', () => {
 });
 
 // test_nil
-describe('null is pre-registered', () => {
+describe('null is exchanged with Polar', () => {
   test('as nil', async () => {
     const p = new Polar();
     await p.loadStr('null(nil);');
@@ -572,7 +572,7 @@ describe('#registerConstant', () => {
   test('errors when calling host language methods on booleans', () => {
     const p = new Polar();
     expect(query(p, 'b = true and b.constructor = Boolean')).rejects.toThrow(
-      'Type error: can only perform lookups on dicts and instances, this is true at line 1, column 5'
+      '[R0004] Type error: can only perform lookups on dicts and instances, this is true at line 1, column 5'
     );
   });
 
@@ -678,7 +678,7 @@ describe('errors', () => {
       const p = new Polar();
       const rule = 'f(a)';
       expect(p.loadStr(rule)).rejects.toThrow(
-        'hit the end of the file unexpectedly. Did you forget a semi-colon at line 1, column 5'
+        '[P0004] hit the end of the file unexpectedly. Did you forget a semi-colon at line 1, column 5'
       );
     });
 
@@ -686,7 +686,7 @@ describe('errors', () => {
       const p = new Polar();
       const rule = '1;';
       expect(p.loadStr(rule)).rejects.toThrow(
-        "did not expect to find the token '1' at line 1, column 1"
+        "[P0005] did not expect to find the token '1' at line 1, column 1"
       );
     });
 
@@ -703,7 +703,7 @@ describe('errors', () => {
     foo(1,2)
   in rule foo at line 1, column 13
     a in b
-Type error: can only use \`in\` on an iterable value, this is Number(Integer(2)) at line 1, column 7`
+[R0004] Type error: can only use \`in\` on an iterable value, this is Number(Integer(2)) at line 1, column 7`
       );
     });
 
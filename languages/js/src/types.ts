@@ -102,6 +102,46 @@ export function isPolarList(v: PolarValue): v is PolarList {
   return (v as PolarList).List !== undefined;
 }
 
+/**
+ * Polar set type.
+ *
+ * @internal
+ */
+interface PolarSet {
+  Set: PolarTerm[];
+}
+
+/**
+ * Type guard to test if a Polar value received from across the WebAssembly
+ * boundary is a Polar set.
+ *
+ * @internal
+ */
+export function isPolarSet(v: PolarValue): v is PolarSet {
+  return (v as PolarSet).Set !== undefined;
+}
+
+/**
+ * Polar datetime type, in milliseconds since the Unix epoch.
+ *
+ * @internal
+ */
+interface PolarDateTime {
+  DateTime: {
+    epoch_ms: number;
+  };
+}
+
+/**
+ * Type guard to test if a Polar value received from across the WebAssembly
+ * boundary is a Polar datetime.
+ *
+ * @internal
+ */
+export function isPolarDateTime(v: PolarValue): v is PolarDateTime {
+  return (v as PolarDateTime).DateTime !== undefined;
+}
+
 /**
  * Polar dictionary type.
  *
@@ -187,16 +227,36 @@ export function isPolarInstance(v: PolarValue): v is PolarInstance {
   return (v as PolarInstance).ExternalInstance !== undefined;
 }
 
+/**
+ * Polar nil type, which crosses the WebAssembly boundary as a bare string.
+ *
+ * @internal
+ */
+type PolarNil = 'Nil';
+
+/**
+ * Type guard to test if a Polar value received from across the WebAssembly
+ * boundary is Polar nil.
+ *
+ * @internal
+ */
+export function isPolarNil(v: PolarValue): v is PolarNil {
+  return v === 'Nil';
+}
+
 /**
  * Union of Polar value types.
  *
  * @internal
  */
 type PolarValue =
+  | PolarNil
   | PolarStr
   | PolarNum
   | PolarBool
   | PolarList
+  | PolarSet
+  | PolarDateTime
   | PolarDict
   | PolarPredicate
   | PolarVariable
@@ -218,12 +278,15 @@ export interface PolarTerm {
  * @internal
  */
 function isPolarValue(v: any): v is PolarValue {
+  if (isPolarNil(v)) return true;
   if (typeof v !== 'object' || v === null) return false;
   return (
     isPolarStr(v) ||
     isPolarNum(v) ||
     isPolarBool(v) ||
     isPolarList(v) ||
+    isPolarSet(v) ||
+    isPolarDateTime(v) ||
     isPolarDict(v) ||
     isPolarPredicate(v) ||
     isPolarVariable(v) ||
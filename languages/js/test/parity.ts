@@ -64,7 +64,7 @@ oso.registerClass(E);
   } catch (e) {
     const expectedName = 'ParseError::UnrecognizedEOF';
     const expectedMessage =
-      'hit the end of the file unexpectedly. Did you forget a semi-colon at line 1, column 19';
+      '[P0004] hit the end of the file unexpectedly. Did you forget a semi-colon at line 1, column 19';
     if (e.name === expectedName && e.message === expectedMessage)
       exceptionThrown = true;
   } finally {
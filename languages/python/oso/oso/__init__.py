@@ -1,3 +1,3 @@
-from polar import polar_class, Variable, Predicate
+from polar import polar_class, polar_uncached, Variable, Predicate
 from .oso import Oso
 from polar.exceptions import OsoError
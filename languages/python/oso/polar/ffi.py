@@ -77,6 +77,10 @@ class Query:
             value = ffi_serialize(value)
         check_result(lib.polar_call_result(self.ptr, call_id, value))
 
+    def uncache_call(self, call_id):
+        """Don't reuse the result of the call for identical lookups."""
+        check_result(lib.polar_uncache_call(self.ptr, call_id))
+
     def question_result(self, call_id, answer):
         answer = 1 if answer else 0
         check_result(lib.polar_question_result(self.ptr, call_id, answer))
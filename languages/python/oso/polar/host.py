@@ -1,5 +1,6 @@
 """Translate between Polar and the host language (Python)."""
 
+from datetime import datetime, timedelta, timezone
 from math import inf, isnan, nan
 
 from .exceptions import (
@@ -141,7 +142,9 @@ class Host:
 
     def to_polar(self, v):
         """Convert a Python object to a Polar term."""
-        if type(v) == bool:
+        if v is None:
+            return {"value": "Nil"}
+        elif type(v) == bool:
             val = {"Boolean": v}
         elif type(v) == int:
             val = {"Number": {"Integer": v}}
@@ -202,6 +205,8 @@ class Host:
     def to_python(self, value):
         """Convert a Polar term to a Python object."""
         value = value["value"]
+        if value == "Nil":
+            return None
         tag = [*value][0]
         if tag in ["String", "Boolean"]:
             return value[tag]
@@ -222,6 +227,11 @@ class Host:
             return number
         elif tag == "List":
             return [self.to_python(e) for e in value[tag]]
+        elif tag == "Set":
+            return {self.to_python(e) for e in value[tag]}
+        elif tag == "DateTime":
+            epoch = datetime(1970, 1, 1, tzinfo=timezone.utc)
+            return epoch + timedelta(milliseconds=value[tag]["epoch_ms"])
         elif tag == "Dictionary":
             return {k: self.to_python(v) for k, v in value[tag]["fields"].items()}
         elif tag == "ExternalInstance":
@@ -65,9 +65,6 @@ class Polar:
         self.ffi_polar = FfiPolar()
         self.host = Host(self.ffi_polar)
 
-        # Register global constants.
-        self.register_constant(None, name="nil")
-
         # Register built-in classes.
         self.register_class(bool, name="Boolean")
         self.register_class(int, name="Integer")
@@ -216,3 +213,10 @@ def polar_class(_cls=None, *, name=None):
         return wrap
 
     return wrap(_cls)
+
+
+def polar_uncached(func):
+    """Decorator for methods with side effects, so that Polar calls them
+    again rather than reusing their result within a query."""
+    func.__polar_uncached__ = True
+    return func
@@ -105,6 +105,8 @@ class Query:
             args = [self.host.to_python(arg) for arg in data["args"]]
             kwargs = data["kwargs"] or {}
             kwargs = {k: self.host.to_python(v) for k, v in kwargs.items()}
+            if getattr(attr, "__polar_uncached__", False):
+                self.ffi_query.uncache_call(call_id)
             result = attr(*args, **kwargs)
         elif not data["args"] is None:
             raise InvalidCallError(
@@ -1,9 +1,10 @@
-from datetime import datetime
+from datetime import datetime, timezone
 from math import inf, isnan, nan
 from pathlib import Path
 
 from polar import (
     polar_class,
+    polar_uncached,
     exceptions,
     Polar,
     Predicate,
@@ -52,14 +53,14 @@ def test_load_function(polar, query, qvar):
         polar.load_file(filename)
     assert (
         str(e.value)
-        == f"Problem loading file: File {filename} has already been loaded."
+        == f"[R0009] Problem loading file: File {filename} has already been loaded."
     )
 
     renamed = Path(__file__).parent / "test_file_renamed.polar"
     with pytest.raises(exceptions.PolarRuntimeError) as e:
         polar.load_file(renamed)
 
-    expected = f"Problem loading file: A file with the same contents as {renamed} named {filename} has already been loaded."
+    expected = f"[R0009] Problem loading file: A file with the same contents as {renamed} named {filename} has already been loaded."
     assert str(e.value) == expected
     assert query("f(x)") == [{"x": 1}, {"x": 2}, {"x": 3}]
     assert qvar("f(x)", "x") == [1, 2, 3]
@@ -400,7 +401,7 @@ def test_parser_errors(polar):
         polar.load_str(rules)
     assert (
         str(e.value)
-        == "hit the end of the file unexpectedly. Did you forget a semi-colon at line 2, column 9"
+        == "[P0004] hit the end of the file unexpectedly. Did you forget a semi-colon at line 2, column 9"
     )
 
     # UnrecognizedToken
@@ -409,7 +410,7 @@ def test_parser_errors(polar):
     """
     with pytest.raises(exceptions.UnrecognizedToken) as e:
         polar.load_str(rules)
-    assert str(e.value) == "did not expect to find the token '1' at line 2, column 5"
+    assert str(e.value) == "[P0005] did not expect to find the token '1' at line 2, column 5"
 
     # ExtraToken -- not sure what causes this
 
@@ -428,7 +429,7 @@ def test_runtime_errors(polar, query):
     foo(1,2)
   in rule foo at line 2, column 17
     a in b
-Type error: can only use `in` on an iterable value, this is Number(Integer(2)) at line 1, column 7"""
+[R0004] Type error: can only use `in` on an iterable value, this is Number(Integer(2)) at line 1, column 7"""
     )
 
 
@@ -620,13 +621,24 @@ def test_datetime(polar, query):
 
 
 def test_nil(polar, query, qvar):
-    """Test that nil is pre-registered as None."""
+    """Test that nil is exchanged with Python as None."""
     polar.load_str("null(nil);")
     assert qvar("null(x)", "x") == [None]
     assert query(Predicate("null", [None])) == [{}]
     assert not query(Predicate("null", [[]]))
 
 
+def test_datetime_literals(polar, qvar):
+    """Test that Polar datetimes are returned as aware Python datetimes."""
+    [t] = qvar("x = @2024-05-01T09:30:00Z", "x")
+    assert t == datetime(2024, 5, 1, 9, 30, tzinfo=timezone.utc)
+
+
+def test_sets(polar, qvar):
+    """Test that Polar sets are returned as Python sets."""
+    assert qvar("x = {2, 1, 2}", "x") == [{1, 2}]
+
+
 def test_other_constants(polar, qvar):
     """Test that other objects may be registered as constants."""
     d = {"a": 1}
@@ -902,6 +914,32 @@ def test_iterators(polar, qeval, qvar):
     assert qvar("x = new Bar([1, 2, 3]).sum()", "x", one=True) == 6
 
 
+def test_external_call_cache(polar, qvar, query):
+    class Foo:
+        count = 0
+
+        def items(self):
+            yield 1
+            yield 2
+
+        def roll(self):
+            self.count += 1
+            return self.count
+
+        @polar_uncached
+        def reroll(self):
+            self.count += 1
+            return self.count
+
+    polar.register_class(Foo)
+    # Each call makes a new generator, rather than reusing the exhausted one.
+    results = query("foo = new Foo() and x in foo.items() and y in foo.items()")
+    assert [(r["x"], r["y"]) for r in results] == [(1, 1), (1, 2), (2, 1), (2, 2)]
+    # Other calls are made once per query, unless they're marked uncached.
+    assert qvar("foo = new Foo() and n in [1, 2] and x = foo.roll()", "x") == [1, 1]
+    assert qvar("foo = new Foo() and n in [1, 2] and x = foo.reroll()", "x") == [1, 2]
+
+
 def test_unexpected_expression(polar):
     """Ensure expression type raises error from core."""
     polar.load_str("f(x) if x > 2;")
@@ -1,5 +1,7 @@
 # frozen_string_literal: true
 
+require 'set'
+
 module Oso
   module Polar
     # Translate between Polar and the host language (Ruby).
@@ -150,6 +152,8 @@ module Oso
       # @return [Hash<String, Object>]
       def to_polar(value) # rubocop:disable Metrics/AbcSize, Metrics/CyclomaticComplexity, Metrics/MethodLength, Metrics/PerceivedComplexity
         value = case true # rubocop:disable Lint/LiteralAsCondition
+                when value.nil?
+                  'Nil'
                 when value.instance_of?(TrueClass) || value.instance_of?(FalseClass)
                   { 'Boolean' => value }
                 when value.instance_of?(Integer)
@@ -189,6 +193,8 @@ module Oso
       # @return [Object]
       # @raise [UnexpectedPolarTypeError] if type cannot be converted to Ruby.
       def to_ruby(data) # rubocop:disable Metrics/AbcSize, Metrics/CyclomaticComplexity, Metrics/MethodLength, Metrics/PerceivedComplexity
+        return nil if data['value'] == 'Nil'
+
         tag, value = data['value'].first
         case tag
         when 'String', 'Boolean'
@@ -212,6 +218,10 @@ module Oso
           num
         when 'List'
           value.map { |el| to_ruby(el) }
+        when 'Set'
+          value.map { |el| to_ruby(el) }.to_set
+        when 'DateTime'
+          Time.at(0, value['epoch_ms'], :millisecond).utc
         when 'Dictionary'
           value['fields'].transform_values { |v| to_ruby(v) }
         when 'ExternalInstance'
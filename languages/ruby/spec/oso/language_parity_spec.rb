@@ -60,7 +60,7 @@ begin
   oso.load_str 'missingSemicolon()'
 rescue Oso::Polar::ParseError::UnrecognizedEOF => e
   exception_thrown = true
-  raise unless e.message == 'hit the end of the file unexpectedly. Did you forget a semi-colon at line 1, column 19'
+  raise unless e.message == '[P0004] hit the end of the file unexpectedly. Did you forget a semi-colon at line 1, column 19'
 end
 raise unless exception_thrown
 
@@ -115,7 +115,7 @@ RSpec.describe Oso::Polar::Polar do # rubocop:disable Metrics/BlockLength
 
       expect { subject.load_file(file.path) }.to raise_error do |e|
         expect(e).to be_an Oso::Polar::ParseError::UnrecognizedToken
-        expect(e.message).to eq("did not expect to find the token ';' at line 1, column 1 in file #{file.path}")
+        expect(e.message).to eq("[P0005] did not expect to find the token ';' at line 1, column 1 in file #{file.path}")
       end
     end
 
@@ -137,7 +137,7 @@ RSpec.describe Oso::Polar::Polar do # rubocop:disable Metrics/BlockLength
     it 'errors if file is already loaded' do
       expect { 2.times { subject.load_file(test_file) } }.to raise_error do |e|
         expect(e).to be_an Oso::Polar::FileLoadingError
-        expect(e.message).to eq("Problem loading file: File #{test_file} has already been loaded.")
+        expect(e.message).to eq("[R0009] Problem loading file: File #{test_file} has already been loaded.")
       end
     end
 
@@ -252,7 +252,7 @@ RSpec.describe Oso::Polar::Polar do # rubocop:disable Metrics/BlockLength
   end
 
   # test_nil
-  context 'nil is pre-registered' do
+  context 'nil is exchanged with Ruby' do
     it 'as nil' do
       subject.load_str('null(nil);')
       expect(subject.query('null(x)').to_a).to eq [{ 'x' => nil }]
@@ -640,7 +640,7 @@ RSpec.describe Oso::Polar::Polar do # rubocop:disable Metrics/BlockLength
       POLAR
       expect { subject.load_str(rule) }.to raise_error do |e|
         expect(e).to be_an Oso::Polar::ParseError::UnrecognizedEOF
-        expect(e.message).to eq('hit the end of the file unexpectedly. Did you forget a semi-colon at line 1, column 5')
+        expect(e.message).to eq('[P0004] hit the end of the file unexpectedly. Did you forget a semi-colon at line 1, column 5')
       end
     end
 
@@ -650,7 +650,7 @@ RSpec.describe Oso::Polar::Polar do # rubocop:disable Metrics/BlockLength
       POLAR
       expect { subject.load_str(rule) }.to raise_error do |e|
         expect(e).to be_an Oso::Polar::ParseError::UnrecognizedToken
-        expect(e.message).to eq("did not expect to find the token '1' at line 1, column 1")
+        expect(e.message).to eq("[P0005] did not expect to find the token '1' at line 1, column 1")
       end
     end
 
@@ -695,7 +695,7 @@ RSpec.describe Oso::Polar::Polar do # rubocop:disable Metrics/BlockLength
               foo(1,2)
             in rule foo at line 1, column 13
               a in b
-          Type error: can only use `in` on an iterable value, this is Number(Integer(2)) at line 1, column 7
+          [R0004] Type error: can only use `in` on an iterable value, this is Number(Integer(2)) at line 1, column 7
         TRACE
         expect(e.message).to eq(error)
       end
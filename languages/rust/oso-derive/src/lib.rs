@@ -1,10 +1,11 @@
+extern crate polar_core;
 extern crate proc_macro;
 extern crate quote;
 extern crate syn;
 
 use proc_macro::TokenStream;
 use quote::quote;
-use syn::{Attribute, Fields, Lit, Meta, MetaNameValue, NestedMeta, Path};
+use syn::{Attribute, Fields, Lit, LitStr, Meta, MetaNameValue, NestedMeta, Path};
 
 #[derive(Debug, PartialEq)]
 enum OsoAttribute {
@@ -127,3 +128,31 @@ pub fn derive_polar_class_impl(ts: TokenStream) -> TokenStream {
     };
     result.into()
 }
+
+/// Embed a policy file in the program as a `&'static str`, like
+/// `include_str!`, but parse it while compiling, so that syntax errors in
+/// the policy fail the build. The path is relative to the crate's root,
+/// i.e., the directory containing its `Cargo.toml`.
+///
+/// ```ignore
+/// oso.load_str(oso::include_polar!("policies/authorization.polar"))?;
+/// ```
+#[proc_macro]
+pub fn include_polar(ts: TokenStream) -> TokenStream {
+    let path = syn::parse_macro_input!(ts as LitStr);
+    let root = std::env::var("CARGO_MANIFEST_DIR").unwrap_or_default();
+    let file = std::path::Path::new(&root).join(path.value());
+    let file = file.to_string_lossy();
+    let result = std::fs::read_to_string(file.as_ref())
+        .map_err(|e| format!("Failed to read {}: {}", file, e))
+        .and_then(|src| {
+            polar_core::parser::parse_lines(0, &src)
+                .map_err(|e| format!("Invalid policy in {}: {}", file, e))
+        });
+    match result {
+        // Include the file rather than its contents, so that the build
+        // is redone when it changes.
+        Ok(_) => quote!(include_str!(#file)).into(),
+        Err(msg) => syn::Error::new(path.span(), msg).to_compile_error().into(),
+    }
+}
@@ -2,6 +2,7 @@
 
 use crate::PolarValue;
 use std::collections::HashMap;
+use std::time::SystemTime;
 
 use crate::{Class, ClassBuilder};
 
@@ -25,6 +26,11 @@ fn dictionary() -> ClassBuilder<HashMap<String, PolarValue>> {
     ClassBuilder::<HashMap<String, PolarValue>>::with_default().name("Dictionary")
 }
 
+/// Datetimes are Polar values, so the class is only for matching them.
+fn datetime() -> ClassBuilder<SystemTime> {
+    Class::builder::<SystemTime>().name("DateTime")
+}
+
 fn option() -> ClassBuilder<Option<PolarValue>> {
     ClassBuilder::<Option<PolarValue>>::with_default()
         .name("Option")
@@ -157,6 +163,7 @@ pub fn classes() -> Vec<Class> {
         list().build(),
         dictionary().build(),
         string().build(),
+        datetime().build(),
         option().build(),
     ]
 }
@@ -1,7 +1,7 @@
 //! Support for dynamic class objects in Rust
 
 use std::any::TypeId;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::sync::Arc;
 
@@ -53,6 +53,8 @@ pub struct Class {
     attributes: Attributes,
     /// Instance methods on `T` that expect a list of `PolarValue`s, and an instance of `&T`
     instance_methods: InstanceMethods,
+    /// Names of instance methods whose results Polar mustn't reuse
+    uncached_methods: HashSet<&'static str>,
     /// Class methods on `T`
     class_methods: ClassMethods,
 
@@ -112,6 +114,11 @@ impl Class {
     fn equals(&self, host: &Host, lhs: &Instance, rhs: &Instance) -> crate::Result<bool> {
         (self.equality_check)(host, lhs, rhs)
     }
+
+    /// Whether the method was added with `add_uncached_method`.
+    pub fn is_uncached(&self, name: &str) -> bool {
+        self.uncached_methods.contains(name)
+    }
 }
 
 #[derive(Clone)]
@@ -135,6 +142,7 @@ where
                 constructor: None,
                 attributes: HashMap::new(),
                 instance_methods: InstanceMethods::new(),
+                uncached_methods: HashSet::new(),
                 class_methods: ClassMethods::new(),
                 class_check: Arc::new(|type_id| TypeId::of::<T>() == type_id),
                 equality_check: Arc::from(equality_not_supported()),
@@ -262,6 +270,18 @@ where
         self
     }
 
+    /// Add a method with side effects, which Polar calls every time rather
+    /// than reusing its result for identical calls within a query.
+    pub fn add_uncached_method<F, Args, R>(mut self, name: &'static str, f: F) -> Self
+    where
+        Args: FromPolarList,
+        F: Method<T, Args, Result = R>,
+        R: ToPolarResult + 'static,
+    {
+        self.class.uncached_methods.insert(name);
+        self.add_method(name, f)
+    }
+
     /// A method that returns multiple values. Every element in the iterator returned by the method will
     /// be a separate polar return value.
     pub fn add_iterator_method<F, Args, I>(mut self, name: &'static str, f: F) -> Self
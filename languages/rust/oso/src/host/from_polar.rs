@@ -5,6 +5,7 @@
 use std::collections::{BTreeMap, BTreeSet, BinaryHeap, HashMap, HashSet, LinkedList, VecDeque};
 use std::convert::TryFrom;
 use std::hash::Hash;
+use std::time::SystemTime;
 
 use impl_trait_for_tuples::*;
 
@@ -97,6 +98,16 @@ impl FromPolar for String {
     }
 }
 
+impl FromPolar for SystemTime {
+    fn from_polar(val: PolarValue) -> crate::Result<Self> {
+        if let PolarValue::DateTime(t) = val {
+            Ok(t)
+        } else {
+            Err(TypeError::expected("DateTime").user())
+        }
+    }
+}
+
 impl FromPolar for bool {
     fn from_polar(val: PolarValue) -> crate::Result<Self> {
         if let PolarValue::Boolean(b) = val {
@@ -181,7 +192,7 @@ impl<T: FromPolar> FromPolar for VecDeque<T> {
 
 impl<T: Eq + Hash + FromPolar> FromPolar for HashSet<T> {
     fn from_polar(val: PolarValue) -> crate::Result<Self> {
-        if let PolarValue::List(l) = val {
+        if let PolarValue::List(l) | PolarValue::Set(l) = val {
             let mut result = HashSet::new();
             for v in l {
                 result.insert(T::from_polar(v)?);
@@ -195,7 +206,7 @@ impl<T: Eq + Hash + FromPolar> FromPolar for HashSet<T> {
 
 impl<T: Eq + Ord + FromPolar> FromPolar for BTreeSet<T> {
     fn from_polar(val: PolarValue) -> crate::Result<Self> {
-        if let PolarValue::List(l) = val {
+        if let PolarValue::List(l) | PolarValue::Set(l) = val {
             let mut result = BTreeSet::new();
             for v in l {
                 result.insert(T::from_polar(v)?);
@@ -223,6 +234,9 @@ impl<T: Ord + FromPolar> FromPolar for BinaryHeap<T> {
 
 impl<T: FromPolar> FromPolar for Option<T> {
     fn from_polar(val: PolarValue) -> crate::Result<Self> {
+        if let PolarValue::Nil = val {
+            return Ok(None);
+        }
         // if the value is a Option<PolarValue>, convert from PolarValue
         if let PolarValue::Instance(ref instance) = &val {
             if let Ok(opt) = instance.downcast::<Option<PolarValue>>(None) {
@@ -305,6 +319,8 @@ impl FromPolar for Instance {
             PolarValue::List(v) => Instance::new(v),
             PolarValue::String(s) => Instance::new(s),
             PolarValue::Map(d) => Instance::new(d),
+            PolarValue::DateTime(t) => Instance::new(t),
+            PolarValue::Nil => Instance::new(Option::<PolarValue>::None),
             PolarValue::Instance(instance) => instance,
             v => {
                 tracing::warn!(value = ?v, "invalid conversion attempted");
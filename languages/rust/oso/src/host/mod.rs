@@ -173,6 +173,8 @@ impl Host {
             PolarValue::Integer(_) => class_tag == "Integer",
             PolarValue::Float(_) => class_tag == "Float",
             PolarValue::String(_) => class_tag == "String",
+            PolarValue::DateTime(_) => class_tag == "DateTime",
+            PolarValue::Nil => class_tag == "Nil",
             _ => false,
         };
         Ok(res)
@@ -4,6 +4,7 @@
 use impl_trait_for_tuples::*;
 
 use std::collections::{BTreeMap, BTreeSet, BinaryHeap, HashMap, HashSet, LinkedList, VecDeque};
+use std::time::SystemTime;
 
 use super::DEFAULT_CLASSES;
 use crate::PolarValue;
@@ -148,6 +149,12 @@ macro_rules! float_to_polar {
 float_to_polar!(f32);
 float_to_polar!(f64);
 
+impl ToPolar for SystemTime {
+    fn to_polar(self) -> PolarValue {
+        PolarValue::DateTime(self)
+    }
+}
+
 impl ToPolar for String {
     fn to_polar(self) -> PolarValue {
         PolarValue::String(self)
@@ -242,7 +249,10 @@ impl ToPolar for PolarValue {
 
 impl<T: ToPolar> ToPolar for Option<T> {
     fn to_polar(self) -> PolarValue {
-        PolarValue::new_from_instance(self.map(|t| t.to_polar()))
+        match self {
+            Some(t) => PolarValue::new_from_instance(Some(t.to_polar())),
+            None => PolarValue::Nil,
+        }
     }
 }
 
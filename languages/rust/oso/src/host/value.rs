@@ -1,5 +1,6 @@
 use polar_core::terms::*;
 use std::collections::hash_map::HashMap;
+use std::time::SystemTime;
 
 use crate::host::{Host, Instance};
 
@@ -19,6 +20,13 @@ pub enum PolarValue {
     Boolean(bool),
     Map(HashMap<String, PolarValue>),
     List(Vec<PolarValue>),
+    /// Distinct values, in no particular order. Rust sets are sent to Polar
+    /// as lists; build this variant to send a Polar set.
+    Set(Vec<PolarValue>),
+    /// An instant, truncated to the millisecond in Polar.
+    DateTime(SystemTime),
+    /// No value, e.g., `None`.
+    Nil,
     Variable(String),
     Instance(Instance),
 }
@@ -31,7 +39,12 @@ impl PartialEq for PolarValue {
             (PolarValue::Integer(i1), PolarValue::Integer(i2)) => i1 == i2,
             (PolarValue::List(l1), PolarValue::List(l2)) => l1 == l2,
             (PolarValue::Map(m1), PolarValue::Map(m2)) => m1 == m2,
+            (PolarValue::Set(s1), PolarValue::Set(s2)) => {
+                s1.len() == s2.len() && s1.iter().all(|v| s2.contains(v))
+            }
             (PolarValue::String(s1), PolarValue::String(s2)) => s1 == s2,
+            (PolarValue::DateTime(t1), PolarValue::DateTime(t2)) => t1 == t2,
+            (PolarValue::Nil, PolarValue::Nil) => true,
             _ => false,
         }
     }
@@ -52,6 +65,8 @@ impl PolarValue {
             Value::Number(Numeric::Float(f)) => PolarValue::Float(*f),
             Value::String(s) => PolarValue::String(s.clone()),
             Value::Boolean(b) => PolarValue::Boolean(*b),
+            Value::DateTime(dt) => PolarValue::DateTime((*dt).into()),
+            Value::Nil => PolarValue::Nil,
             Value::Dictionary(dict) => {
                 let mut map = HashMap::new();
                 for (k, v) in &dict.fields {
@@ -71,6 +86,13 @@ impl PolarValue {
                 }
                 PolarValue::List(list)
             }
+            Value::Set(set) => {
+                let mut elements = vec![];
+                for t in &set.elements {
+                    elements.push(PolarValue::from_term(t, host)?);
+                }
+                PolarValue::Set(elements)
+            }
             Value::Variable(Symbol(sym)) => PolarValue::Variable(sym.clone()),
             Value::Expression(_) => {
                 return Err(crate::OsoError::Custom {
@@ -97,6 +119,8 @@ This may mean you performed an operation in your policy over an unbound variable
             PolarValue::Float(f) => Value::Number(Numeric::Float(*f)),
             PolarValue::String(s) => Value::String(s.clone()),
             PolarValue::Boolean(b) => Value::Boolean(*b),
+            PolarValue::DateTime(t) => Value::DateTime((*t).into()),
+            PolarValue::Nil => Value::Nil,
             PolarValue::Map(map) => {
                 let mut dict = Dictionary::new();
                 for (k, v) in map {
@@ -107,11 +131,16 @@ This may mean you performed an operation in your policy over an unbound variable
                 Value::Dictionary(dict)
             }
             PolarValue::Instance(instance) => {
+                let class_tag = instance
+                    .class(host)
+                    .ok()
+                    .map(|class| Symbol(class.name.clone()));
                 let id = host.cache_instance(instance.clone(), None);
                 Value::ExternalInstance(ExternalInstance {
                     constructor: None,
                     repr: Some(std::any::type_name::<Self>().to_owned()),
                     instance_id: id,
+                    class_tag,
                 })
             }
             PolarValue::List(l) => {
@@ -121,6 +150,13 @@ This may mean you performed an operation in your policy over an unbound variable
                 }
                 Value::List(list)
             }
+            PolarValue::Set(s) => {
+                let mut elements = vec![];
+                for v in s {
+                    elements.push(v.to_term(host))
+                }
+                Value::Set(Set::new(elements))
+            }
             PolarValue::Variable(s) => Value::Variable(Symbol(s.clone())),
         };
         Term::new_from_ffi(value)
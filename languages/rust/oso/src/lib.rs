@@ -31,6 +31,13 @@
 //! # }
 //! ```
 //!
+//! With the `derive` feature, `include_polar!` embeds a policy file in the
+//! program, and fails the build if the policy has a syntax error:
+//!
+//! ```ignore
+//! oso.load_str(oso::include_polar!("policies/authorization.polar"))?;
+//! ```
+//!
 //! You can register classes with oso, which makes it possible to use them for type checking,
 //! as well as accessing attributes in policies.
 //! The `PolarClass` derive macro can handle some of this
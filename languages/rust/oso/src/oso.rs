@@ -37,8 +37,6 @@ impl Oso {
             oso.register_class(class)
                 .expect("failed to register builtin class");
         }
-        oso.register_constant(Option::<crate::PolarValue>::None, "nil")
-            .expect("failed to register the constant None");
         oso
     }
 
@@ -98,6 +96,15 @@ impl Oso {
         self.check_inline_queries()
     }
 
+    /// Load a policy package: a directory of polar files with a
+    /// `polar-package.json` manifest listing the files in load order and the
+    /// rules the package exports. Rules it doesn't export are private to it.
+    /// Packages it depends on are loaded from the directories next to it.
+    pub fn load_package<P: AsRef<std::path::Path>>(&self, path: P) -> crate::Result<()> {
+        self.inner.load_package(path)?;
+        self.check_inline_queries()
+    }
+
     /// Load a string of polar source directly.
     /// # Examples
     /// ```ignore
@@ -154,6 +161,54 @@ impl Oso {
         self.register_constant(class, &class_name)
     }
 
+    /// Add the fact `name(args...)` to the knowledge base without going
+    /// through the parser, e.g., to sync role assignments from a database.
+    /// # Examples
+    /// ```ignore
+    /// oso.add_fact("has_role", (user, "admin"));
+    /// ```
+    pub fn add_fact(&mut self, name: &str, args: impl ToPolarList) -> crate::Result<()> {
+        let args = args
+            .to_polar_list()
+            .iter()
+            .map(|value| value.to_term(&mut self.host))
+            .collect();
+        self.inner.add_fact(Symbol(name.to_string()), args)?;
+        Ok(())
+    }
+
+    /// Remove facts `name(args...)` from the knowledge base, and return how
+    /// many were removed.
+    pub fn remove_fact(&mut self, name: &str, args: impl ToPolarList) -> crate::Result<usize> {
+        let args = args
+            .to_polar_list()
+            .iter()
+            .map(|value| value.to_term(&mut self.host))
+            .collect();
+        Ok(self.inner.remove_fact(Symbol(name.to_string()), args)?)
+    }
+
+    /// Remove facts `name(args...)` whose arguments match `pattern`, in
+    /// which variables match any value, and return how many were removed.
+    /// # Examples
+    /// ```ignore
+    /// oso.remove_facts_matching("has_role", ("alice", PolarValue::Variable("_".into())));
+    /// ```
+    pub fn remove_facts_matching(
+        &mut self,
+        name: &str,
+        pattern: impl ToPolarList,
+    ) -> crate::Result<usize> {
+        let pattern = pattern
+            .to_polar_list()
+            .iter()
+            .map(|value| value.to_term(&mut self.host))
+            .collect();
+        Ok(self
+            .inner
+            .remove_facts_matching(Symbol(name.to_string()), pattern)?)
+    }
+
     /// Register a rust type as a Polar constant.
     /// See [`oso::Class`] docs.
     pub fn register_constant<V: crate::host::ToPolar + Send + Sync>(
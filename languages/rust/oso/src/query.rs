@@ -15,6 +15,7 @@ impl Iterator for Query {
     }
 }
 
+/// Results of a query, computed one at a time as the iterator is advanced.
 pub struct Query {
     inner: polar_core::polar::Query,
     /// Stores a map from call_id to the iterator the call iterates through
@@ -41,7 +42,9 @@ impl Query {
             let event = event.unwrap();
             tracing::debug!(event=?event);
             let result = match event {
-                QueryEvent::None => Ok(()),
+                QueryEvent::None | QueryEvent::Heartbeat { .. } | QueryEvent::Progress { .. } => {
+                    Ok(())
+                }
                 QueryEvent::Done { .. } => return None,
                 QueryEvent::Result { bindings, .. } => {
                     return Some(ResultSet::from_bindings(bindings, self.host.clone()));
@@ -187,6 +190,11 @@ impl Query {
                 .iter()
                 .map(|v| PolarValue::from_term(v, &self.host))
                 .collect::<crate::Result<Vec<PolarValue>>>()?;
+            if let Ok(class) = instance.class(&self.host) {
+                if class.is_uncached(&name.0) {
+                    self.inner.uncache_call(call_id);
+                }
+            }
             instance.call(&name.0, args, &mut self.host)
         } else {
             instance.get_attr(&name.0, &mut self.host)
@@ -91,7 +91,7 @@ fn test() {
 
     assert_eq!(
         oso.load_str("missingSemicolon()").unwrap_err().to_string(),
-        "hit the end of the file unexpectedly. Did you forget a semi-colon at line 1, column 19"
+        "[P0004] hit the end of the file unexpectedly. Did you forget a semi-colon at line 1, column 19"
     );
 
     // let d = D("")
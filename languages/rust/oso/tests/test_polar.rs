@@ -1,7 +1,8 @@
 /// Common tests for all integrations.
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::io::Write;
 use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use oso::{Class, FromPolar, Oso, OsoError, PolarClass, PolarValue};
 use polar_core::error as polar_error;
@@ -131,6 +132,40 @@ fn test_data_conversions_polar_values() -> oso::Result<()> {
     Ok(())
 }
 
+#[test]
+fn test_add_fact() -> oso::Result<()> {
+    common::setup();
+    let mut test = test_oso();
+    test.load_str(r#"allow(actor, "read", doc) if has_role(actor, "owner", doc);"#);
+    test.oso.add_fact(
+        "has_role",
+        (Actor::new(String::from("sam")), "owner", "doc1"),
+    )?;
+
+    let results = test
+        .oso
+        .query_rule("allow", (PolarValue::Variable("a".into()), "read", "doc1"))?
+        .collect::<oso::Result<Vec<_>>>()?;
+    assert_eq!(results.len(), 1);
+    let actor: Actor = results[0].get_typed("a")?;
+    assert_eq!(actor.name, "sam");
+    Ok(())
+}
+
+#[test]
+fn test_remove_facts() -> oso::Result<()> {
+    common::setup();
+    let mut test = test_oso();
+    for (user, role) in &[("sam", "owner"), ("sam", "viewer"), ("ann", "viewer")] {
+        test.oso.add_fact("has_role", (*user, *role))?;
+    }
+    assert_eq!(test.oso.remove_fact("has_role", ("ann", "viewer"))?, 1);
+    let any = PolarValue::Variable("_role".into());
+    assert_eq!(test.oso.remove_facts_matching("has_role", ("sam", any))?, 2);
+    test.qnull("has_role(_user, _role)");
+    Ok(())
+}
+
 // TODO (dhatch): No predicate right now.
 #[ignore]
 #[test]
@@ -196,7 +231,7 @@ fn test_load_file_error_contains_filename() {
         assert_eq!(
             err.to_string(),
             format!(
-                "did not expect to find the token ';' at line 1, column 1 in file {}",
+                "[P0005] did not expect to find the token ';' at line 1, column 1 in file {}",
                 tempfile.path().to_string_lossy().into_owned()
             )
         );
@@ -244,7 +279,7 @@ fn test_already_loaded_file_error() -> oso::Result<()> {
                 polar_error::ErrorKind::Runtime(polar_error::RuntimeError::FileLoading { .. }),
             ..
         })
-    if err.to_string() == format!("Problem loading file: File {} has already been loaded.", path.to_string_lossy())),
+    if err.to_string() == format!("[R0009] Problem loading file: File {} has already been loaded.", path.to_string_lossy())),
         "Error was {:?}",
         &err
     );
@@ -394,6 +429,34 @@ fn test_host_method_dict() {
 
 // test_host_method_nil skipped. Covered by option tests.
 
+#[test]
+fn test_compare_host_strings() -> oso::Result<()> {
+    common::setup();
+
+    let mut oso = test_oso();
+
+    #[derive(PolarClass, Debug, Clone)]
+    struct User {
+        #[polar(attribute)]
+        name: String,
+    }
+
+    oso.oso.register_class(User::get_polar_class())?;
+    let alice = User {
+        name: String::from("alice"),
+    };
+    oso.oso.register_constant(alice, "alice")?;
+
+    oso.qeval(r#"alice.name == "alice""#);
+    oso.qeval(r#"alice.name != "bob""#);
+    oso.qeval(r#"alice.name < "alicf" and alice.name <= "alice""#);
+    oso.qeval(r#""bob" > alice.name and alice.name >= "al""#);
+    oso.qnull(r#"alice.name < "abc""#);
+    oso.qnull(r#"alice.name != "alice""#);
+
+    Ok(())
+}
+
 #[test]
 fn test_duplicate_register_class() -> oso::Result<()> {
     common::setup();
@@ -761,6 +824,72 @@ fn test_predicate_return_list() {
     assert_eq!(result.keys().count(), 0);
 }
 
+#[test]
+fn test_host_sets() -> oso::Result<()> {
+    common::setup();
+
+    let mut oso = test_oso();
+    oso.load_str(
+        r#"is_admin(roles) if "admin" in roles;
+           shared(a, b, common) if intersection(a, b, common);"#,
+    );
+
+    let roles: HashSet<&str> = vec!["admin", "viewer"].into_iter().collect();
+    assert!(oso.oso.query_rule("is_admin", (roles,))?.next().is_some());
+
+    let mut query = oso.oso.query_rule(
+        "shared",
+        (
+            vec![1, 2, 3].into_iter().collect::<BTreeSet<i64>>(),
+            vec![2, 3, 4],
+            PolarValue::Variable("common".to_owned()),
+        ),
+    )?;
+    let result = query.next().unwrap()?;
+    let common: BTreeSet<i64> = result.get_typed("common")?;
+    assert_eq!(common, vec![2, 3].into_iter().collect());
+    assert_eq!(
+        result.get("common"),
+        Some(PolarValue::Set(vec![
+            PolarValue::Integer(3),
+            PolarValue::Integer(2)
+        ]))
+    );
+    Ok(())
+}
+
+#[test]
+fn test_host_datetimes() -> oso::Result<()> {
+    common::setup();
+
+    let mut oso = test_oso();
+    oso.load_str(
+        r#"active(expires_at: DateTime) if @2024-05-01T12:00:00Z < expires_at;
+           launch(@2024-05-01T09:30:00+02:00);"#,
+    );
+
+    let expiry = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+    // 2024-05-01T12:00:00Z
+    let noon = 1_714_564_800;
+    assert!(oso
+        .oso
+        .query_rule("active", (expiry(noon + 1),))?
+        .next()
+        .is_some());
+    assert!(oso
+        .oso
+        .query_rule("active", (expiry(noon),))?
+        .next()
+        .is_none());
+
+    let mut query = oso
+        .oso
+        .query_rule("launch", (PolarValue::Variable("t".to_owned()),))?;
+    let launch: SystemTime = query.next().unwrap()?.get_typed("t")?;
+    assert_eq!(launch, expiry(noon - 4 * 60 * 60 - 30 * 60));
+    Ok(())
+}
+
 // TODO (dhatch): API not great.
 
 #[test]
@@ -913,6 +1042,36 @@ fn test_nil() {
     oso.qnull("x in nil");
 }
 
+#[test]
+fn test_nil_attributes() -> oso::Result<()> {
+    common::setup();
+
+    let mut oso = test_oso();
+
+    #[derive(PolarClass, Debug, Clone)]
+    struct User {
+        #[polar(attribute)]
+        manager: Option<String>,
+    }
+
+    oso.oso.register_class(User::get_polar_class())?;
+    oso.oso.register_constant(
+        User {
+            manager: Some("bob".to_owned()),
+        },
+        "alice",
+    )?;
+    oso.oso.register_constant(User { manager: None }, "bob")?;
+
+    oso.qeval("alice.manager != nil and alice.manager.unwrap() = \"bob\"");
+    oso.qeval("bob.manager == nil and bob.manager = nil");
+    oso.qnull("bob.manager != nil");
+    oso.qnull("alice.manager = nil");
+    oso.qvar_one("x = bob.manager.name", "x", Option::<String>::None);
+
+    Ok(())
+}
+
 #[test]
 fn test_expression_error() {
     common::setup();
@@ -923,3 +1082,14 @@ fn test_expression_error() {
     let err = oso.query_err("f(x)");
     assert!(err.contains("unbound"));
 }
+
+#[test]
+fn test_include_polar() -> oso::Result<()> {
+    common::setup();
+    let mut test = test_oso();
+    test.oso
+        .load_str(oso::include_polar!("tests/test_file.polar"))?;
+    let results: Vec<i64> = test.qvar("f(x)", "x");
+    assert_eq!(results, vec![1, 2, 3]);
+    Ok(())
+}
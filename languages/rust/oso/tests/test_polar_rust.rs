@@ -509,6 +509,56 @@ fn test_values() {
     assert!(result == vec![vec![1, 2, 3]]);
 }
 
+#[test]
+fn test_external_call_cache() {
+    let _ = tracing_subscriber::fmt::try_init();
+
+    #[derive(PolarClass)]
+    struct Foo {
+        count: std::sync::atomic::AtomicI64,
+    }
+
+    impl Foo {
+        fn new() -> Self {
+            Self {
+                count: std::sync::atomic::AtomicI64::new(0),
+            }
+        }
+
+        fn one_two(&self) -> Vec<i64> {
+            vec![1, 2]
+        }
+
+        fn roll(&self) -> i64 {
+            self.count.fetch_add(1, std::sync::atomic::Ordering::SeqCst) + 1
+        }
+    }
+
+    let mut test = OsoTest::new();
+    test.oso
+        .register_class(
+            Foo::get_polar_class_builder()
+                .set_constructor(Foo::new)
+                .add_iterator_method("one_two", Foo::one_two)
+                .add_method("roll", Foo::roll)
+                .add_uncached_method("reroll", Foo::roll)
+                .build(),
+        )
+        .unwrap();
+
+    // Iterating over the same method's results twice sees all of them each time.
+    let results: Vec<i64> = test.qvar(
+        "foo = new Foo() and x in foo.one_two() and _ in foo.one_two()",
+        "x",
+    );
+    assert_eq!(results, vec![1, 1, 2, 2]);
+    // Other calls are made once per query, unless they're added as uncached.
+    let results: Vec<i64> = test.qvar("foo = new Foo() and _ in [1, 2] and x = foo.roll()", "x");
+    assert_eq!(results, vec![1, 1]);
+    let results: Vec<i64> = test.qvar("foo = new Foo() and _ in [1, 2] and x = foo.reroll()", "x");
+    assert_eq!(results, vec![1, 2]);
+}
+
 #[test]
 fn test_arg_number() {
     let _ = tracing_subscriber::fmt::try_init();
@@ -102,6 +102,122 @@ pub extern "C" fn polar_load(
     })
 }
 
+#[no_mangle]
+pub extern "C" fn polar_load_versioned(
+    polar_ptr: *mut Polar,
+    src: *const c_char,
+    filename: *const c_char,
+    version: *const c_char,
+) -> i32 {
+    ffi_try!({
+        let polar = unsafe { ffi_ref!(polar_ptr) };
+        let src = unsafe { ffi_string!(src) };
+        let filename = if filename.is_null() {
+            None
+        } else {
+            Some(unsafe { ffi_string!(filename) }.to_string())
+        };
+        let version = unsafe { ffi_string!(version) };
+
+        match polar.load_versioned(&src, filename, version.to_string()) {
+            Err(err) => {
+                set_error(err);
+                POLAR_FAILURE
+            }
+            Ok(_) => POLAR_SUCCESS,
+        }
+    })
+}
+
+/// Load `src` like `polar_load`, with `options` a JSON serialized
+/// `LoadOptions`, e.g., `{"inline_queries": "Execute"}`.
+#[no_mangle]
+pub extern "C" fn polar_load_with_options(
+    polar_ptr: *mut Polar,
+    src: *const c_char,
+    filename: *const c_char,
+    options: *const c_char,
+) -> i32 {
+    ffi_try!({
+        let polar = unsafe { ffi_ref!(polar_ptr) };
+        let src = unsafe { ffi_string!(src) };
+        let filename = if filename.is_null() {
+            None
+        } else {
+            Some(unsafe { ffi_string!(filename) }.to_string())
+        };
+        let options = unsafe { ffi_string!(options) };
+        match serde_json::from_str(&options) {
+            Ok(options) => match polar.load_with_options(&src, filename, &options) {
+                Ok(_) => POLAR_SUCCESS,
+                Err(e) => {
+                    set_error(e);
+                    POLAR_FAILURE
+                }
+            },
+            Err(e) => {
+                set_error(error::RuntimeError::Serialization { msg: e.to_string() }.into());
+                POLAR_FAILURE
+            }
+        }
+    })
+}
+
+/// Load the policy package in directory `path`.
+#[no_mangle]
+pub extern "C" fn polar_load_package(polar_ptr: *mut Polar, path: *const c_char) -> i32 {
+    ffi_try!({
+        let polar = unsafe { ffi_ref!(polar_ptr) };
+        let path = unsafe { ffi_string!(path) };
+        match polar.load_package(path.as_ref()) {
+            Err(err) => {
+                set_error(err);
+                POLAR_FAILURE
+            }
+            Ok(_) => POLAR_SUCCESS,
+        }
+    })
+}
+
+/// Declare the actor and resource types the host authorizes; `types` is a
+/// JSON serialized `AuthorizationTypes`.
+#[no_mangle]
+pub extern "C" fn polar_declare_authorization_types(
+    polar_ptr: *mut Polar,
+    types: *const c_char,
+) -> i32 {
+    ffi_try!({
+        let polar = unsafe { ffi_ref!(polar_ptr) };
+        let types = unsafe { ffi_string!(types) };
+        match serde_json::from_str(&types) {
+            Ok(types) => {
+                polar.declare_authorization_types(types);
+                POLAR_SUCCESS
+            }
+            Err(e) => {
+                set_error(error::RuntimeError::Serialization { msg: e.to_string() }.into());
+                POLAR_FAILURE
+            }
+        }
+    })
+}
+
+/// Fail if the loaded `allow` rules don't cover the declared authorization
+/// types.
+#[no_mangle]
+pub extern "C" fn polar_check_coverage(polar_ptr: *mut Polar) -> i32 {
+    ffi_try!({
+        let polar = unsafe { ffi_ref!(polar_ptr) };
+        match polar.check_coverage() {
+            Err(err) => {
+                set_error(err);
+                POLAR_FAILURE
+            }
+            Ok(_) => POLAR_SUCCESS,
+        }
+    })
+}
+
 #[no_mangle]
 pub extern "C" fn polar_clear_rules(polar_ptr: *mut Polar) -> i32 {
     ffi_try!({
@@ -135,6 +251,294 @@ pub extern "C" fn polar_register_constant(
     })
 }
 
+/// Add the fact `name(args...)`; `args` is a JSON serialized list of terms.
+#[no_mangle]
+pub extern "C" fn polar_add_fact(
+    polar_ptr: *mut Polar,
+    name: *const c_char,
+    args: *const c_char,
+) -> i32 {
+    ffi_try!({
+        let polar = unsafe { ffi_ref!(polar_ptr) };
+        let name = unsafe { ffi_string!(name) };
+        let args = unsafe { ffi_string!(args) };
+        match serde_json::from_str(&args) {
+            Ok(args) => match polar.add_fact(terms::Symbol::new(name.as_ref()), args) {
+                Ok(_) => POLAR_SUCCESS,
+                Err(e) => {
+                    set_error(e);
+                    POLAR_FAILURE
+                }
+            },
+            Err(e) => {
+                set_error(error::RuntimeError::Serialization { msg: e.to_string() }.into());
+                POLAR_FAILURE
+            }
+        }
+    })
+}
+
+/// Add many facts `name(args...)` at once; `facts` is a JSON serialized list
+/// of the arguments of each fact, as lists of values.
+#[no_mangle]
+pub extern "C" fn polar_load_facts(
+    polar_ptr: *mut Polar,
+    name: *const c_char,
+    facts: *const c_char,
+) -> i32 {
+    ffi_try!({
+        let polar = unsafe { ffi_ref!(polar_ptr) };
+        let name = unsafe { ffi_string!(name) };
+        let facts = unsafe { ffi_string!(facts) };
+        match serde_json::from_str::<Vec<Vec<terms::Value>>>(&facts) {
+            Ok(facts) => {
+                match polar.load_facts(terms::Symbol::new(name.as_ref()), facts.into_iter()) {
+                    Ok(_) => POLAR_SUCCESS,
+                    Err(e) => {
+                        set_error(e);
+                        POLAR_FAILURE
+                    }
+                }
+            }
+            Err(e) => {
+                set_error(error::RuntimeError::Serialization { msg: e.to_string() }.into());
+                POLAR_FAILURE
+            }
+        }
+    })
+}
+
+/// Remove facts `name(args...)`; `args` is a JSON serialized list of terms.
+#[no_mangle]
+pub extern "C" fn polar_remove_fact(
+    polar_ptr: *mut Polar,
+    name: *const c_char,
+    args: *const c_char,
+) -> i32 {
+    ffi_try!({
+        let polar = unsafe { ffi_ref!(polar_ptr) };
+        let name = unsafe { ffi_string!(name) };
+        let args = unsafe { ffi_string!(args) };
+        match serde_json::from_str(&args) {
+            Ok(args) => match polar.remove_fact(terms::Symbol::new(name.as_ref()), args) {
+                Ok(_) => POLAR_SUCCESS,
+                Err(e) => {
+                    set_error(e);
+                    POLAR_FAILURE
+                }
+            },
+            Err(e) => {
+                set_error(error::RuntimeError::Serialization { msg: e.to_string() }.into());
+                POLAR_FAILURE
+            }
+        }
+    })
+}
+
+/// Remove facts `name(args...)` whose arguments match `pattern`, a JSON
+/// serialized list of terms in which variables match any value.
+#[no_mangle]
+pub extern "C" fn polar_remove_facts_matching(
+    polar_ptr: *mut Polar,
+    name: *const c_char,
+    pattern: *const c_char,
+) -> i32 {
+    ffi_try!({
+        let polar = unsafe { ffi_ref!(polar_ptr) };
+        let name = unsafe { ffi_string!(name) };
+        let pattern = unsafe { ffi_string!(pattern) };
+        match serde_json::from_str(&pattern) {
+            Ok(pattern) => {
+                match polar.remove_facts_matching(terms::Symbol::new(name.as_ref()), pattern) {
+                    Ok(_) => POLAR_SUCCESS,
+                    Err(e) => {
+                        set_error(e);
+                        POLAR_FAILURE
+                    }
+                }
+            }
+            Err(e) => {
+                set_error(error::RuntimeError::Serialization { msg: e.to_string() }.into());
+                POLAR_FAILURE
+            }
+        }
+    })
+}
+
+/// Limit the policies and queries the instance parses. `limits` is a JSON
+/// serialized `ParserLimits`.
+#[no_mangle]
+pub extern "C" fn polar_set_parser_limits(polar_ptr: *mut Polar, limits: *const c_char) -> i32 {
+    ffi_try!({
+        let polar = unsafe { ffi_ref!(polar_ptr) };
+        let limits = unsafe { ffi_string!(limits) };
+        match serde_json::from_str(&limits) {
+            Ok(limits) => {
+                polar.set_parser_limits(limits);
+                POLAR_SUCCESS
+            }
+            Err(e) => {
+                set_error(error::RuntimeError::Serialization { msg: e.to_string() }.into());
+                POLAR_FAILURE
+            }
+        }
+    })
+}
+
+/// Run untrusted policies within sandbox limits. `config` is a JSON
+/// serialized `SandboxConfig`, where `{}` is the strictest preset, or `null`
+/// to remove every limit.
+#[no_mangle]
+pub extern "C" fn polar_set_sandbox(polar_ptr: *mut Polar, config: *const c_char) -> i32 {
+    ffi_try!({
+        let polar = unsafe { ffi_ref!(polar_ptr) };
+        let config = unsafe { ffi_string!(config) };
+        match serde_json::from_str(&config) {
+            Ok(config) => {
+                polar.set_sandbox(config);
+                POLAR_SUCCESS
+            }
+            Err(e) => {
+                set_error(error::RuntimeError::Serialization { msg: e.to_string() }.into());
+                POLAR_FAILURE
+            }
+        }
+    })
+}
+
+/// Set how queries order strings. `collation` is a JSON serialized
+/// `StringCollation`, e.g., `"CaseInsensitive"`.
+#[no_mangle]
+pub extern "C" fn polar_set_string_collation(
+    polar_ptr: *mut Polar,
+    collation: *const c_char,
+) -> i32 {
+    ffi_try!({
+        let polar = unsafe { ffi_ref!(polar_ptr) };
+        let collation = unsafe { ffi_string!(collation) };
+        match serde_json::from_str(&collation) {
+            Ok(collation) => {
+                polar.set_string_collation(collation);
+                POLAR_SUCCESS
+            }
+            Err(e) => {
+                set_error(error::RuntimeError::Serialization { msg: e.to_string() }.into());
+                POLAR_FAILURE
+            }
+        }
+    })
+}
+
+/// `enabled` is treated as a bool: 0 for false, anything else for true.
+#[no_mangle]
+pub extern "C" fn polar_set_normalize_strings(polar_ptr: *mut Polar, enabled: u32) -> i32 {
+    ffi_try!({
+        let polar = unsafe { ffi_ref!(polar_ptr) };
+        polar.set_normalize_strings(enabled != 0);
+        POLAR_SUCCESS
+    })
+}
+
+/// `enabled` is treated as a bool: 0 for false, anything else for true.
+#[no_mangle]
+pub extern "C" fn polar_set_reorder_conjunctions(polar_ptr: *mut Polar, enabled: u32) -> i32 {
+    ffi_try!({
+        let polar = unsafe { ffi_ref!(polar_ptr) };
+        polar.set_reorder_conjunctions(enabled != 0);
+        POLAR_SUCCESS
+    })
+}
+
+/// `enabled` is treated as a bool: 0 for false, anything else for true.
+#[no_mangle]
+pub extern "C" fn polar_set_rule_metrics(polar_ptr: *mut Polar, enabled: u32) -> i32 {
+    ffi_try!({
+        let polar = unsafe { ffi_ref!(polar_ptr) };
+        polar.set_rule_metrics(enabled != 0);
+        POLAR_SUCCESS
+    })
+}
+
+/// The hit and success counts of every loaded rule, JSON serialized.
+#[no_mangle]
+pub extern "C" fn polar_rule_metrics(polar_ptr: *mut Polar) -> *const c_char {
+    ffi_try!({
+        let polar = unsafe { ffi_ref!(polar_ptr) };
+        let metrics_json = serde_json::to_string(&polar.rule_metrics()).unwrap();
+        CString::new(metrics_json)
+            .expect("JSON should not contain any 0 bytes")
+            .into_raw()
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn polar_reset_rule_metrics(polar_ptr: *mut Polar) -> i32 {
+    ffi_try!({
+        let polar = unsafe { ffi_ref!(polar_ptr) };
+        polar.reset_rule_metrics();
+        POLAR_SUCCESS
+    })
+}
+
+/// Make queries fail once they produce more than `max` results. 0 means no
+/// limit, the default.
+#[no_mangle]
+pub extern "C" fn polar_set_max_results(polar_ptr: *mut Polar, max: u64) -> i32 {
+    ffi_try!({
+        let polar = unsafe { ffi_ref!(polar_ptr) };
+        polar.set_max_results(if max == 0 { None } else { Some(max as usize) });
+        POLAR_SUCCESS
+    })
+}
+
+/// Answer lookups of attributes on the instance `instance_id` in every
+/// query from `fields`, a JSON serialized dictionary of terms by attribute
+/// name; `null` removes the instance's snapshot.
+#[no_mangle]
+pub extern "C" fn polar_set_instance_snapshot(
+    polar_ptr: *mut Polar,
+    instance_id: u64,
+    fields: *const c_char,
+) -> i32 {
+    ffi_try!({
+        let polar = unsafe { ffi_ref!(polar_ptr) };
+        let fields = unsafe { ffi_string!(fields) };
+        match serde_json::from_str(&fields) {
+            Ok(fields) => {
+                polar.set_instance_snapshot(instance_id, fields);
+                POLAR_SUCCESS
+            }
+            Err(e) => {
+                set_error(error::RuntimeError::Serialization { msg: e.to_string() }.into());
+                POLAR_FAILURE
+            }
+        }
+    })
+}
+
+/// Sort `terms`, a JSON serialized list of terms, into canonical order, and
+/// return them as JSON; see `compare_terms`. Returns null on error.
+#[no_mangle]
+pub extern "C" fn polar_sort_terms(polar_ptr: *mut Polar, terms: *const c_char) -> *const c_char {
+    ffi_try!({
+        let polar = unsafe { ffi_ref!(polar_ptr) };
+        let terms = unsafe { ffi_string!(terms) };
+        match serde_json::from_str::<Vec<terms::Term>>(&terms) {
+            Ok(mut terms) => {
+                polar.sort_terms(&mut terms);
+                let terms_json = serde_json::to_string(&terms).unwrap();
+                CString::new(terms_json)
+                    .expect("JSON should not contain any 0 bytes")
+                    .into_raw()
+            }
+            Err(e) => {
+                set_error(error::RuntimeError::Serialization { msg: e.to_string() }.into());
+                null()
+            }
+        }
+    })
+}
+
 // @Note(steve): trace is treated as a bool. 0 for false, anything else for true.
 // If we get more than one flag on these ffi methods, consider renaming it flags and making it a bitflags field.
 // Then we wont have to update the ffi to add new optional things like logging or tracing or whatever.
@@ -192,6 +596,25 @@ pub extern "C" fn polar_new_query(
     })
 }
 
+/// Resume a query from the JSON state returned by `polar_suspend_query`.
+#[no_mangle]
+pub extern "C" fn polar_resume_query(
+    polar_ptr: *mut Polar,
+    suspended: *const c_char,
+) -> *mut Query {
+    ffi_try!({
+        let polar = unsafe { ffi_ref!(polar_ptr) };
+        let suspended = unsafe { ffi_string!(suspended) };
+        match polar.resume_query(&suspended) {
+            Ok(q) => box_ptr!(q),
+            Err(e) => {
+                set_error(e);
+                null_mut()
+            }
+        }
+    })
+}
+
 #[no_mangle]
 pub extern "C" fn polar_next_polar_message(polar_ptr: *mut Polar) -> *const c_char {
     ffi_try!({
@@ -304,6 +727,69 @@ pub extern "C" fn polar_call_result(
     })
 }
 
+#[no_mangle]
+pub extern "C" fn polar_lookup_many_result(
+    query_ptr: *mut Query,
+    call_id: u64,
+    results: *const c_char,
+) -> i32 {
+    ffi_try!({
+        let query = unsafe { ffi_ref!(query_ptr) };
+        let s = unsafe { ffi_string!(results) };
+        let results = match serde_json::from_str(&s) {
+            Ok(results) => results,
+            Err(e) => {
+                set_error(error::RuntimeError::Serialization { msg: e.to_string() }.into());
+                return POLAR_FAILURE;
+            }
+        };
+        match query.lookup_many_result(call_id, results) {
+            Ok(_) => POLAR_SUCCESS,
+            Err(e) => {
+                set_error(e);
+                POLAR_FAILURE
+            }
+        }
+    })
+}
+
+/// Answer lookups of attributes on the instance `instance_id` for the rest
+/// of the query from `fields`, a JSON serialized dictionary of terms by
+/// attribute name.
+#[no_mangle]
+pub extern "C" fn polar_snapshot_instance(
+    query_ptr: *mut Query,
+    instance_id: u64,
+    fields: *const c_char,
+) -> i32 {
+    ffi_try!({
+        let query = unsafe { ffi_ref!(query_ptr) };
+        let fields = unsafe { ffi_string!(fields) };
+        match serde_json::from_str(&fields) {
+            Ok(fields) => match query.snapshot_instance(instance_id, fields) {
+                Ok(_) => POLAR_SUCCESS,
+                Err(e) => {
+                    set_error(e);
+                    POLAR_FAILURE
+                }
+            },
+            Err(e) => {
+                set_error(error::RuntimeError::Serialization { msg: e.to_string() }.into());
+                POLAR_FAILURE
+            }
+        }
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn polar_uncache_call(query_ptr: *mut Query, call_id: u64) -> i32 {
+    ffi_try!({
+        let query = unsafe { ffi_ref!(query_ptr) };
+        query.uncache_call(call_id);
+        POLAR_SUCCESS
+    })
+}
+
 #[no_mangle]
 pub extern "C" fn polar_question_result(query_ptr: *mut Query, call_id: u64, result: i32) -> i32 {
     ffi_try!({
@@ -319,6 +805,118 @@ pub extern "C" fn polar_question_result(query_ptr: *mut Query, call_id: u64, res
     })
 }
 
+#[no_mangle]
+pub extern "C" fn polar_set_heartbeat_interval(query_ptr: *mut Query, interval: u64) -> i32 {
+    ffi_try!({
+        let query = unsafe { ffi_ref!(query_ptr) };
+        query.set_heartbeat_interval(interval);
+        POLAR_SUCCESS
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn polar_set_progress_interval(query_ptr: *mut Query, interval: u64) -> i32 {
+    ffi_try!({
+        let query = unsafe { ffi_ref!(query_ptr) };
+        query.set_progress_interval(interval);
+        POLAR_SUCCESS
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn polar_enable_bulk_lookups(query_ptr: *mut Query) -> i32 {
+    ffi_try!({
+        let query = unsafe { ffi_ref!(query_ptr) };
+        query.enable_bulk_lookups();
+        POLAR_SUCCESS
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn polar_enable_concurrent_calls(query_ptr: *mut Query) -> i32 {
+    ffi_try!({
+        let query = unsafe { ffi_ref!(query_ptr) };
+        query.enable_concurrent_calls();
+        POLAR_SUCCESS
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn polar_enable_stats(query_ptr: *mut Query) -> i32 {
+    ffi_try!({
+        let query = unsafe { ffi_ref!(query_ptr) };
+        query.enable_stats();
+        POLAR_SUCCESS
+    })
+}
+
+/// Skip results that duplicate earlier ones in the bindings of `variables`,
+/// a JSON serialized list of variable names, or `null` for all of them.
+#[no_mangle]
+pub extern "C" fn polar_deduplicate_results(
+    query_ptr: *mut Query,
+    variables: *const c_char,
+) -> i32 {
+    ffi_try!({
+        let query = unsafe { ffi_ref!(query_ptr) };
+        let variables = unsafe { ffi_string!(variables) };
+        match serde_json::from_str(&variables) {
+            Ok(variables) => {
+                query.deduplicate_results(variables);
+                POLAR_SUCCESS
+            }
+            Err(e) => {
+                set_error(error::RuntimeError::Serialization { msg: e.to_string() }.into());
+                POLAR_FAILURE
+            }
+        }
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn polar_start_recording(query_ptr: *mut Query) -> i32 {
+    ffi_try!({
+        let query = unsafe { ffi_ref!(query_ptr) };
+        query.start_recording();
+        POLAR_SUCCESS
+    })
+}
+
+/// The query's interactions with the host as JSON, or null if it isn't
+/// being recorded.
+#[no_mangle]
+pub extern "C" fn polar_query_recording(query_ptr: *mut Query) -> *const c_char {
+    ffi_try!({
+        let query = unsafe { ffi_ref!(query_ptr) };
+        if let Some(recording) = query.recording() {
+            let recording_json = serde_json::to_string(recording).unwrap();
+            CString::new(recording_json)
+                .expect("JSON should not contain any 0 bytes")
+                .into_raw()
+        } else {
+            null()
+        }
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn polar_replay(query_ptr: *mut Query, recording: *const c_char) -> i32 {
+    ffi_try!({
+        let query = unsafe { ffi_ref!(query_ptr) };
+        let recording = unsafe { ffi_string!(recording) };
+        match serde_json::from_str(&recording) {
+            Ok(recording) => {
+                query.replay(recording);
+                POLAR_SUCCESS
+            }
+            Err(e) => {
+                set_error(error::RuntimeError::Serialization { msg: e.to_string() }.into());
+                POLAR_FAILURE
+            }
+        }
+    })
+}
+
 #[no_mangle]
 pub extern "C" fn polar_application_error(query_ptr: *mut Query, message: *mut c_char) -> i32 {
     ffi_try!({
@@ -354,6 +952,70 @@ pub extern "C" fn polar_next_query_message(query_ptr: *mut Query) -> *const c_ch
     })
 }
 
+/// Serialize the state of a query as JSON, for `polar_resume_query`.
+#[no_mangle]
+pub extern "C" fn polar_suspend_query(query_ptr: *mut Query) -> *const c_char {
+    ffi_try!({
+        let query = unsafe { ffi_ref!(query_ptr) };
+        match query.suspend() {
+            Ok(suspended) => CString::new(suspended)
+                .expect("JSON should not contain any 0 bytes")
+                .into_raw(),
+            Err(e) => {
+                set_error(e);
+                null()
+            }
+        }
+    })
+}
+
+/// How the query's latest result was derived, as a JSON serialized trace,
+/// or null if it has no results yet.
+#[no_mangle]
+pub extern "C" fn polar_trace_for_last_result(query_ptr: *mut Query) -> *const c_char {
+    ffi_try!({
+        let query = unsafe { ffi_ref!(query_ptr) };
+        if let Some(trace) = query.trace_for_last_result() {
+            let trace_json = serde_json::to_string(&trace).unwrap();
+            CString::new(trace_json)
+                .expect("JSON should not contain any 0 bytes")
+                .into_raw()
+        } else {
+            null()
+        }
+    })
+}
+
+/// The rules that justified the query's latest result, as a JSON
+/// serialized proof, or null if it has no results yet.
+#[no_mangle]
+pub extern "C" fn polar_proof_for_last_result(query_ptr: *mut Query) -> *const c_char {
+    ffi_try!({
+        let query = unsafe { ffi_ref!(query_ptr) };
+        if let Some(proof) = query.proof_for_last_result() {
+            let proof_json = serde_json::to_string(&proof).unwrap();
+            CString::new(proof_json)
+                .expect("JSON should not contain any 0 bytes")
+                .into_raw()
+        } else {
+            null()
+        }
+    })
+}
+
+/// The readable names the query's latest result shows variables the VM
+/// made up with, as a JSON object from made-up to readable name.
+#[no_mangle]
+pub extern "C" fn polar_variable_names(query_ptr: *mut Query) -> *const c_char {
+    ffi_try!({
+        let query = unsafe { ffi_ref!(query_ptr) };
+        let names_json = serde_json::to_string(query.variable_names()).unwrap();
+        CString::new(names_json)
+            .expect("JSON should not contain any 0 bytes")
+            .into_raw()
+    })
+}
+
 #[no_mangle]
 pub extern "C" fn polar_query_source_info(query_ptr: *mut Query) -> *const c_char {
     ffi_try!({
@@ -391,6 +1053,33 @@ pub extern "C" fn polar_bind(
     })
 }
 
+#[no_mangle]
+pub extern "C" fn polar_bind_constant(
+    query_ptr: *mut Query,
+    name: *const c_char,
+    value: *const c_char,
+) -> i32 {
+    ffi_try!({
+        let query = unsafe { ffi_ref!(query_ptr) };
+        let name = unsafe { ffi_string!(name) };
+        let value = unsafe { ffi_string!(value) };
+        let value = serde_json::from_str(&value);
+        match value {
+            Ok(value) => match query.bind_constant(terms::Symbol::new(name.as_ref()), value) {
+                Ok(_) => POLAR_SUCCESS,
+                Err(e) => {
+                    set_error(e);
+                    POLAR_FAILURE
+                }
+            },
+            Err(e) => {
+                set_error(error::RuntimeError::Serialization { msg: e.to_string() }.into());
+                POLAR_FAILURE
+            }
+        }
+    })
+}
+
 #[no_mangle]
 pub extern "C" fn polar_get_external_id(polar_ptr: *mut Polar) -> u64 {
     ffi_try!({
@@ -3,12 +3,17 @@
 /// Bindings associate variables in the VM with constraints or values.
 use std::collections::{HashMap, HashSet};
 
+use serde::{Deserialize, Serialize};
+
 use crate::error::{PolarResult, RuntimeError};
-use crate::folder::{fold_term, Folder};
+use crate::folder::{fold_dictionary, fold_list, fold_term, Folder};
 use crate::formatting::ToPolarString;
-use crate::terms::{has_rest_var, Operation, Operator, Symbol, Term, Value};
+use crate::terms::{
+    has_rest_var, Dictionary, Operation, Operator, Symbol, Term, TermList, Value, CYCLE_MARKER,
+    REST_FIELD,
+};
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Binding(pub Symbol, pub Term);
 
 // TODO This is only public for debugger and inverter.
@@ -82,7 +87,7 @@ enum BindingManagerVariableState {
 /// A binding is created with the `bind` method.
 ///
 /// The constraints or value associated with a variable is retrieved with `variable_state`.
-#[derive(Clone, Debug, Default)]
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct BindingManager {
     bindings: BindingStack,
     followers: HashMap<FollowerId, (BindingManager, Bsp)>,
@@ -222,12 +227,17 @@ impl BindingManager {
     /// If `term` is a list, dereference all items in the list.
     /// Otherwise, return `term`.
     pub fn deref(&self, term: &Term) -> Term {
+        self.deref_unseen(term, &mut vec![])
+    }
+
+    /// Dereference `term`, without following any rest variable in `seen`.
+    fn deref_unseen(&self, term: &Term, seen: &mut Vec<Symbol>) -> Term {
         match &term.value() {
             Value::List(list) => {
                 // Deref all elements.
                 let mut derefed: Vec<Term> =
                     // TODO(gj): reduce recursion here.
-                    list.iter().map(|t| self.deref(t)).collect();
+                    list.iter().map(|t| self.deref_unseen(t, seen)).collect();
 
                 // If last element was a rest variable, append the list it derefed to.
                 if has_rest_var(list) {
@@ -247,7 +257,12 @@ impl BindingManager {
             },
             Value::RestVariable(v) => match self.variable_state(v) {
                 VariableState::Bound(value) => match value.value() {
-                    Value::List(l) if has_rest_var(l) => self.deref(&value),
+                    Value::List(l) if has_rest_var(l) && !seen.contains(v) => {
+                        seen.push(v.clone());
+                        let derefed = self.deref_unseen(&value, seen);
+                        seen.pop();
+                        derefed
+                    }
                     _ => value,
                 },
                 _ => term.clone(),
@@ -258,14 +273,22 @@ impl BindingManager {
 
     /// Dereference all variables in term, including within nested structures like
     /// lists and dictionaries.
+    ///
+    /// A variable whose value contains itself (e.g., after `x = [x]`) is
+    /// replaced at the point of recurrence by a variable named `<cycle>`.
     pub fn deep_deref(&self, term: &Term) -> Term {
         pub struct Derefer<'a> {
             binding_manager: &'a BindingManager,
+            /// Variables whose values are currently being dereferenced.
+            expanding: Vec<Symbol>,
         }
 
         impl<'a> Derefer<'a> {
             pub fn new(binding_manager: &'a BindingManager) -> Self {
-                Self { binding_manager }
+                Self {
+                    binding_manager,
+                    expanding: vec![],
+                }
             }
         }
 
@@ -273,14 +296,55 @@ impl BindingManager {
             fn fold_term(&mut self, t: Term) -> Term {
                 match t.value() {
                     Value::Expression(_) => t,
-                    Value::List(_) => fold_term(self.binding_manager.deref(&t), self),
-                    Value::Variable(_) | Value::RestVariable(_) => {
-                        let derefed = self.binding_manager.deref(&t);
-                        fold_term(derefed, self)
+                    Value::Variable(v) | Value::RestVariable(v) if self.expanding.contains(v) => {
+                        t.clone_with_value(Value::Variable(Symbol::new(CYCLE_MARKER)))
+                    }
+                    Value::Variable(v) | Value::RestVariable(v) => {
+                        match self.binding_manager.variable_state(v) {
+                            VariableState::Bound(value) => {
+                                self.expanding.push(v.clone());
+                                let derefed = self.fold_term(value);
+                                self.expanding.pop();
+                                derefed
+                            }
+                            _ => t,
+                        }
                     }
                     _ => fold_term(t, self),
                 }
             }
+
+            fn fold_list(&mut self, list: TermList) -> TermList {
+                let has_rest = has_rest_var(&list);
+                let mut derefed = fold_list(list, self);
+                // Splice in the list bound to a trailing rest variable.
+                if has_rest {
+                    if let Some(last) = derefed.pop() {
+                        match last.value() {
+                            Value::List(terms) => derefed.extend(terms.iter().cloned()),
+                            _ => derefed.push(last),
+                        }
+                    }
+                }
+                derefed
+            }
+
+            fn fold_dictionary(&mut self, dict: Dictionary) -> Dictionary {
+                let has_rest = dict.rest_var().is_some();
+                let mut derefed = fold_dictionary(dict, self);
+                // Merge in the fields of the dict bound to a rest variable.
+                if has_rest {
+                    let rest = Symbol::new(REST_FIELD);
+                    if let Some(Value::Dictionary(bound)) =
+                        derefed.fields.get(&rest).map(Term::value)
+                    {
+                        let fields = bound.fields.clone();
+                        derefed.fields.shift_remove(&rest);
+                        derefed.fields.extend(fields);
+                    }
+                }
+                derefed
+            }
         }
 
         Derefer::new(self).fold_term(term.clone())
@@ -321,6 +385,36 @@ impl BindingManager {
         VariableState::Unbound
     }
 
+    /// Whether `variable` has constraints, as opposed to being bound, unbound
+    /// or just unified with other unbound variables.
+    pub fn is_constrained(&self, variable: &Symbol) -> bool {
+        let bsp = self.bsp();
+        let mut next = variable;
+        while let Some(value) = self.value(next, bsp) {
+            match value.value() {
+                Value::Expression(_) => return true,
+                Value::Variable(v) | Value::RestVariable(v) if v != variable => next = v,
+                _ => return false,
+            }
+        }
+        false
+    }
+
+    /// The variables `variable` is unified with, itself first, if they're all
+    /// unbound, as opposed to bound or constrained.
+    pub fn unbound_aliases(&self, variable: &Symbol) -> Option<Vec<Symbol>> {
+        let bsp = self.bsp();
+        let mut aliases = vec![variable.clone()];
+        while let Some(value) = self.value(aliases.last().unwrap(), bsp) {
+            match value.value() {
+                Value::Variable(v) | Value::RestVariable(v) if v == variable => break,
+                Value::Variable(v) | Value::RestVariable(v) => aliases.push(v.clone()),
+                _ => return None,
+            }
+        }
+        Some(aliases)
+    }
+
     /// Return all variables used in this binding manager.
     pub fn variables(&self) -> HashSet<Symbol> {
         self.bindings
@@ -811,6 +905,22 @@ mod test {
         );
     }
 
+    #[test]
+    fn deep_deref_cycle() {
+        let mut bm = BindingManager::default();
+        let x = sym!("x");
+        let rest = sym!("rest");
+        bm.bind(&x, term!([x.clone()])).unwrap();
+        let rest_list = vec![term!(1), term!(Value::RestVariable(rest.clone()))];
+        bm.bind(&rest, term!(Value::List(rest_list))).unwrap();
+        assert_eq!(bm.deep_deref(&term!(x)).to_polar(), "[<cycle>]");
+        assert_eq!(
+            bm.deref(&term!(Value::List(vec![term!(Value::RestVariable(rest))])))
+                .to_polar(),
+            "[1, 1, *rest]"
+        );
+    }
+
     #[test]
     fn bind() {
         let x = sym!("x");
@@ -0,0 +1,707 @@
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+use indexmap::IndexMap;
+use regex::Regex;
+
+use crate::error::PolarResult;
+use crate::formatting::ToPolarString;
+use crate::numerics::Numeric;
+use crate::terms::*;
+use crate::vm::{Goal, Goals, PolarVirtualMachine};
+
+/// A built-in predicate. It receives the call's arguments and returns the
+/// goals that implement it.
+pub type Builtin = fn(&PolarVirtualMachine, &[Term]) -> PolarResult<Goals>;
+
+/// Look up the builtin called `name` that takes `arity` arguments.
+///
+/// Builtins are only consulted when the knowledge base has no rules by the
+/// same name, so existing policies keep their own definitions.
+pub fn builtin(name: &Symbol, arity: usize) -> Option<Builtin> {
+    match (name.0.as_str(), arity) {
+        ("dict_of", 2) => Some(dict_of),
+        ("sort", 2) => Some(sort),
+        ("sort_by", 3) => Some(sort_by),
+        ("group_by", 2) => Some(group_by),
+        ("group_by", 3) => Some(group_by_key),
+        ("distinct", 1) => Some(distinct_solutions),
+        ("distinct", 2) => Some(distinct),
+        ("zip", 3) => Some(zip),
+        ("enumerate", 2) => Some(enumerate),
+        ("has_key", 2) => Some(has_key),
+        ("keys", 2) => Some(keys),
+        ("values", 2) => Some(values),
+        ("merge", 3) => Some(merge),
+        ("without", 3) => Some(without),
+        ("set", 2) => Some(set),
+        ("union", 3) => Some(union),
+        ("intersection", 3) => Some(intersection),
+        ("difference", 3) => Some(difference),
+        ("equals_ignore_case", 2) => Some(equals_ignore_case),
+        ("length", 2) => Some(length),
+        ("append", 3) => Some(append),
+        ("nth", 3) => Some(nth),
+        ("reverse", 2) => Some(reverse),
+        ("string_length", 2) => Some(string_length),
+        ("starts_with", 2) => Some(starts_with),
+        ("ends_with", 2) => Some(ends_with),
+        ("contains", 2) => Some(contains),
+        ("matches_regex", 2) => Some(matches_regex),
+        _ => None,
+    }
+}
+
+/// Order two terms canonically: first by kind of value (booleans, numbers,
+/// strings, datetimes, lists, tuples, sets, ranges, dictionaries, external instances, calls,
+/// variables, rest variables, expressions, then patterns),
+/// then by content. Numbers compare numerically regardless of representation,
+/// with NaN after every other number, and external instances compare by
+/// instance id. The order is total, so it's suitable for sorting and
+/// deduplicating any terms, e.g., query results of mixed types.
+pub fn compare_terms(left: &Term, right: &Term) -> Ordering {
+    compare_terms_collated(left, right, StringCollation::Binary)
+}
+
+/// Like `compare_terms`, but order strings by `collation`.
+pub fn compare_terms_collated(left: &Term, right: &Term, collation: StringCollation) -> Ordering {
+    fn rank(value: &Value) -> u8 {
+        match value {
+            Value::Nil => 0,
+            Value::Boolean(_) => 1,
+            Value::Number(_) => 2,
+            Value::String(_) => 3,
+            Value::DateTime(_) => 4,
+            Value::List(_) => 5,
+            Value::Tuple(_) => 6,
+            Value::Set(_) => 7,
+            Value::Range(_) => 8,
+            Value::Dictionary(_) => 9,
+            Value::ExternalInstance(_) => 10,
+            Value::Call(_) => 11,
+            Value::Variable(_) => 12,
+            Value::RestVariable(_) => 13,
+            Value::Expression(_) => 14,
+            Value::Pattern(_) => 15,
+        }
+    }
+
+    fn compare_numbers(l: &Numeric, r: &Numeric) -> Ordering {
+        let is_nan = |n: &Numeric| matches!(n, Numeric::Float(f) if f.is_nan());
+        l.partial_cmp(r)
+            .unwrap_or_else(|| is_nan(l).cmp(&is_nan(r)))
+    }
+
+    fn sorted_fields(dict: &Dictionary) -> Vec<(&Symbol, &Term)> {
+        let mut fields: Vec<_> = dict.fields.iter().collect();
+        fields.sort_by_key(|(name, _)| *name);
+        fields
+    }
+
+    let compare_lists = |l: &[Term], r: &[Term]| -> Ordering {
+        l.iter()
+            .zip(r.iter())
+            .map(|(l, r)| compare_terms_collated(l, r, collation))
+            .find(|o| *o != Ordering::Equal)
+            .unwrap_or_else(|| l.len().cmp(&r.len()))
+    };
+
+    match (left.value(), right.value()) {
+        (Value::Boolean(l), Value::Boolean(r)) => l.cmp(r),
+        (Value::Number(l), Value::Number(r)) => compare_numbers(l, r),
+        (Value::String(l), Value::String(r)) => collation.compare(l, r),
+        (Value::DateTime(l), Value::DateTime(r)) => l.cmp(r),
+        (Value::List(l), Value::List(r)) => compare_lists(l, r),
+        (Value::Tuple(l), Value::Tuple(r)) => compare_lists(l, r),
+        // Set elements are already in canonical order.
+        (Value::Set(l), Value::Set(r)) => compare_lists(&l.elements, &r.elements),
+        (Value::Range(l), Value::Range(r)) => compare_lists(
+            &[l.start.clone(), l.end.clone()],
+            &[r.start.clone(), r.end.clone()],
+        ),
+        (Value::Dictionary(l), Value::Dictionary(r)) => {
+            let (l, r) = (sorted_fields(l), sorted_fields(r));
+            l.iter()
+                .zip(r.iter())
+                .map(|((lk, lv), (rk, rv))| {
+                    lk.cmp(rk)
+                        .then_with(|| compare_terms_collated(lv, rv, collation))
+                })
+                .find(|o| *o != Ordering::Equal)
+                .unwrap_or_else(|| l.len().cmp(&r.len()))
+        }
+        (Value::ExternalInstance(l), Value::ExternalInstance(r)) => {
+            l.instance_id.cmp(&r.instance_id)
+        }
+        (l, r) => rank(l)
+            .cmp(&rank(r))
+            .then_with(|| l.to_polar().cmp(&r.to_polar())),
+    }
+}
+
+/// Unify the builtin's output argument with `value`.
+fn output(args: &[Term], value: Value) -> PolarResult<Goals> {
+    Ok(vec![Goal::Unify {
+        left: args.last().unwrap().clone(),
+        right: Term::new_temporary(value),
+    }])
+}
+
+/// Dereference a list argument, or fail with a type error naming the builtin.
+fn list_arg(vm: &PolarVirtualMachine, builtin: &str, arg: &Term) -> PolarResult<TermList> {
+    let value = vm.deep_deref(arg);
+    match value.value() {
+        Value::List(list) if !has_rest_var(list) => Ok(list.clone()),
+        _ => Err(vm.type_error(
+            arg,
+            format!("{} expects a list, got: {}", builtin, value.to_polar()),
+        )),
+    }
+}
+
+/// Dereference a dictionary argument, or fail with a type error naming the builtin.
+fn dict_arg(vm: &PolarVirtualMachine, builtin: &str, arg: &Term) -> PolarResult<Dictionary> {
+    let value = vm.deep_deref(arg);
+    match value.value() {
+        Value::Dictionary(dict) => Ok(dict.clone()),
+        _ => Err(vm.type_error(
+            arg,
+            format!(
+                "{} expects a dictionary, got: {}",
+                builtin,
+                value.to_polar()
+            ),
+        )),
+    }
+}
+
+/// Dereference a string argument, or fail with a type error naming the builtin.
+fn string_arg(vm: &PolarVirtualMachine, builtin: &str, arg: &Term) -> PolarResult<String> {
+    let value = vm.deep_deref(arg);
+    match value.value() {
+        Value::String(s) => Ok(s.clone()),
+        _ => Err(vm.type_error(
+            arg,
+            format!("{} expects a string, got: {}", builtin, value.to_polar()),
+        )),
+    }
+}
+
+/// Succeed if `condition` holds, or else backtrack.
+fn succeed_if(condition: bool) -> PolarResult<Goals> {
+    if condition {
+        Ok(vec![])
+    } else {
+        Ok(vec![Goal::Backtrack])
+    }
+}
+
+/// Dereference a set argument, or fail with a type error naming the builtin.
+/// Lists are accepted too, and converted to sets.
+fn set_arg(vm: &PolarVirtualMachine, builtin: &str, arg: &Term) -> PolarResult<Set> {
+    let value = vm.deep_deref(arg);
+    match value.value() {
+        Value::Set(set) => Ok(set.clone()),
+        Value::List(list) if !has_rest_var(list) => Ok(Set::new(list.clone())),
+        _ => Err(vm.type_error(
+            arg,
+            format!("{} expects a set, got: {}", builtin, value.to_polar()),
+        )),
+    }
+}
+
+/// `dict_of(pairs, dict)`: build a dictionary from a list of `[key, value]` pairs.
+/// Later pairs replace earlier ones with the same key.
+fn dict_of(vm: &PolarVirtualMachine, args: &[Term]) -> PolarResult<Goals> {
+    let mut fields = Fields::new();
+    for pair in list_arg(vm, "dict_of", &args[0])? {
+        let (key, value) = match pair.value() {
+            Value::List(kv) if kv.len() == 2 => (&kv[0], &kv[1]),
+            _ => {
+                return Err(vm.type_error(
+                    &pair,
+                    format!(
+                        "dict_of expects [key, value] pairs, got: {}",
+                        pair.to_polar()
+                    ),
+                ))
+            }
+        };
+        match key.value() {
+            Value::String(k) => fields.insert(Symbol::new(k), value.clone()),
+            _ => {
+                return Err(vm.type_error(
+                    key,
+                    format!("dict_of keys must be strings, got: {}", key.to_polar()),
+                ))
+            }
+        };
+    }
+    output(args, Value::Dictionary(Dictionary { fields }))
+}
+
+/// `sort(list, sorted)`: sort a list in canonical term order, with strings
+/// ordered by the VM's collation.
+fn sort(vm: &PolarVirtualMachine, args: &[Term]) -> PolarResult<Goals> {
+    let mut list = list_arg(vm, "sort", &args[0])?;
+    list.sort_by(|l, r| compare_terms_collated(l, r, vm.string_collation));
+    output(args, Value::List(list))
+}
+
+/// `sort_by(list, key, sorted)`: sort a list by looking up the field or
+/// attribute `key` on each element. Elements with equal keys keep their order.
+fn sort_by(vm: &PolarVirtualMachine, args: &[Term]) -> PolarResult<Goals> {
+    keyed_pairs(vm, "sort_by", args, sort_pairs)
+}
+
+/// Collect `[elem.key, elem]` pairs for each element of the list `args[0]`
+/// with key `args[1]`, then pass them and the output argument to `then`.
+fn keyed_pairs(
+    vm: &PolarVirtualMachine,
+    builtin: &str,
+    args: &[Term],
+    then: Builtin,
+) -> PolarResult<Goals> {
+    let key = vm.deep_deref(&args[1]);
+    if !matches!(key.value(), Value::String(_)) {
+        return Err(vm.type_error(
+            &args[1],
+            format!("{} expects a string key, got: {}", builtin, key.to_polar()),
+        ));
+    }
+
+    let (elem, elem_key, pairs) = (vm.gensym("elem"), vm.gensym("key"), vm.gensym("pairs"));
+    let var = |name: &Symbol| Term::new_temporary(Value::Variable(name.clone()));
+    let condition = Term::new_temporary(Value::Expression(op!(
+        And,
+        term!(op!(In, var(&elem), args[0].clone())),
+        term!(op!(Dot, var(&elem), key, var(&elem_key)))
+    )));
+    let template = Term::new_temporary(Value::List(vec![var(&elem_key), var(&elem)]));
+    Ok(vec![
+        Goal::Query {
+            term: term!(op!(FindAll, template, condition, var(&pairs))),
+        },
+        Goal::CallBuiltin {
+            builtin: then,
+            args: vec![var(&pairs), args[2].clone()],
+        },
+    ])
+}
+
+/// Sort `[key, value]` pairs by key, and output the values.
+fn sort_pairs(vm: &PolarVirtualMachine, args: &[Term]) -> PolarResult<Goals> {
+    let mut pairs = list_arg(vm, "sort_by", &args[0])?;
+    pairs.sort_by(|l, r| match (l.value(), r.value()) {
+        (Value::List(l), Value::List(r)) => {
+            compare_terms_collated(&l[0], &r[0], vm.string_collation)
+        }
+        _ => unreachable!("sort_by pairs are lists"),
+    });
+    let sorted = pairs
+        .into_iter()
+        .map(|pair| match pair.value() {
+            Value::List(pair) => pair[1].clone(),
+            _ => unreachable!("sort_by pairs are lists"),
+        })
+        .collect();
+    output(args, Value::List(sorted))
+}
+
+/// `group_by(pairs, groups)`: group the values of a list of `[key, value]`
+/// pairs into a dictionary from each key to the list of its values.
+fn group_by(vm: &PolarVirtualMachine, args: &[Term]) -> PolarResult<Goals> {
+    let mut groups: IndexMap<Symbol, TermList> = IndexMap::new();
+    for pair in list_arg(vm, "group_by", &args[0])? {
+        let (key, value) = match pair.value() {
+            Value::List(kv) if kv.len() == 2 => (&kv[0], &kv[1]),
+            _ => {
+                return Err(vm.type_error(
+                    &pair,
+                    format!(
+                        "group_by expects [key, value] pairs, got: {}",
+                        pair.to_polar()
+                    ),
+                ))
+            }
+        };
+        match key.value() {
+            Value::String(k) => groups
+                .entry(Symbol::new(k))
+                .or_default()
+                .push(value.clone()),
+            _ => {
+                return Err(vm.type_error(
+                    key,
+                    format!("group_by keys must be strings, got: {}", key.to_polar()),
+                ))
+            }
+        }
+    }
+    let fields = groups
+        .into_iter()
+        .map(|(k, values)| (k, Term::new_temporary(Value::List(values))))
+        .collect();
+    output(args, Value::Dictionary(Dictionary { fields }))
+}
+
+/// `group_by(list, key, groups)`: group the elements of a list by the field or
+/// attribute `key` of each element.
+fn group_by_key(vm: &PolarVirtualMachine, args: &[Term]) -> PolarResult<Goals> {
+    keyed_pairs(vm, "group_by", args, group_by)
+}
+
+/// `distinct(list, deduped)`: remove canonically equal elements from a list,
+/// keeping the first of each.
+fn distinct(vm: &PolarVirtualMachine, args: &[Term]) -> PolarResult<Goals> {
+    let mut deduped: TermList = vec![];
+    for elem in list_arg(vm, "distinct", &args[0])? {
+        if !deduped
+            .iter()
+            .any(|seen| compare_terms(seen, &elem) == Ordering::Equal)
+        {
+            deduped.push(elem);
+        }
+    }
+    output(args, Value::List(deduped))
+}
+
+/// `distinct(query)`: succeed once for each distinct binding of the variables
+/// in `query`, in the order they are first found.
+fn distinct_solutions(vm: &PolarVirtualMachine, args: &[Term]) -> PolarResult<Goals> {
+    let query = vm.deep_deref(&args[0]);
+    if !matches!(query.value(), Value::Call(_) | Value::Expression(_)) {
+        return Err(vm.type_error(
+            &args[0],
+            format!("distinct expects a query, got: {}", query.to_polar()),
+        ));
+    }
+
+    let mut vars: Vec<Symbol> = query.free_variables().into_iter().collect();
+    vars.sort();
+    let vars = Term::new_temporary(Value::List(
+        vars.into_iter()
+            .map(|v| Term::new_temporary(Value::Variable(v)))
+            .collect(),
+    ));
+    let (solutions, deduped) = (vm.gensym("solutions"), vm.gensym("deduped"));
+    let var = |name: &Symbol| Term::new_temporary(Value::Variable(name.clone()));
+    Ok(vec![
+        Goal::Query {
+            term: term!(op!(FindAll, vars.clone(), query, var(&solutions))),
+        },
+        Goal::CallBuiltin {
+            builtin: distinct,
+            args: vec![var(&solutions), var(&deduped)],
+        },
+        Goal::Query {
+            term: term!(op!(In, vars, var(&deduped))),
+        },
+    ])
+}
+
+/// `zip(a, b, pairs)`: pair up the elements of two lists, stopping at the end
+/// of the shorter one.
+fn zip(vm: &PolarVirtualMachine, args: &[Term]) -> PolarResult<Goals> {
+    let left = list_arg(vm, "zip", &args[0])?;
+    let right = list_arg(vm, "zip", &args[1])?;
+    let pairs = left
+        .into_iter()
+        .zip(right)
+        .map(|(l, r)| Term::new_temporary(Value::List(vec![l, r])))
+        .collect();
+    output(args, Value::List(pairs))
+}
+
+/// `enumerate(list, indexed)`: pair each element of a list with its index,
+/// as `[index, element]`.
+fn enumerate(vm: &PolarVirtualMachine, args: &[Term]) -> PolarResult<Goals> {
+    output(args, indexed(list_arg(vm, "enumerate", &args[0])?))
+}
+
+/// The elements of a list paired with their indexes, as `[index, element]`.
+fn indexed(list: TermList) -> Value {
+    Value::List(
+        list.into_iter()
+            .enumerate()
+            .map(|(i, elem)| {
+                let index = Term::new_temporary(Value::Number(Numeric::Integer(i as i64)));
+                Term::new_temporary(Value::List(vec![index, elem]))
+            })
+            .collect(),
+    )
+}
+
+/// `length(list, n)`: the number of elements in a list.
+fn length(vm: &PolarVirtualMachine, args: &[Term]) -> PolarResult<Goals> {
+    let list = list_arg(vm, "length", &args[0])?;
+    output(args, Value::Number(Numeric::Integer(list.len() as i64)))
+}
+
+/// `append(a, b, ab)`: the elements of `a` followed by those of `b`. With
+/// `ab` a list and `a` or `b` unbound, succeed once per way of splitting it.
+fn append(vm: &PolarVirtualMachine, args: &[Term]) -> PolarResult<Goals> {
+    let is_unbound = |arg: &Term| matches!(vm.deep_deref(arg).value(), Value::Variable(_));
+    if is_unbound(&args[0]) || is_unbound(&args[1]) {
+        let ab = list_arg(vm, "append", &args[2])?;
+        let splits = (0..=ab.len())
+            .map(|i| {
+                let (a, b) = ab.split_at(i);
+                let a = Term::new_temporary(Value::List(a.to_vec()));
+                let b = Term::new_temporary(Value::List(b.to_vec()));
+                Term::new_temporary(Value::List(vec![a, b]))
+            })
+            .collect();
+        let a_and_b = Term::new_temporary(Value::List(vec![args[0].clone(), args[1].clone()]));
+        return Ok(vec![Goal::Query {
+            term: term!(op!(In, a_and_b, Term::new_temporary(Value::List(splits)))),
+        }]);
+    }
+    let mut ab = list_arg(vm, "append", &args[0])?;
+    ab.extend(list_arg(vm, "append", &args[1])?);
+    output(args, Value::List(ab))
+}
+
+/// `nth(list, index, element)`: the element of a list at an index, counting
+/// from 0, or fail if there isn't one. With an unbound `index`, succeed once
+/// per element.
+fn nth(vm: &PolarVirtualMachine, args: &[Term]) -> PolarResult<Goals> {
+    let list = list_arg(vm, "nth", &args[0])?;
+    let index = vm.deep_deref(&args[1]);
+    match index.value() {
+        Value::Number(Numeric::Integer(i)) => {
+            match usize::try_from(*i).ok().and_then(|i| list.get(i)) {
+                Some(element) => output(args, element.value().clone()),
+                None => Ok(vec![Goal::Backtrack]),
+            }
+        }
+        Value::Variable(_) => {
+            let pair = Term::new_temporary(Value::List(vec![index, args[2].clone()]));
+            Ok(vec![Goal::Query {
+                term: term!(op!(In, pair, Term::new_temporary(indexed(list)))),
+            }])
+        }
+        _ => Err(vm.type_error(
+            &args[1],
+            format!("nth expects an integer index, got: {}", index.to_polar()),
+        )),
+    }
+}
+
+/// `reverse(list, reversed)`: the elements of a list in reverse order.
+fn reverse(vm: &PolarVirtualMachine, args: &[Term]) -> PolarResult<Goals> {
+    let mut list = list_arg(vm, "reverse", &args[0])?;
+    list.reverse();
+    output(args, Value::List(list))
+}
+
+/// `has_key(dict, key)`: succeed if `dict` has a field named `key`, without
+/// looking the field up. With an unbound `key`, succeed once per key.
+fn has_key(vm: &PolarVirtualMachine, args: &[Term]) -> PolarResult<Goals> {
+    let dict = dict_arg(vm, "has_key", &args[0])?;
+    let key = vm.deep_deref(&args[1]);
+    match key.value() {
+        Value::String(k) if dict.fields.contains_key(&Symbol::new(k)) => Ok(vec![]),
+        Value::String(_) => Ok(vec![Goal::Backtrack]),
+        Value::Variable(_) => Ok(vec![Goal::Query {
+            term: term!(op!(In, key, Term::new_temporary(key_list(&dict)))),
+        }]),
+        _ => Err(vm.type_error(
+            &args[1],
+            format!("has_key expects a string key, got: {}", key.to_polar()),
+        )),
+    }
+}
+
+/// The keys of a dictionary as a list of strings, in field order.
+fn key_list(dict: &Dictionary) -> Value {
+    Value::List(
+        dict.fields
+            .keys()
+            .map(|k| Term::new_temporary(Value::String(k.0.clone())))
+            .collect(),
+    )
+}
+
+/// `keys(dict, keys)`: the keys of a dictionary as a list of strings.
+fn keys(vm: &PolarVirtualMachine, args: &[Term]) -> PolarResult<Goals> {
+    let dict = dict_arg(vm, "keys", &args[0])?;
+    output(args, key_list(&dict))
+}
+
+/// `values(dict, values)`: the values of a dictionary as a list, in the same
+/// order as `keys`.
+fn values(vm: &PolarVirtualMachine, args: &[Term]) -> PolarResult<Goals> {
+    let dict = dict_arg(vm, "values", &args[0])?;
+    output(args, Value::List(dict.fields.values().cloned().collect()))
+}
+
+/// `merge(base, overrides, merged)`: a dictionary with the fields of both
+/// arguments. Fields of `overrides` replace those of `base` with the same key.
+fn merge(vm: &PolarVirtualMachine, args: &[Term]) -> PolarResult<Goals> {
+    let mut merged = dict_arg(vm, "merge", &args[0])?;
+    merged
+        .fields
+        .extend(dict_arg(vm, "merge", &args[1])?.fields);
+    output(args, Value::Dictionary(merged))
+}
+
+/// `without(dict, key, rest)`: a dictionary with the fields of `dict` except
+/// `key`, which may be a string or a list of strings.
+fn without(vm: &PolarVirtualMachine, args: &[Term]) -> PolarResult<Goals> {
+    let mut rest = dict_arg(vm, "without", &args[0])?;
+    let key = vm.deep_deref(&args[1]);
+    let removed = match key.value() {
+        Value::List(keys) if !has_rest_var(keys) => keys.clone(),
+        _ => vec![key.clone()],
+    };
+    for k in removed {
+        match k.value() {
+            Value::String(k) => rest.fields.shift_remove(&Symbol::new(k)),
+            _ => {
+                return Err(vm.type_error(
+                    &args[1],
+                    format!("without expects string keys, got: {}", k.to_polar()),
+                ))
+            }
+        };
+    }
+    output(args, Value::Dictionary(rest))
+}
+
+/// `set(list, set)`: the distinct elements of a list as a set.
+fn set(vm: &PolarVirtualMachine, args: &[Term]) -> PolarResult<Goals> {
+    output(args, Value::Set(set_arg(vm, "set", &args[0])?))
+}
+
+/// `union(a, b, set)`: the elements in either `a` or `b`.
+fn union(vm: &PolarVirtualMachine, args: &[Term]) -> PolarResult<Goals> {
+    let mut elements = set_arg(vm, "union", &args[0])?.elements;
+    elements.extend(set_arg(vm, "union", &args[1])?.elements);
+    output(args, Value::Set(Set::new(elements)))
+}
+
+/// `intersection(a, b, set)`: the elements in both `a` and `b`.
+fn intersection(vm: &PolarVirtualMachine, args: &[Term]) -> PolarResult<Goals> {
+    let left = set_arg(vm, "intersection", &args[0])?;
+    let right = set_arg(vm, "intersection", &args[1])?;
+    let elements = left
+        .elements
+        .into_iter()
+        .filter(|e| right.contains(e))
+        .collect();
+    output(args, Value::Set(Set::new(elements)))
+}
+
+/// `difference(a, b, set)`: the elements in `a` but not in `b`.
+fn difference(vm: &PolarVirtualMachine, args: &[Term]) -> PolarResult<Goals> {
+    let left = set_arg(vm, "difference", &args[0])?;
+    let right = set_arg(vm, "difference", &args[1])?;
+    let elements = left
+        .elements
+        .into_iter()
+        .filter(|e| !right.contains(e))
+        .collect();
+    output(args, Value::Set(Set::new(elements)))
+}
+
+/// `equals_ignore_case(a, b)`: succeed if the strings `a` and `b` are equal
+/// after lowercasing, e.g., to compare email addresses or usernames.
+fn equals_ignore_case(vm: &PolarVirtualMachine, args: &[Term]) -> PolarResult<Goals> {
+    let a = string_arg(vm, "equals_ignore_case", &args[0])?;
+    let b = string_arg(vm, "equals_ignore_case", &args[1])?;
+    succeed_if(StringCollation::CaseInsensitive.compare(&a, &b) == Ordering::Equal)
+}
+
+/// `string_length(s, n)`: the number of characters in the string `s`.
+fn string_length(vm: &PolarVirtualMachine, args: &[Term]) -> PolarResult<Goals> {
+    let s = string_arg(vm, "string_length", &args[0])?;
+    output(
+        args,
+        Value::Number(Numeric::Integer(s.chars().count() as i64)),
+    )
+}
+
+/// `starts_with(s, prefix)`: succeed if the string `s` starts with `prefix`,
+/// e.g., to match resource paths under a directory.
+fn starts_with(vm: &PolarVirtualMachine, args: &[Term]) -> PolarResult<Goals> {
+    let s = string_arg(vm, "starts_with", &args[0])?;
+    succeed_if(s.starts_with(&string_arg(vm, "starts_with", &args[1])?))
+}
+
+/// `ends_with(s, suffix)`: succeed if the string `s` ends with `suffix`.
+fn ends_with(vm: &PolarVirtualMachine, args: &[Term]) -> PolarResult<Goals> {
+    let s = string_arg(vm, "ends_with", &args[0])?;
+    succeed_if(s.ends_with(&string_arg(vm, "ends_with", &args[1])?))
+}
+
+/// `contains(s, substring)`: succeed if `substring` occurs in the string `s`.
+fn contains(vm: &PolarVirtualMachine, args: &[Term]) -> PolarResult<Goals> {
+    let s = string_arg(vm, "contains", &args[0])?;
+    succeed_if(s.contains(&string_arg(vm, "contains", &args[1])?))
+}
+
+/// Most compiled patterns kept by `matches_regex` per thread.
+const MAX_CACHED_REGEXES: usize = 256;
+
+thread_local! {
+    /// Patterns compiled by `matches_regex`, since policies tend to check
+    /// many strings against the same few patterns.
+    static REGEXES: RefCell<HashMap<String, Regex>> = RefCell::new(HashMap::new());
+}
+
+/// `matches_regex(s, pattern)`: succeed if the regular expression `pattern`
+/// matches anywhere in the string `s`, e.g., to match URL paths. Patterns
+/// can be anchored with `^` and `$` to match the whole string.
+fn matches_regex(vm: &PolarVirtualMachine, args: &[Term]) -> PolarResult<Goals> {
+    let s = string_arg(vm, "matches_regex", &args[0])?;
+    let pattern = string_arg(vm, "matches_regex", &args[1])?;
+    REGEXES.with(|regexes| {
+        let mut regexes = regexes.borrow_mut();
+        if !regexes.contains_key(&pattern) {
+            let regex = Regex::new(&pattern).map_err(|e| {
+                vm.type_error(
+                    &args[1],
+                    format!("matches_regex got an invalid pattern: {}", e),
+                )
+            })?;
+            if regexes.len() >= MAX_CACHED_REGEXES {
+                regexes.clear();
+            }
+            regexes.insert(pattern.clone(), regex);
+        }
+        succeed_if(regexes[&pattern].is_match(&s))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compare_terms() {
+        let sorted = |mut terms: Vec<Term>| {
+            terms.sort_by(compare_terms);
+            terms
+        };
+        assert_eq!(
+            sorted(vec![term!("a"), term!(2), term!(1.5), term!(false)]),
+            vec![term!(false), term!(1.5), term!(2), term!("a")]
+        );
+        assert_eq!(
+            sorted(vec![term!([1, 2]), term!([1]), term!([0, 5])]),
+            vec![term!([0, 5]), term!([1]), term!([1, 2])]
+        );
+
+        // Dictionaries compare by their sorted fields, not field order.
+        let ab = term!(btreemap! {sym!("a") => term!(1), sym!("b") => term!(2)});
+        let ba = Term::new_from_test(Value::Dictionary(Dictionary {
+            fields: indexmap! {sym!("b") => term!(2), sym!("a") => term!(1)},
+        }));
+        let ac = term!(btreemap! {sym!("a") => term!(1), sym!("c") => term!(0)});
+        assert_eq!(compare_terms(&ab, &ba), Ordering::Equal);
+        assert_eq!(compare_terms(&ab, &ac), Ordering::Less);
+    }
+}
@@ -0,0 +1,86 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::counter::Counter;
+use crate::error::PolarResult;
+use crate::events::QueryEvent;
+use crate::runnable::Runnable;
+use crate::terms::{Term, TermList};
+use crate::vm::{Goal, PolarVirtualMachine};
+
+/// The collector implements findall-style aggregation in Polar.
+///
+/// It is a `Runnable` that runs a condition using `vm`, and for every result
+/// records the value of `template` under that result's bindings.
+///
+/// Collecting always succeeds; when the condition has no results, the collected
+/// list is empty. The collected values are passed back to the parent VM through
+/// `collected`, which is shared with a `Goal::UnifyCollected`.
+#[derive(Clone)]
+pub struct Collector {
+    vm: PolarVirtualMachine,
+
+    /// The term to evaluate for each result.
+    template: Term,
+
+    /// Values of `template` collected so far.
+    results: TermList,
+
+    /// Collected values to return to the parent VM.
+    collected: Rc<RefCell<TermList>>,
+}
+
+impl Collector {
+    pub fn new(
+        vm: &PolarVirtualMachine,
+        condition: Term,
+        template: Term,
+        collected: Rc<RefCell<TermList>>,
+    ) -> Self {
+        Self {
+            vm: vm.clone_with_goals(vec![Goal::Query { term: condition }]),
+            template,
+            results: vec![],
+            collected,
+        }
+    }
+}
+
+impl Runnable for Collector {
+    fn run(&mut self, _: Option<&mut Counter>) -> PolarResult<QueryEvent> {
+        loop {
+            // Pass most events through, but collect results.
+            match self.vm.run(None)? {
+                QueryEvent::Done { .. } => {
+                    *self.collected.borrow_mut() = self.results.drain(..).collect();
+                    return Ok(QueryEvent::Done {
+                        result: true,
+                        policy_version: None,
+                        stats: None,
+                    });
+                }
+                QueryEvent::Result { .. } => {
+                    let value = self.vm.deep_deref(&self.template);
+                    self.results.push(value);
+                }
+                event => return Ok(event),
+            }
+        }
+    }
+
+    fn external_question_result(&mut self, call_id: u64, answer: bool) -> PolarResult<()> {
+        self.vm.external_question_result(call_id, answer)
+    }
+
+    fn external_call_result(&mut self, call_id: u64, term: Option<Term>) -> PolarResult<()> {
+        self.vm.external_call_result(call_id, term)
+    }
+
+    fn debug_command(&mut self, command: &str) -> PolarResult<()> {
+        self.vm.debug_command(command)
+    }
+
+    fn clone_runnable(&self) -> Box<dyn Runnable> {
+        Box::new(self.clone())
+    }
+}
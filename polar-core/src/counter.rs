@@ -5,6 +5,9 @@ use crate::numerics::MOST_POSITIVE_EXACT_FLOAT;
 
 const MAX_ID: u64 = (MOST_POSITIVE_EXACT_FLOAT - 1) as u64;
 
+/// A shared source of monotonically increasing integer IDs.
+///
+/// Clones share the same underlying count.
 #[derive(Clone)]
 pub struct Counter {
     next: Arc<AtomicU64>,
@@ -42,6 +45,12 @@ impl Counter {
             self.next.fetch_add(1, Ordering::SeqCst)
         }
     }
+
+    /// Make sure IDs from now on are at least `next`, e.g., to avoid reusing
+    /// IDs handed out by another counter.
+    pub fn advance_to(&self, next: u64) {
+        self.next.fetch_max(next, Ordering::SeqCst);
+    }
 }
 
 #[test]
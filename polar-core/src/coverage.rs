@@ -0,0 +1,178 @@
+//! The actor and resource types a host authorizes, and the actions on each
+//! resource, so that a policy can be checked for resource actions that no
+//! `allow` rule mentions, e.g., a new endpoint nobody wrote a policy for.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde::{Deserialize, Serialize};
+
+use crate::formatting::source_lines;
+use crate::kb::KnowledgeBase;
+use crate::rules::Rule;
+use crate::terms::*;
+
+/// Name of the rules checked for coverage, `allow(actor, action, resource)`.
+pub const ALLOW: &str = "allow";
+
+/// The closed set of types a host authorizes, e.g.,
+///
+/// ```json
+/// {
+///   "actors": ["User", "ApiKey"],
+///   "resources": {"Document": ["read", "write"], "Folder": ["list"]},
+///   "unhandled": [["Folder", "list"]]
+/// }
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AuthorizationTypes {
+    pub actors: Vec<String>,
+    /// Resource type → the actions on it.
+    pub resources: BTreeMap<String, Vec<String>>,
+    /// Resource actions deliberately left without rules, which are denied.
+    pub unhandled: Vec<(String, String)>,
+}
+
+/// The class named by a parameter's specializer: `Some(None)` if it has no
+/// specializer, and `None` if it's specialized on something else.
+fn specializer_class(rule: &Rule, i: usize) -> Option<Option<&str>> {
+    match rule.params[i].specializer.as_ref().map(Term::value) {
+        None => Some(None),
+        Some(Value::Pattern(Pattern::Instance(InstanceLiteral { tag, .. }))) => {
+            Some(Some(tag.0.as_str()))
+        }
+        Some(_) => None,
+    }
+}
+
+/// Problems with how `allow` rules in `kb` cover `types`: rules specialized
+/// on undeclared types, and declared resource actions that no rule mentions
+/// and that aren't marked unhandled.
+///
+/// A rule mentions an action with a string parameter, or every action with
+/// a variable, and mentions the resource type it specializes its resource
+/// on, or every type if it doesn't. So a rule that narrows its action in its
+/// body, e.g., with `action in ["read", "write"]`, covers every action.
+pub fn check_coverage(types: &AuthorizationTypes, kb: &KnowledgeBase) -> Vec<String> {
+    let mut problems = vec![];
+    let rules = kb
+        .rules
+        .get(&Symbol::new(ALLOW))
+        .map(|generic_rule| generic_rule.rules())
+        .unwrap_or_default();
+    let undeclared = |rule: &Rule, i: usize, param: &str, class: &str| {
+        let mut msg = format!(
+            "{} rule specializes its {} on {}, which isn't a declared {} type",
+            ALLOW, param, class, param
+        );
+        let specializer = rule.params[i].specializer.as_ref().unwrap();
+        if let Some(ref source) = specializer
+            .get_source_id()
+            .and_then(|id| kb.sources.get_source(id))
+        {
+            msg.push('\n');
+            msg.push_str(&source_lines(source, specializer.offset(), 0));
+        }
+        msg
+    };
+
+    // (resource, action) pairs mentioned, with `None` for every one.
+    let mut covered: BTreeSet<(Option<&str>, Option<&str>)> = BTreeSet::new();
+    for rule in rules.iter().filter(|rule| rule.params.len() == 3) {
+        if let Some(Some(actor)) = specializer_class(rule, 0) {
+            if !types.actors.iter().any(|a| a == actor) {
+                problems.push(undeclared(rule, 0, "actor", actor));
+            }
+        }
+        let resource = match specializer_class(rule, 2) {
+            Some(Some(resource)) if !types.resources.contains_key(resource) => {
+                problems.push(undeclared(rule, 2, "resource", resource));
+                continue;
+            }
+            Some(resource) => resource,
+            None => continue,
+        };
+        let action = match rule.params[1].parameter.value() {
+            Value::String(action) => Some(action.as_str()),
+            Value::Variable(_) => None,
+            _ => continue,
+        };
+        covered.insert((resource, action));
+    }
+
+    for (resource, actions) in &types.resources {
+        for action in actions {
+            let is_covered = [Some(resource.as_str()), None].iter().any(|r| {
+                covered.contains(&(*r, Some(action.as_str()))) || covered.contains(&(*r, None))
+            });
+            let is_unhandled = types
+                .unhandled
+                .iter()
+                .any(|(r, a)| r == resource && a == action);
+            if !is_covered && !is_unhandled {
+                problems.push(format!(
+                    "No {} rule covers action \"{}\" on resource {}",
+                    ALLOW, action, resource
+                ));
+            }
+        }
+    }
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::polar::Polar;
+
+    #[test]
+    fn test_check_coverage() {
+        let types = AuthorizationTypes {
+            actors: vec!["User".to_string()],
+            resources: btreemap! {
+                "Document".to_string() => vec!["read".to_string(), "write".to_string()],
+                "Folder".to_string() => vec!["list".to_string(), "delete".to_string()],
+            },
+            unhandled: vec![("Folder".to_string(), "delete".to_string())],
+        };
+        let problems = |src: &str| {
+            let polar = Polar::new();
+            polar.load_str(src).unwrap();
+            let kb = polar.kb.read().unwrap();
+            check_coverage(&types, &kb)
+        };
+
+        assert_eq!(
+            problems(r#"allow(_: User, "read", _: Document);"#),
+            vec![
+                "No allow rule covers action \"write\" on resource Document",
+                "No allow rule covers action \"list\" on resource Folder",
+            ]
+        );
+
+        // Variables cover every action, and unspecialized resources every type.
+        assert!(
+            problems(r#"allow(_: User, _action, _: Document); allow(_, "list", _);"#).is_empty()
+        );
+
+        let src = r#"allow(_: Admin, _, _: Doc); allow(_, _, _: Folder);"#;
+        let snippet = |column: usize| format!("001: {}\n{}^", src, " ".repeat(column + 5));
+        assert_eq!(
+            problems(src),
+            vec![
+                format!(
+                    "allow rule specializes its actor on Admin, \
+                     which isn't a declared actor type\n{}",
+                    snippet(9)
+                ),
+                format!(
+                    "allow rule specializes its resource on Doc, \
+                     which isn't a declared resource type\n{}",
+                    snippet(22)
+                ),
+                "No allow rule covers action \"read\" on resource Document".to_string(),
+                "No allow rule covers action \"write\" on resource Document".to_string(),
+            ]
+        );
+    }
+}
@@ -0,0 +1,254 @@
+//! Dates and times, written in policies as ISO-8601 literals after an `@`,
+//! e.g., `@2024-05-01` or `@2024-05-01T09:30:00+02:00`, and compared in
+//! time order, e.g., `now < grant.expires_at`.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+const MS_PER_DAY: i64 = 86_400_000;
+
+/// An instant, to the millisecond.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct DateTime {
+    /// Milliseconds since the Unix epoch, 1970-01-01T00:00:00Z.
+    pub epoch_ms: i64,
+}
+
+/// The `n` ASCII digits of `s` as a number.
+fn digits(s: &str, n: usize) -> Option<i64> {
+    if s.len() == n && s.bytes().all(|b| b.is_ascii_digit()) {
+        s.parse().ok()
+    } else {
+        None
+    }
+}
+
+fn is_leap_year(year: i64) -> bool {
+    year % 4 == 0 && (year % 100 != 0 || year % 400 == 0)
+}
+
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Days since 1970-01-01 of a date in the proleptic Gregorian calendar.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// The date `days` since 1970-01-01, as (year, month, day).
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400;
+    (if month <= 2 { year + 1 } else { year }, month, day)
+}
+
+/// Milliseconds since midnight of a time, `HH:MM[:SS[.fff]]`.
+fn parse_time(time: &str) -> Option<i64> {
+    let (time, fraction) = match time.find('.') {
+        Some(i) => (&time[..i], Some(&time[i + 1..])),
+        None => (time, None),
+    };
+    let mut parts = time.split(':');
+    let hour = digits(parts.next()?, 2).filter(|h| *h < 24)?;
+    let minute = digits(parts.next()?, 2).filter(|m| *m < 60)?;
+    let second = match parts.next() {
+        Some(second) => digits(second, 2).filter(|s| *s < 60)?,
+        None if fraction.is_none() => 0,
+        None => return None,
+    };
+    if parts.next().is_some() {
+        return None;
+    }
+    // Fractions finer than milliseconds are truncated.
+    let ms = match fraction {
+        Some(f) if !f.is_empty() && f.bytes().all(|b| b.is_ascii_digit()) => {
+            format!("{:0<3}", &f[..f.len().min(3)]).parse().ok()?
+        }
+        Some(_) => return None,
+        None => 0,
+    };
+    Some(((hour * 60 + minute) * 60 + second) * 1000 + ms)
+}
+
+/// Milliseconds to add to a local time to get UTC, for `Z` or `±HH:MM`.
+fn parse_offset(offset: &str) -> Option<i64> {
+    if offset == "Z" || offset == "z" {
+        return Some(0);
+    }
+    let sign = match offset.as_bytes().first()? {
+        b'+' => -1,
+        b'-' => 1,
+        _ => return None,
+    };
+    let (hours, minutes) = offset[1..].split_at(offset[1..].find(':')?);
+    let hours = digits(hours, 2).filter(|h| *h < 24)?;
+    let minutes = digits(&minutes[1..], 2).filter(|m| *m < 60)?;
+    Some(sign * (hours * 60 + minutes) * 60_000)
+}
+
+impl DateTime {
+    pub fn from_epoch_ms(epoch_ms: i64) -> Self {
+        Self { epoch_ms }
+    }
+
+    /// Parse an ISO-8601 date, `YYYY-MM-DD`, which is taken as midnight
+    /// UTC, or date and time, `YYYY-MM-DDTHH:MM[:SS[.fff]]` followed by `Z`
+    /// or an offset from UTC like `+02:00`.
+    pub fn parse(s: &str) -> Option<Self> {
+        let (date, time) = match s.find(['T', 't']) {
+            Some(i) => (&s[..i], Some(&s[i + 1..])),
+            None => (s, None),
+        };
+        let mut parts = date.split('-');
+        let year = digits(parts.next()?, 4)?;
+        let month = digits(parts.next()?, 2).filter(|m| (1..=12).contains(m))?;
+        let day =
+            digits(parts.next()?, 2).filter(|d| (1..=days_in_month(year, month)).contains(d))?;
+        if parts.next().is_some() {
+            return None;
+        }
+        let ms = match time {
+            Some(time) => {
+                let zone = time.find(['Z', 'z', '+', '-'])?;
+                parse_time(&time[..zone])? + parse_offset(&time[zone..])?
+            }
+            None => 0,
+        };
+        Some(Self::from_epoch_ms(
+            days_from_civil(year, month, day) * MS_PER_DAY + ms,
+        ))
+    }
+
+    /// The instant in ISO-8601, in UTC, with milliseconds only if it has
+    /// any, e.g., `2024-05-01T07:30:00Z`.
+    pub fn to_iso8601(&self) -> String {
+        let (year, month, day) = civil_from_days(self.epoch_ms.div_euclid(MS_PER_DAY));
+        let ms = self.epoch_ms.rem_euclid(MS_PER_DAY);
+        let (seconds, ms) = (ms / 1000, ms % 1000);
+        let mut iso = format!(
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+            year,
+            month,
+            day,
+            seconds / 3600,
+            seconds / 60 % 60,
+            seconds % 60
+        );
+        if ms != 0 {
+            iso.push_str(&format!(".{:03}", ms));
+        }
+        iso.push('Z');
+        iso
+    }
+}
+
+/// Host times, truncated to the millisecond.
+impl From<SystemTime> for DateTime {
+    fn from(time: SystemTime) -> Self {
+        let epoch_ms = match time.duration_since(UNIX_EPOCH) {
+            Ok(since) => since.as_millis() as i64,
+            // Round towards the past, as for times after the epoch.
+            Err(e) => -(e.duration().as_nanos() as i64 + 999_999) / 1_000_000,
+        };
+        Self::from_epoch_ms(epoch_ms)
+    }
+}
+
+impl From<DateTime> for SystemTime {
+    fn from(time: DateTime) -> Self {
+        let since = Duration::from_millis(time.epoch_ms.unsigned_abs());
+        if time.epoch_ms < 0 {
+            UNIX_EPOCH - since
+        } else {
+            UNIX_EPOCH + since
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_datetimes() {
+        let ms = |s: &str| DateTime::parse(s).map(|dt| dt.epoch_ms);
+        assert_eq!(ms("1970-01-01"), Some(0));
+        assert_eq!(ms("1970-01-02T00:00:01Z"), Some(MS_PER_DAY + 1000));
+        assert_eq!(ms("2000-03-01T00:00Z"), Some(951_868_800_000));
+        assert_eq!(ms("1969-12-31T23:59:59.5Z"), Some(-500));
+        assert_eq!(ms("1970-01-01T02:00:00+02:00"), Some(0));
+        assert_eq!(ms("1969-12-31T22:30:00-01:30"), Some(0));
+        assert_eq!(ms("1970-01-01T00:00:00.123456Z"), Some(123));
+
+        for invalid in &[
+            "2024-02-30",
+            "2023-02-29",
+            "2024-13-01",
+            "24-01-01",
+            "2024-01-01T10:00:00",
+            "2024-01-01T24:00:00Z",
+            "2024-01-01T10:00:00+0200",
+            "2024-01-01T10:00.5Z",
+            "2024-01-01T10:00:00.Z",
+            "2024-01-01-01",
+        ] {
+            assert_eq!(DateTime::parse(invalid), None, "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_format_datetimes() {
+        for iso in &[
+            "1970-01-01T00:00:00Z",
+            "2024-02-29T23:59:59.999Z",
+            "1900-03-01T12:00:00Z",
+            "0001-01-01T00:00:00Z",
+            "9999-12-31T23:59:59Z",
+        ] {
+            assert_eq!(DateTime::parse(iso).unwrap().to_iso8601(), *iso);
+        }
+        assert_eq!(
+            DateTime::parse("2024-05-01T09:30:00+02:00")
+                .unwrap()
+                .to_iso8601(),
+            "2024-05-01T07:30:00Z"
+        );
+    }
+
+    #[test]
+    fn test_system_times() {
+        let time = UNIX_EPOCH + Duration::from_micros(1_500_250);
+        assert_eq!(DateTime::from(time).epoch_ms, 1500);
+        assert_eq!(
+            SystemTime::from(DateTime::from(time)),
+            UNIX_EPOCH + Duration::from_millis(1500)
+        );
+
+        let before = UNIX_EPOCH - Duration::from_micros(1_500_250);
+        assert_eq!(DateTime::from(before).epoch_ms, -1501);
+        assert_eq!(
+            SystemTime::from(DateTime::from_epoch_ms(-1501)),
+            UNIX_EPOCH - Duration::from_millis(1501)
+        );
+    }
+}
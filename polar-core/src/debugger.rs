@@ -3,7 +3,6 @@ use std::rc::Rc;
 
 use super::error::PolarResult;
 use super::formatting::{source_lines, ToPolarString};
-use super::sources::*;
 use super::terms::*;
 use super::traces::*;
 
@@ -76,14 +75,11 @@ pub struct Debugger {
 impl Debugger {
     /// Retrieve the original source line (and, optionally, additional lines of context) for the
     /// current query.
-    fn query_source(&self, query: &Term, sources: &Sources, num_lines: usize) -> String {
-        query
-            .get_source_id()
-            .and_then(|id| sources.get_source(id))
-            .map_or_else(
-                || "".to_string(),
-                |source| source_lines(&source, query.offset(), num_lines),
-            )
+    fn query_source(&self, query: &Term, vm: &PolarVirtualMachine, num_lines: usize) -> String {
+        vm.source(query).map_or_else(
+            || "".to_string(),
+            |source| source_lines(&source, query.offset(), num_lines),
+        )
     }
 
     /// When the [`VM`](../vm/struct.PolarVirtualMachine.html) hits a breakpoint, check if
@@ -137,7 +133,7 @@ impl Debugger {
                         args,
                     }) if args.len() == 1 => None,
                     _ => {
-                        let source = self.query_source(&q, &vm.kb.read().unwrap().sources, 3);
+                        let source = self.query_source(q, vm, 3);
                         Some(format!("{}\n\n{}\n", vm.query_summary(q), source))
                     }
                 }
@@ -193,7 +189,7 @@ impl Debugger {
                 return Some(Goal::Debug {
                     message: vm.queries.last().map_or_else(
                         || "".to_string(),
-                        |query| self.query_source(&query, &vm.kb.read().unwrap().sources, lines),
+                        |query| self.query_source(query, vm, lines),
                     ),
                 });
             }
@@ -269,9 +265,9 @@ impl Debugger {
                                 } else {
                                     let _ = write!(st, "in query ");
                                 }
-                                let (row, column) = crate::lexer::loc_to_pos(&source.src, t.offset());
-                                let _ = write!(st, "at line {}, column {}", row + 1, column + 1);
-                                if let Some(filename) = source.filename {
+                                let position = source.position(t.offset());
+                                let _ = write!(st, "at line {}, column {}", position.row + 1, position.column + 1);
+                                if let Some(filename) = position.filename {
                                     let _ = write!(st, " in file {}", filename);
                                 }
                                 let _ = writeln!(st);
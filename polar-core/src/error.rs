@@ -2,6 +2,7 @@ use serde::{Deserialize, Serialize};
 
 use std::fmt;
 
+use crate::formatting::source_lines;
 use crate::sources::*;
 use crate::terms::*;
 
@@ -10,19 +11,77 @@ use crate::terms::*;
 pub struct PolarError {
     pub kind: ErrorKind,
     pub context: Option<ErrorContext>,
+    /// Rules that were being evaluated when a runtime error occurred,
+    /// outermost first.
+    #[serde(default)]
+    pub stack: Vec<StackFrame>,
 }
 
+/// A rule being evaluated, and the call that invoked it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StackFrame {
+    pub rule: String,
+    /// The call's arguments, with their values when the error occurred.
+    pub args: Vec<String>,
+    pub file: Option<String>,
+    /// Line of the call, starting from 1.
+    pub line: Option<usize>,
+    /// Column of the call, starting from 1.
+    pub column: Option<usize>,
+}
+
+impl fmt::Display for StackFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}({})", self.rule, self.args.join(", "))?;
+        if let (Some(line), Some(column)) = (self.line, self.column) {
+            write!(f, " at line {}, column {}", line, column)?;
+        }
+        if let Some(ref file) = self.file {
+            write!(f, " in file {}", file)?;
+        }
+        Ok(())
+    }
+}
+
+/// How errors are serialized for hosts: the error itself, alongside the
+/// pieces they need to present it without parsing `formatted`.
 #[derive(Clone, Deserialize, Serialize)]
 pub struct FormattedPolarError {
     pub kind: ErrorKind,
     pub formatted: String,
+    #[serde(default)]
+    pub code: String,
+    /// The error message, without its location.
+    #[serde(default)]
+    pub message: String,
+    #[serde(default)]
+    pub file: Option<String>,
+    /// Line of the error, starting from 1.
+    #[serde(default)]
+    pub line: Option<usize>,
+    /// Column of the error, starting from 1.
+    #[serde(default)]
+    pub column: Option<usize>,
+    /// The source line of the error, with a caret under the column.
+    #[serde(default)]
+    pub snippet: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub stack: Vec<StackFrame>,
 }
 
 impl From<PolarError> for FormattedPolarError {
     fn from(other: PolarError) -> Self {
+        let context = other.context.as_ref();
         Self {
             formatted: other.to_string(),
+            code: other.code().to_string(),
+            message: other.kind.to_string(),
+            file: context.and_then(|c| c.source.filename.clone()),
+            line: context.map(|c| c.row + 1),
+            column: context.map(|c| c.column + 1),
+            snippet: context.map(ErrorContext::snippet),
             kind: other.kind,
+            stack: other.stack,
         }
     }
 }
@@ -35,41 +94,75 @@ pub enum ErrorKind {
     Parameter(ParameterError),
 }
 
+impl ErrorKind {
+    /// Stable code identifying the kind of error, e.g. `P0004`. The letter
+    /// gives the category and the number the variant; codes are never reused
+    /// when variants are added or removed.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Parse(e) => e.code(),
+            Self::Runtime(e) => e.code(),
+            Self::Operational(e) => e.code(),
+            Self::Parameter(e) => e.code(),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ErrorContext {
     pub source: Source,
     pub row: usize,
     pub column: usize,
+    /// Byte offset of the error in the source.
+    #[serde(default)]
+    pub offset: usize,
+}
+
+impl ErrorContext {
+    fn new(source: &Source, offset: usize) -> Self {
+        let Position { row, column, .. } = source.position(offset);
+        Self {
+            source: source.clone(),
+            row,
+            column,
+            offset,
+        }
+    }
+
+    /// The source line the error is on, with a caret under its column.
+    pub fn snippet(&self) -> String {
+        source_lines(&self.source, self.offset, 0)
+    }
 }
 
 impl PolarError {
+    /// Stable code identifying the kind of error; see [`ErrorKind::code`].
+    pub fn code(&self) -> &'static str {
+        self.kind.code()
+    }
+
     pub fn set_context(mut self, source: Option<&Source>, term: Option<&Term>) -> Self {
         match (&self.kind, source, term) {
             (ErrorKind::Parse(e), Some(source), _) => match e {
                 ParseError::IntegerOverflow { loc, .. }
                 | ParseError::InvalidTokenCharacter { loc, .. }
                 | ParseError::InvalidToken { loc, .. }
+                | ParseError::InvalidDateTime { loc, .. }
+                | ParseError::InvalidEscape { loc, .. }
                 | ParseError::UnrecognizedEOF { loc }
                 | ParseError::UnrecognizedToken { loc, .. }
                 | ParseError::ExtraToken { loc, .. }
                 | ParseError::WrongValueType { loc, .. }
+                | ParseError::InvalidAnnotation { loc, .. }
+                | ParseError::LimitExceeded { loc, .. }
                 | ParseError::ReservedWord { loc, .. } => {
-                    let (row, column) = crate::lexer::loc_to_pos(&source.src, *loc);
-                    self.context.replace(ErrorContext {
-                        source: source.clone(),
-                        row,
-                        column,
-                    });
+                    self.context.replace(ErrorContext::new(source, *loc));
                 }
                 _ => {}
             },
             (_, Some(source), Some(term)) => {
-                let (row, column) = crate::lexer::loc_to_pos(&source.src, term.offset());
-                self.context.replace(ErrorContext {
-                    source: source.clone(),
-                    row,
-                    column,
-                });
+                self.context
+                    .replace(ErrorContext::new(source, term.offset()));
             }
             _ => {}
         }
@@ -82,6 +175,7 @@ impl From<ParseError> for PolarError {
         Self {
             kind: ErrorKind::Parse(err),
             context: None,
+            stack: vec![],
         }
     }
 }
@@ -91,6 +185,7 @@ impl From<RuntimeError> for PolarError {
         Self {
             kind: ErrorKind::Runtime(err),
             context: None,
+            stack: vec![],
         }
     }
 }
@@ -100,6 +195,7 @@ impl From<OperationalError> for PolarError {
         Self {
             kind: ErrorKind::Operational(err),
             context: None,
+            stack: vec![],
         }
     }
 }
@@ -109,22 +205,38 @@ impl From<ParameterError> for PolarError {
         Self {
             kind: ErrorKind::Parameter(err),
             context: None,
+            stack: vec![],
         }
     }
 }
 
 pub type PolarResult<T> = std::result::Result<T, PolarError>;
 
-impl std::error::Error for PolarError {}
+impl std::error::Error for PolarError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match &self.kind {
+            ErrorKind::Parse(e) => Some(e),
+            ErrorKind::Runtime(e) => Some(e),
+            ErrorKind::Operational(e) => Some(e),
+            ErrorKind::Parameter(e) => Some(e),
+        }
+    }
+}
 
-impl fmt::Display for PolarError {
+impl fmt::Display for ErrorKind {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match &self.kind {
-            ErrorKind::Parse(e) => write!(f, "{}", e)?,
-            ErrorKind::Runtime(e) => write!(f, "{}", e)?,
-            ErrorKind::Operational(e) => write!(f, "{}", e)?,
-            ErrorKind::Parameter(e) => write!(f, "{}", e)?,
+        match self {
+            Self::Parse(e) => write!(f, "{}", e),
+            Self::Runtime(e) => write!(f, "{}", e),
+            Self::Operational(e) => write!(f, "{}", e),
+            Self::Parameter(e) => write!(f, "{}", e),
         }
+    }
+}
+
+impl fmt::Display for PolarError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.kind)?;
         if let Some(ref context) = self.context {
             write!(f, "{}", context)?;
         }
@@ -166,17 +278,59 @@ pub enum ParseError {
         token: String,
         loc: usize,
     },
+    InvalidDateTime {
+        token: String,
+        loc: usize,
+    },
+    /// A backslash in a string that doesn't start a known escape sequence,
+    /// or a `\u{...}` escape that isn't a unicode scalar value.
+    InvalidEscape {
+        token: String,
+        loc: usize,
+    },
     WrongValueType {
         loc: usize,
         term: Term,
         expected: String,
     },
+    InvalidAnnotation {
+        name: String,
+        loc: usize,
+    },
+    /// The source exceeds one of the parser's configured limits.
+    LimitExceeded {
+        limit: String,
+        max: usize,
+        loc: usize,
+    },
 }
 
+impl ParseError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::IntegerOverflow { .. } => "P0001",
+            Self::InvalidTokenCharacter { .. } => "P0002",
+            Self::InvalidToken { .. } => "P0003",
+            Self::UnrecognizedEOF { .. } => "P0004",
+            Self::UnrecognizedToken { .. } => "P0005",
+            Self::ExtraToken { .. } => "P0006",
+            Self::ReservedWord { .. } => "P0007",
+            Self::InvalidFloat { .. } => "P0008",
+            Self::WrongValueType { .. } => "P0009",
+            Self::InvalidAnnotation { .. } => "P0010",
+            Self::LimitExceeded { .. } => "P0011",
+            Self::InvalidDateTime { .. } => "P0012",
+            Self::InvalidEscape { .. } => "P0013",
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
 impl fmt::Display for ErrorContext {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, " at line {}, column {}", self.row + 1, self.column + 1)?;
-        if let Some(ref filename) = self.source.filename {
+        if let Some(filename) = self.source.position(self.offset).filename {
             write!(f, " in file {}", filename)?;
         }
         Ok(())
@@ -185,6 +339,7 @@ impl fmt::Display for ErrorContext {
 
 impl fmt::Display for ParseError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[{}] ", self.code())?;
         match self {
             Self::IntegerOverflow { token, .. } => {
                 write!(f, "'{}' caused an integer overflow", token.escape_debug())
@@ -220,9 +375,27 @@ impl fmt::Display for ParseError {
                 "{} was parsed as a float, but is invalid",
                 token.escape_debug()
             ),
+            Self::InvalidDateTime { token, .. } => write!(
+                f,
+                "@{} is not a valid ISO-8601 date, or date and time with a UTC offset",
+                token.escape_debug()
+            ),
+            Self::InvalidEscape { token, .. } => {
+                write!(f, "{} is not a valid escape sequence in a string", token)
+            }
             Self::WrongValueType { term, expected, .. } => {
                 write!(f, "Wrong value type: {}. Expected a {}", term, expected)
             }
+            Self::LimitExceeded { limit, max, .. } => {
+                write!(f, "exceeded the parser's limit on {} of {}", limit, max)
+            }
+            Self::InvalidAnnotation { name, .. } => {
+                write!(
+                    f,
+                    "Invalid arguments to annotation @{}",
+                    name.escape_debug()
+                )
+            }
         }
     }
 }
@@ -262,9 +435,72 @@ pub enum RuntimeError {
     IncompatibleBindings {
         msg: String,
     },
+    /// A negated goal has unbound variables that are used after the negation.
+    UnsafeNegation {
+        negated: String,
+        vars: Vec<Symbol>,
+    },
+    /// A query produced more results than the `Polar` instance allows.
+    TooManyResults {
+        max: usize,
+    },
+    /// A replayed query asked something other than what its recording holds.
+    ReplayDiverged {
+        expected: String,
+        found: String,
+    },
+    /// A call to a rule with a `@budget` annotation did more work than it
+    /// allows.
+    BudgetExceeded {
+        rule: String,
+        msg: String,
+    },
+    /// Inline queries run on load that didn't succeed, each with its
+    /// location and why.
+    InlineQueriesFailed {
+        failures: Vec<String>,
+    },
+    /// The policy's `allow` rules don't cover the declared authorization
+    /// types.
+    IncompleteCoverage {
+        problems: Vec<String>,
+    },
+    /// A call left arguments unbound that the rules it calls declare `+`.
+    ModeViolation {
+        call: String,
+        unbound: Vec<String>,
+    },
+    /// A sandboxed query exceeded one of its limits.
+    SandboxLimitExceeded {
+        limit: String,
+        max: u64,
+    },
 }
 
 impl RuntimeError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::ArithmeticError { .. } => "R0001",
+            Self::Serialization { .. } => "R0002",
+            Self::Unsupported { .. } => "R0003",
+            Self::TypeError { .. } => "R0004",
+            Self::UnboundVariable { .. } => "R0005",
+            Self::StackOverflow { .. } => "R0006",
+            Self::QueryTimeout { .. } => "R0007",
+            Self::Application { .. } => "R0008",
+            Self::FileLoading { .. } => "R0009",
+            Self::IncompatibleBindings { .. } => "R0010",
+            Self::UnsafeNegation { .. } => "R0011",
+            Self::TooManyResults { .. } => "R0012",
+            Self::ReplayDiverged { .. } => "R0013",
+            Self::BudgetExceeded { .. } => "R0014",
+            Self::InlineQueriesFailed { .. } => "R0015",
+            Self::IncompleteCoverage { .. } => "R0016",
+            Self::ModeViolation { .. } => "R0017",
+            Self::SandboxLimitExceeded { .. } => "R0018",
+        }
+    }
+
     pub fn add_stack_trace(&mut self, vm: &crate::vm::PolarVirtualMachine) {
         match self {
             Self::Application { stack_trace, .. } | Self::TypeError { stack_trace, .. } => {
@@ -275,31 +511,76 @@ impl RuntimeError {
     }
 }
 
+impl std::error::Error for RuntimeError {}
+
 impl fmt::Display for RuntimeError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::TypeError {
+                stack_trace: Some(stack_trace),
+                ..
+            }
+            | Self::Application {
+                stack_trace: Some(stack_trace),
+                ..
+            } => writeln!(f, "{}", stack_trace)?,
+            _ => {}
+        }
+        write!(f, "[{}] ", self.code())?;
         match self {
             Self::ArithmeticError { msg } => write!(f, "Arithmetic error: {}", msg),
             Self::Serialization { msg } => write!(f, "Serialization error: {}", msg),
             Self::Unsupported { msg } => write!(f, "Not supported: {}", msg),
-            Self::TypeError { msg, stack_trace } => {
-                if let Some(stack_trace) = stack_trace {
-                    writeln!(f, "{}", stack_trace)?;
-                }
-                write!(f, "Type error: {}", msg)
-            }
+            Self::TypeError { msg, .. } => write!(f, "Type error: {}", msg),
             Self::UnboundVariable { sym } => write!(f, "{} is an unbound variable", sym.0),
             Self::StackOverflow { msg } => write!(f, "Hit a stack limit: {}", msg),
             Self::QueryTimeout { msg } => write!(f, "Query timeout: {}", msg),
-            Self::Application { msg, stack_trace } => {
-                if let Some(stack_trace) = stack_trace {
-                    writeln!(f, "{}", stack_trace)?;
-                }
-                write!(f, "Application error: {}", msg)
-            }
+            Self::Application { msg, .. } => write!(f, "Application error: {}", msg),
             Self::FileLoading { msg } => write!(f, "Problem loading file: {}", msg),
             Self::IncompatibleBindings { msg } => {
                 write!(f, "Attempted binding was incompatible: {}", msg)
             }
+            Self::UnsafeNegation { negated, vars } => {
+                let vars = vars.iter().map(|v| v.0.as_str()).collect::<Vec<_>>();
+                write!(
+                    f,
+                    "Unsafe negation: `not {}` is evaluated while {} {} unbound, \
+                     but used after the negation",
+                    negated,
+                    vars.join(", "),
+                    if vars.len() == 1 { "is" } else { "are" }
+                )
+            }
+            Self::TooManyResults { max } => write!(
+                f,
+                "Query produced more than the maximum of {} results; \
+                 is a variable missing a constraint?",
+                max
+            ),
+            Self::ReplayDiverged { expected, found } => write!(
+                f,
+                "Replayed query diverged from its recording: expected {}, found {}",
+                expected, found
+            ),
+            Self::BudgetExceeded { rule, msg } => {
+                write!(f, "Rule {} exceeded its budget: {}", rule, msg)
+            }
+            Self::InlineQueriesFailed { failures } => {
+                write!(f, "Inline queries failed: {}", failures.join("; "))
+            }
+            Self::IncompleteCoverage { problems } => {
+                write!(f, "Incomplete policy coverage:\n{}", problems.join("\n"))
+            }
+            Self::ModeViolation { call, unbound } => write!(
+                f,
+                "Mode violation: {} is called with {} unbound, but its rules declare {} +",
+                call,
+                unbound.join(", "),
+                if unbound.len() == 1 { "it" } else { "them" }
+            ),
+            Self::SandboxLimitExceeded { limit, max } => {
+                write!(f, "exceeded the sandbox's limit on {} of {}", limit, max)
+            }
         }
     }
 }
@@ -309,12 +590,28 @@ pub enum OperationalError {
     Unimplemented(String),
     Unknown,
 
-    /// An invariant has been broken internally.
+    /// An invariant has been broken internally, e.g., by a bug in the VM or
+    /// a panic that poisoned a lock. This is the error for internal failures:
+    /// the VM returns it rather than panicking, since panics abort on wasm32,
+    /// and hosts already raise it as an operational error.
     InvalidState(String),
 }
 
+impl OperationalError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Unimplemented(_) => "O0001",
+            Self::Unknown => "O0002",
+            Self::InvalidState(_) => "O0003",
+        }
+    }
+}
+
+impl std::error::Error for OperationalError {}
+
 impl fmt::Display for OperationalError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[{}] ", self.code())?;
         match self {
             Self::Unimplemented(s) => write!(f, "{} is not yet implemented", s),
             Self::InvalidState(s) => write!(f, "Invalid state: {}", s),
@@ -331,8 +628,21 @@ impl fmt::Display for OperationalError {
 /// Parameter passed to FFI lib function is invalid.
 pub struct ParameterError(pub String);
 
+impl ParameterError {
+    pub fn code(&self) -> &'static str {
+        "F0001"
+    }
+}
+
+impl std::error::Error for ParameterError {}
+
 impl fmt::Display for ParameterError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Invalid parameter used in FFI function: {}", self.0)
+        write!(
+            f,
+            "[{}] Invalid parameter used in FFI function: {}",
+            self.code(),
+            self.0
+        )
     }
 }
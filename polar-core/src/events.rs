@@ -7,6 +7,21 @@ use super::runnable::Runnable;
 use super::terms::*;
 use super::traces::*;
 
+/// What a query cost to run, reported when it's done.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct QueryStats {
+    /// Results the query produced.
+    pub results: usize,
+    /// Goals the VM executed.
+    pub goals_executed: u64,
+    /// Calls, lookups and checks the host was asked to make.
+    pub external_calls: u64,
+    /// The most choice points on the stack of any VM at once.
+    pub peak_choice_points: usize,
+    /// Time from the query starting to run until it was done.
+    pub duration_ms: u64,
+}
+
 #[allow(clippy::large_enum_variant)]
 #[must_use]
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -16,6 +31,14 @@ pub enum QueryEvent {
     /// This runnable is complete with `result`.
     Done {
         result: bool,
+        /// Version of the policy the query was evaluated against, if it has one.
+        /// Only set for the query's final `Done`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        policy_version: Option<String>,
+        /// What the query cost to run, if enabled with `Query::enable_stats`.
+        /// Only set for the query's final `Done`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        stats: Option<QueryStats>,
     },
 
     /// Run `runnable`, and report the result to its parent using `call_id`
@@ -90,6 +113,9 @@ pub enum QueryEvent {
     Result {
         bindings: Bindings,
         trace: Option<TraceResult>,
+        /// Version of the policy that produced the result, if it has one.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        policy_version: Option<String>,
     },
 
     ExternalOp {
@@ -102,4 +128,72 @@ pub enum QueryEvent {
         call_id: u64,
         iterable: Term,
     },
+
+    /// Look up several attributes of one external instance at once. Emitted
+    /// before the individual `ExternalCall`s for those attributes when bulk
+    /// lookups are enabled; answer with `Query::lookup_many_result`, and
+    /// `ExternalCall`s are only emitted for attributes left out of the answer.
+    ExternalLookupMany {
+        call_id: u64,
+        /// ID of the external instance to look the attributes up on.
+        instance_id: u64,
+        /// Field names to look up.
+        attributes: Vec<Symbol>,
+    },
+
+    /// Lookups on external instances that a conjunction is about to make,
+    /// emitted together when concurrent calls are enabled so the host can
+    /// make them concurrently. Their instances and arguments are already
+    /// ground, so none depends on another's answer. Answer each with
+    /// `Query::call_result`, in any order, before asking for the next event.
+    /// Later `ExternalCall`s are only emitted for calls left unanswered.
+    ExternalCalls {
+        calls: Vec<ExternalCallRequest>,
+    },
+
+    /// Emitted periodically during long queries when a heartbeat interval is
+    /// set. Requires no response; call `next_event` again to continue.
+    Heartbeat {
+        /// Goals executed by the query so far.
+        goals_executed: u64,
+    },
+
+    /// Emitted periodically when a progress interval is set, so hosts can
+    /// show how a slow query is going and offer to cancel it. Requires no
+    /// response; call `next_event` again to continue.
+    Progress {
+        /// Goals executed by the query so far.
+        goals_executed: u64,
+        /// Choice points created by the query so far.
+        choice_points: u64,
+        /// Milliseconds since the progress interval was set.
+        elapsed_ms: u64,
+    },
+}
+
+/// One of the calls in a `QueryEvent::ExternalCalls`, with the fields of a
+/// `QueryEvent::ExternalCall`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ExternalCallRequest {
+    pub call_id: u64,
+    pub instance: Term,
+    pub attribute: Symbol,
+    pub args: Option<Vec<Term>>,
+    pub kwargs: Option<BTreeMap<Symbol, Term>>,
+}
+
+/// An external lookup that a query could require, as recorded by a dry run.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ExternalLookup {
+    /// The value the lookup starts from.
+    pub receiver: Term,
+    /// Attributes looked up on `receiver` to reach the instance this lookup is made on,
+    /// e.g., `["owner"]` for `resource.owner.name`.
+    pub path: Vec<Symbol>,
+    /// Field name to lookup or method name to call.
+    pub attribute: Symbol,
+    /// List of arguments to a method call.
+    pub args: Option<Vec<Term>>,
+    /// A map of keyword arguments to a method call.
+    pub kwargs: Option<BTreeMap<Symbol, Term>>,
 }
@@ -0,0 +1,27 @@
+//! Fact sources: host storage that answers calls to designated predicates,
+//! e.g., `has_role/3` backed by an index of role assignments, in place of
+//! facts in the knowledge base and without external call events.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::error::PolarResult;
+use crate::terms::*;
+
+/// Answers calls to a predicate with the facts that match them.
+pub trait FactSource: Send + Sync {
+    /// The arguments of the facts that may match a call with `args`, which
+    /// are fully dereferenced: unbound arguments are variables. A source
+    /// may use the bound arguments to narrow its answer, but needn't filter
+    /// exactly, since each fact is unified with the call's arguments.
+    fn facts(&self, args: &[Term]) -> PolarResult<Vec<Vec<Term>>>;
+}
+
+impl<F: Fn(&[Term]) -> PolarResult<Vec<Vec<Term>>> + Send + Sync> FactSource for F {
+    fn facts(&self, args: &[Term]) -> PolarResult<Vec<Vec<Term>>> {
+        self(args)
+    }
+}
+
+/// Fact sources by the name and arity of the predicate they answer.
+pub type FactSources = HashMap<(Symbol, usize), Arc<dyn FactSource>>;
@@ -78,6 +78,9 @@ pub trait Folder: Sized {
     fn fold_list(&mut self, l: TermList) -> TermList {
         fold_list(l, self)
     }
+    fn fold_set(&mut self, s: Set) -> Set {
+        fold_set(s, self)
+    }
     fn fold_operation(&mut self, o: Operation) -> Operation {
         fold_operation(o, self)
     }
@@ -86,11 +89,22 @@ pub trait Folder: Sized {
     }
 }
 
-pub fn fold_rule<T: Folder>(Rule { name, params, body }: Rule, fld: &mut T) -> Rule {
+pub fn fold_rule<T: Folder>(
+    Rule {
+        name,
+        params,
+        guard,
+        body,
+        annotations,
+    }: Rule,
+    fld: &mut T,
+) -> Rule {
     Rule {
         name: fld.fold_name(name),
         params: params.into_iter().map(|p| fld.fold_param(p)).collect(),
+        guard: guard.map(|g| fld.fold_term(g)),
         body: fld.fold_term(body),
+        annotations,
     }
 }
 
@@ -103,11 +117,19 @@ pub fn fold_value<T: Folder>(v: Value, fld: &mut T) -> Value {
         Value::Number(n) => Value::Number(fld.fold_number(n)),
         Value::String(s) => Value::String(fld.fold_string(s)),
         Value::Boolean(b) => Value::Boolean(fld.fold_boolean(b)),
+        Value::DateTime(d) => Value::DateTime(d),
+        Value::Nil => Value::Nil,
         Value::ExternalInstance(e) => Value::ExternalInstance(fld.fold_external_instance(e)),
         Value::Dictionary(d) => Value::Dictionary(fld.fold_dictionary(d)),
         Value::Pattern(p) => Value::Pattern(fld.fold_pattern(p)),
         Value::Call(c) => Value::Call(fld.fold_call(c)),
         Value::List(l) => Value::List(fld.fold_list(l)),
+        Value::Set(s) => Value::Set(fld.fold_set(s)),
+        Value::Tuple(t) => Value::Tuple(fld.fold_list(t)),
+        Value::Range(Range { start, end }) => Value::Range(Range {
+            start: fld.fold_term(start),
+            end: fld.fold_term(end),
+        }),
         Value::Variable(v) => Value::Variable(fld.fold_variable(v)),
         Value::RestVariable(r) => Value::RestVariable(fld.fold_rest_variable(r)),
         Value::Expression(o) => Value::Expression(fld.fold_operation(o)),
@@ -135,6 +157,7 @@ pub fn fold_external_instance<T: Folder>(
         instance_id,
         constructor,
         repr,
+        class_tag,
     }: ExternalInstance,
     fld: &mut T,
 ) -> ExternalInstance {
@@ -142,6 +165,7 @@ pub fn fold_external_instance<T: Folder>(
         instance_id: fld.fold_instance_id(instance_id),
         constructor: constructor.map(|t| fld.fold_term(t)),
         repr: repr.map(|r| fld.fold_string(r)),
+        class_tag: class_tag.map(|t| fld.fold_name(t)),
     }
 }
 
@@ -160,7 +184,7 @@ pub fn fold_dictionary<T: Folder>(Dictionary { fields }: Dictionary, fld: &mut T
         fields: fields
             .into_iter()
             .map(|(k, v)| (fld.fold_name(k), fld.fold_term(v)))
-            .collect::<BTreeMap<Symbol, Term>>(),
+            .collect::<Fields>(),
     }
 }
 
@@ -194,6 +218,11 @@ pub fn fold_list<T: Folder>(l: TermList, fld: &mut T) -> TermList {
         .collect::<TermList>()
 }
 
+/// Folding may bind or rename elements, so the result is re-sorted.
+pub fn fold_set<T: Folder>(Set { elements }: Set, fld: &mut T) -> Set {
+    Set::new(fld.fold_list(elements))
+}
+
 pub fn fold_operator<T: Folder>(o: Operator, _fld: &mut T) -> Operator {
     o
 }
@@ -216,12 +245,14 @@ pub fn fold_param<T: Folder>(
     Parameter {
         parameter,
         specializer,
+        mode,
     }: Parameter,
     fld: &mut T,
 ) -> Parameter {
     Parameter {
         parameter: fld.fold_term(parameter),
         specializer: specializer.map(|t| fld.fold_term(t)),
+        mode,
     }
 }
 
@@ -257,18 +288,19 @@ mod tests {
             instance_id: 1,
             constructor: None,
             repr: None,
+            class_tag: None,
         }));
         let instance_pattern = term!(value!(Pattern::Instance(InstanceLiteral {
             tag: sym!("d"),
             fields: Dictionary {
-                fields: btreemap! {
+                fields: indexmap! {
                     sym!("e") => term!(call!("f", [2])),
                     sym!("g") => term!(op!(Add, term!(3), term!(4))),
                 }
             }
         })));
         let dict_pattern = term!(Value::Pattern(Pattern::Dictionary(Dictionary {
-            fields: btreemap! {
+            fields: indexmap! {
                 sym!("i") => term!("j"),
                 sym!("k") => term!("l"),
             },
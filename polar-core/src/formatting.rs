@@ -9,6 +9,7 @@
 //! In addition, there are special cases like traces and sources that have their own
 //! formatting requirements.
 
+use crate::naming::VariableNames;
 use crate::rules::*;
 use crate::sources::*;
 use crate::terms::*;
@@ -18,23 +19,33 @@ pub use to_polar::*;
 
 impl Trace {
     /// Return the string representation of this `Trace`
+    /// Terms without source are shown with readable names for the variables
+    /// the VM made up.
     pub fn draw(&self, vm: &crate::vm::PolarVirtualMachine) -> String {
         let mut res = String::new();
-        self.draw_trace(vm, 0, &mut res);
+        self.draw_trace(vm, 0, &mut VariableNames::default(), &mut res);
         res
     }
 
-    fn draw_trace(&self, vm: &crate::vm::PolarVirtualMachine, nest: usize, res: &mut String) {
+    fn draw_trace(
+        &self,
+        vm: &crate::vm::PolarVirtualMachine,
+        nest: usize,
+        names: &mut VariableNames,
+        res: &mut String,
+    ) {
         if matches!(&self.node, Node::Term(term)
             if matches!(term.value(), Value::Expression(Operation { operator: Operator::And, ..})))
         {
             for c in &self.children {
-                c.draw_trace(vm, nest + 1, res);
+                c.draw_trace(vm, nest + 1, names, res);
             }
         } else {
             let polar_str = match self.node {
                 Node::Rule(ref r) => vm.rule_source(r),
-                Node::Term(ref t) => vm.term_source(t, false),
+                Node::Term(ref t) => vm
+                    .source_text(t)
+                    .unwrap_or_else(|| names.rename(t.clone()).to_polar()),
             };
             let indented = polar_str
                 .split('\n')
@@ -46,7 +57,7 @@ impl Trace {
             if !self.children.is_empty() {
                 res.push('\n');
                 for c in &self.children {
-                    c.draw_trace(vm, nest + 1, res);
+                    c.draw_trace(vm, nest + 1, names, res);
                 }
                 for _ in 0..nest {
                     res.push_str("  ");
@@ -62,29 +73,25 @@ impl Trace {
 /// above and below it.
 // @TODO: Can we have the caret under the whole range of the expression instead of just the beginning.
 pub fn source_lines(source: &Source, offset: usize, num_lines: usize) -> String {
-    // Sliding window of lines: current line + indicator + additional context above + below.
-    let max_lines = num_lines * 2 + 2;
-    let push_line = |lines: &mut Vec<String>, line: String| {
-        if lines.len() == max_lines {
-            lines.remove(0);
-        }
-        lines.push(line);
-    };
-    let mut index = 0;
-    let mut lines = Vec::new();
-    let mut target = None;
+    let (target, column) = source.line_column(offset);
+    let first = target.saturating_sub(num_lines);
     let prefix_len = "123: ".len();
-    for (lineno, line) in source.src.lines().enumerate() {
-        push_line(&mut lines, format!("{:03}: {}", lineno + 1, line));
-        let end = index + line.len() + 1; // Adding one to account for new line byte.
-        if target.is_none() && end >= offset {
-            target = Some(lineno);
-            let spaces = " ".repeat(offset - index + prefix_len);
-            push_line(&mut lines, format!("{}^", spaces));
-        }
-        index = end;
-        if target.is_some() && lineno == target.unwrap() + num_lines {
-            break;
+    let mut lines = Vec::new();
+    for (lineno, line) in source
+        .lines()
+        .enumerate()
+        .skip(first)
+        .take(target + num_lines + 1 - first)
+    {
+        lines.push(format!("{:03}: {}", lineno + 1, line));
+        if lineno == target {
+            // Keep tabs, so that the caret lines up however wide they're shown.
+            let indent: String = line
+                .chars()
+                .take(column)
+                .map(|c| if c == '\t' { '\t' } else { ' ' })
+                .collect();
+            lines.push(format!("{}{}^", " ".repeat(prefix_len), indent));
         }
     }
     lines.join("\n")
@@ -108,6 +115,49 @@ pub fn format_params(args: &[Parameter], sep: &str) -> String {
         .join(sep)
 }
 
+/// Formats a rule guard as a ` when ...` clause, or nothing if there is no guard.
+pub fn format_guard(guard: &Option<Term>, sep: &str) -> String {
+    match guard {
+        Some(guard) => match guard.value() {
+            Value::Expression(Operation {
+                operator: Operator::And,
+                args,
+            }) => format!(" when {}", format_args(Operator::And, args, sep)),
+            _ => format!(" when {}", guard.to_polar()),
+        },
+        None => String::new(),
+    }
+}
+
+/// Formats a string as a quoted literal, escaping what the lexer unescapes,
+/// and other control characters as `\u{...}`.
+pub fn format_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            '\0' => quoted.push_str("\\0"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{{{:x}}}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Formats a rule's annotations, each followed by a space.
+pub fn format_annotations(annotations: &[Annotation]) -> String {
+    annotations
+        .iter()
+        .map(|annotation| format!("{} ", annotation.to_polar()))
+        .collect()
+}
+
 /// Formats a vector of rules as a string-separated list.
 #[allow(clippy::ptr_arg)]
 pub fn format_rules(rules: &Rules, sep: &str) -> String {
@@ -125,6 +175,8 @@ fn precedence(o: &Operator) -> i32 {
         Operator::New => 10,
         Operator::Cut => 10,
         Operator::ForAll => 10,
+        Operator::FindAll => 10,
+        Operator::Match => 10,
         Operator::Dot => 9,
         Operator::In => 8,
         Operator::Isa => 8,
@@ -168,7 +220,7 @@ pub fn to_polar_parens(op: Operator, t: &Term) -> String {
 }
 
 pub mod display {
-    use crate::formatting::{format_args, format_params};
+    use crate::formatting::{format_annotations, format_args, format_guard, format_params};
     use std::fmt;
     use std::sync::Arc;
 
@@ -270,6 +322,29 @@ pub mod display {
                     instance.to_polar(),
                     field.to_polar(),
                 ),
+                Goal::LookupMany {
+                    instance,
+                    attributes,
+                    ..
+                } => write!(
+                    fmt,
+                    "LookupMany({}.{{{}}})",
+                    instance.to_polar(),
+                    attributes
+                        .iter()
+                        .map(|attribute| attribute.0.as_str())
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                ),
+                Goal::LookupConcurrent { lookups } => write!(
+                    fmt,
+                    "LookupConcurrent({})",
+                    lookups
+                        .iter()
+                        .map(|lookup| lookup.to_polar())
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                ),
                 Goal::PopQuery { term } => write!(fmt, "PopQuery({})", term.to_polar()),
                 Goal::Query { term } => write!(fmt, "Query({})", term.to_polar()),
                 Goal::Run { .. } => write!(fmt, "Run(...)"),
@@ -314,19 +389,20 @@ pub mod display {
                     operator: Operator::And,
                     args,
                 }) => {
+                    let head = format!(
+                        "{}{}({}){}",
+                        format_annotations(&self.annotations),
+                        self.name.to_polar(),
+                        format_params(&self.params, ", "),
+                        format_guard(&self.guard, ",\n  ")
+                    );
                     if args.is_empty() {
-                        write!(
-                            fmt,
-                            "{}({});",
-                            self.name.to_polar(),
-                            format_params(&self.params, ", ")
-                        )
+                        write!(fmt, "{};", head)
                     } else {
                         write!(
                             fmt,
-                            "{}({}) if {};",
-                            self.name.to_polar(),
-                            format_params(&self.params, ", "),
+                            "{} if {};",
+                            head,
                             format_args(Operator::And, &args, ",\n  "),
                         )
                     }
@@ -347,7 +423,10 @@ pub mod display {
 }
 
 pub mod to_polar {
-    use crate::formatting::{format_args, format_params, to_polar_parens};
+    use crate::formatting::{
+        format_annotations, format_args, format_guard, format_params, format_string,
+        to_polar_parens,
+    };
     use crate::rules::*;
     use crate::terms::*;
 
@@ -359,13 +438,14 @@ pub mod to_polar {
 
     impl ToPolarString for Dictionary {
         fn to_polar(&self) -> String {
-            let fields = self
-                .fields
-                .iter()
+            let mut fields = self
+                .listed_fields()
                 .map(|(k, v)| format!("{}: {}", k.to_polar(), v.to_polar()))
-                .collect::<Vec<String>>()
-                .join(", ");
-            format!("{{{}}}", fields)
+                .collect::<Vec<String>>();
+            if let Some(rest) = self.rest_var() {
+                fields.push(format!("*{}", rest.to_polar()));
+            }
+            format!("{{{}}}", fields.join(", "))
         }
     }
 
@@ -411,9 +491,11 @@ pub mod to_polar {
                 In => "in",
                 Cut => "cut",
                 ForAll => "forall",
+                FindAll => "findall",
                 Debug => "debug",
                 Print => "print",
                 Isa => "matches",
+                Match => "match",
             }
             .to_string()
         }
@@ -428,24 +510,53 @@ pub mod to_polar {
                 Debug => "debug()".to_owned(),
                 Print => format!("print({})", format_args(self.operator, &self.args, ", ")),
                 Cut => "cut".to_owned(),
-                ForAll => format!(
+                ForAll if self.args.len() == 2 => format!(
                     "forall({}, {})",
                     self.args[0].to_polar(),
                     self.args[1].to_polar()
                 ),
-                New => {
-                    if self.args.len() == 1 {
-                        format!("new {}", to_polar_parens(self.operator, &self.args[0]))
-                    } else {
-                        format!(
-                            "new ({}, {})",
-                            to_polar_parens(self.operator, &self.args[0]),
-                            self.args[1].to_polar()
-                        )
+                // List comprehension
+                FindAll => {
+                    let comprehension = |template: &Term, condition: &Term| {
+                        let conditions = match condition.value() {
+                            Value::Expression(Operation {
+                                operator: And,
+                                args,
+                            }) => format_args(And, args, ", "),
+                            _ => condition.to_polar(),
+                        };
+                        format!("[{} | {}]", template.to_polar(), conditions)
+                    };
+                    match self.args.len() {
+                        2 => comprehension(&self.args[0], &self.args[1]),
+                        3 => format!(
+                            "{} = {}",
+                            comprehension(&self.args[0], &self.args[1]),
+                            self.args[2].to_polar()
+                        ),
+                        // Invalid
+                        _ => format!("findall({})", format_args(self.operator, &self.args, ", ")),
                     }
                 }
+                Match if !self.args.len().is_multiple_of(2) => format!(
+                    "match {} {{ {} }}",
+                    to_polar_parens(self.operator, &self.args[0]),
+                    self.args[1..]
+                        .chunks(2)
+                        .map(|b| format!("{} => {};", b[0].to_polar(), b[1].to_polar()))
+                        .collect::<Vec<_>>()
+                        .join(" ")
+                ),
+                New if self.args.len() == 1 => {
+                    format!("new {}", to_polar_parens(self.operator, &self.args[0]))
+                }
+                New if self.args.len() == 2 => format!(
+                    "new ({}, {})",
+                    to_polar_parens(self.operator, &self.args[0]),
+                    self.args[1].to_polar()
+                ),
                 // Lookup operator
-                Dot => {
+                Dot if self.args.len() >= 2 => {
                     let call_term = if let Value::String(s) = self.args[1].value() {
                         s.to_string()
                     } else {
@@ -464,7 +575,7 @@ pub mod to_polar {
                     }
                 }
                 // Unary operators
-                Not => format!(
+                Not if self.args.len() == 1 => format!(
                     "{} {}",
                     self.operator.to_polar(),
                     to_polar_parens(self.operator, &self.args[0])
@@ -505,17 +616,31 @@ pub mod to_polar {
                     &self.args,
                     &format!(" {} ", self.operator.to_polar()),
                 ),
+                // Invalid
+                ForAll | New | Dot | Not | Match => format!(
+                    "{}({})",
+                    self.operator.to_polar(),
+                    format_args(self.operator, &self.args, ", ")
+                ),
             }
         }
     }
 
     impl ToPolarString for Parameter {
         fn to_polar(&self) -> String {
+            let mode = match self.mode {
+                Some(Mode::In) => "+",
+                Some(Mode::Out) => "-",
+                None => "",
+            };
             match &self.specializer {
-                None => self.parameter.to_polar(),
-                Some(specializer) => {
-                    format!("{}: {}", self.parameter.to_polar(), specializer.to_polar())
-                }
+                None => format!("{}{}", mode, self.parameter.to_polar()),
+                Some(specializer) => format!(
+                    "{}{}: {}",
+                    mode,
+                    self.parameter.to_polar(),
+                    specializer.to_polar()
+                ),
             }
         }
     }
@@ -542,6 +667,21 @@ pub mod to_polar {
         }
     }
 
+    impl ToPolarString for Annotation {
+        fn to_polar(&self) -> String {
+            if self.args.is_empty() && self.kwargs.is_none() {
+                format!("@{}", self.name.to_polar())
+            } else {
+                let call = Call {
+                    name: self.name.clone(),
+                    args: self.args.clone(),
+                    kwargs: self.kwargs.clone(),
+                };
+                format!("@{}", call.to_polar())
+            }
+        }
+    }
+
     impl ToPolarString for Rule {
         fn to_polar(&self) -> String {
             match &self.body.value() {
@@ -549,17 +689,19 @@ pub mod to_polar {
                     operator: Operator::And,
                     args,
                 }) => {
+                    let head = format!(
+                        "{}{}({}){}",
+                        format_annotations(&self.annotations),
+                        self.name.to_polar(),
+                        format_params(&self.params, ", "),
+                        format_guard(&self.guard, " and ")
+                    );
                     if args.is_empty() {
-                        format!(
-                            "{}({});",
-                            self.name.to_polar(),
-                            format_params(&self.params, ", ")
-                        )
+                        format!("{};", head)
                     } else {
                         format!(
-                            "{}({}) if {};",
-                            self.name.to_polar(),
-                            format_params(&self.params, ", "),
+                            "{} if {};",
+                            head,
                             format_args(Operator::And, &args, " and "),
                         )
                     }
@@ -594,7 +736,7 @@ pub mod to_polar {
         fn to_polar(&self) -> String {
             match self {
                 Value::Number(i) => format!("{}", i),
-                Value::String(s) => format!("\"{}\"", s),
+                Value::String(s) => format_string(s),
                 Value::Boolean(b) => {
                     if *b {
                         "true".to_string()
@@ -602,11 +744,31 @@ pub mod to_polar {
                         "false".to_string()
                     }
                 }
+                Value::DateTime(d) => format!("@{}", d.to_iso8601()),
+                Value::Nil => "nil".to_string(),
                 Value::Dictionary(i) => i.to_polar(),
                 Value::Pattern(i) => i.to_polar(),
                 Value::ExternalInstance(i) => i.to_polar(),
                 Value::Call(c) => c.to_polar(),
                 Value::List(l) => format!("[{}]", format_args(Operator::And, l, ", "),),
+                Value::Range(Range { start, end }) => {
+                    // Bounds other than values and lookups are parenthesized,
+                    // e.g., `1..(n + 1)`.
+                    let bound = |t: &Term| match t.value() {
+                        Value::Expression(Operation {
+                            operator: Operator::Dot,
+                            ..
+                        }) => t.to_polar(),
+                        Value::Expression(_) => format!("({})", t.to_polar()),
+                        _ => t.to_polar(),
+                    };
+                    format!("{}..{}", bound(start), bound(end))
+                }
+                Value::Tuple(t) if t.len() == 1 => format!("({},)", t[0].to_polar()),
+                Value::Tuple(t) => format!("({})", format_args(Operator::And, t, ", ")),
+                // `{}` would read back as a dictionary.
+                Value::Set(s) if s.is_empty() => "set()".to_string(),
+                Value::Set(s) => format!("{{{}}}", format_args(Operator::And, &s.elements, ", ")),
                 Value::Variable(s) => s.to_polar(),
                 Value::RestVariable(s) => format!("*{}", s.to_polar()),
                 Value::Expression(e) => e.to_polar(),
@@ -185,10 +185,10 @@ fn filter_inverted_constraints(
 /// A Runnable that runs a query and inverts the results in three ways:
 ///
 /// 1. If no results are emitted (indicating failure), return true.
-/// 2. If at least one result is emitted containing a partial, invert the partial's constraints,
+/// 2. If every result emitted contains a partial, invert the partials' constraints,
 ///    pass the inverted partials back to the parent Runnable via a shared BindingStack, and return
 ///    true.
-/// 3. In all other cases, return false.
+/// 3. In all other cases, return false, as soon as a result without a partial is emitted.
 impl Runnable for Inverter {
     fn run(&mut self, _: Option<&mut Counter>) -> PolarResult<QueryEvent> {
         if self.follower.is_none() {
@@ -216,10 +216,18 @@ impl Runnable for Inverter {
                             // TODO (dhatch): Would be nice to come up with a better way of doing this.
                             self.add_constraints.borrow_mut().extend(constraints);
 
-                            return Ok(QueryEvent::Done { result: true });
+                            return Ok(QueryEvent::Done {
+                                result: true,
+                                policy_version: None,
+                                stats: None,
+                            });
                         }
                     }
-                    return Ok(QueryEvent::Done { result });
+                    return Ok(QueryEvent::Done {
+                        result,
+                        policy_version: None,
+                        stats: None,
+                    });
                 }
                 QueryEvent::Result { .. } => {
                     // Retrieve new bindings made when running inverted query.
@@ -227,6 +235,18 @@ impl Runnable for Inverter {
                         .vm
                         .remove_binding_follower(&self.follower.unwrap())
                         .unwrap();
+
+                    // A result that constrains nothing outside the inversion
+                    // makes it fail, whatever else the query would find, so
+                    // don't look for more.
+                    let constraints = results_to_constraints(vec![binding_follower.clone()]);
+                    if filter_inverted_constraints(constraints, &self.vm, self.bsp).is_empty() {
+                        return Ok(QueryEvent::Done {
+                            result: false,
+                            policy_version: None,
+                            stats: None,
+                        });
+                    }
                     self.results.push(binding_follower);
                     self.follower = Some(self.vm.add_binding_follower());
                 }
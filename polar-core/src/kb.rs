@@ -1,15 +1,43 @@
 use std::collections::HashMap;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError, RwLock, RwLockReadGuard};
 
 pub use super::bindings::Bindings;
 use super::counter::Counter;
+use super::error::{OperationalError, PolarResult};
+use super::metrics::RuleMetrics;
 use super::rules::*;
 use super::sources::*;
 use super::terms::*;
 
+/// Distinguishes the states of every call memo, so that calls made before
+/// one was cleared aren't remembered after.
+static MEMO_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// How many calls a memo remembers before it starts over.
+const MEMO_CAPACITY: usize = 10_000;
+
+/// Whether ground calls to `@cached` predicates succeeded, remembered across
+/// queries until the rules or constants change.
+struct CallMemo {
+    generation: u64,
+    results: HashMap<Call, bool>,
+}
+
+impl Default for CallMemo {
+    fn default() -> Self {
+        Self {
+            generation: MEMO_GENERATION.fetch_add(1, Ordering::Relaxed),
+            results: HashMap::new(),
+        }
+    }
+}
+
 /// A map of bindings: variable name → value. The VM uses a stack internally,
 /// but can translate to and from this type.
 
-#[derive(Default)]
+#[derive(Clone, Default)]
 pub struct KnowledgeBase {
     pub constants: Bindings,
     pub rules: HashMap<Symbol, GenericRule>,
@@ -19,6 +47,12 @@ pub struct KnowledgeBase {
     /// For call IDs, instance IDs, symbols, etc.
     id_counter: Counter,
     pub inline_queries: Vec<Term>,
+    /// Version of the loaded policy, if it was loaded with one.
+    pub version: Option<String>,
+    /// Rule hit and success counters, if enabled.
+    pub rule_metrics: Option<Arc<RuleMetrics>>,
+    /// Ground calls to `@cached` predicates, by whether they succeeded.
+    call_memo: Arc<Mutex<CallMemo>>,
 }
 
 impl KnowledgeBase {
@@ -30,6 +64,9 @@ impl KnowledgeBase {
             id_counter: Counter::default(),
             gensym_counter: Counter::default(),
             inline_queries: vec![],
+            version: None,
+            rule_metrics: None,
+            call_memo: Arc::default(),
         }
     }
 
@@ -45,15 +82,21 @@ impl KnowledgeBase {
         self.id_counter.clone()
     }
 
+    pub fn gensym_counter(&self) -> Counter {
+        self.gensym_counter.clone()
+    }
+
     /// Generate a new symbol.
     pub fn gensym(&self, prefix: &str) -> Symbol {
-        let next = self.gensym_counter.next();
-        if prefix == "_" {
-            Symbol(format!("_{}", next))
-        } else if prefix.starts_with('_') {
-            Symbol(format!("{}_{}", prefix, next))
-        } else {
-            Symbol(format!("_{}_{}", prefix, next))
+        gensym(&self.gensym_counter, prefix)
+    }
+
+    /// Precompute each rule's class dispatch, which queries would otherwise
+    /// work out on their first call to it, and the rules applicable to the
+    /// classes its rules specialize, until rules are added or removed.
+    pub fn freeze(&mut self) {
+        for generic_rule in self.rules.values_mut() {
+            generic_rule.freeze(&self.constants);
         }
     }
 
@@ -61,15 +104,165 @@ impl KnowledgeBase {
     #[cfg(test)]
     pub fn add_generic_rule(&mut self, rule: GenericRule) {
         self.rules.insert(rule.name.clone(), rule);
+        self.clear_memo();
+    }
+
+    /// Add a rule to the generic rule of the same name, creating it if need be.
+    pub fn add_rule(&mut self, rule: Rule) {
+        let name = rule.name.clone();
+        self.rules
+            .entry(name.clone())
+            .or_insert_with(|| GenericRule::new(name, vec![]))
+            .add_rule(Arc::new(rule));
+        self.clear_memo();
+    }
+
+    /// Remove the rules called `name` for which `remove` returns true, and
+    /// return how many were removed.
+    pub fn remove_rules<F: Fn(&Rule) -> bool>(&mut self, name: &Symbol, remove: F) -> usize {
+        let removed = self
+            .rules
+            .get_mut(name)
+            .map_or(0, |generic_rule| generic_rule.remove_rules(remove));
+        if removed > 0 {
+            self.clear_memo();
+        }
+        removed
+    }
+
+    /// Remove every rule.
+    pub fn clear_rules(&mut self) {
+        self.rules.clear();
+        self.clear_memo();
     }
 
     /// Define a constant variable.
     pub fn constant(&mut self, name: Symbol, value: Term) {
         self.constants.insert(name, value);
+        self.clear_memo();
     }
 
     /// Return true if a constant with the given name has been defined.
     pub fn is_constant(&self, name: &Symbol) -> bool {
         self.constants.contains_key(name)
     }
+
+    /// Whether the ground `call` to a `@cached` predicate succeeded, if it's
+    /// been made since the memo was last cleared, or else the generation of
+    /// the memo, to pass to `memoize` along with the answer once it's found.
+    pub fn memoized(&self, call: &Call) -> Result<bool, u64> {
+        // Nothing panics while holding the lock, so even if it's poisoned,
+        // the memo is whole.
+        let memo = self
+            .call_memo
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        memo.results.get(call).copied().ok_or(memo.generation)
+    }
+
+    /// Remember whether the ground `call` succeeded, unless the memo has been
+    /// cleared since `generation`. A full memo forgets everything it holds.
+    pub fn memoize(&self, generation: u64, call: Call, success: bool) {
+        let mut memo = self
+            .call_memo
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if memo.generation == generation {
+            if memo.results.len() >= MEMO_CAPACITY {
+                memo.results.clear();
+            }
+            memo.results.insert(call, success);
+        }
+    }
+
+    /// Forget the calls remembered by `memoize`, e.g., because rules were
+    /// added or removed. Clones of the knowledge base stop sharing a memo,
+    /// since their rules may differ.
+    pub fn clear_memo(&mut self) {
+        self.call_memo = Arc::default();
+    }
+
+    /// Rules with an annotation called `annotation`, ordered by rule name and
+    /// then by definition.
+    pub fn annotated_rules(&self, annotation: &str) -> Rules {
+        let mut generic_rules: Vec<&GenericRule> = self.rules.values().collect();
+        generic_rules.sort_by(|a, b| a.name.0.cmp(&b.name.0));
+        generic_rules
+            .into_iter()
+            .flat_map(GenericRule::rules)
+            .filter(|rule| rule.annotation(annotation).is_some())
+            .collect()
+    }
+}
+
+/// The knowledge base a query reads its rules from.
+#[derive(Clone)]
+pub enum SharedKb {
+    /// A `Polar`'s, which loading policies changes, so reads take its lock.
+    Locked(Arc<RwLock<KnowledgeBase>>),
+    /// A `FrozenPolar`'s, which never changes, so reads take no lock.
+    /// `queries` is the knowledge base the queries were parsed into, which
+    /// holds their sources.
+    Frozen {
+        kb: Arc<KnowledgeBase>,
+        queries: Arc<RwLock<KnowledgeBase>>,
+    },
+}
+
+impl SharedKb {
+    /// Read the knowledge base, failing if a panic poisoned its lock while
+    /// it was being changed, possibly leaving it half changed.
+    pub fn read(&self) -> PolarResult<KbReadGuard<'_>> {
+        match self {
+            Self::Locked(kb) => kb.read().map(KbReadGuard::Locked).map_err(|_| {
+                OperationalError::InvalidState("knowledge base lock poisoned".to_string()).into()
+            }),
+            Self::Frozen { kb, .. } => Ok(KbReadGuard::Frozen(kb)),
+        }
+    }
+
+    /// The source with ID `id`, whether it's a policy's or a query's.
+    pub fn get_source(&self, id: u64) -> Option<Source> {
+        // Sources only give errors context, so a poisoned lock leaves it out.
+        let locked = |kb: &RwLock<KnowledgeBase>| kb.read().ok()?.sources.get_source(id);
+        match self {
+            Self::Locked(kb) => locked(kb),
+            Self::Frozen { kb, queries } => kb.sources.get_source(id).or_else(|| locked(queries)),
+        }
+    }
+}
+
+impl From<Arc<RwLock<KnowledgeBase>>> for SharedKb {
+    fn from(kb: Arc<RwLock<KnowledgeBase>>) -> Self {
+        Self::Locked(kb)
+    }
+}
+
+/// A knowledge base being read through a `SharedKb`.
+pub enum KbReadGuard<'a> {
+    Locked(RwLockReadGuard<'a, KnowledgeBase>),
+    Frozen(&'a KnowledgeBase),
+}
+
+impl Deref for KbReadGuard<'_> {
+    type Target = KnowledgeBase;
+
+    fn deref(&self) -> &KnowledgeBase {
+        match self {
+            Self::Locked(kb) => kb,
+            Self::Frozen(kb) => kb,
+        }
+    }
+}
+
+/// Generate a new symbol using `counter`.
+pub(crate) fn gensym(counter: &Counter, prefix: &str) -> Symbol {
+    let next = counter.next();
+    if prefix == "_" {
+        Symbol(format!("_{}", next))
+    } else if prefix.starts_with('_') {
+        Symbol(format!("{}_{}", prefix, next))
+    } else {
+        Symbol(format!("_{}_{}", prefix, next))
+    }
 }
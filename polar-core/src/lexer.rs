@@ -1,29 +1,27 @@
 #![allow(clippy::upper_case_acronyms)]
 
 use super::error::ParseError;
-use super::terms::Symbol;
+use super::terms::{DateTime, Symbol};
 use std::iter::Peekable;
 use std::str::{CharIndices, FromStr};
 
 pub type SrcPos = (usize, usize);
 
-// Take a location in a string and return the row and column.
+/// Take a byte offset in a string and return its row and character column,
+/// both counted from 0. Rows end at `\n`, so `\r\n` line endings work too,
+/// and a tab or multi-byte character is one column. An offset past the end
+/// is taken as the end, and one inside a character as that character.
 pub fn loc_to_pos(src: &str, loc: usize) -> SrcPos {
-    let mut row = 0;
-    let mut col = 0;
-    let mut chars = src.chars();
-    for _ in 0..loc {
-        let c = chars.next();
-        match c {
-            Some('\n') => {
-                row += 1;
-                col = 0;
-            }
-            Some(_) => col += 1,
-            None => panic!("loc is longer than the string."),
-        }
+    let mut loc = loc.min(src.len());
+    while !src.is_char_boundary(loc) {
+        loc -= 1;
     }
-    (row, col)
+    let before = &src[..loc];
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    (
+        before.matches('\n').count(),
+        before[line_start..].chars().count(),
+    )
 }
 
 pub struct Lexer<'input> {
@@ -50,32 +48,36 @@ pub enum Token {
     String(String),
     Boolean(bool),
     Symbol(Symbol),
-    Colon, // :
-    Comma, // ,
-    LB,    // [
-    RB,    // ]
-    LP,    // (
-    RP,    // )
-    LCB,   // {
-    RCB,   // }
-    Dot,   // .
-    New,   // new
-    Bang,  // !
-    Mul,   // *
-    Div,   // /
-    Mod,   // mod
-    Rem,   // rem
-    Add,   // +
-    Sub,   // -
-    Eq,    // ==
-    Neq,   // !=
-    Leq,   // <=
-    Geq,   // >=
-    Lt,    // <
-    Gt,    // >
-    Unify, // =
+    DateTime(DateTime),
+    Colon,  // :
+    Comma,  // ,
+    LB,     // [
+    RB,     // ]
+    LP,     // (
+    RP,     // )
+    LCB,    // {
+    RCB,    // }
+    Dot,    // .
+    DotDot, // ..
+    New,    // new
+    Nil,    // nil
+    Bang,   // !
+    Mul,    // *
+    Div,    // /
+    Mod,    // mod
+    Rem,    // rem
+    Add,    // +
+    Sub,    // -
+    Eq,     // ==
+    Neq,    // !=
+    Leq,    // <=
+    Geq,    // >=
+    Lt,     // <
+    Gt,     // >
+    Unify,  // =
     Assign,
     Pipe,      // |
+    At,        // @
     SemiColon, // ;
     Query,     // ?=
     In,        // in
@@ -85,10 +87,13 @@ pub enum Token {
     Isa,       // isa
     ForAll,    // forall
     If,        // if
+    When,      // when
     And,       // and
     Or,        // or
     Not,       // not
     Matches,   // matches
+    Match,     // match
+    Arrow,     // =>
 }
 
 impl ToString for Token {
@@ -99,6 +104,7 @@ impl ToString for Token {
             Token::String(s) => s.clone(),
             Token::Boolean(b) => b.to_string(),
             Token::Symbol(sym) => sym.0.clone(),
+            Token::DateTime(d) => format!("@{}", d.to_iso8601()),
             Token::Colon => ":".to_owned(),         // :
             Token::Comma => ",".to_owned(),         // ,
             Token::LB => "[".to_owned(),            // [
@@ -108,7 +114,9 @@ impl ToString for Token {
             Token::LCB => "{".to_owned(),           // {
             Token::RCB => "}".to_owned(),           // }
             Token::Dot => ".".to_owned(),           // .
+            Token::DotDot => "..".to_owned(),       // ..
             Token::New => "new".to_owned(),         // new
+            Token::Nil => "nil".to_owned(),         // nil
             Token::Bang => "!".to_owned(),          // !
             Token::Mul => "*".to_owned(),           // *
             Token::Div => "/".to_owned(),           // /
@@ -125,6 +133,7 @@ impl ToString for Token {
             Token::Unify => "=".to_owned(),         // =
             Token::Assign => ":=".to_owned(),       // :=
             Token::Pipe => "|".to_owned(),          // |
+            Token::At => "@".to_owned(),            // @
             Token::SemiColon => ";".to_owned(),     // ;
             Token::Query => "?=".to_owned(),        // ?=
             Token::In => "in".to_owned(),           // in
@@ -134,10 +143,13 @@ impl ToString for Token {
             Token::Isa => "isa".to_owned(),         // isa
             Token::ForAll => "forall".to_owned(),   // forall
             Token::If => "if".to_owned(),           // if
+            Token::When => "when".to_owned(),       // when
             Token::And => "and".to_owned(),         // and
             Token::Or => "or".to_owned(),           // or
             Token::Not => "not".to_owned(),         // not
             Token::Matches => "matches".to_owned(), // matches
+            Token::Match => "match".to_owned(),     // match
+            Token::Arrow => "=>".to_owned(),        // =>
         }
     }
 }
@@ -223,6 +235,8 @@ impl<'input> Lexer<'input> {
             Some(Ok((start, Token::Float(f64::NAN), last + 1)))
         } else if &self.buf == "new" {
             Some(Ok((start, Token::New, last + 1)))
+        } else if &self.buf == "nil" {
+            Some(Ok((start, Token::Nil, last + 1)))
         } else if &self.buf == "in" {
             Some(Ok((start, Token::In, last + 1)))
         } else if &self.buf == "cut" {
@@ -237,6 +251,8 @@ impl<'input> Lexer<'input> {
             Some(Ok((start, Token::ForAll, last + 1)))
         } else if &self.buf == "if" {
             Some(Ok((start, Token::If, last + 1)))
+        } else if &self.buf == "when" {
+            Some(Ok((start, Token::When, last + 1)))
         } else if &self.buf == "and" {
             Some(Ok((start, Token::And, last + 1)))
         } else if &self.buf == "or" {
@@ -245,6 +261,8 @@ impl<'input> Lexer<'input> {
             Some(Ok((start, Token::Not, last + 1)))
         } else if &self.buf == "matches" {
             Some(Ok((start, Token::Matches, last + 1)))
+        } else if &self.buf == "match" {
+            Some(Ok((start, Token::Match, last + 1)))
         } else if &self.buf == "mod" {
             Some(Ok((start, Token::Mod, last + 1)))
         } else if &self.buf == "rem" {
@@ -279,14 +297,11 @@ impl<'input> Lexer<'input> {
                     '\\' => {
                         self.c = self.chars.next();
                         if let Some((_, char)) = self.c {
-                            let escaped_char = match char {
-                                'n' => '\n',
-                                'r' => '\r',
-                                't' => '\t',
-                                '0' => '\0',
-                                c => c,
-                            };
-                            self.buf.push(escaped_char);
+                            self.c = self.chars.next();
+                            match self.scan_escape(char, i) {
+                                Ok(escaped_char) => self.buf.push(escaped_char),
+                                Err(e) => return Some(Err(e)),
+                            }
                         } else {
                             return Some(Err(ParseError::InvalidTokenCharacter {
                                 token: self.buf.clone(),
@@ -294,7 +309,6 @@ impl<'input> Lexer<'input> {
                                 loc: i,
                             }));
                         }
-                        self.c = self.chars.next();
                     }
                     _ => {
                         self.buf.push(char);
@@ -312,6 +326,42 @@ impl<'input> Lexer<'input> {
         Some(Ok((start, Token::String(self.buf.clone()), last + 1)))
     }
 
+    /// Scan the rest of an escape sequence in a string, which starts with a
+    /// `\` at `loc` followed by `c`: one of `\n`, `\r`, `\t`, `\0`, or
+    /// `\u{...}` with the one to six hex digits of a unicode scalar value.
+    /// Any other character is escaped as itself, e.g., `\"`, `\d` or a `\u`
+    /// without a `{`.
+    fn scan_escape(&mut self, c: char, loc: usize) -> Result<char, ParseError> {
+        let invalid = |token: String| ParseError::InvalidEscape { token, loc };
+        match c {
+            'n' => Ok('\n'),
+            'r' => Ok('\r'),
+            't' => Ok('\t'),
+            '0' => Ok('\0'),
+            'u' => {
+                if !matches!(self.c, Some((_, '{'))) {
+                    return Ok('u');
+                }
+                self.c = self.chars.next();
+                let mut digits = String::new();
+                while let Some((_, c)) = self.c.filter(|(_, c)| c.is_ascii_hexdigit()) {
+                    digits.push(c);
+                    self.c = self.chars.next();
+                }
+                let token = format!("\\u{{{}", digits);
+                if !matches!(self.c, Some((_, '}'))) || digits.is_empty() || digits.len() > 6 {
+                    return Err(invalid(token));
+                }
+                self.c = self.chars.next();
+                u32::from_str_radix(&digits, 16)
+                    .ok()
+                    .and_then(char::from_u32)
+                    .ok_or_else(|| invalid(token + "}"))
+            }
+            c => Ok(c),
+        }
+    }
+
     #[inline]
     fn push_char(&mut self, c: char) {
         self.buf.push(c);
@@ -344,7 +394,9 @@ impl<'input> Lexer<'input> {
 
         last = self.match_digits(last);
 
-        if let Some((i, '.')) = self.c {
+        // `1..` starts a range, not the float `1.`.
+        let is_range = matches!(self.chars.peek(), Some((_, '.')));
+        if let (Some((i, '.')), false) = (self.c, is_range) {
             self.push_char('.');
             last = i;
             parse_as_float = true;
@@ -396,6 +448,35 @@ impl<'input> Lexer<'input> {
         }
     }
 
+    /// Scan an `@`, or a datetime literal like `@2024-05-01T09:30:00Z`.
+    fn scan_at(&mut self, i: usize) -> Option<Spanned<Token, usize, ParseError>> {
+        if !matches!(self.chars.peek(), Some((_, c)) if c.is_ascii_digit()) {
+            return self.scan_1c_op(i, Token::At);
+        }
+        self.buf.clear();
+        self.c = self.chars.next();
+        let mut last = i;
+        while let Some((j, c)) = self.c {
+            // A `.` only continues fractional seconds, and not e.g. a range.
+            let continues = match c {
+                '.' => matches!(self.chars.peek(), Some((_, c)) if c.is_ascii_digit()),
+                _ => c.is_ascii_alphanumeric() || matches!(c, ':' | '+' | '-'),
+            };
+            if !continues {
+                break;
+            }
+            self.push_char(c);
+            last = j;
+        }
+        match DateTime::parse(&self.buf) {
+            Some(datetime) => Some(Ok((i, Token::DateTime(datetime), last + 1))),
+            None => Some(Err(ParseError::InvalidDateTime {
+                token: self.buf.clone(),
+                loc: i,
+            })),
+        }
+    }
+
     /// Scan a one character operator to token.
     #[inline]
     #[allow(clippy::unnecessary_wraps)]
@@ -433,6 +514,21 @@ impl<'input> Lexer<'input> {
         }
     }
 
+    /// Scan `=`, `==` or `=>`.
+    #[inline]
+    #[allow(clippy::unnecessary_wraps)]
+    fn scan_equals(&mut self, i: usize) -> Option<Spanned<Token, usize, ParseError>> {
+        let start = i;
+        self.c = self.chars.next();
+        let token = match self.c {
+            Some((_, '=')) => Token::Eq,
+            Some((_, '>')) => Token::Arrow,
+            _ => return Some(Ok((start, Token::Unify, start + 1))),
+        };
+        self.c = self.chars.next();
+        Some(Ok((start, token, start + 2)))
+    }
+
     /// Scan an operator to token unless next_char is the next char in which case scan to next_token.
     #[inline]
     #[allow(clippy::unnecessary_wraps)]
@@ -469,12 +565,13 @@ impl<'input> Iterator for Lexer<'input> {
                 '"' => self.scan_string(i),
                 '0'..='9' => self.scan_number(i, char),
                 ':' => self.scan_1c_or_2c_op(i, Token::Colon, '=', Token::Assign),
-                '=' => self.scan_1c_or_2c_op(i, Token::Unify, '=', Token::Eq),
+                '=' => self.scan_equals(i),
                 '<' => self.scan_1c_or_2c_op(i, Token::Lt, '=', Token::Leq),
                 '>' => self.scan_1c_or_2c_op(i, Token::Gt, '=', Token::Geq),
                 '!' => self.scan_1c_or_2c_op(i, Token::Bang, '=', Token::Neq),
                 '?' => self.scan_2c_op(i, '=', Token::Query),
                 '|' => self.scan_1c_op(i, Token::Pipe),
+                '@' => self.scan_at(i),
                 ',' => self.scan_1c_op(i, Token::Comma),
                 '[' => self.scan_1c_op(i, Token::LB),
                 ']' => self.scan_1c_op(i, Token::RB),
@@ -482,7 +579,7 @@ impl<'input> Iterator for Lexer<'input> {
                 '}' => self.scan_1c_op(i, Token::RCB),
                 '(' => self.scan_1c_op(i, Token::LP),
                 ')' => self.scan_1c_op(i, Token::RP),
-                '.' => self.scan_1c_op(i, Token::Dot),
+                '.' => self.scan_1c_or_2c_op(i, Token::Dot, '.', Token::DotDot),
                 '+' => self.scan_1c_op(i, Token::Add),
                 '-' => self.scan_1c_op(i, Token::Sub),
                 '*' => self.scan_1c_op(i, Token::Mul),
@@ -508,6 +605,13 @@ mod tests {
         assert_eq!(loc_to_pos(src, 6), (1, 0));
         assert_eq!(loc_to_pos(src, 13), (2, 0));
         assert_eq!(loc_to_pos(src, 18), (2, 5));
+
+        // Offsets are in bytes, and columns in characters.
+        let src = "f(\"é\", x);\r\n\tg(\"日本\", y);";
+        assert_eq!(loc_to_pos(src, src.find('x').unwrap()), (0, 7));
+        assert_eq!(loc_to_pos(src, src.find('y').unwrap()), (1, 9));
+        assert_eq!(loc_to_pos(src, src.find('本').unwrap() + 1), (1, 5));
+        assert_eq!(loc_to_pos(src, 1000), (1, 12));
     }
 
     #[test]
@@ -551,6 +655,30 @@ mod tests {
         assert!(
             matches!(tok, Some(Ok((_, Token::String(s), _))) if &s == r#"this is a "sub" string"#)
         );
+
+        let s = r#""a\tb\n\\ \u{e9}\u{1F4AF}\u{0}""#;
+        let mut lexer = Lexer::new(&s);
+        assert!(
+            matches!(lexer.next(), Some(Ok((0, Token::String(s), 31))) if s == "a\tb\n\\ é💯\0")
+        );
+
+        let mut lexer = Lexer::new(r#""\d+\q\users""#);
+        assert!(matches!(lexer.next(), Some(Ok((_, Token::String(s), _))) if s == "d+qusers"));
+
+        for (s, escape) in &[
+            (r#""\u{}""#, "\\u{"),
+            (r#""\u{1234567}""#, "\\u{1234567"),
+            (r#""\u{e9""#, "\\u{e9"),
+            (r#""\u{d800}""#, "\\u{d800}"),
+            (r#""\u{110000}""#, "\\u{110000}"),
+        ] {
+            let mut lexer = Lexer::new(s);
+            assert!(
+                matches!(lexer.next(), Some(Err(ParseError::InvalidEscape { token, loc: 1 })) if &token == escape),
+                "{}",
+                s
+            );
+        }
     }
 
     #[test]
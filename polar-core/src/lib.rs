@@ -4,29 +4,46 @@
 #[macro_use]
 extern crate maplit;
 
+#[cfg(test)]
+#[macro_use]
+extern crate indexmap;
+
 #[macro_use]
 pub mod macros;
 
 mod bindings;
-mod counter;
+mod builtins;
+mod collector;
+pub mod counter;
+pub mod coverage;
+pub mod datetime;
 mod debugger;
 pub mod error;
 pub mod events;
+pub mod facts;
 mod folder;
 pub mod formatting;
 mod inverter;
 pub mod kb;
 mod lexer;
 pub mod messages;
+pub mod metrics;
+mod naming;
 mod numerics;
+pub mod packages;
 pub mod parser;
 mod partial;
+pub mod plan;
 pub mod polar;
+pub mod replay;
 mod rewrites;
 pub mod rules;
 mod runnable;
-mod sources;
+pub mod sandbox;
+pub mod sources;
 pub mod terms;
+#[cfg(feature = "testing")]
+pub mod testing;
 pub mod traces;
 mod visitor;
 mod vm;
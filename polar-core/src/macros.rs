@@ -185,7 +185,9 @@ macro_rules! rule {
         Rule {
             name: sym!($name),
             params,
+            guard: None,
             body: term!(op!(And, $(term!($body)),+)),
+            annotations: vec![],
         }}
     };
     ($name:expr, [$($args:tt)*]) => {{
@@ -194,7 +196,9 @@ macro_rules! rule {
         Rule {
             name: sym!($name),
             params,
+            guard: None,
             body: term!(op!(And)),
+            annotations: vec![],
         }
     }};
 }
@@ -227,6 +231,7 @@ impl From<(Symbol, Term)> for TestHelper<Parameter> {
         Self(Parameter {
             parameter: arg.1.clone_with_value(Value::Variable(arg.0)),
             specializer: Some(term!(specializer)),
+            mode: None,
         })
     }
 }
@@ -239,6 +244,7 @@ impl From<Value> for TestHelper<Parameter> {
         Self(Parameter {
             parameter: Term::new_temporary(name),
             specializer: None,
+            mode: None,
         })
     }
 }
@@ -251,7 +257,9 @@ impl<S: AsRef<str>> From<S> for TestHelper<Symbol> {
 
 impl From<BTreeMap<Symbol, Term>> for TestHelper<Dictionary> {
     fn from(other: BTreeMap<Symbol, Term>) -> Self {
-        Self(Dictionary { fields: other })
+        Self(Dictionary {
+            fields: other.into_iter().collect(),
+        })
     }
 }
 
@@ -311,7 +319,9 @@ impl From<Symbol> for TestHelper<Value> {
 }
 impl From<BTreeMap<Symbol, Term>> for TestHelper<Value> {
     fn from(other: BTreeMap<Symbol, Term>) -> Self {
-        Self(Value::Dictionary(Dictionary { fields: other }))
+        Self(Value::Dictionary(Dictionary {
+            fields: other.into_iter().collect(),
+        }))
     }
 }
 
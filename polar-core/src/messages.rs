@@ -1,6 +1,7 @@
 use serde::{Deserialize, Serialize};
 use std::collections::VecDeque;
-use std::sync::{Arc, Mutex};
+use std::fmt;
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum MessageKind {
@@ -14,18 +15,60 @@ pub struct Message {
     pub msg: String,
 }
 
-#[derive(Clone, Debug)]
+/// Receives the messages the engine emits, e.g., output of the `print`
+/// builtin and warnings about loaded policies, in place of the queue.
+pub trait MessageSink: Send + Sync {
+    fn message(&self, message: Message);
+}
+
+impl<F: Fn(Message) + Send + Sync> MessageSink for F {
+    fn message(&self, message: Message) {
+        self(message)
+    }
+}
+
+/// Messages emitted by the engine. Buffered until retrieved with `next`,
+/// unless a `MessageSink` is set, in which case they're sent to it instead.
+#[derive(Clone)]
 pub struct MessageQueue {
     messages: Arc<Mutex<VecDeque<Message>>>,
+    sink: Arc<RwLock<Option<Arc<dyn MessageSink>>>>,
+}
+
+impl fmt::Debug for MessageQueue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MessageQueue")
+            .field("messages", &self.messages)
+            .field("sink", &self.sink.read().unwrap().is_some())
+            .finish()
+    }
 }
 
 impl MessageQueue {
     pub fn new() -> Self {
         Self {
             messages: Arc::new(Mutex::new(VecDeque::new())),
+            sink: Arc::new(RwLock::new(None)),
         }
     }
 
+    /// A queue that sends every message to `sink`.
+    pub fn with_sink(sink: Arc<dyn MessageSink>) -> Self {
+        let queue = Self::new();
+        queue.set_sink(Some(sink));
+        queue
+    }
+
+    /// Send messages to `sink` from now on, or buffer them if `None`.
+    /// Applies to every clone of this queue.
+    pub fn set_sink(&self, sink: Option<Arc<dyn MessageSink>>) {
+        *self.sink.write().unwrap() = sink;
+    }
+
+    pub fn has_sink(&self) -> bool {
+        self.sink.read().unwrap().is_some()
+    }
+
     pub fn next(&self) -> Option<Message> {
         if let Ok(mut messages) = self.messages.lock() {
             messages.pop_front()
@@ -35,13 +78,23 @@ impl MessageQueue {
     }
 
     pub fn push(&self, kind: MessageKind, msg: String) {
-        let mut messages = self.messages.lock().unwrap();
-        messages.push_back(Message { kind, msg });
+        self.extend(Some(Message { kind, msg }))
     }
 
     pub fn extend<T: IntoIterator<Item = Message>>(&self, iter: T) {
-        let mut messages = self.messages.lock().unwrap();
-        messages.extend(iter)
+        // A panic while holding either lock can't leave it half updated, so
+        // poisoned locks are still fine to use.
+        if let Some(sink) = self
+            .sink
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .as_ref()
+        {
+            iter.into_iter().for_each(|message| sink.message(message));
+        } else {
+            let mut messages = self.messages.lock().unwrap_or_else(PoisonError::into_inner);
+            messages.extend(iter)
+        }
     }
 }
 
@@ -0,0 +1,142 @@
+//! Counts of how often each rule is tried and how often it succeeds, kept
+//! across queries, e.g., to find rules a policy never uses in production, or
+//! to check that new rules are being exercised.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, PoisonError, RwLock, Weak};
+
+use serde::{Deserialize, Serialize};
+
+use crate::formatting::ToPolarString;
+use crate::kb::KnowledgeBase;
+use crate::rules::{GenericRule, Rule};
+
+struct Counters {
+    /// Keeps the rule's address, which counters are found by, from being
+    /// reused while they're kept, without keeping the rule itself.
+    rule: Weak<Rule>,
+    hits: AtomicU64,
+    successes: AtomicU64,
+}
+
+/// Hit and success counters for the rules of a knowledge base, shared by
+/// every query against it, including concurrent ones.
+#[derive(Default)]
+pub struct RuleMetrics {
+    /// Rule address → its counters.
+    counters: RwLock<HashMap<usize, Arc<Counters>>>,
+}
+
+/// How often a rule was used, as reported by `Polar::rule_metrics`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RuleCounts {
+    /// The rule, formatted.
+    pub rule: String,
+    /// Number of times the rule applied to a call and was tried.
+    pub hits: u64,
+    /// Number of times the rule's body succeeded, which may be more than
+    /// once per hit if it has several results.
+    pub successes: u64,
+}
+
+impl RuleMetrics {
+    fn counters(&self, rule: &Arc<Rule>) -> Arc<Counters> {
+        let key = Arc::as_ptr(rule) as usize;
+        // Nothing panics while holding the lock, so even if it's poisoned,
+        // the table is whole.
+        let all = self.counters.read().unwrap_or_else(PoisonError::into_inner);
+        if let Some(counters) = all.get(&key) {
+            return counters.clone();
+        }
+        drop(all);
+        let mut all = self
+            .counters
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        if !all.contains_key(&key) && all.len().is_power_of_two() {
+            // Drop the counters of rules since removed, e.g., by reloading,
+            // as often as the table doubles in size.
+            all.retain(|_, counters| counters.rule.strong_count() > 0);
+        }
+        let counters = all.entry(key).or_insert_with(|| {
+            Arc::new(Counters {
+                rule: Arc::downgrade(rule),
+                hits: AtomicU64::new(0),
+                successes: AtomicU64::new(0),
+            })
+        });
+        counters.clone()
+    }
+
+    /// Count `rule` as tried, or as having succeeded.
+    pub(crate) fn count(&self, rule: &Arc<Rule>, success: bool) {
+        let counters = self.counters(rule);
+        let counter = if success {
+            &counters.successes
+        } else {
+            &counters.hits
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts for every rule in `kb`, including those never hit, ordered by
+    /// rule name and then by definition.
+    pub fn report(&self, kb: &KnowledgeBase) -> Vec<RuleCounts> {
+        let all = self.counters.read().unwrap();
+        let mut generic_rules: Vec<&GenericRule> = kb.rules.values().collect();
+        generic_rules.sort_by(|a, b| a.name.0.cmp(&b.name.0));
+        generic_rules
+            .into_iter()
+            .flat_map(GenericRule::rules)
+            .map(|rule| {
+                let (hits, successes) = all
+                    .get(&(Arc::as_ptr(&rule) as usize))
+                    .map(|counters| {
+                        (
+                            counters.hits.load(Ordering::Relaxed),
+                            counters.successes.load(Ordering::Relaxed),
+                        )
+                    })
+                    .unwrap_or_default();
+                RuleCounts {
+                    rule: rule.to_polar(),
+                    hits,
+                    successes,
+                }
+            })
+            .collect()
+    }
+
+    /// Set every count back to zero.
+    pub fn reset(&self) {
+        self.counters.write().unwrap().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rules::Parameter;
+    use crate::terms::*;
+
+    #[test]
+    fn test_counters_of_removed_rules_are_dropped() {
+        let metrics = RuleMetrics::default();
+        for i in 0..100 {
+            let rule = Arc::new(rule!("f", [i]));
+            metrics.count(&rule, false);
+        }
+        let live: Vec<Arc<Rule>> = (0..10).map(|i| Arc::new(rule!("g", [i]))).collect();
+        for rule in &live {
+            metrics.count(rule, true);
+        }
+        assert!(metrics.counters.read().unwrap().len() < 64);
+        for rule in &live {
+            metrics.count(rule, false);
+            let counters = metrics.counters(rule);
+            assert_eq!(counters.hits.load(Ordering::Relaxed), 1);
+            assert_eq!(counters.successes.load(Ordering::Relaxed), 1);
+        }
+    }
+}
@@ -0,0 +1,137 @@
+//! Readable names for the variables the VM makes up, e.g., `_x_12` for the
+//! `x` of a rule it ran, so that hosts are shown `x` rather than names that
+//! depend on how many variables were made before.
+
+use std::collections::{BTreeMap, HashSet};
+
+use crate::folder::{fold_term, Folder};
+use crate::terms::*;
+
+/// The name a made-up variable was made from: `x` for `_x_12`, or for the
+/// `_x_12_15` made from it when a rule is run, or `_` for the `_12` made for
+/// an anonymous variable.
+pub fn source_name(var: &Symbol) -> Option<&str> {
+    let is_number = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+    let mut name = var.0.strip_prefix('_')?;
+    let mut numbered = false;
+    while let Some(i) = name.rfind('_').filter(|i| is_number(&name[i + 1..])) {
+        name = &name[..i];
+        numbered = true;
+    }
+    if is_number(name) {
+        Some("_")
+    } else if numbered && !name.is_empty() {
+        Some(name)
+    } else {
+        None
+    }
+}
+
+/// Readable names given to made-up variables, in the order they're seen:
+/// each the name it was made from, numbered from `_2` if that's taken.
+#[derive(Clone, Debug, Default)]
+pub struct VariableNames {
+    /// Made-up variable → its readable name.
+    names: BTreeMap<Symbol, Symbol>,
+    /// Names that can't be given, e.g., because a query variable has them.
+    taken: HashSet<Symbol>,
+}
+
+impl VariableNames {
+    /// Names that avoid `taken`, and every variable in `terms` that isn't
+    /// made up.
+    pub fn new<'a>(
+        taken: impl IntoIterator<Item = Symbol>,
+        terms: impl IntoIterator<Item = &'a Term>,
+    ) -> Self {
+        let mut taken: HashSet<Symbol> = taken.into_iter().collect();
+        let mut vars = HashSet::new();
+        for term in terms {
+            term.variables(&mut vars);
+        }
+        taken.extend(vars.into_iter().filter(|var| source_name(var).is_none()));
+        Self {
+            names: BTreeMap::new(),
+            taken,
+        }
+    }
+
+    /// The readable name of `var`, or `var` if it isn't made up.
+    pub fn name(&mut self, var: &Symbol) -> Symbol {
+        if let Some(name) = self.names.get(var) {
+            return name.clone();
+        }
+        let base = match source_name(var) {
+            Some(base) => base,
+            None => return var.clone(),
+        };
+        let name = (1..)
+            .map(|i| match (base, i) {
+                ("_", _) => Symbol(format!("_{}", i)),
+                (_, 1) => Symbol::new(base),
+                _ => Symbol(format!("{}_{}", base, i)),
+            })
+            .find(|name| !self.taken.contains(name))
+            .unwrap();
+        self.taken.insert(name.clone());
+        self.names.insert(var.clone(), name.clone());
+        name
+    }
+
+    /// `term` with its made-up variables renamed.
+    pub fn rename(&mut self, term: Term) -> Term {
+        fold_term(term, self)
+    }
+
+    /// The names given so far, by made-up variable.
+    pub fn into_mapping(self) -> BTreeMap<Symbol, Symbol> {
+        self.names
+    }
+}
+
+impl Folder for VariableNames {
+    fn fold_variable(&mut self, v: Symbol) -> Symbol {
+        self.name(&v)
+    }
+
+    fn fold_rest_variable(&mut self, v: Symbol) -> Symbol {
+        self.name(&v)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::formatting::ToPolarString;
+
+    #[test]
+    fn test_source_names() {
+        assert_eq!(source_name(&sym!("_x_12")), Some("x"));
+        assert_eq!(source_name(&sym!("_value_1")), Some("value"));
+        assert_eq!(source_name(&sym!("__x_3")), Some("_x"));
+        assert_eq!(source_name(&sym!("_value_3_7")), Some("value"));
+        assert_eq!(source_name(&sym!("_7")), Some("_"));
+        assert_eq!(source_name(&sym!("_7_9")), Some("_"));
+        for var in &["x", "_this", "_x_", "_x_1a", "__3", "_"] {
+            assert_eq!(source_name(&sym!(*var)), None, "{}", var);
+        }
+    }
+
+    #[test]
+    fn test_variable_names() {
+        let term = term!(op!(
+            And,
+            term!(op!(Unify, term!(sym!("_x_12")), term!(sym!("x")))),
+            term!(op!(Unify, term!(sym!("_x_14")), term!(sym!("_y_13")))),
+            term!(op!(Unify, term!(sym!("_4")), term!(sym!("_5"))))
+        ));
+        let mut names = VariableNames::new(vec![sym!("y")], vec![&term]);
+        assert_eq!(
+            names.rename(term).to_polar(),
+            "x_2 = x and x_3 = y_2 and _1 = _2"
+        );
+        assert_eq!(names.name(&sym!("_x_12")), sym!("x_2"));
+        assert_eq!(names.name(&sym!("_this")), sym!("_this"));
+        assert_eq!(names.into_mapping().len(), 5);
+    }
+}
@@ -0,0 +1,279 @@
+//! Policy packages: directories of `.polar` files with a manifest naming
+//! the package, the order to load its files in, and the rules it exports.
+//!
+//! A package's other rules are private: they're renamed on load to names
+//! that can't be written in Polar, e.g., `rbac#has_permission`, so that
+//! they can only be called from within the package.
+//!
+//! Packages can depend on other packages, which are looked for in the
+//! directories next to the dependent package, and loaded before it.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use semver::{Version, VersionReq};
+use serde::{Deserialize, Serialize};
+
+use crate::error::{PolarResult, RuntimeError};
+use crate::folder::{fold_call, fold_operation, fold_rule, Folder};
+use crate::rules::Rule;
+use crate::terms::*;
+
+/// Name of the manifest file in a package directory.
+pub const MANIFEST: &str = "polar-package.json";
+
+/// The contents of a package's manifest, e.g.,
+///
+/// ```json
+/// {
+///   "name": "rbac",
+///   "version": "1.0.0",
+///   "files": ["roles.polar", "rbac.polar"],
+///   "exports": ["has_role", "allow"],
+///   "dependencies": {"roles": "^2.1"}
+/// }
+/// ```
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PackageManifest {
+    pub name: String,
+    pub version: String,
+    /// Paths of the package's policy files relative to its directory, in
+    /// the order they're loaded.
+    pub files: Vec<String>,
+    /// Names of the rules that can be called from outside the package.
+    pub exports: Vec<String>,
+    /// Names of the packages this one depends on, and the versions of each
+    /// it works with, as semantic version requirements.
+    #[serde(default)]
+    pub dependencies: BTreeMap<String, String>,
+}
+
+impl PackageManifest {
+    /// The private name of each rule in `defined` that isn't exported.
+    pub fn private_names(&self, defined: &HashSet<Symbol>) -> HashMap<Symbol, Symbol> {
+        defined
+            .iter()
+            .filter(|name| !self.exports.contains(&name.0))
+            .map(|name| (name.clone(), Symbol(format!("{}#{}", self.name, name.0))))
+            .collect()
+    }
+}
+
+/// Rename rules, and the calls to them, from their names in a package to
+/// their private names.
+pub struct Privatizer<'a> {
+    pub renames: &'a HashMap<Symbol, Symbol>,
+}
+
+impl<'a> Privatizer<'a> {
+    fn rename(&self, name: Symbol) -> Symbol {
+        self.renames.get(&name).cloned().unwrap_or(name)
+    }
+}
+
+impl<'a> Folder for Privatizer<'a> {
+    fn fold_rule(&mut self, rule: Rule) -> Rule {
+        let rule = fold_rule(rule, self);
+        Rule {
+            name: self.rename(rule.name),
+            ..rule
+        }
+    }
+
+    fn fold_call(&mut self, call: Call) -> Call {
+        let call = fold_call(call, self);
+        Call {
+            name: self.rename(call.name),
+            ..call
+        }
+    }
+
+    /// Method calls and constructors aren't calls to rules.
+    fn fold_operation(&mut self, o: Operation) -> Operation {
+        let skip = match o.operator {
+            Operator::Dot => Some(1),
+            Operator::New => Some(0),
+            _ => None,
+        };
+        match skip {
+            Some(skip) => Operation {
+                operator: o.operator,
+                args: o
+                    .args
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, arg)| if i == skip { arg } else { self.fold_term(arg) })
+                    .collect(),
+            },
+            None => fold_operation(o, self),
+        }
+    }
+}
+
+fn loading_error(msg: String) -> crate::error::PolarError {
+    RuntimeError::FileLoading { msg }.into()
+}
+
+pub(crate) fn read_file(file: &Path) -> PolarResult<String> {
+    std::fs::read_to_string(file)
+        .map_err(|e| loading_error(format!("Failed to read {}: {}", file.display(), e)))
+}
+
+/// Read the manifest of the package in directory `dir`.
+pub(crate) fn read_manifest(dir: &Path) -> PolarResult<PackageManifest> {
+    serde_json::from_str(&read_file(&dir.join(MANIFEST))?).map_err(|e| {
+        loading_error(format!(
+            "Invalid manifest for package at {}: {}",
+            dir.display(),
+            e
+        ))
+    })
+}
+
+fn parse_version(manifest: &PackageManifest) -> PolarResult<Version> {
+    Version::parse(&manifest.version).map_err(|e| {
+        loading_error(format!(
+            "Invalid version {} of package {}: {}",
+            manifest.version, manifest.name, e
+        ))
+    })
+}
+
+/// A package found next to the one being loaded.
+struct Candidate {
+    version: Version,
+    dir: PathBuf,
+    manifest: PackageManifest,
+}
+
+/// Packages in the subdirectories of `dir`, by name, newest first.
+fn available_packages(dir: Option<&Path>) -> PolarResult<HashMap<String, Vec<Candidate>>> {
+    let mut available: HashMap<String, Vec<Candidate>> = HashMap::new();
+    let entries = match dir.map(std::fs::read_dir) {
+        Some(Ok(entries)) => entries,
+        _ => return Ok(available),
+    };
+    for entry in entries.flatten() {
+        let dir = entry.path();
+        if !dir.join(MANIFEST).is_file() {
+            continue;
+        }
+        let manifest = read_manifest(&dir)?;
+        let version = parse_version(&manifest)?;
+        available
+            .entry(manifest.name.clone())
+            .or_default()
+            .push(Candidate {
+                version,
+                dir,
+                manifest,
+            });
+    }
+    for candidates in available.values_mut() {
+        candidates.sort_by(|a, b| b.version.cmp(&a.version));
+    }
+    Ok(available)
+}
+
+struct Resolver<'a> {
+    available: HashMap<String, Vec<Candidate>>,
+    loaded: &'a HashMap<String, PackageManifest>,
+    /// Package name → the version chosen, and the package and requirement
+    /// it was chosen for.
+    chosen: HashMap<String, (Version, String, String)>,
+    /// Names of the packages being resolved, each depending on the next.
+    path: Vec<String>,
+    /// Packages to load, in order.
+    order: Vec<(PathBuf, PackageManifest)>,
+}
+
+impl<'a> Resolver<'a> {
+    fn visit(&mut self, dir: PathBuf, manifest: PackageManifest) -> PolarResult<()> {
+        self.path.push(manifest.name.clone());
+        for (name, requirement) in &manifest.dependencies {
+            let req = VersionReq::parse(requirement).map_err(|e| {
+                loading_error(format!(
+                    "Invalid requirement {} on package {} in package {}: {}",
+                    requirement, name, manifest.name, e
+                ))
+            })?;
+            if self.path.contains(name) {
+                let start = self.path.iter().position(|n| n == name).unwrap();
+                let mut cycle = self.path[start..].to_vec();
+                cycle.push(name.clone());
+                return Err(loading_error(format!(
+                    "Packages depend on each other in a cycle: {}",
+                    cycle.join(" -> ")
+                )));
+            }
+            if let Some(loaded) = self.loaded.get(name) {
+                if !req.matches(&parse_version(loaded)?) {
+                    return Err(loading_error(format!(
+                        "Package {} requires {} {}, but version {} is already loaded",
+                        manifest.name, name, requirement, loaded.version
+                    )));
+                }
+                continue;
+            }
+            if let Some((version, other, other_requirement)) = self.chosen.get(name) {
+                if !req.matches(version) {
+                    return Err(loading_error(format!(
+                        "Conflicting requirements on package {}: {} requires {}, \
+                         but {} requires {}, which resolved to version {}",
+                        name, manifest.name, requirement, other, other_requirement, version
+                    )));
+                }
+                continue;
+            }
+            let candidate = self
+                .available
+                .get(name)
+                .and_then(|candidates| candidates.iter().find(|c| req.matches(&c.version)))
+                .ok_or_else(|| {
+                    loading_error(format!(
+                        "No version of package {} matches {}, as required by {}",
+                        name, requirement, manifest.name
+                    ))
+                })?;
+            let (version, dir, dependency) = (
+                candidate.version.clone(),
+                candidate.dir.clone(),
+                candidate.manifest.clone(),
+            );
+            self.chosen.insert(
+                name.clone(),
+                (version, manifest.name.clone(), requirement.clone()),
+            );
+            self.visit(dir, dependency)?;
+        }
+        self.path.pop();
+        self.order.push((dir, manifest));
+        Ok(())
+    }
+}
+
+/// The packages to load for the package `manifest` in `dir`, in the order
+/// to load them: each after its dependencies. Dependencies already in
+/// `loaded` aren't loaded again, but must have a version that matches.
+pub(crate) fn resolve(
+    dir: &Path,
+    manifest: PackageManifest,
+    loaded: &HashMap<String, PackageManifest>,
+) -> PolarResult<Vec<(PathBuf, PackageManifest)>> {
+    if loaded.contains_key(&manifest.name) {
+        return Err(loading_error(format!(
+            "Package {} is already loaded",
+            manifest.name
+        )));
+    }
+    parse_version(&manifest)?;
+    let mut resolver = Resolver {
+        available: available_packages(dir.parent())?,
+        loaded,
+        chosen: HashMap::new(),
+        path: vec![],
+        order: vec![],
+    };
+    resolver.visit(dir.to_path_buf(), manifest)?;
+    Ok(resolver.order)
+}
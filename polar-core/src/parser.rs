@@ -8,11 +8,30 @@ pub enum ValueOrLogical {
     Either(Term),
 }
 
+/// `-term`: a negative number if `term` is a number, and `0 - term`
+/// otherwise.
+fn negate(term: &Term) -> Value {
+    match term.value() {
+        Value::Number(Numeric::Integer(i)) => Value::Number((-i).into()),
+        Value::Number(Numeric::Float(f)) => Value::Number((-f).into()),
+        _ => {
+            let zero = term.clone_with_value(Value::Number(0.into()));
+            let args = vec![zero, term.clone()];
+            Value::Expression(Operation {
+                operator: Operator::Sub,
+                args,
+            })
+        }
+    }
+}
+
 lalrpop_mod!(
     #[allow(clippy::all, dead_code, unused_imports, unused_mut)]
     polar
 );
 
+use serde::{Deserialize, Serialize};
+
 use super::error::{self, PolarResult};
 use super::lexer::{self, Lexer};
 use super::rules::*;
@@ -24,6 +43,104 @@ pub enum Line {
     Query(Term),
 }
 
+/// Limits on the sources the parser accepts, for hosts that load untrusted
+/// policies. `None` means no limit, which is the default.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ParserLimits {
+    /// Length of a source, in bytes.
+    #[serde(default)]
+    pub max_source_len: Option<usize>,
+    /// Depth of nested parentheses, lists and dictionaries.
+    #[serde(default)]
+    pub max_depth: Option<usize>,
+    /// Number of rules in a source.
+    #[serde(default)]
+    pub max_rules: Option<usize>,
+    /// Number of elements in a list, or fields in a dictionary.
+    #[serde(default)]
+    pub max_collection_len: Option<usize>,
+}
+
+impl ParserLimits {
+    /// Check the limits that don't need `src` to be parsed, so that sources
+    /// exceeding them are rejected before any work is done on them.
+    pub fn check_source(&self, src: &str) -> PolarResult<()> {
+        let exceeded = |limit: &str, max: usize, loc: usize| -> PolarResult<()> {
+            Err(error::ParseError::LimitExceeded {
+                limit: limit.to_string(),
+                max,
+                loc,
+            }
+            .into())
+        };
+
+        if let Some(max) = self.max_source_len {
+            if src.len() > max {
+                return exceeded("source length", max, 0);
+            }
+        }
+        if self.max_depth.is_none() && self.max_collection_len.is_none() {
+            return Ok(());
+        }
+
+        // Open brackets, with the number of elements seen in lists and
+        // dictionaries.
+        let mut open: Vec<(usize, Option<usize>)> = vec![];
+        for token in Lexer::new(src) {
+            // Lexing errors are left for the parser to report.
+            let (loc, token, _) = match token {
+                Ok(token) => token,
+                Err(_) => break,
+            };
+            match token {
+                Token::LP | Token::LB | Token::LCB => {
+                    if self.max_depth.is_some_and(|max| open.len() >= max) {
+                        return exceeded("nesting depth", self.max_depth.unwrap(), loc);
+                    }
+                    let elements = match token {
+                        Token::LP => None,
+                        _ => Some(1),
+                    };
+                    open.push((loc, elements));
+                }
+                Token::RP | Token::RB | Token::RCB => {
+                    open.pop();
+                }
+                Token::Comma => {
+                    if let Some((start, Some(elements))) = open.last_mut() {
+                        *elements += 1;
+                        if self.max_collection_len.is_some_and(|max| *elements > max) {
+                            return exceeded(
+                                "collection length",
+                                self.max_collection_len.unwrap(),
+                                *start,
+                            );
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Check the number of rules in a parsed source.
+    pub fn check_lines(&self, lines: &[Line]) -> PolarResult<()> {
+        if let Some(max) = self.max_rules {
+            let mut rules = lines.iter().filter(|line| matches!(line, Line::Rule(_)));
+            if let Some(Line::Rule(rule)) = rules.nth(max) {
+                return Err(error::ParseError::LimitExceeded {
+                    limit: "number of rules".to_string(),
+                    max,
+                    loc: rule.body.offset(),
+                }
+                .into());
+            }
+        }
+        Ok(())
+    }
+}
+
 fn to_parse_error(e: ParseError<usize, lexer::Token, error::ParseError>) -> error::ParseError {
     match e {
         ParseError::InvalidToken { location: loc } => error::ParseError::InvalidToken { loc },
@@ -68,7 +185,7 @@ pub fn parse_query(src_id: u64, src: &str) -> PolarResult<Term> {
         .map_err(|e| to_parse_error(e).into())
 }
 
-#[cfg(test)]
+#[cfg(any(test, feature = "testing"))]
 pub fn parse_rules(src_id: u64, src: &str) -> PolarResult<Vec<Rule>> {
     polar::RulesParser::new()
         .parse(src_id, Lexer::new(src))
@@ -107,6 +224,89 @@ mod tests {
         assert_eq!(exp, term!(op!(Dot, term!(sym!("a")), term!("b"))));
     }
 
+    #[test]
+    fn test_dict_field_order() {
+        let dict = parse_term("{b: 1, a: 2, c: 3}");
+        assert_eq!(dict.to_polar(), "{b: 1, a: 2, c: 3}");
+        let json = serde_json::to_string(dict.value()).unwrap();
+        assert!(json.find("\"b\"").unwrap() < json.find("\"a\"").unwrap());
+
+        // Field order doesn't affect equality.
+        assert_eq!(dict, parse_term("{a: 2, b: 1, c: 3}"));
+
+        let pattern = parse_query("x matches Foo{y: 1, x: 2}");
+        assert_eq!(pattern.to_polar(), "x matches Foo{y: 1, x: 2}");
+    }
+
+    #[test]
+    fn test_rule_guards() {
+        let rule = parse_rule("f(x) when x > 10 if g(x);");
+        assert_eq!(rule.to_polar(), "f(x) when x > 10 if g(x);");
+        let rule = parse_rule("f(x: Foo) when x.a = 1 and x.b = 2;");
+        assert_eq!(rule.to_polar(), "f(x: Foo{}) when x.a = 1 and x.b = 2;");
+        assert_eq!(parse_rule("f(x) if x > 10;").guard, None);
+    }
+
+    #[test]
+    fn test_deprecated_rules() {
+        let rule = parse_rule(r#"@deprecated("use g") f(x) if g(x);"#);
+        assert_eq!(rule.deprecation(), Some("use g".to_string()));
+        assert_eq!(rule.to_polar(), r#"@deprecated("use g") f(x) if g(x);"#);
+        let rule = parse_rule("@deprecated f(x);");
+        assert_eq!(rule.deprecation(), Some("".to_string()));
+        assert_eq!(rule.to_polar(), "@deprecated f(x);");
+        assert_eq!(parse_rule("f(x);").deprecation(), None);
+        assert!(matches!(
+            super::parse_rules(0, "@deprecated(1) f(x);")
+                .expect_err("parse error")
+                .kind,
+            error::ErrorKind::Parse(error::ParseError::InvalidAnnotation { .. })
+        ));
+    }
+
+    #[test]
+    fn test_budget_annotations() {
+        let rule = parse_rule("@budget(goals: 1000, ms: 50) f(x) if g(x);");
+        assert_eq!(
+            rule.budget(),
+            Some(Budget {
+                goals: Some(1000),
+                ms: Some(50)
+            })
+        );
+        assert_eq!(parse_rule("f(x);").budget(), None);
+        for src in &[
+            "@budget f(x);",
+            "@budget(1000) f(x);",
+            "@budget(goals: -1) f(x);",
+            "@budget(steps: 10) f(x);",
+        ] {
+            assert!(matches!(
+                super::parse_rules(0, src).expect_err("parse error").kind,
+                error::ErrorKind::Parse(error::ParseError::InvalidAnnotation { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn test_annotations() {
+        let rule = parse_rule(r#"@audit @tag("pii") @owner(team: "billing") f(x);"#);
+        assert_eq!(rule.annotations.len(), 3);
+        let tag = rule.annotation("tag").unwrap();
+        assert_eq!(tag.args, vec![term!("pii")]);
+        let owner = rule.annotation("owner").unwrap();
+        assert_eq!(
+            owner.kwargs.as_ref().unwrap()[&sym!("team")],
+            term!("billing")
+        );
+        assert_eq!(
+            rule.to_polar(),
+            r#"@audit @tag("pii") @owner(team: "billing") f(x);"#
+        );
+        assert!(rule.annotation("priority").is_none());
+        assert!(parse_rule("f(x);").annotations.is_empty());
+    }
+
     #[test]
     fn try_it_with_macros() {
         let int = parse_term(" 123");
@@ -197,6 +397,36 @@ mod tests {
         );
     }
 
+    #[test]
+    fn parse_negation() {
+        assert_eq!(parse_query("- 5"), term!(-5));
+        assert_eq!(parse_query("-(5)"), term!(-5));
+        assert_eq!(parse_query("x = -y").to_polar(), "x = 0 - y");
+        assert_eq!(parse_query("-x.y * 2").to_polar(), "(0 - x.y) * 2");
+        assert_eq!(parse_query("x - -1").to_polar(), "x - -1");
+        assert_eq!(parse_query("2 * -x").to_polar(), "2 * (0 - x)");
+        assert_eq!(parse_query("x matches -1").to_polar(), "x matches -1");
+    }
+
+    #[test]
+    fn parse_ranges() {
+        assert_eq!(parse_query("x in 1..10").to_polar(), "x in 1..10");
+        assert_eq!(parse_query("-1..n").to_polar(), "-1..n");
+        assert_eq!(parse_query("1.5..(n + 1)").to_polar(), "1.5..(n + 1)");
+        assert_eq!(parse_query("x.y..-x.z").to_polar(), "x.y..(0 - x.z)");
+        assert_eq!(parse_query("x in -1..1").to_polar(), "x in -1..1");
+        assert_eq!(parse_query("x in -y").to_polar(), "x in (0 - y)");
+    }
+
+    #[test]
+    fn parse_modes() {
+        let rule = parse_rule("f(+x, -y: Foo, z, -1);");
+        let modes: Vec<_> = rule.params.iter().map(|param| param.mode).collect();
+        assert_eq!(modes, vec![Some(Mode::In), Some(Mode::Out), None, None]);
+        assert_eq!(rule.to_polar(), "f(+x, -y: Foo{}, z, -1);");
+        assert_eq!(parse_rule("g(+x: (1));").to_polar(), "g(+x: 1);");
+    }
+
     #[test]
     fn test_parse_specializers() {
         let rule = parse_rule(r#"f(x: 1);"#);
@@ -285,6 +515,19 @@ mod tests {
         parse_rules(0, f).expect_err("parse error");
     }
 
+    #[test]
+    fn test_parse_instance_literal_terms() {
+        let f = r#"a(x) if x = Foo{a: 1, b: [Bar{}]};"#;
+        let results = parse_rules(0, f).unwrap();
+        assert_eq!(
+            results[0].to_polar(),
+            r#"a(x) if x = new Foo(a: 1, b: [new Bar()]);"#
+        );
+        // A variable before a `match`'s branches isn't an instance literal.
+        let f = r#"a(x) if match x { 1 => true; };"#;
+        parse_rules(0, f).unwrap();
+    }
+
     #[test]
     fn test_parse_matches() {
         let term = parse_query("{} matches {}");
@@ -328,6 +571,74 @@ mod tests {
         assert_eq!(parse_query(q).to_polar(), q, "{} -- {}", q, parse_query(q));
     }
 
+    #[test]
+    fn test_parse_dict_rest_vars() {
+        let q = "{id: id, **rest} = {**other}";
+        assert_eq!(parse_query(q).to_polar(), q);
+        assert_eq!(
+            parse_rule("f(x: {a: 1, **r});").to_polar(),
+            "f(x: {a: 1, **r});"
+        );
+
+        // The rest variable comes last, and isn't allowed in instance literals.
+        for q in &["{**rest, id: 1} = x", "x matches Foo{id: 1, **rest}"] {
+            assert!(matches!(
+                super::parse_query(0, q).expect_err("parse error"),
+                error::PolarError {
+                    kind: error::ErrorKind::Parse(error::ParseError::UnrecognizedToken { .. }),
+                    ..
+                }
+            ));
+        }
+    }
+
+    #[test]
+    fn test_parse_datetimes() {
+        assert_eq!(
+            parse_query("@2024-05-01T09:30:00+02:00"),
+            term!(Value::DateTime(
+                DateTime::parse("2024-05-01T07:30:00Z").unwrap()
+            ))
+        );
+        let q = "x.expires_at > @2024-05-01T00:00:00.250Z";
+        assert_eq!(parse_query(q).to_polar(), q);
+        assert_eq!(
+            parse_rule("f(@2024-05-01);").to_polar(),
+            "f(@2024-05-01T00:00:00Z);"
+        );
+        assert!(matches!(
+            super::parse_query(0, "x < @2024-02-30").expect_err("parse error"),
+            error::PolarError {
+                kind: error::ErrorKind::Parse(error::ParseError::InvalidDateTime { .. }),
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_parse_string_escapes() {
+        let q = r#""say \"hi\"\tto \u{1F600}\\""#;
+        assert_eq!(parse_query(q), term!("say \"hi\"\tto 😀\\"));
+        assert_eq!(parse_query(q).to_polar(), r#""say \"hi\"\tto 😀\\""#);
+        assert_eq!(term!("bell\u{7}").to_polar(), r#""bell\u{7}""#);
+        assert_eq!(parse_query(r#"x = "\q""#).to_polar(), r#"x = "q""#);
+        assert!(matches!(
+            super::parse_query(0, r#"x = "\u{d800}""#).expect_err("parse error"),
+            error::PolarError {
+                kind: error::ErrorKind::Parse(error::ParseError::InvalidEscape { loc: 5, .. }),
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_parse_nil() {
+        assert_eq!(parse_query("nil"), term!(Value::Nil));
+        let q = "x.manager != nil";
+        assert_eq!(parse_query(q).to_polar(), q);
+        assert_eq!(parse_rule("f(nil, [nil]);").to_polar(), "f(nil, [nil]);");
+    }
+
     #[test]
     fn test_primitive_methods() {
         let q = r#""abc".startswith("a")"#;
@@ -343,6 +654,16 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_bang_is_not() {
+        assert_eq!(parse_query("!f(x)"), parse_query("not f(x)"));
+        assert_eq!(
+            parse_query("!(x = 1 or x = 2) and x != 3"),
+            parse_query("not (x = 1 or x = 2) and x != 3")
+        );
+        assert_eq!(parse_rule("f(x) if !g(x);").to_polar(), "f(x) if not g(x);");
+    }
+
     #[test]
     fn test_catching_wrong_types() {
         for bad_query in &[
@@ -351,6 +672,7 @@ mod tests {
             "{x: 1 < 2}",
             "{x: 1 < 2}",
             "not 1",
+            "!1",
             "1 and 2",
             "1 + print(\"x\")",
             "forall([1, 2, 3], x < 1)",
@@ -145,7 +145,11 @@ impl Runnable for IsaConstraintCheck {
                 self.alternative_check = None;
             } else if self.alternative_check.is_none() {
                 // If both checks fail, we fail.
-                return Ok(QueryEvent::Done { result: false });
+                return Ok(QueryEvent::Done {
+                    result: false,
+                    policy_version: None,
+                    stats: None,
+                });
             }
         }
 
@@ -164,7 +168,11 @@ impl Runnable for IsaConstraintCheck {
                     return Ok(primary);
                 }
             } else {
-                return Ok(QueryEvent::Done { result: true });
+                return Ok(QueryEvent::Done {
+                    result: true,
+                    policy_version: None,
+                    stats: None,
+                });
             }
         }
     }
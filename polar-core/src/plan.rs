@@ -0,0 +1,123 @@
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+use super::builtins::builtin;
+use super::kb::KnowledgeBase;
+use super::rules::Parameter;
+use super::terms::*;
+use super::visitor::{walk_operation, walk_term, Visitor};
+
+/// How a query would be evaluated, worked out from the knowledge base without
+/// running it.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct QueryPlan {
+    /// The query's predicate calls, in the order they'd be run.
+    pub goals: Vec<GoalPlan>,
+    /// Number of choice points the query is expected to leave: one for each
+    /// goal with several candidate rules, and one for each `or`.
+    pub choice_points: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct GoalPlan {
+    /// The goal, as Polar source.
+    pub goal: String,
+    /// Rules that could apply to the goal, in the order they were defined.
+    /// When more than one applies, the VM orders them by specificity when
+    /// the goal is run.
+    pub candidates: Vec<String>,
+    /// Number of rules defined for the predicate.
+    pub rules_defined: usize,
+    /// Positions of arguments whose values narrow the candidates using the
+    /// rule index.
+    pub indexed_args: Vec<usize>,
+    /// Whether the goal is answered by a builtin rather than by rules.
+    pub builtin: bool,
+}
+
+/// Record the goals of a query and count its choice points.
+#[derive(Default)]
+struct PlanVisitor {
+    calls: Vec<Term>,
+    alternatives: usize,
+}
+
+impl Visitor for PlanVisitor {
+    fn visit_term(&mut self, t: &Term) {
+        match t.value() {
+            // Arguments of a call are data, not further calls.
+            Value::Call(_) => self.calls.push(t.clone()),
+            _ => walk_term(self, t),
+        }
+    }
+
+    fn visit_operation(&mut self, o: &Operation) {
+        match o.operator {
+            // Method calls on the left of a lookup aren't predicates.
+            Operator::Dot => self.visit_term(&o.args[0]),
+            Operator::Or => {
+                self.alternatives += 1;
+                walk_operation(self, o)
+            }
+            _ => walk_operation(self, o),
+        }
+    }
+}
+
+/// Plan the evaluation of a rewritten query `term`.
+pub fn explain(kb: &KnowledgeBase, term: &Term) -> QueryPlan {
+    let mut visitor = PlanVisitor::default();
+    visitor.visit_term(term);
+
+    let mut plan = QueryPlan {
+        goals: vec![],
+        choice_points: visitor.alternatives,
+    };
+    for call_term in visitor.calls {
+        let call = match call_term.value() {
+            Value::Call(call) => call,
+            _ => unreachable!("plan goals are calls"),
+        };
+        let goal = match kb.rules.get(&call.name) {
+            None => GoalPlan {
+                goal: call_term.to_polar(),
+                candidates: vec![],
+                rules_defined: 0,
+                indexed_args: vec![],
+                builtin: builtin(&call.name, call.args.len()).is_some(),
+            },
+            Some(generic_rule) => {
+                let rules = generic_rule.rules();
+                let candidates = generic_rule.get_applicable_rules(&call.args);
+                let indexed_args = call
+                    .args
+                    .iter()
+                    .enumerate()
+                    .filter(|(i, arg)| {
+                        arg.is_ground()
+                            && rules
+                                .iter()
+                                .any(|rule| rule.params.get(*i).is_some_and(Parameter::is_ground))
+                    })
+                    .map(|(i, _)| i)
+                    .collect();
+                if candidates.len() > 1 {
+                    plan.choice_points += 1;
+                }
+                GoalPlan {
+                    goal: call_term.to_polar(),
+                    candidates: rules
+                        .iter()
+                        .filter(|rule| candidates.iter().any(|c| Arc::ptr_eq(c, rule)))
+                        .map(|rule| rule.to_polar())
+                        .collect(),
+                    rules_defined: rules.len(),
+                    indexed_args,
+                    builtin: false,
+                }
+            }
+        };
+        plan.goals.push(goal);
+    }
+    plan
+}
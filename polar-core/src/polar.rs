@@ -1,24 +1,202 @@
-use super::error::PolarResult;
+pub use super::builtins::{compare_terms, compare_terms_collated};
+use super::coverage::{self, AuthorizationTypes};
+use super::error::{OperationalError, ParameterError, PolarResult, RuntimeError};
 use super::events::*;
+use super::facts::{FactSource, FactSources};
+use super::folder::{fold_name, fold_string, Folder};
 use super::kb::*;
 use super::messages::*;
+use super::metrics::{RuleCounts, RuleMetrics};
+use super::naming::VariableNames;
+use super::packages::{self, PackageManifest, Privatizer};
 use super::parser;
+use super::plan::{self, QueryPlan};
+use super::replay::{self, Answer, InstanceRenamer, Interaction, Recording};
 use super::rewrites::*;
 use super::rules::*;
 use super::runnable::Runnable;
+use super::sandbox::SandboxConfig;
 use super::sources::*;
 use super::terms::*;
+use super::traces::{
+    Decision, DecisionLogger, GoalSink, GoalSummary, Instrumentation, Proof, StackSnapshot,
+    TraceResult,
+};
 use super::vm::*;
-use super::warnings::check_singletons;
+use super::warnings::{check_negation_cycles, check_recursion, check_singletons};
 
-use std::collections::{HashMap, HashSet};
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::ops::ControlFlow;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::rc::Rc;
 use std::sync::{Arc, RwLock};
 
+use serde::{Deserialize, Serialize};
+use unicode_normalization::{is_nfc, UnicodeNormalization};
+
+/// Put strings and dictionary keys in Unicode Normalization Form C, so that
+/// differently composed but canonically equivalent strings are equal.
+struct Nfc;
+
+impl Nfc {
+    fn normalize(s: String) -> String {
+        if is_nfc(&s) {
+            s
+        } else {
+            s.nfc().collect()
+        }
+    }
+
+    fn normalize_term(term: Term, enabled: bool) -> Term {
+        if enabled {
+            Nfc.fold_term(term)
+        } else {
+            term
+        }
+    }
+}
+
+impl Folder for Nfc {
+    fn fold_string(&mut self, s: String) -> String {
+        fold_string(Self::normalize(s), self)
+    }
+
+    fn fold_name(&mut self, n: Symbol) -> Symbol {
+        fold_name(Symbol(Self::normalize(n.0)), self)
+    }
+}
+
+/// The state of a suspended `Query`, as serialized by `Query::suspend`.
+#[derive(Serialize, Deserialize)]
+struct SuspendedQuery {
+    vm: SuspendedVm,
+    term: Term,
+    done: bool,
+    results: usize,
+    max_results: Option<usize>,
+    normalize_strings: bool,
+}
+
+/// Whether `term` can be an argument of a fact added with `Polar::add_fact`,
+/// or, if `pattern`, of a pattern to remove facts with.
+fn is_fact_arg(term: &Term, pattern: bool) -> bool {
+    let all = |terms: &[Term]| terms.iter().all(|t| is_fact_arg(t, pattern));
+    match term.value() {
+        Value::Number(_)
+        | Value::String(_)
+        | Value::Boolean(_)
+        | Value::Nil
+        | Value::ExternalInstance(_) => true,
+        Value::Variable(_) => pattern,
+        Value::List(terms) | Value::Tuple(terms) => all(terms),
+        Value::Set(set) => all(&set.elements),
+        Value::Dictionary(dict) => dict.fields.values().all(|t| is_fact_arg(t, pattern)),
+        _ => false,
+    }
+}
+
+fn check_fact_args(args: &[Term], pattern: bool) -> PolarResult<()> {
+    match args.iter().find(|arg| !is_fact_arg(arg, pattern)) {
+        Some(arg) => Err(ParameterError(format!(
+            "Arguments of facts must be data, got: {}",
+            arg.to_polar()
+        ))
+        .into()),
+        None => Ok(()),
+    }
+}
+
+/// The rule for the fact `name(args...)`, which has an empty body.
+fn fact_rule(name: Symbol, args: Vec<Term>, normalize: bool) -> PolarResult<Rule> {
+    check_fact_args(&args, false)?;
+    let params = args
+        .into_iter()
+        .map(|arg| Parameter {
+            parameter: Nfc::normalize_term(arg, normalize),
+            specializer: None,
+            mode: None,
+        })
+        .collect();
+    Ok(Rule {
+        name,
+        params,
+        guard: None,
+        body: Term::new_temporary(Value::Expression(Operation {
+            operator: Operator::And,
+            args: vec![],
+        })),
+        annotations: vec![],
+    })
+}
+
+/// Whether the fact argument `value` matches `pattern`, in which variables
+/// match any value, but the same one wherever they appear.
+fn matches_fact_arg(pattern: &Term, value: &Term, bindings: &mut HashMap<Symbol, Term>) -> bool {
+    let all = |patterns: &[Term], values: &[Term], bindings: &mut HashMap<Symbol, Term>| {
+        patterns.len() == values.len()
+            && patterns
+                .iter()
+                .zip(values)
+                .all(|(p, v)| matches_fact_arg(p, v, bindings))
+    };
+    match (pattern.value(), value.value()) {
+        (Value::Variable(var), _) => match bindings.get(var) {
+            Some(bound) => bound == value,
+            None => {
+                bindings.insert(var.clone(), value.clone());
+                true
+            }
+        },
+        (Value::List(patterns), Value::List(values))
+        | (Value::Tuple(patterns), Value::Tuple(values)) => all(patterns, values, bindings),
+        (Value::Dictionary(patterns), Value::Dictionary(values)) => {
+            patterns.fields.len() == values.fields.len()
+                && patterns.fields.iter().all(|(k, p)| {
+                    values
+                        .fields
+                        .get(k)
+                        .is_some_and(|v| matches_fact_arg(p, v, bindings))
+                })
+        }
+        _ => pattern == value,
+    }
+}
+
+/// A running query. It only runs while the host asks for its next event,
+/// and stops after each result until asked again, so results are never
+/// buffered and memory use doesn't grow with the number of results.
 pub struct Query {
     runnable_stack: Vec<(Box<dyn Runnable>, u64)>, // Tuple of Runnable + call_id.
     vm: PolarVirtualMachine,
     term: Term,
     done: bool,
+    /// Set once the query has panicked, after which it can't continue.
+    poisoned: bool,
+    /// Results returned so far, and the most allowed, if limited.
+    results: usize,
+    max_results: Option<usize>,
+    /// Whether to NFC-normalize strings from the host.
+    normalize_strings: bool,
+    /// Interactions with the host so far, if recording.
+    recording: Option<Recording>,
+    /// Interactions left to replay, if replaying.
+    replaying: Option<VecDeque<Interaction>>,
+    /// Whether to report `QueryStats` when done, and the host calls counted
+    /// for them so far.
+    stats: bool,
+    external_calls: u64,
+    /// The variables whose bindings identify a result, `None` for all of
+    /// them, if deduplicating results, and those of the results so far.
+    dedup: Option<Option<Vec<Symbol>>>,
+    seen_results: HashSet<Vec<(Symbol, Term)>>,
+    /// Where to log the query's decision, and whether it's been logged.
+    decision_logger: Option<Arc<dyn DecisionLogger>>,
+    decision_logged: Cell<bool>,
+    /// The proof of the latest result, taken while its bindings still hold.
+    decision_proof: Option<Proof>,
+    /// The readable names of the made-up variables in the latest result.
+    variable_names: BTreeMap<Symbol, Symbol>,
 }
 
 impl Query {
@@ -28,6 +206,20 @@ impl Query {
             vm,
             term,
             done: false,
+            poisoned: false,
+            results: 0,
+            max_results: None,
+            normalize_strings: false,
+            recording: None,
+            replaying: None,
+            stats: false,
+            external_calls: 0,
+            dedup: None,
+            seen_results: HashSet::new(),
+            decision_logger: None,
+            decision_logged: Cell::new(false),
+            decision_proof: None,
+            variable_names: BTreeMap::new(),
         }
     }
 
@@ -43,28 +235,219 @@ impl Query {
     /// 3. Immediately request the next event, which will execute Runnable B.
     /// 4. When Runnable B emits a Done event, pop Runnable B off the stack and return its result as
     ///    an answer to Runnable A.
+    ///
+    /// A bug in the VM returns an `OperationalError::InvalidState` rather than
+    /// panicking, so that hosts can run untrusted policies and queries.
     pub fn next_event(&mut self) -> PolarResult<QueryEvent> {
-        let mut counter = self.vm.id_counter();
-        match self.top_runnable().run(Some(&mut counter))? {
-            QueryEvent::Run { runnable, call_id } => {
-                self.push_runnable(runnable, call_id);
-                self.next_event()
+        if self.poisoned {
+            return Err(OperationalError::InvalidState(
+                "query can't continue after an internal error".to_string(),
+            )
+            .into());
+        }
+        let event = match catch_unwind(AssertUnwindSafe(|| self.next_host_event())) {
+            Ok(event) => event,
+            Err(panic) => {
+                self.poisoned = true;
+                let msg = panic
+                    .downcast_ref::<&str>()
+                    .map(|msg| msg.to_string())
+                    .or_else(|| panic.downcast_ref::<String>().cloned())
+                    .unwrap_or_else(|| "unknown error".to_string());
+                Err(OperationalError::InvalidState(format!("query panicked: {}", msg)).into())
+            }
+        };
+        match &event {
+            Ok(QueryEvent::Result { .. }) if self.decision_logger.is_some() => {
+                self.decision_proof = self.proof_for_last_result()
             }
-            QueryEvent::Done { result } => {
-                if let Some((_, result_call_id)) = self.pop_runnable() {
-                    self.top_runnable()
-                        .external_question_result(result_call_id, result)?;
-                    self.next_event()
-                } else {
-                    // VM is done.
-                    assert!(self.runnable_stack.is_empty());
-                    Ok(QueryEvent::Done { result })
+            Ok(QueryEvent::Done { .. }) => self.log_decision(true, None),
+            Err(e) => self.log_decision(true, Some(e.to_string())),
+            _ => {}
+        }
+        event
+    }
+
+    /// Log the query's decision, if there's a decision logger and it hasn't
+    /// been logged yet.
+    fn log_decision(&self, completed: bool, error: Option<String>) {
+        let logger = match &self.decision_logger {
+            Some(logger) if !self.decision_logged.replace(true) => logger,
+            _ => return,
+        };
+        let trace = if logger.wants_traces() {
+            self.trace_for_last_result().map(|trace| trace.formatted)
+        } else {
+            None
+        };
+        logger.log(&Decision {
+            query: self.vm.term_source(&self.term, false),
+            results: self.results,
+            completed,
+            error,
+            policy_version: self.vm.policy_version(),
+            duration_ms: self.vm.query_elapsed_ms(),
+            proof: self.decision_proof.clone(),
+            trace,
+        });
+    }
+
+    /// The next event for the host. When replaying, events for which the
+    /// recording holds answers are answered from it instead.
+    fn next_host_event(&mut self) -> PolarResult<QueryEvent> {
+        loop {
+            let event = self.run_next_event()?;
+            if !replay::is_external(&event) {
+                return Ok(event);
+            }
+            if let Some(recording) = self.recording.as_mut() {
+                recording.interactions.push(Interaction {
+                    event: event.clone(),
+                    answers: vec![],
+                });
+            }
+            let replaying = match self.replaying.as_mut() {
+                Some(replaying) => replaying,
+                None => return Ok(event),
+            };
+            let interaction =
+                replaying
+                    .pop_front()
+                    .ok_or_else(|| RuntimeError::ReplayDiverged {
+                        expected: "the end of the recording".to_string(),
+                        found: replay::describe(&event),
+                    })?;
+            let (expected, recorded_ids) = replay::normalize(&interaction.event);
+            let (found, ids) = replay::normalize(&event);
+            if expected != found {
+                return Err(RuntimeError::ReplayDiverged { expected, found }.into());
+            }
+            let ids: HashMap<u64, u64> = recorded_ids.into_iter().zip(ids).collect();
+            let call_id = replay::call_id(&event).unwrap_or_default();
+            for answer in interaction.answers {
+                match InstanceRenamer(&ids).rename(answer) {
+                    Answer::Call(value) => self.call_result(call_id, value)?,
+                    Answer::ConcurrentCall(index, value) => {
+                        let call_id = match &event {
+                            QueryEvent::ExternalCalls { calls } if index < calls.len() => {
+                                calls[index].call_id
+                            }
+                            _ => {
+                                return Err(RuntimeError::ReplayDiverged {
+                                    expected: format!("a concurrent call at index {}", index),
+                                    found: replay::describe(&event),
+                                }
+                                .into())
+                            }
+                        };
+                        self.call_result(call_id, value)?
+                    }
+                    Answer::Question(result) => self.question_result(call_id, result)?,
+                    Answer::LookupMany(results) => self.lookup_many_result(call_id, results)?,
+                    Answer::Error(message) => self.application_error(message)?,
+                    Answer::Snapshot(instance_id, fields) => {
+                        self.snapshot_instance(instance_id, fields)?
+                    }
                 }
             }
-            ev => Ok(ev),
         }
     }
 
+    fn run_next_event(&mut self) -> PolarResult<QueryEvent> {
+        loop {
+            let mut counter = self.vm.id_counter();
+            return match self.top_runnable().run(Some(&mut counter))? {
+                QueryEvent::Run { runnable, call_id } => {
+                    self.push_runnable(runnable, call_id);
+                    continue;
+                }
+                QueryEvent::Done { result, .. } => {
+                    if let Some((_, result_call_id)) = self.pop_runnable() {
+                        self.top_runnable()
+                            .external_question_result(result_call_id, result)?;
+                        continue;
+                    } else {
+                        // VM is done.
+                        assert!(self.runnable_stack.is_empty());
+                        let stats = if self.stats {
+                            Some(QueryStats {
+                                results: self.results,
+                                goals_executed: self.vm.goals_executed(),
+                                external_calls: self.external_calls,
+                                peak_choice_points: self.vm.peak_choice_points(),
+                                duration_ms: self.vm.query_elapsed_ms(),
+                            })
+                        } else {
+                            None
+                        };
+                        Ok(QueryEvent::Done {
+                            result,
+                            policy_version: self.vm.policy_version(),
+                            stats,
+                        })
+                    }
+                }
+                QueryEvent::Result { bindings, .. } if self.is_duplicate(&bindings) => continue,
+                QueryEvent::Result { .. } if Some(self.results) == self.max_results => {
+                    Err(error::RuntimeError::TooManyResults { max: self.results }.into())
+                }
+                QueryEvent::Result {
+                    bindings,
+                    trace,
+                    policy_version,
+                } => {
+                    self.results += 1;
+                    Ok(QueryEvent::Result {
+                        bindings: self.name_variables(bindings),
+                        trace,
+                        policy_version,
+                    })
+                }
+                ev => {
+                    if let QueryEvent::ExternalCalls { calls } = &ev {
+                        self.external_calls += calls.len() as u64;
+                    } else if replay::is_external(&ev) {
+                        self.external_calls += 1;
+                    }
+                    Ok(ev)
+                }
+            };
+        }
+    }
+
+    /// `bindings` with the variables the VM made up, e.g., `_y_12` for the `y`
+    /// of a rule, given readable names that no query variable has.
+    fn name_variables(&mut self, bindings: Bindings) -> Bindings {
+        let mut names = VariableNames::new(bindings.keys().cloned(), bindings.values());
+        let bindings = bindings
+            .into_iter()
+            .map(|(var, value)| (var, names.rename(value)))
+            .collect();
+        self.variable_names = names.into_mapping();
+        bindings
+    }
+
+    /// Whether a result with `bindings` duplicates an earlier one, if
+    /// deduplicating results.
+    fn is_duplicate(&mut self, bindings: &Bindings) -> bool {
+        let key = match &self.dedup {
+            None => return false,
+            Some(None) => {
+                let mut key: Vec<_> = bindings
+                    .iter()
+                    .map(|(var, value)| (var.clone(), value.clone()))
+                    .collect();
+                key.sort_by(|(l, _), (r, _)| l.cmp(r));
+                key
+            }
+            Some(Some(vars)) => vars
+                .iter()
+                .filter_map(|var| bindings.get(var).map(|value| (var.clone(), value.clone())))
+                .collect(),
+        };
+        !self.seen_results.insert(key)
+    }
+
     fn top_runnable(&mut self) -> &mut (dyn Runnable) {
         self.runnable_stack
             .last_mut()
@@ -81,18 +464,106 @@ impl Query {
     }
 
     pub fn call_result(&mut self, call_id: u64, value: Option<Term>) -> PolarResult<()> {
+        let normalize = self.normalize_strings;
+        let value = value.map(|value| Nfc::normalize_term(value, normalize));
+        let index = self
+            .recording
+            .as_ref()
+            .and_then(|recording| recording.interactions.last())
+            .and_then(|interaction| replay::concurrent_call_index(&interaction.event, call_id));
+        self.record(|| match index {
+            Some(index) => Answer::ConcurrentCall(index, value.clone()),
+            None => Answer::Call(value.clone()),
+        });
         self.top_runnable().external_call_result(call_id, value)
     }
 
+    /// Answer a `QueryEvent::ExternalLookupMany` with the values of some or all
+    /// of the requested attributes. Any left out are requested individually.
+    pub fn lookup_many_result(
+        &mut self,
+        call_id: u64,
+        results: BTreeMap<Symbol, Term>,
+    ) -> PolarResult<()> {
+        let normalize = self.normalize_strings;
+        let results: BTreeMap<_, _> = results
+            .into_iter()
+            .map(|(name, value)| (name, Nfc::normalize_term(value, normalize)))
+            .collect();
+        self.record(|| Answer::LookupMany(results.clone()));
+        self.vm.lookup_many_result(call_id, results)
+    }
+
+    /// Answer lookups of the attributes in `fields` on the instance
+    /// `instance_id` for the rest of the query from their values there,
+    /// without further events. Hosts can call it when they construct an
+    /// instance for a `QueryEvent::MakeExternal`, or when they first see one,
+    /// so that read-mostly objects cost one round trip rather than one per
+    /// attribute looked up.
+    pub fn snapshot_instance(
+        &mut self,
+        instance_id: u64,
+        fields: BTreeMap<Symbol, Term>,
+    ) -> PolarResult<()> {
+        let normalize = self.normalize_strings;
+        let fields: BTreeMap<_, _> = fields
+            .into_iter()
+            .map(|(name, value)| (name, Nfc::normalize_term(value, normalize)))
+            .collect();
+        self.record(|| Answer::Snapshot(instance_id, fields.clone()));
+        self.vm.snapshot_instance(instance_id, fields);
+        Ok(())
+    }
+
+    /// Don't reuse the result of external call `call_id` for identical lookups
+    /// later in the query. Other lookups are cached for the duration of the
+    /// query, except method calls that return an external instance, so hosts
+    /// should call this before answering calls to methods with side effects.
+    pub fn uncache_call(&mut self, call_id: u64) {
+        self.vm.uncache_call(call_id)
+    }
+
     pub fn question_result(&mut self, call_id: u64, result: bool) -> PolarResult<()> {
+        self.record(|| Answer::Question(result));
         self.top_runnable()
             .external_question_result(call_id, result)
     }
 
     pub fn application_error(&mut self, message: String) -> PolarResult<()> {
+        self.record(|| Answer::Error(message.clone()));
         self.vm.external_error(message)
     }
 
+    /// Record every event the query emits for the host, and the host's
+    /// answers, until the query is done; `recording` returns them.
+    pub fn start_recording(&mut self) {
+        self.recording = Some(Recording::default());
+    }
+
+    /// The interactions with the host recorded so far, if recording.
+    pub fn recording(&self) -> Option<&Recording> {
+        self.recording.as_ref()
+    }
+
+    /// Answer the query's questions from `recording` rather than asking the
+    /// host, failing with `RuntimeError::ReplayDiverged` if the query asks
+    /// something else. Instances the recorded query constructed aren't
+    /// constructed again.
+    pub fn replay(&mut self, recording: Recording) {
+        self.replaying = Some(recording.interactions.into());
+    }
+
+    /// Add an answer from the host to the latest recorded interaction.
+    fn record(&mut self, answer: impl FnOnce() -> Answer) {
+        if let Some(interaction) = self
+            .recording
+            .as_mut()
+            .and_then(|recording| recording.interactions.last_mut())
+        {
+            interaction.answers.push(answer());
+        }
+    }
+
     pub fn debug_command(&mut self, command: &str) -> PolarResult<()> {
         self.top_runnable().debug_command(command)
     }
@@ -101,13 +572,203 @@ impl Query {
         self.vm.messages.next()
     }
 
+    /// Send the messages this query emits to `sink` instead of the
+    /// `Polar` instance's sink or queue.
+    pub fn set_message_sink(&mut self, sink: Arc<dyn MessageSink>) {
+        self.vm.messages = MessageQueue::with_sink(sink);
+    }
+
+    /// Report what the query cost to run, as `QueryStats`, in its final
+    /// `QueryEvent::Done`. Off by default.
+    pub fn enable_stats(&mut self) {
+        self.stats = true;
+    }
+
+    /// Skip results whose bindings of `variables`, e.g., only `resource` in a
+    /// query listing authorized resources, or of every variable if `None`,
+    /// equal those of an earlier result, such as the same resource allowed
+    /// by two rules. Off by default.
+    pub fn deduplicate_results(&mut self, variables: Option<Vec<Symbol>>) {
+        self.dedup = Some(variables);
+    }
+
+    /// Emit a `QueryEvent::ExternalLookupMany` when the query is about to look
+    /// up several attributes of one instance, e.g., to match `x matches {a: 1, b: 2}`,
+    /// so the host can fetch them together. Off by default.
+    pub fn enable_bulk_lookups(&mut self) {
+        self.vm.enable_bulk_lookups()
+    }
+
+    /// Emit a `QueryEvent::ExternalCalls` when a conjunction is about to look
+    /// up attributes or call methods of instances with ground arguments, so
+    /// the host can make those calls concurrently rather than one by one.
+    /// The calls are made before the conjuncts run, so some may turn out to
+    /// be unneeded, e.g., if an earlier conjunct fails. Off by default.
+    pub fn enable_concurrent_calls(&mut self) {
+        self.vm.enable_concurrent_calls()
+    }
+
+    /// Emit a `QueryEvent::Heartbeat` after every `interval` goals the query
+    /// executes. Hosts running queries in an async executor can use it to yield,
+    /// report progress, or cancel. 0 (the default) disables heartbeats.
+    pub fn set_heartbeat_interval(&mut self, interval: u64) {
+        self.vm.set_heartbeat_interval(interval)
+    }
+
+    /// Emit a `QueryEvent::Progress` with cumulative statistics after every
+    /// `interval` goals the query executes, for hosts that display progress of
+    /// slow queries. 0 (the default) disables progress events.
+    pub fn set_progress_interval(&mut self, interval: u64) {
+        self.vm.set_progress_interval(interval)
+    }
+
+    /// How the latest `QueryEvent::Result` was derived, formatted as it
+    /// would be with tracing on. Lets hosts query without tracing, and pay
+    /// for formatting a trace only for the results that need one, e.g.,
+    /// denials or a sample of requests. `None` before the first result.
+    pub fn trace_for_last_result(&self) -> Option<TraceResult> {
+        self.vm.trace_for_last_result()
+    }
+
+    /// The readable names the latest `QueryEvent::Result` shows variables
+    /// the VM made up with, by made-up variable, e.g., `_y_12` → `y` for the
+    /// `y` of a rule. Empty before the first result.
+    pub fn variable_names(&self) -> &BTreeMap<Symbol, Symbol> {
+        &self.variable_names
+    }
+
+    /// The rules that justified the latest `QueryEvent::Result`, with the
+    /// arguments they were called with: a compact alternative to
+    /// `trace_for_last_result`. `None` before the first result. Call it
+    /// before asking for the next event, which may undo the bindings.
+    pub fn proof_for_last_result(&self) -> Option<Proof> {
+        self.vm.proof_for_last_result()
+    }
+
+    /// Report every goal the query executes to `sink`, with a timestamp, for
+    /// performance analysis. This is much lower-level and more verbose than
+    /// tracing, which records how results were derived.
+    pub fn set_goal_sink(&mut self, sink: Rc<RefCell<dyn GoalSink>>) {
+        self.vm.set_goal_sink(sink)
+    }
+
+    /// Serialize the state of the query, e.g., while it waits for the host to
+    /// answer an `ExternalCall`, so that `Polar::resume_query` can resume it
+    /// later, possibly in another process. Settings like the goal sink and
+    /// heartbeat interval aren't included. Its decision is logged by the
+    /// query that resumes it, not this one.
+    pub fn suspend(&self) -> PolarResult<String> {
+        if !self.runnable_stack.is_empty() {
+            return Err(OperationalError::Unimplemented(
+                "suspending a query while it runs a sub-query".to_owned(),
+            )
+            .into());
+        }
+        if self.poisoned {
+            return Err(OperationalError::InvalidState(
+                "can't suspend a query that panicked".to_owned(),
+            )
+            .into());
+        }
+        let suspended = SuspendedQuery {
+            vm: self.vm.suspend()?,
+            term: self.term.clone(),
+            done: self.done,
+            results: self.results,
+            max_results: self.max_results,
+            normalize_strings: self.normalize_strings,
+        };
+        let suspended =
+            serde_json::to_string(&suspended).map_err(|e| RuntimeError::Serialization {
+                msg: format!("can't suspend the query here: {}", e),
+            })?;
+        self.decision_logged.set(true);
+        Ok(suspended)
+    }
+
+    /// Make this query a dry run: instead of asking the host for the results
+    /// of external calls, record them and continue as if each returned an
+    /// unknown instance. Every other external question is answered `true`,
+    /// except for specializer ordering, which is answered `false`.
+    ///
+    /// Once the query is done, `required_lookups` returns every lookup the
+    /// policy could make.
+    pub fn enable_dry_run(&mut self) {
+        self.vm.enable_dry_run()
+    }
+
+    /// Return the external lookups recorded by a dry run, or `None` if this
+    /// query isn't one.
+    pub fn required_lookups(&self) -> Option<Vec<ExternalLookup>> {
+        self.vm.dry_run_lookups()
+    }
+
+    /// Return the current goal stack and choice points of the query's VM,
+    /// e.g., for use by external debugging and visualization tools.
+    ///
+    /// The snapshot doesn't include the state of nested runnables, such as
+    /// the one evaluating a `not`.
+    pub fn stack_snapshot(&self) -> StackSnapshot {
+        self.vm.stack_snapshot()
+    }
+
+    /// Run the query, calling `f` with the bindings of each result as it's
+    /// found, until `f` returns `ControlFlow::Break` or there are no more.
+    /// Breaking stops the query, dropping its choice points, so it does no
+    /// more work. Events that need an answer from the host, e.g.,
+    /// `QueryEvent::ExternalCall`, are returned, to answer before calling
+    /// this again; `None` means the query is done.
+    pub fn for_each_result<F>(&mut self, mut f: F) -> PolarResult<Option<QueryEvent>>
+    where
+        F: FnMut(&Bindings) -> ControlFlow<()>,
+    {
+        while !self.done {
+            match self.next_event()? {
+                QueryEvent::Result { bindings, .. } => {
+                    if f(&bindings).is_break() {
+                        self.stop();
+                    }
+                }
+                QueryEvent::Done { .. } => self.done = true,
+                QueryEvent::Heartbeat { .. } | QueryEvent::Progress { .. } => {}
+                event => return Ok(Some(event)),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Stop the query before it's done, discarding what's left to evaluate.
+    pub fn stop(&mut self) {
+        self.runnable_stack.clear();
+        let _ = self.vm.halt();
+        self.done = true;
+        self.log_decision(false, None);
+    }
+
     pub fn source_info(&self) -> String {
         self.vm.term_source(&self.term, true)
     }
 
     pub fn bind(&mut self, name: Symbol, value: Term) -> PolarResult<()> {
+        let value = Nfc::normalize_term(value, self.normalize_strings);
         self.vm.bind(&name, value)
     }
+
+    /// Expose `value` as the constant `name` to this query and the rules it
+    /// uses, e.g., for per-request data like the current time. Unlike `bind`,
+    /// the constant isn't included in results. Must be called before `bind`.
+    pub fn bind_constant(&mut self, name: Symbol, value: Term) -> PolarResult<()> {
+        let value = Nfc::normalize_term(value, self.normalize_strings);
+        self.vm.bind_query_constant(name, value)
+    }
+}
+
+/// Queries dropped before they're done, e.g., after the first result, log
+/// their decision then.
+impl Drop for Query {
+    fn drop(&mut self) {
+        self.log_decision(false, None);
+    }
 }
 
 // Query as an iterator returns `None` after the first time `Done` is seen
@@ -126,13 +787,64 @@ impl Iterator for Query {
     }
 }
 
+/// What loading a policy does with its inline queries, e.g., `?= f(1);`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum InlineQueries {
+    /// Keep them for the host to run with `Polar::next_inline_query`.
+    #[default]
+    Collect,
+    /// Run them once the policy is loaded, and fail the load if any of them
+    /// has no results or an error, leaving the rules as they were before it.
+    /// Queries that need the host to answer an event, e.g., to call a
+    /// method, fail too.
+    Execute,
+    /// Ignore them.
+    Skip,
+}
+
+/// Options for `Polar::load_with_options`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LoadOptions {
+    pub inline_queries: InlineQueries,
+    /// Where the policy was generated from, if it was, for errors and
+    /// traces to report positions in.
+    pub source_map: Option<SourceMap>,
+    /// Fail the load if the policy doesn't cover the declared authorization
+    /// types; see `Polar::check_coverage`. Set it on the last of several
+    /// loads, once every rule is loaded.
+    pub check_coverage: bool,
+}
+
+/// Maximum number of distinct query strings whose parsed form is cached.
+const MAX_QUERY_CACHE_SIZE: usize = 1_000;
+
 pub struct Polar {
     pub kb: Arc<RwLock<KnowledgeBase>>,
+    /// A frozen copy of `kb` that queries read without its lock, if this is
+    /// a `FrozenPolar`'s instance.
+    frozen_kb: Option<Arc<KnowledgeBase>>,
     messages: MessageQueue,
     /// Set of filenames already loaded
     loaded_files: Arc<RwLock<HashSet<String>>>,
     /// Map from source code loaded to the filename it was loaded as
     loaded_content: Arc<RwLock<HashMap<String, String>>>,
+    /// Map from query source to its parsed and rewritten term
+    query_cache: Arc<RwLock<HashMap<String, Term>>>,
+    parser_limits: Arc<RwLock<parser::ParserLimits>>,
+    max_results: Arc<RwLock<Option<usize>>>,
+    string_collation: Arc<RwLock<StringCollation>>,
+    normalize_strings: Arc<RwLock<bool>>,
+    reorder_conjunctions: Arc<RwLock<bool>>,
+    /// Packages loaded so far, by name.
+    packages: Arc<RwLock<HashMap<String, PackageManifest>>>,
+    fact_sources: Arc<RwLock<FactSources>>,
+    instrumentation: Arc<RwLock<Option<Instrumentation>>>,
+    /// Instance ID → fields that answer lookups on the instance in every query.
+    instance_snapshots: Arc<RwLock<HashMap<u64, BTreeMap<Symbol, Term>>>>,
+    authorization_types: Arc<RwLock<Option<AuthorizationTypes>>>,
+    decision_logger: Arc<RwLock<Option<Arc<dyn DecisionLogger>>>>,
+    sandbox: Arc<RwLock<Option<SandboxConfig>>>,
 }
 
 impl Default for Polar {
@@ -145,12 +857,198 @@ impl Polar {
     pub fn new() -> Self {
         Self {
             kb: Arc::new(RwLock::new(KnowledgeBase::new())),
+            frozen_kb: None,
             messages: MessageQueue::new(),
             loaded_content: Arc::new(RwLock::new(HashMap::new())), // file content -> file name
             loaded_files: Arc::new(RwLock::new(HashSet::new())),   // set of file names
+            query_cache: Arc::new(RwLock::new(HashMap::new())),
+            parser_limits: Arc::new(RwLock::new(parser::ParserLimits::default())),
+            max_results: Arc::new(RwLock::new(None)),
+            string_collation: Arc::new(RwLock::new(StringCollation::default())),
+            normalize_strings: Arc::new(RwLock::new(false)),
+            reorder_conjunctions: Arc::new(RwLock::new(false)),
+            packages: Arc::new(RwLock::new(HashMap::new())),
+            fact_sources: Arc::new(RwLock::new(FactSources::new())),
+            instance_snapshots: Arc::new(RwLock::new(HashMap::new())),
+            authorization_types: Arc::new(RwLock::new(None)),
+            decision_logger: Arc::new(RwLock::new(None)),
+            instrumentation: Arc::new(RwLock::new(None)),
+            sandbox: Arc::new(RwLock::new(None)),
+        }
+    }
+
+    /// Limit the size and shape of the policies and queries this instance
+    /// parses, so that it can load ones from untrusted sources.
+    pub fn set_parser_limits(&self, limits: parser::ParserLimits) {
+        *self.parser_limits.write().unwrap() = limits;
+        // Queries parsed under the old limits must be checked again.
+        self.query_cache.write().unwrap().clear();
+    }
+
+    /// Stop queries that produce more than `max` results with a
+    /// `RuntimeError::TooManyResults`, rather than let a query with an
+    /// unconstrained variable enumerate an unbounded number of answers.
+    /// `None`, the default, means no limit.
+    pub fn set_max_results(&self, max: Option<usize>) {
+        *self.max_results.write().unwrap() = max;
+    }
+
+    /// Run untrusted policies within the limits of `config`, e.g.,
+    /// `SandboxConfig::default()`, which replace any parser limits and limit
+    /// on results set before. `None` removes every limit. Only affects
+    /// policies loaded and queries made afterwards.
+    pub fn set_sandbox(&self, config: Option<SandboxConfig>) {
+        let (parser_limits, max_results) = config
+            .as_ref()
+            .map(|config| (config.parser_limits.clone(), config.max_results))
+            .unwrap_or_default();
+        self.set_parser_limits(parser_limits);
+        self.set_max_results(max_results);
+        *self.sandbox.write().unwrap() = config;
+    }
+
+    /// Order strings compared by queries with `<`, `<=`, `>` and `>=`, and
+    /// sorted by `sort` and `sort_by`, according to `collation`.
+    pub fn set_string_collation(&self, collation: StringCollation) {
+        *self.string_collation.write().unwrap() = collation;
+    }
+
+    /// Sort `terms` into canonical order, as `sort` would in a query, with
+    /// strings ordered by the configured collation; see `compare_terms`.
+    /// Lets hosts order results of mixed types consistently.
+    pub fn sort_terms(&self, terms: &mut [Term]) {
+        let collation = *self.string_collation.read().unwrap();
+        terms.sort_by(|l, r| compare_terms_collated(l, r, collation));
+    }
+
+    /// Answer calls to the predicate `name` with `arity` arguments from
+    /// `source`, e.g., an index of role assignments kept by the host. The
+    /// facts it answers are tried before any rules for the predicate. Only
+    /// affects queries made afterwards. `None` removes the predicate's source.
+    pub fn set_fact_source(&self, name: Symbol, arity: usize, source: Option<Arc<dyn FactSource>>) {
+        let mut sources = self.fact_sources.write().unwrap();
+        match source {
+            Some(source) => sources.insert((name, arity), source),
+            None => sources.remove(&(name, arity)),
+        };
+    }
+
+    /// Answer lookups of the attributes in `fields` on the instance
+    /// `instance_id`, e.g., a registered constant that doesn't change, from
+    /// their values there, in every query made afterwards, rather than with
+    /// `QueryEvent::ExternalCall`s. Method calls and attributes not in `fields`
+    /// are still asked of the host. `None` removes the instance's snapshot.
+    pub fn set_instance_snapshot(&self, instance_id: u64, fields: Option<BTreeMap<Symbol, Term>>) {
+        let mut snapshots = self.instance_snapshots.write().unwrap();
+        match fields {
+            Some(fields) => {
+                let normalize = *self.normalize_strings.read().unwrap();
+                let fields = fields
+                    .into_iter()
+                    .map(|(name, value)| (name, Nfc::normalize_term(value, normalize)))
+                    .collect();
+                snapshots.insert(instance_id, fields)
+            }
+            None => snapshots.remove(&instance_id),
+        };
+    }
+
+    /// Log the decision of every query made afterwards to `logger`: once it's
+    /// done, fails with an error, or is dropped.
+    pub fn set_decision_logger(&self, logger: impl DecisionLogger + 'static) {
+        *self.decision_logger.write().unwrap() = Some(Arc::new(logger));
+    }
+
+    /// Stop logging the decisions of queries made afterwards.
+    pub fn clear_decision_logger(&self) {
+        *self.decision_logger.write().unwrap() = None;
+    }
+
+    /// Declare the actor and resource types the host authorizes, and the
+    /// actions on each resource, for `check_coverage`.
+    pub fn declare_authorization_types(&self, types: AuthorizationTypes) {
+        *self.authorization_types.write().unwrap() = Some(types);
+    }
+
+    /// Fail if the loaded `allow` rules specialize on undeclared types, or
+    /// don't mention a declared resource action that isn't marked unhandled.
+    /// Succeeds if no types were declared.
+    pub fn check_coverage(&self) -> PolarResult<()> {
+        let types = self.authorization_types.read().unwrap();
+        let problems = match types.as_ref() {
+            Some(types) => coverage::check_coverage(types, &self.kb.read().unwrap()),
+            None => vec![],
+        };
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(RuntimeError::IncompleteCoverage { problems }.into())
         }
     }
 
+    /// Whether to count how often each rule is tried and succeeds in queries
+    /// made afterwards. Counts are kept across queries until reset, and
+    /// discarded when disabled.
+    pub fn set_rule_metrics(&self, enabled: bool) {
+        let mut kb = self.kb.write().unwrap();
+        if !enabled {
+            kb.rule_metrics = None;
+        } else if kb.rule_metrics.is_none() {
+            kb.rule_metrics = Some(Arc::new(RuleMetrics::default()));
+        }
+    }
+
+    /// Hit and success counts for every loaded rule, including those never
+    /// hit, or nothing if rule metrics aren't enabled.
+    pub fn rule_metrics(&self) -> Vec<RuleCounts> {
+        let kb = self.kb.read().unwrap();
+        kb.rule_metrics
+            .as_ref()
+            .map(|metrics| metrics.report(&kb))
+            .unwrap_or_default()
+    }
+
+    /// Set every rule's counts back to zero.
+    pub fn reset_rule_metrics(&self) {
+        if let Some(metrics) = &self.kb.read().unwrap().rule_metrics {
+            metrics.reset();
+        }
+    }
+
+    /// Call `hook` with a summary of every goal executed by queries made
+    /// afterwards: its kind, the rule it ran in and the query depth, e.g., to
+    /// profile which rules are expensive. Unlike `Query::set_goal_sink`,
+    /// nothing is formatted, so hooks that only count are cheap.
+    pub fn set_instrumentation(&self, hook: impl Fn(&GoalSummary) + Send + Sync + 'static) {
+        *self.instrumentation.write().unwrap() = Some(Arc::new(hook));
+    }
+
+    /// Stop calling the hook set with `set_instrumentation` in queries made
+    /// afterwards.
+    pub fn clear_instrumentation(&self) {
+        *self.instrumentation.write().unwrap() = None;
+    }
+
+    /// Put strings in policies, queries and values from the host in Unicode
+    /// Normalization Form C, so that, e.g., a `"café"` spelled with a combining
+    /// accent unifies with one spelled with a precomposed `é`. Only affects
+    /// policies loaded and queries made afterwards. Off by default.
+    pub fn set_normalize_strings(&self, enabled: bool) {
+        *self.normalize_strings.write().unwrap() = enabled;
+        self.query_cache.write().unwrap().clear();
+    }
+
+    /// Run the conjuncts of each `and` in the order the bindings so far make
+    /// best, rather than as written: conjuncts whose variables are all bound
+    /// first, then those narrowed by a bound argument, and comparisons,
+    /// negations and the like once their variables are bound. `cut`,
+    /// `print`, `debug`, `:=` and `new` stay where they're written. Results
+    /// are the same, but may come in a different order. Only affects queries
+    /// made afterwards. Off by default.
+    pub fn set_reorder_conjunctions(&self, enabled: bool) {
+        *self.reorder_conjunctions.write().unwrap() = enabled;
+    }
+
     fn check_file(&self, src: &str, filename: &str) -> PolarResult<()> {
         match (
             self.loaded_content.read().unwrap().get(src),
@@ -195,20 +1093,195 @@ impl Polar {
     }
 
     pub fn load(&self, src: &str, filename: Option<String>) -> PolarResult<()> {
+        self.load_with_options(src, filename, &LoadOptions::default())
+    }
+
+    /// Like `load`, but handle inline queries, report positions in the
+    /// policy, and check its coverage as `options` say.
+    pub fn load_with_options(
+        &self,
+        src: &str,
+        filename: Option<String>,
+        options: &LoadOptions,
+    ) -> PolarResult<()> {
+        // Failing inline queries fail the whole load, so be ready to undo it.
+        let before = (options.inline_queries == InlineQueries::Execute).then(|| {
+            (
+                self.kb.read().unwrap().clone(),
+                self.loaded_content.read().unwrap().clone(),
+                self.loaded_files.read().unwrap().clone(),
+            )
+        });
+        let queries = self.load_source(src, filename, None, None, options)?;
+        if let Err(error) = self.execute_inline_queries(queries) {
+            if let Some((kb, loaded_content, loaded_files)) = before {
+                *self.kb.write().unwrap() = kb;
+                *self.loaded_content.write().unwrap() = loaded_content;
+                *self.loaded_files.write().unwrap() = loaded_files;
+            }
+            return Err(error);
+        }
+        if options.check_coverage {
+            self.check_coverage()?;
+        }
+        Ok(())
+    }
+
+    /// Load `src` and tag the policy with `version`. Results of queries are
+    /// stamped with the version of the policy that produced them, so that
+    /// decisions can be audited across reloads.
+    pub fn load_versioned(
+        &self,
+        src: &str,
+        filename: Option<String>,
+        version: String,
+    ) -> PolarResult<()> {
+        self.load_source(src, filename, Some(version), None, &LoadOptions::default())?;
+        Ok(())
+    }
+
+    /// Load the package in directory `path`, after its dependencies, which
+    /// are looked for in the directories next to it. Each package's files
+    /// are loaded in the order listed in its manifest, with rules it doesn't
+    /// export made private to it.
+    pub fn load_package<P: AsRef<std::path::Path>>(&self, path: P) -> PolarResult<PackageManifest> {
+        let path = path.as_ref();
+        let manifest = packages::read_manifest(path)?;
+        let resolved = {
+            let loaded = self.packages.read().unwrap();
+            packages::resolve(path, manifest.clone(), &loaded)?
+        };
+        for (dir, package) in resolved {
+            self.load_package_files(&dir, &package)?;
+            self.packages
+                .write()
+                .unwrap()
+                .insert(package.name.clone(), package);
+        }
+        Ok(manifest)
+    }
+
+    /// Packages loaded so far, by name.
+    pub fn loaded_packages(&self) -> HashMap<String, PackageManifest> {
+        self.packages.read().unwrap().clone()
+    }
+
+    fn load_package_files(
+        &self,
+        path: &std::path::Path,
+        manifest: &PackageManifest,
+    ) -> PolarResult<()> {
+        let mut files = vec![];
+        let mut defined = HashSet::new();
+        for file in &manifest.files {
+            let file = path.join(file);
+            if file.extension().is_none_or(|ext| ext != "polar") {
+                return Err(RuntimeError::FileLoading {
+                    msg: format!(
+                        "Policy files must have the .polar extension: {}",
+                        file.display()
+                    ),
+                }
+                .into());
+            }
+            let src = packages::read_file(&file)?;
+            let filename = file.to_string_lossy().into_owned();
+            let source = Source {
+                filename: Some(filename.clone()),
+                src: src.clone(),
+                source_map: None,
+            };
+            for line in
+                parser::parse_lines(0, &src).map_err(|e| e.set_context(Some(&source), None))?
+            {
+                if let parser::Line::Rule(rule) = line {
+                    defined.insert(rule.name);
+                }
+            }
+            files.push((src, filename));
+        }
+        if let Some(name) = manifest
+            .exports
+            .iter()
+            .find(|name| !defined.contains(&sym!(name.as_str())))
+        {
+            return Err(RuntimeError::FileLoading {
+                msg: format!(
+                    "Package {} exports {}, which it doesn't define",
+                    manifest.name, name
+                ),
+            }
+            .into());
+        }
+
+        let renames = manifest.private_names(&defined);
+        for (src, filename) in files {
+            self.load_source(
+                &src,
+                Some(filename),
+                None,
+                Some(&renames),
+                &LoadOptions::default(),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Version of the most recently loaded policy, if it was loaded with one.
+    pub fn policy_version(&self) -> Option<String> {
+        self.kb.read().unwrap().version.clone()
+    }
+
+    /// Loaded rules annotated with `@annotation`, e.g. every `@audit` rule.
+    pub fn annotated_rules(&self, annotation: &str) -> Rules {
+        self.kb.read().unwrap().annotated_rules(annotation)
+    }
+
+    fn load_source(
+        &self,
+        src: &str,
+        filename: Option<String>,
+        version: Option<String>,
+        private_names: Option<&HashMap<Symbol, Symbol>>,
+        options: &LoadOptions,
+    ) -> PolarResult<Vec<Term>> {
         if let Some(ref filename) = filename {
             self.check_file(src, filename)?;
         }
         let source = Source {
             filename,
-            src: src.to_owned(),
+            src: if *self.normalize_strings.read().unwrap() {
+                Nfc::normalize(src.to_owned())
+            } else {
+                src.to_owned()
+            },
+            source_map: options.source_map.clone(),
         };
+        let src = &source.src;
+        let limits = self.parser_limits.read().unwrap().clone();
+        limits
+            .check_source(src)
+            .map_err(|e| e.set_context(Some(&source), None))?;
         let mut kb = self.kb.write().unwrap();
-        let src_id = kb.new_id();
-        let mut lines =
-            parser::parse_lines(src_id, src).map_err(|e| e.set_context(Some(&source), None))?;
+        let src_id = new_source_id();
+        let mut lines = parser::parse_lines(src_id, src)
+            .and_then(|lines| limits.check_lines(&lines).map(|_| lines))
+            .map_err(|e| e.set_context(Some(&source), None))?;
+        if let Some(renames) = private_names {
+            let mut privatizer = Privatizer { renames };
+            lines = lines
+                .into_iter()
+                .map(|line| match line {
+                    parser::Line::Rule(rule) => parser::Line::Rule(privatizer.fold_rule(rule)),
+                    parser::Line::Query(term) => parser::Line::Query(privatizer.fold_term(term)),
+                })
+                .collect();
+        }
         lines.reverse();
         kb.sources.add_source(source, src_id);
         let mut warnings = vec![];
+        let mut loaded = HashSet::new();
+        let mut to_execute = vec![];
         while let Some(line) = lines.pop() {
             match line {
                 parser::Line::Rule(rule) => {
@@ -216,24 +1289,47 @@ impl Polar {
                     warnings.append(&mut rule_warnings);
                     let rule = rewrite_rule(rule, &mut kb);
 
-                    let name = rule.name.clone();
-                    let generic_rule = kb
-                        .rules
-                        .entry(name.clone())
-                        .or_insert_with(|| GenericRule::new(name, vec![]));
-                    generic_rule.add_rule(Arc::new(rule));
-                }
-                parser::Line::Query(term) => {
-                    kb.inline_queries.push(term);
+                    loaded.insert(rule.name.clone());
+                    kb.add_rule(rule);
                 }
+                parser::Line::Query(term) => match options.inline_queries {
+                    InlineQueries::Collect => kb.inline_queries.push(term),
+                    InlineQueries::Execute => to_execute.push(term),
+                    InlineQueries::Skip => {}
+                },
             }
         }
+        warnings.append(&mut check_negation_cycles(&kb, &loaded));
+        warnings.append(&mut check_recursion(&kb, &loaded));
+        if version.is_some() {
+            kb.version = version;
+        }
         self.messages.extend(warnings.iter().map(|m| Message {
             kind: MessageKind::Warning,
             msg: m.to_owned(),
         }));
 
-        Ok(())
+        Ok(to_execute)
+    }
+
+    /// Run inline queries, and fail with every one that doesn't succeed.
+    fn execute_inline_queries(&self, queries: Vec<Term>) -> PolarResult<()> {
+        let mut failures = vec![];
+        for term in queries {
+            let mut query = self.new_query_from_term(term, false);
+            let failure = match query.next_event() {
+                Ok(QueryEvent::Result { .. }) => continue,
+                Ok(QueryEvent::Done { .. }) => "no results".to_string(),
+                Ok(_) => "needs the host to answer it".to_string(),
+                Err(e) => e.to_string(),
+            };
+            failures.push(format!("{}: {}", query.source_info(), failure));
+        }
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(RuntimeError::InlineQueriesFailed { failures }.into())
+        }
     }
 
     // Used in integration tests
@@ -244,11 +1340,15 @@ impl Polar {
     /// Clear rules from the knowledge base
     pub fn clear_rules(&self) {
         let mut kb = self.kb.write().unwrap();
-        kb.rules.clear();
+        kb.clear_rules();
         kb.sources = Sources::default();
         kb.inline_queries.clear();
+        kb.version = None;
         self.loaded_content.write().unwrap().clear();
         self.loaded_files.write().unwrap().clear();
+        self.packages.write().unwrap().clear();
+        // Cached queries refer to sources that were just cleared.
+        self.query_cache.write().unwrap().clear();
     }
 
     pub fn next_inline_query(&self, trace: bool) -> Option<Query> {
@@ -257,30 +1357,122 @@ impl Polar {
     }
 
     pub fn new_query(&self, src: &str, trace: bool) -> PolarResult<Query> {
+        let term = self.query_term(src)?;
+        Ok(self.start_query(term, trace))
+    }
+
+    /// Like `new_query`, but consult the rules of `overlay` along with this
+    /// knowledge base's. When rules from both apply equally, the overlay's are
+    /// tried first. Only the overlay's rules are used, not its constants.
+    ///
+    /// This lets hosts customize a shared base policy, e.g., per tenant,
+    /// by loading the customizations into their own `Polar`.
+    pub fn new_query_with_overlay(
+        &self,
+        src: &str,
+        overlay: &Arc<RwLock<KnowledgeBase>>,
+        trace: bool,
+    ) -> PolarResult<Query> {
+        let mut query = self.new_query(src, trace)?;
+        query.vm.set_overlay(overlay.clone());
+        Ok(query)
+    }
+
+    /// Describe how the query `src` would be evaluated against the current
+    /// rules, without running it: the candidate rules for each of its goals,
+    /// which arguments the rule index can use, and the choice points it leaves.
+    pub fn explain(&self, src: &str) -> PolarResult<QueryPlan> {
+        let term = self.query_term(src)?;
+        Ok(plan::explain(&self.kb.read().unwrap(), &term))
+    }
+
+    /// Parse and rewrite the query `src`, or reuse an earlier parse of it.
+    fn query_term(&self, src: &str) -> PolarResult<Term> {
+        if let Some(term) = self.query_cache.read().unwrap().get(src) {
+            return Ok(term.clone());
+        }
+
         let source = Source {
             filename: None,
-            src: src.to_owned(),
+            src: if *self.normalize_strings.read().unwrap() {
+                Nfc::normalize(src.to_owned())
+            } else {
+                src.to_owned()
+            },
+            source_map: None,
         };
+        self.parser_limits
+            .read()
+            .unwrap()
+            .check_source(&source.src)
+            .map_err(|e| e.set_context(Some(&source), None))?;
         let term = {
             let mut kb = self.kb.write().unwrap();
-            let src_id = kb.new_id();
-            let term =
-                parser::parse_query(src_id, src).map_err(|e| e.set_context(Some(&source), None))?;
+            let src_id = new_source_id();
+            let term = parser::parse_query(src_id, &source.src)
+                .map_err(|e| e.set_context(Some(&source), None))?;
             kb.sources.add_source(source, src_id);
-            term
+            rewrite_term(term, &mut kb)
         };
-        Ok(self.new_query_from_term(term, trace))
+
+        let mut cache = self.query_cache.write().unwrap();
+        if cache.len() < MAX_QUERY_CACHE_SIZE {
+            cache.insert(src.to_owned(), term.clone());
+        }
+        Ok(term)
     }
 
     pub fn new_query_from_term(&self, mut term: Term, trace: bool) -> Query {
+        term = Nfc::normalize_term(term, *self.normalize_strings.read().unwrap());
         {
             let mut kb = self.kb.write().unwrap();
             term = rewrite_term(term, &mut kb);
         }
+        self.start_query(term, trace)
+    }
+
+    /// Make a query for an already rewritten term.
+    fn start_query(&self, term: Term, trace: bool) -> Query {
         let query = Goal::Query { term: term.clone() };
-        let vm =
-            PolarVirtualMachine::new(self.kb.clone(), trace, vec![query], self.messages.clone());
-        Query::new(vm, term)
+        let kb = match &self.frozen_kb {
+            Some(kb) => SharedKb::Frozen {
+                kb: kb.clone(),
+                queries: self.kb.clone(),
+            },
+            None => SharedKb::Locked(self.kb.clone()),
+        };
+        let mut vm = PolarVirtualMachine::new(kb, trace, vec![query], self.messages.clone());
+        vm.string_collation = *self.string_collation.read().unwrap();
+        vm.fact_sources = Arc::new(self.fact_sources.read().unwrap().clone());
+        vm.reorder_conjunctions = *self.reorder_conjunctions.read().unwrap();
+        vm.instrumentation = self.instrumentation.read().unwrap().clone();
+        if let Some(config) = self.sandbox.read().unwrap().as_ref() {
+            vm.sandbox(config);
+        }
+        for (instance_id, fields) in self.instance_snapshots.read().unwrap().iter() {
+            vm.snapshot_instance(*instance_id, fields.clone());
+        }
+        let mut query = Query::new(vm, term);
+        query.max_results = *self.max_results.read().unwrap();
+        query.normalize_strings = *self.normalize_strings.read().unwrap();
+        query.decision_logger = self.decision_logger.read().unwrap().clone();
+        query
+    }
+
+    /// Resume a query suspended by `Query::suspend`. The knowledge base must
+    /// hold the same rules as the one the query was made against.
+    pub fn resume_query(&self, suspended: &str) -> PolarResult<Query> {
+        let suspended: SuspendedQuery =
+            serde_json::from_str(suspended).map_err(|e| RuntimeError::Serialization {
+                msg: format!("invalid suspended query: {}", e),
+            })?;
+        let mut query = self.start_query(suspended.term, false);
+        query.vm.resume(suspended.vm);
+        query.done = suspended.done;
+        query.results = suspended.results;
+        query.max_results = suspended.max_results;
+        query.normalize_strings = suspended.normalize_strings;
+        Ok(query)
     }
 
     // @TODO: Direct load_rules endpoint.
@@ -289,13 +1481,203 @@ impl Polar {
         self.kb.read().unwrap().new_id()
     }
 
+    /// Add the fact `name(args...)` to the knowledge base, as if loaded from
+    /// a policy, but without parsing. The arguments must be data: no
+    /// variables, expressions or patterns. Running queries see the fact in
+    /// calls they make afterwards.
+    pub fn add_fact(&self, name: Symbol, args: Vec<Term>) -> PolarResult<()> {
+        let normalize = *self.normalize_strings.read().unwrap();
+        let rule = fact_rule(name, args, normalize)?;
+        self.kb.write().unwrap().add_rule(rule);
+        Ok(())
+    }
+
+    /// Add many facts `name(args...)` at once, without parsing, e.g., to
+    /// load role assignments from a database. Each item of `facts` is the
+    /// arguments of one fact, which must be data as for `add_fact`. If any
+    /// fact is invalid, none are added. Returns how many were added.
+    pub fn load_facts(
+        &self,
+        name: Symbol,
+        facts: impl Iterator<Item = Vec<Value>>,
+    ) -> PolarResult<usize> {
+        let normalize = *self.normalize_strings.read().unwrap();
+        let rules = facts
+            .map(|args| {
+                let args = args.into_iter().map(Term::new_temporary).collect();
+                fact_rule(name.clone(), args, normalize)
+            })
+            .collect::<PolarResult<Vec<_>>>()?;
+        let count = rules.len();
+        let mut kb = self.kb.write().unwrap();
+        for rule in rules {
+            kb.add_rule(rule);
+        }
+        Ok(count)
+    }
+
+    /// Remove facts `name(args...)` from the knowledge base, whether added
+    /// with `add_fact` or loaded from a policy, and return how many were
+    /// removed. Running queries see the change in calls they make afterwards.
+    pub fn remove_fact(&self, name: Symbol, args: Vec<Term>) -> PolarResult<usize> {
+        check_fact_args(&args, false)?;
+        let normalize = *self.normalize_strings.read().unwrap();
+        let args: Vec<Term> = args
+            .into_iter()
+            .map(|arg| Nfc::normalize_term(arg, normalize))
+            .collect();
+        Ok(self.remove_facts(&name, |params| {
+            params.len() == args.len() && params.iter().zip(&args).all(|(p, a)| p == a)
+        }))
+    }
+
+    /// Remove facts `name(args...)` whose arguments match `pattern`, in
+    /// which variables match any value, e.g., `has_role("alice", _role)`
+    /// removes every role of "alice". Returns how many were removed.
+    pub fn remove_facts_matching(&self, name: Symbol, pattern: Vec<Term>) -> PolarResult<usize> {
+        check_fact_args(&pattern, true)?;
+        let normalize = *self.normalize_strings.read().unwrap();
+        let pattern: Vec<Term> = pattern
+            .into_iter()
+            .map(|arg| Nfc::normalize_term(arg, normalize))
+            .collect();
+        Ok(self.remove_facts(&name, |params| {
+            let mut bindings = HashMap::new();
+            params.len() == pattern.len()
+                && pattern
+                    .iter()
+                    .zip(params)
+                    .all(|(p, arg)| matches_fact_arg(p, arg, &mut bindings))
+        }))
+    }
+
+    fn remove_facts<F: Fn(&[Term]) -> bool>(&self, name: &Symbol, matches: F) -> usize {
+        self.kb.write().unwrap().remove_rules(name, |rule| {
+            rule.is_fact() && {
+                let params: Vec<Term> = rule.params.iter().map(|p| p.parameter.clone()).collect();
+                matches(&params)
+            }
+        })
+    }
+
     pub fn register_constant(&self, name: Symbol, value: Term) {
+        let value = Nfc::normalize_term(value, *self.normalize_strings.read().unwrap());
         self.kb.write().unwrap().constant(name, value)
     }
 
+    /// Messages emitted while loading policies and running queries,
+    /// buffered until a `MessageSink` is set.
     pub fn next_message(&self) -> Option<Message> {
         self.messages.next()
     }
+
+    /// Send messages, e.g., output of `print` and warnings, to `sink` as
+    /// they're emitted, instead of buffering them for `next_message`.
+    /// `None` restores buffering.
+    pub fn set_message_sink(&self, sink: Option<Arc<dyn MessageSink>>) {
+        self.messages.set_sink(sink)
+    }
+
+    /// Copy the rules and settings loaded so far into a read-only
+    /// `FrozenPolar`, with each rule's class dispatch, and the rules it
+    /// sorts for the classes they specialize, precomputed. Later changes to
+    /// this instance don't affect it.
+    pub fn freeze(&self) -> FrozenPolar {
+        let mut polar = self.copy();
+        let kb = {
+            let mut kb = polar.kb.write().unwrap();
+            kb.freeze();
+            kb.clone()
+        };
+        polar.frozen_kb = Some(Arc::new(kb));
+        FrozenPolar {
+            polar: Arc::new(polar),
+        }
+    }
+
+    /// A copy of this instance that changes independently of it, but emits
+    /// messages to the same queue and shares its ID counters, so that
+    /// instances made for one can be used with the other.
+    fn copy(&self) -> Polar {
+        fn copied<T: Clone>(value: &RwLock<T>) -> Arc<RwLock<T>> {
+            Arc::new(RwLock::new(value.read().unwrap().clone()))
+        }
+        Polar {
+            kb: copied(&self.kb),
+            frozen_kb: None,
+            messages: self.messages.clone(),
+            loaded_files: copied(&self.loaded_files),
+            loaded_content: copied(&self.loaded_content),
+            query_cache: copied(&self.query_cache),
+            parser_limits: copied(&self.parser_limits),
+            max_results: copied(&self.max_results),
+            string_collation: copied(&self.string_collation),
+            normalize_strings: copied(&self.normalize_strings),
+            reorder_conjunctions: copied(&self.reorder_conjunctions),
+            packages: copied(&self.packages),
+            fact_sources: copied(&self.fact_sources),
+            instrumentation: copied(&self.instrumentation),
+            instance_snapshots: copied(&self.instance_snapshots),
+            authorization_types: copied(&self.authorization_types),
+            decision_logger: copied(&self.decision_logger),
+            sandbox: copied(&self.sandbox),
+        }
+    }
+}
+
+/// A read-only copy of a `Polar` instance, made by `Polar::freeze`, that
+/// only offers queries. Whether each rule's applicable rules depend only on
+/// the classes of its arguments, and which apply, in what order, to the
+/// classes it specializes, is worked out when it's frozen rather than on
+/// the first call. Since the rules can't change, queries read them without
+/// a lock. Clones share it, including across threads.
+#[derive(Clone)]
+pub struct FrozenPolar {
+    polar: Arc<Polar>,
+}
+
+impl FrozenPolar {
+    pub fn new_query(&self, src: &str, trace: bool) -> PolarResult<Query> {
+        self.polar.new_query(src, trace)
+    }
+
+    pub fn new_query_from_term(&self, term: Term, trace: bool) -> Query {
+        self.polar.new_query_from_term(term, trace)
+    }
+
+    /// See `Polar::resume_query`.
+    pub fn resume_query(&self, suspended: &str) -> PolarResult<Query> {
+        self.polar.resume_query(suspended)
+    }
+
+    /// See `Polar::explain`.
+    pub fn explain(&self, src: &str) -> PolarResult<QueryPlan> {
+        self.polar.explain(src)
+    }
+
+    pub fn get_external_id(&self) -> u64 {
+        self.polar.get_external_id()
+    }
+
+    pub fn policy_version(&self) -> Option<String> {
+        self.polar.policy_version()
+    }
+
+    /// See `Polar::rule_metrics`.
+    pub fn rule_metrics(&self) -> Vec<RuleCounts> {
+        self.polar.rule_metrics()
+    }
+
+    /// See `Polar::reset_rule_metrics`.
+    pub fn reset_rule_metrics(&self) {
+        self.polar.reset_rule_metrics()
+    }
+
+    /// A `Polar` instance with this one's rules and settings, to load more
+    /// into and freeze again. Changes to it don't affect this one.
+    pub fn thaw(&self) -> Polar {
+        self.polar.copy()
+    }
 }
 
 #[cfg(test)]
@@ -308,4 +1690,92 @@ mod tests {
         let _query = polar.new_query("1 = 1", false);
         let _ = polar.load_str("f(_);");
     }
+
+    #[test]
+    fn test_stack_snapshot() {
+        let polar = Polar::new();
+        polar.load_str("f(1); f(2); f(3);").unwrap();
+        let mut query = polar.new_query("f(x) and x > 1", false).unwrap();
+
+        let snapshot = query.stack_snapshot();
+        assert!(snapshot.choices.is_empty());
+        assert_eq!(snapshot.goals, vec!["Query(f(x) and x > 1)".to_string()]);
+
+        assert!(matches!(
+            query.next_event().unwrap(),
+            QueryEvent::Result { .. }
+        ));
+        let snapshot = query.stack_snapshot();
+        let rule_choice = snapshot
+            .choices
+            .iter()
+            .find(|choice| !choice.rules.is_empty())
+            .unwrap();
+        assert_eq!(rule_choice.alternatives, 1);
+        assert_eq!(rule_choice.rules, vec![sym!("f")]);
+    }
+
+    #[test]
+    fn test_query_cache() {
+        let polar = Polar::new();
+        polar.load_str("f(1); f(2);").unwrap();
+
+        let results = |mut query: Query| {
+            let mut xs = vec![];
+            while let QueryEvent::Result { bindings, .. } = query.next_event().unwrap() {
+                xs.push(bindings[&sym!("x")].clone());
+            }
+            xs
+        };
+
+        // The same source is parsed and rewritten once, and the queries are independent.
+        let first = polar.new_query("f(x) and x.abs() > 0", false).unwrap();
+        let second = polar.new_query("f(x) and x.abs() > 0", false).unwrap();
+        assert_eq!(first.term, second.term);
+        assert_eq!(polar.query_cache.read().unwrap().len(), 1);
+        assert_eq!(results(polar.new_query("f(x)", false).unwrap()).len(), 2);
+        assert_eq!(results(polar.new_query("f(x)", false).unwrap()).len(), 2);
+
+        // Parse errors aren't cached.
+        assert!(polar.new_query("f(", false).is_err());
+        assert!(polar.new_query("f(", false).is_err());
+        assert_eq!(polar.query_cache.read().unwrap().len(), 2);
+
+        polar.clear_rules();
+        assert!(polar.query_cache.read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_panics_are_errors() {
+        use crate::counter::Counter;
+
+        #[derive(Clone)]
+        struct Panicking;
+
+        impl Runnable for Panicking {
+            fn run(&mut self, _: Option<&mut Counter>) -> PolarResult<QueryEvent> {
+                panic!("internal bug")
+            }
+
+            fn clone_runnable(&self) -> Box<dyn Runnable> {
+                Box::new(self.clone())
+            }
+        }
+
+        let polar = Polar::new();
+        let mut query = polar.new_query("1 = 1", false).unwrap();
+        query.push_runnable(Box::new(Panicking), 0);
+        let err = query.next_event().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "[O0003] Invalid state: query panicked: internal bug"
+        );
+        // The query can't be resumed, but the knowledge base is still usable.
+        assert!(query.next_event().is_err());
+        assert!(polar
+            .new_query("1 = 1", false)
+            .unwrap()
+            .next_event()
+            .is_ok());
+    }
 }
@@ -0,0 +1,177 @@
+//! Recording of the questions a query asks the host and the host's answers,
+//! so that the query can be replayed without the host, e.g., to reproduce
+//! an authorization decision or in regression tests.
+
+use std::collections::{BTreeMap, HashMap};
+
+use serde::{Deserialize, Serialize};
+
+use crate::events::QueryEvent;
+use crate::folder::Folder;
+use crate::terms::{Symbol, Term};
+
+/// An answer from the host to a question asked by a query.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Answer {
+    /// `Query::call_result`
+    Call(Option<Term>),
+    /// `Query::call_result` for the call at an index of a
+    /// `QueryEvent::ExternalCalls`
+    ConcurrentCall(usize, Option<Term>),
+    /// `Query::question_result`
+    Question(bool),
+    /// `Query::lookup_many_result`
+    LookupMany(BTreeMap<Symbol, Term>),
+    /// `Query::application_error`
+    Error(String),
+    /// `Query::snapshot_instance`
+    Snapshot(u64, BTreeMap<Symbol, Term>),
+}
+
+/// An event a query emitted for the host, and the host's answers to it.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Interaction {
+    pub event: QueryEvent,
+    pub answers: Vec<Answer>,
+}
+
+/// The interactions of a query with the host, in order.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Recording {
+    pub interactions: Vec<Interaction>,
+}
+
+/// Whether `event` is one the host acts on: a question, or a request to
+/// construct an instance.
+pub(crate) fn is_external(event: &QueryEvent) -> bool {
+    matches!(
+        event,
+        QueryEvent::MakeExternal { .. }
+            | QueryEvent::ExternalCall { .. }
+            | QueryEvent::ExternalIsa { .. }
+            | QueryEvent::ExternalIsaWithPath { .. }
+            | QueryEvent::ExternalIsSubSpecializer { .. }
+            | QueryEvent::ExternalIsSubclass { .. }
+            | QueryEvent::ExternalUnify { .. }
+            | QueryEvent::ExternalOp { .. }
+            | QueryEvent::NextExternal { .. }
+            | QueryEvent::ExternalLookupMany { .. }
+            | QueryEvent::ExternalCalls { .. }
+    )
+}
+
+/// The ID the host answers `event` with, if it needs an answer.
+pub(crate) fn call_id(event: &QueryEvent) -> Option<u64> {
+    match event {
+        QueryEvent::ExternalCall { call_id, .. }
+        | QueryEvent::ExternalIsa { call_id, .. }
+        | QueryEvent::ExternalIsaWithPath { call_id, .. }
+        | QueryEvent::ExternalIsSubSpecializer { call_id, .. }
+        | QueryEvent::ExternalIsSubclass { call_id, .. }
+        | QueryEvent::ExternalUnify { call_id, .. }
+        | QueryEvent::ExternalOp { call_id, .. }
+        | QueryEvent::NextExternal { call_id, .. }
+        | QueryEvent::ExternalLookupMany { call_id, .. } => Some(*call_id),
+        _ => None,
+    }
+}
+
+/// The index in `event` of the call `call_id`, if it's a
+/// `QueryEvent::ExternalCalls`.
+pub(crate) fn concurrent_call_index(event: &QueryEvent, call_id: u64) -> Option<usize> {
+    match event {
+        QueryEvent::ExternalCalls { calls } => calls.iter().position(|c| c.call_id == call_id),
+        _ => None,
+    }
+}
+
+/// `event` as JSON without its call IDs, and with its instance IDs numbered
+/// in order of appearance, since both differ from run to run.
+pub(crate) fn describe(event: &QueryEvent) -> String {
+    normalize(event).0
+}
+
+/// `describe(event)`, and the instance IDs it numbered, in order.
+pub(crate) fn normalize(event: &QueryEvent) -> (String, Vec<u64>) {
+    let mut json = serde_json::to_value(event).expect("external events serialize");
+    if let Some(fields) = json
+        .as_object_mut()
+        .and_then(|variant| variant.values_mut().next())
+        .and_then(|fields| fields.as_object_mut())
+    {
+        fields.remove("call_id");
+        if let Some(calls) = fields
+            .get_mut("calls")
+            .and_then(|calls| calls.as_array_mut())
+        {
+            for call in calls.iter_mut().filter_map(|call| call.as_object_mut()) {
+                call.remove("call_id");
+            }
+        }
+    }
+    let mut instance_ids = vec![];
+    number_instances(&mut json, &mut instance_ids);
+    (json.to_string(), instance_ids)
+}
+
+/// Replace each instance ID in `json` with its index in `instance_ids`,
+/// adding those not there yet.
+fn number_instances(json: &mut serde_json::Value, instance_ids: &mut Vec<u64>) {
+    match json {
+        serde_json::Value::Object(fields) => {
+            for (key, value) in fields.iter_mut() {
+                match value.as_u64() {
+                    Some(id) if key == "instance_id" => {
+                        let index = instance_ids.iter().position(|i| *i == id);
+                        let index = index.unwrap_or_else(|| {
+                            instance_ids.push(id);
+                            instance_ids.len() - 1
+                        });
+                        *value = index.into();
+                    }
+                    _ => number_instances(value, instance_ids),
+                }
+            }
+        }
+        serde_json::Value::Array(values) => {
+            for value in values {
+                number_instances(value, instance_ids)
+            }
+        }
+        _ => (),
+    }
+}
+
+/// Replaces the instance IDs of a recording with those of the query
+/// replaying it.
+pub(crate) struct InstanceRenamer<'a>(pub &'a HashMap<u64, u64>);
+
+impl InstanceRenamer<'_> {
+    /// `answer` with its instance IDs replaced.
+    pub(crate) fn rename(&mut self, answer: Answer) -> Answer {
+        match answer {
+            Answer::Call(value) => Answer::Call(value.map(|t| self.fold_term(t))),
+            Answer::ConcurrentCall(index, value) => {
+                Answer::ConcurrentCall(index, value.map(|t| self.fold_term(t)))
+            }
+            Answer::LookupMany(results) => Answer::LookupMany(self.fold_fields(results)),
+            Answer::Snapshot(instance_id, fields) => {
+                Answer::Snapshot(self.fold_instance_id(instance_id), self.fold_fields(fields))
+            }
+            answer => answer,
+        }
+    }
+
+    fn fold_fields(&mut self, fields: BTreeMap<Symbol, Term>) -> BTreeMap<Symbol, Term> {
+        fields
+            .into_iter()
+            .map(|(name, value)| (name, self.fold_term(value)))
+            .collect()
+    }
+}
+
+impl Folder for InstanceRenamer<'_> {
+    fn fold_instance_id(&mut self, id: u64) -> u64 {
+        self.0.get(&id).copied().unwrap_or(id)
+    }
+}
@@ -1,5 +1,6 @@
 use std::collections::HashMap;
 
+use super::counter::Counter;
 use super::folder::*;
 use super::kb::*;
 use super::rules::*;
@@ -7,27 +8,49 @@ use super::terms::*;
 
 /// Rename each non-constant variable in a term or rule to a fresh variable.
 pub struct Renamer<'kb> {
-    kb: &'kb KnowledgeBase,
+    counter: Counter,
+    constants: Vec<&'kb Bindings>,
     renames: HashMap<Symbol, Symbol>,
 }
 
 impl<'kb> Renamer<'kb> {
+    /// Rename using the knowledge base's symbol counter, leaving its constants alone.
     pub fn new(kb: &'kb KnowledgeBase) -> Self {
         Self {
-            kb,
+            counter: kb.gensym_counter(),
+            constants: vec![&kb.constants],
+            renames: HashMap::new(),
+        }
+    }
+
+    /// Rename every variable, numbering fresh variables with `counter`.
+    pub fn with_counter(counter: &Counter) -> Self {
+        Self {
+            counter: counter.clone(),
+            constants: vec![],
             renames: HashMap::new(),
         }
     }
+
+    /// Also leave `constants` alone.
+    pub fn keeping(mut self, constants: &'kb Bindings) -> Self {
+        self.constants.push(constants);
+        self
+    }
+
+    fn is_constant(&self, v: &Symbol) -> bool {
+        self.constants.iter().any(|c| c.contains_key(v))
+    }
 }
 
 impl<'kb> Folder for Renamer<'kb> {
     fn fold_variable(&mut self, v: Symbol) -> Symbol {
-        if self.kb.is_constant(&v) {
+        if self.is_constant(&v) {
             v
         } else if let Some(w) = self.renames.get(&v) {
             w.clone()
         } else {
-            let w = self.kb.gensym(&v.0);
+            let w = gensym(&self.counter, &v.0);
             self.renames.insert(v, w.clone());
             w
         }
@@ -37,7 +60,7 @@ impl<'kb> Folder for Renamer<'kb> {
         if let Some(s) = self.renames.get(&r) {
             s.clone()
         } else {
-            let s = self.kb.gensym(&r.0);
+            let s = gensym(&self.counter, &r.0);
             self.renames.insert(r, s.clone());
             s
         }
@@ -70,6 +93,7 @@ impl<'kb> Rewriter<'kb> {
                 true
             }
             Operator::New if o.args.len() == 1 => true,
+            Operator::FindAll if o.args.len() == 2 => true,
             _ => false,
         }
     }
@@ -80,6 +104,7 @@ fn temp_name(o: &Operator) -> &'static str {
         Operator::Add | Operator::Div | Operator::Mul | Operator::Sub => "op",
         Operator::Dot => "value",
         Operator::New => "instance",
+        Operator::FindAll => "list",
         _ => "temp",
     }
 }
@@ -89,7 +114,19 @@ fn temp_name(o: &Operator) -> &'static str {
 /// conjunction, creating one if necessary.
 impl<'kb> Folder for Rewriter<'kb> {
     /// Rewrite a rule, pushing expressions in the head into the body.
-    fn fold_rule(&mut self, Rule { name, body, params }: Rule) -> Rule {
+    fn fold_rule(
+        &mut self,
+        Rule {
+            name,
+            params,
+            guard,
+            body,
+            annotations,
+        }: Rule,
+    ) -> Rule {
+        // Lookups in a guard stay in the guard, so that they are evaluated
+        // before the body.
+        let guard = guard.map(|g| self.fold_term(g));
         let mut body = self.fold_term(body);
 
         self.stack.push(vec![]);
@@ -102,7 +139,13 @@ impl<'kb> Folder for Rewriter<'kb> {
                 args: terms.into_iter().chain(rewrites).collect(),
             }));
         }
-        Rule { name, body, params }
+        Rule {
+            name,
+            params,
+            guard,
+            body,
+            annotations,
+        }
     }
 
     /// Rewrite an expression as a temp, and push a rewritten
@@ -121,7 +164,7 @@ impl<'kb> Folder for Rewriter<'kb> {
             }
             Value::Expression(o) if self.needs_rewrite(o) => {
                 // Rewrite sub-expressions, then push a temp onto the args.
-                let mut new = fold_operation(o.clone(), self);
+                let mut new = self.fold_operation(o.clone());
                 let temp = Value::Variable(self.kb.gensym(temp_name(&o.operator)));
                 new.args.push(Term::new_temporary(temp.clone()));
 
@@ -156,6 +199,37 @@ impl<'kb> Folder for Rewriter<'kb> {
                     })
                     .collect(),
             },
+            Operator::FindAll if o.args.len() >= 2 => {
+                // The template is evaluated once for each result of the condition,
+                // so its rewrites belong at the end of the condition.
+                let mut args = o.args.into_iter();
+                let template = args.next().unwrap();
+                let condition = args.next().unwrap();
+
+                self.stack.push(vec![]);
+                let template = self.fold_term(template);
+                let rewrites = self.stack.pop().unwrap();
+                let mut condition = self.fold_term(condition);
+                if !rewrites.is_empty() {
+                    let terms = unwrap_and(&condition);
+                    condition.replace_value(Value::Expression(Operation {
+                        operator: Operator::And,
+                        args: terms.into_iter().chain(rewrites).collect(),
+                    }));
+                }
+
+                Operation {
+                    operator: Operator::FindAll,
+                    args: vec![template, condition]
+                        .into_iter()
+                        .chain(args.map(|arg| self.fold_term(arg)))
+                        .collect(),
+                }
+            }
+            Operator::Match if !o.args.len().is_multiple_of(2) => {
+                let desugared = desugar_match(self.kb, o.args);
+                self.fold_operation(desugared)
+            }
             _ => fold_operation(o, self),
         }
     }
@@ -177,6 +251,64 @@ impl<'kb> Folder for Rewriter<'kb> {
     }
 }
 
+/// Desugar `match value { p1 => b1; p2 => b2; ... }` into
+///
+/// ```text
+/// (value matches p1 and b1) or
+/// (not value matches p1 and value matches p2 and b2) or ...
+/// ```
+///
+/// so that only the first branch whose pattern matches runs. A `_` pattern
+/// matches anything, and makes any later branches unreachable. A value that
+/// is an expression is evaluated once, into a temporary.
+fn desugar_match(kb: &KnowledgeBase, args: TermList) -> Operation {
+    let op = |operator, args| Value::Expression(Operation { operator, args });
+    let mut args = args.into_iter();
+    let value = args.next().unwrap();
+    let (value, binding) = if matches!(value.value(), Value::Expression(_)) {
+        let temp = value.clone_with_value(Value::Variable(kb.gensym("match")));
+        let binding =
+            value.clone_with_value(op(Operator::Unify, vec![temp.clone(), value.clone()]));
+        (temp, Some(binding))
+    } else {
+        (value, None)
+    };
+
+    let isa = |pattern: &Term| {
+        pattern.clone_with_value(op(Operator::Isa, vec![value.clone(), pattern.clone()]))
+    };
+    let mut previous: Vec<Term> = vec![];
+    let mut branches = vec![];
+    while let (Some(pattern), Some(body)) = (args.next(), args.next()) {
+        let wildcard = matches!(pattern.value(), Value::Variable(v) if v.0 == "_");
+        let mut conditions: TermList = previous
+            .iter()
+            .map(|p| p.clone_with_value(op(Operator::Not, vec![isa(p)])))
+            .collect();
+        if !wildcard {
+            conditions.push(isa(&pattern));
+        }
+        conditions.push(body.clone());
+        branches.push(body.clone_with_value(op(Operator::And, conditions)));
+        if wildcard {
+            break;
+        }
+        previous.push(pattern);
+    }
+
+    let disjunction = Operation {
+        operator: Operator::Or,
+        args: branches,
+    };
+    match binding {
+        Some(binding) => Operation {
+            operator: Operator::And,
+            args: vec![binding, Term::new_temporary(Value::Expression(disjunction))],
+        },
+        None => disjunction,
+    }
+}
+
 /// Replace the left value with And(right, left).
 fn and_wrap(left: &mut Term, right: Term) {
     let new_value = Value::Expression(Operation {
@@ -186,14 +318,15 @@ fn and_wrap(left: &mut Term, right: Term) {
     left.replace_value(new_value);
 }
 
-/// Return a cloned list of arguments from And(*args).
+/// Return a cloned list of arguments from And(*args), or just the term if it
+/// isn't a conjunction.
 pub fn unwrap_and(term: &Term) -> TermList {
     match term.value() {
         Value::Expression(Operation {
             operator: Operator::And,
             args,
         }) => args.clone(),
-        _ => panic!("expected And, found {}", term.to_polar()),
+        _ => vec![term.clone()],
     }
 }
 
@@ -233,6 +366,36 @@ mod tests {
         );
     }
 
+    #[test]
+    fn rewrite_comprehensions() {
+        let mut kb = KnowledgeBase::new();
+        let query = parse_query("ys = [x.y * 2 | x in xs, x > 1]");
+        assert_eq!(query.to_polar(), "ys = [x.y * 2 | x in xs, x > 1]");
+        assert_eq!(
+            rewrite_term(query, &mut kb).to_polar(),
+            "[_op_2 | x in xs, x > 1, x.y = _value_1, _value_1 * 2 = _op_2] = _list_3 and ys = _list_3"
+        );
+    }
+
+    #[test]
+    fn rewrite_match() {
+        let mut kb = KnowledgeBase::new();
+        let query = parse_query(
+            r#"match req.action { "read" => x = 1; Admin => x = 2; _ => x = 3; "never" => x = 4; }"#,
+        );
+        assert_eq!(
+            query.to_polar(),
+            r#"match (req.action) { "read" => x = 1; Admin{} => x = 2; _ => x = 3; "never" => x = 4; }"#
+        );
+        assert_eq!(
+            rewrite_term(query, &mut kb).to_polar(),
+            "req.action = _value_2 and _match_1 = _value_2 and \
+             (_match_1 matches \"read\" and x = 1) or \
+             (not _match_1 matches \"read\" and _match_1 matches Admin{} and x = 2) or \
+             (not _match_1 matches \"read\" and not _match_1 matches Admin{} and x = 3)"
+        );
+    }
+
     #[test]
     fn rewrite_rules() {
         let mut kb = KnowledgeBase::new();
@@ -1,13 +1,32 @@
 use serde::{Deserialize, Serialize};
-use std::collections::{BTreeSet, HashMap};
-use std::sync::Arc;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
 
+use super::bindings::Bindings;
+use super::counter::Counter;
+use super::folder::Folder;
+use super::rewrites::Renamer;
 use super::terms::*;
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct Parameter {
     pub parameter: Term,
     pub specializer: Option<Term>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<Mode>,
+}
+
+/// How a rule expects a variable parameter to be called, declared with a
+/// prefix: `f(+x, -y)`.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Mode {
+    /// `+x`: bound by the caller. Calls that leave it unbound are errors,
+    /// and calls are put off until it's bound when conjuncts are reordered.
+    In,
+    /// `-y`: bound by the rule, so when conjuncts are reordered, the ones
+    /// that use it are put off until the call has run.
+    Out,
 }
 
 impl Parameter {
@@ -16,16 +35,134 @@ impl Parameter {
     }
 }
 
+/// Metadata written before a rule as `@name`, `@name(args)` or
+/// `@name(key: value)`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Annotation {
+    pub name: Symbol,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub args: TermList,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kwargs: Option<BTreeMap<Symbol, Term>>,
+}
+
+impl Annotation {
+    /// Annotations the VM acts on only accept the arguments it understands;
+    /// any other annotation may take anything.
+    pub fn is_valid(&self) -> bool {
+        match self.name.0.as_str() {
+            "deprecated" => {
+                self.kwargs.is_none()
+                    && match &self.args[..] {
+                        [] => true,
+                        [message] => matches!(message.value(), Value::String(_)),
+                        _ => false,
+                    }
+            }
+            "cached" => self.args.is_empty() && self.kwargs.is_none(),
+            "budget" => {
+                let is_limit = |(key, value): (&Symbol, &Term)| {
+                    matches!(key.0.as_str(), "goals" | "ms")
+                        && matches!(value.value(), Value::Number(Numeric::Integer(n)) if *n >= 0)
+                };
+                self.args.is_empty()
+                    && self
+                        .kwargs
+                        .as_ref()
+                        .is_some_and(|kwargs| !kwargs.is_empty() && kwargs.iter().all(is_limit))
+            }
+            _ => true,
+        }
+    }
+}
+
+/// Limits from a `@budget(goals: n, ms: n)` annotation on the work a call
+/// to the rule may do: the goals the VM executes, and the time it takes,
+/// from entering the rule until it's done, including any retries on
+/// backtracking.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct Budget {
+    pub goals: Option<u64>,
+    pub ms: Option<u64>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct Rule {
     pub name: Symbol,
     pub params: Vec<Parameter>,
+    /// Conditions from a `when` clause, checked once the specializers have
+    /// matched and before the body.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub guard: Option<Term>,
     pub body: Term,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub annotations: Vec<Annotation>,
 }
 
 impl Rule {
     pub fn is_ground(&self) -> bool {
-        self.params.iter().all(|p| p.is_ground())
+        self.guard.is_none() && self.params.iter().all(|p| p.is_ground())
+    }
+
+    /// Whether the rule is a fact: it has no specializers, guard or body.
+    pub fn is_fact(&self) -> bool {
+        self.guard.is_none()
+            && self.params.iter().all(|p| p.specializer.is_none())
+            && matches!(self.body.value(), Value::Expression(Operation { operator: Operator::And, args }) if args.is_empty())
+    }
+
+    /// The rule's first annotation called `name`, if any.
+    pub fn annotation(&self, name: &str) -> Option<&Annotation> {
+        self.annotations.iter().find(|a| a.name.0 == name)
+    }
+
+    /// Message from a `@deprecated` annotation, empty if it has none. Calls to
+    /// deprecated rules produce warnings.
+    pub fn deprecation(&self) -> Option<String> {
+        self.annotation("deprecated")
+            .map(|a| match a.args.first().map(Term::value) {
+                Some(Value::String(message)) => message.clone(),
+                _ => String::new(),
+            })
+    }
+
+    /// Limits from a `@budget` annotation, if it has one.
+    pub fn budget(&self) -> Option<Budget> {
+        let kwargs = self.annotation("budget")?.kwargs.as_ref()?;
+        let limit = |key: &str| match kwargs.get(&Symbol::new(key)).map(Term::value) {
+            Some(Value::Number(Numeric::Integer(n))) => Some(*n as u64),
+            _ => None,
+        };
+        Some(Budget {
+            goals: limit("goals"),
+            ms: limit("ms"),
+        })
+    }
+
+    /// Whether the rule applies to arguments depending only on their classes:
+    /// its parameters are distinct variables, specialized, if at all, by a
+    /// class without fields, and it has no guard.
+    pub fn dispatches_by_class(&self) -> bool {
+        let mut names = HashSet::new();
+        self.guard.is_none()
+            && self.params.iter().all(|param| {
+                matches!(param.parameter.value(), Value::Variable(name) if names.insert(name))
+                    && param.specializer.as_ref().is_none_or(|specializer| {
+                        match specializer.value() {
+                            Value::Pattern(Pattern::Instance(literal)) => {
+                                literal.fields.fields.is_empty()
+                            }
+                            _ => false,
+                        }
+                    })
+            })
+    }
+
+    /// Rename every variable in the rule's parameters and body to a fresh one
+    /// numbered by `counter`, so that the rule can be combined with others
+    /// without capturing their variables.
+    pub fn rename_vars(&self, counter: &Counter) -> Self {
+        Renamer::with_counter(counter).fold_rule(self.clone())
     }
 }
 
@@ -57,6 +194,25 @@ impl RuleIndex {
         }
     }
 
+    /// Remove a rule indexed by `index_rule`, and any indices left empty.
+    pub fn remove_rule(&mut self, rule_id: u64, params: &[Parameter], i: usize) {
+        if i < params.len() {
+            let key = if params[i].is_ground() {
+                Some(params[i].parameter.value().clone())
+            } else {
+                None
+            };
+            if let Some(index) = self.index.get_mut(&key) {
+                index.remove_rule(rule_id, params, i + 1);
+                if index.rules.is_empty() && index.index.is_empty() {
+                    self.index.remove(&key);
+                }
+            }
+        } else {
+            self.rules.remove(&rule_id);
+        }
+    }
+
     #[allow(clippy::comparison_chain)]
     pub fn get_applicable_rules(&self, args: &[Term], i: usize) -> RuleSet {
         if i < args.len() {
@@ -94,12 +250,45 @@ impl RuleIndex {
     }
 }
 
+/// The classes of a call's arguments, `None` for those no rule specializes.
+pub type DispatchKey = Vec<Option<Symbol>>;
+
+/// Distinguishes the states of every dispatch cache, so that rules found
+/// applicable before a cache was cleared aren't cached after.
+static DISPATCH_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// The rules applicable to calls with arguments of each combination of
+/// classes, most specific first.
+struct DispatchCache {
+    generation: u64,
+    entries: HashMap<DispatchKey, Rules>,
+}
+
+impl DispatchCache {
+    fn new() -> Self {
+        Self {
+            generation: DISPATCH_GENERATION.fetch_add(1, Ordering::Relaxed),
+            entries: HashMap::new(),
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct GenericRule {
     pub name: Symbol,
     rules: HashMap<u64, Arc<Rule>>,
     index: RuleIndex,
     next_rule_id: u64,
+    dispatch_cache: Arc<Mutex<DispatchCache>>,
+    /// Modes of the parameters, by arity, for arities with rules that
+    /// declare any.
+    modes: HashMap<usize, Vec<Option<Mode>>>,
+    /// Number of rules by arity.
+    arities: BTreeMap<usize, usize>,
+    /// `class_dispatch` by arity, precomputed by `freeze`.
+    class_dispatches: HashMap<usize, Option<Vec<bool>>>,
+    /// How many of the rules are annotated `@cached`.
+    cached_rules: usize,
 }
 
 impl GenericRule {
@@ -109,6 +298,11 @@ impl GenericRule {
             rules: Default::default(),
             index: Default::default(),
             next_rule_id: 0,
+            dispatch_cache: Arc::new(Mutex::new(DispatchCache::new())),
+            modes: HashMap::new(),
+            arities: BTreeMap::new(),
+            class_dispatches: HashMap::new(),
+            cached_rules: 0,
         };
 
         for rule in rules {
@@ -126,6 +320,93 @@ impl GenericRule {
             "Rule id already used."
         );
         self.index.index_rule(rule_id, &rule.params[..], 0);
+        self.add_modes(&rule);
+        *self.arities.entry(rule.params.len()).or_default() += 1;
+        if rule.annotation("cached").is_some() {
+            self.cached_rules += 1;
+        }
+        self.clear_dispatch_cache();
+    }
+
+    /// Remove the rules for which `remove` returns true, and return how many
+    /// were removed.
+    pub fn remove_rules<F: Fn(&Rule) -> bool>(&mut self, remove: F) -> usize {
+        let ids: Vec<u64> = self
+            .rules
+            .iter()
+            .filter(|(_, rule)| remove(rule))
+            .map(|(id, _)| *id)
+            .collect();
+        for id in &ids {
+            let rule = self.rules.remove(id).expect("Rule missing");
+            self.index.remove_rule(*id, &rule.params[..], 0);
+            let arity = rule.params.len();
+            match self.arities.get_mut(&arity) {
+                Some(count) if *count > 1 => *count -= 1,
+                _ => {
+                    self.arities.remove(&arity);
+                }
+            }
+            if rule.annotation("cached").is_some() {
+                self.cached_rules -= 1;
+            }
+        }
+        if !ids.is_empty() {
+            self.modes.clear();
+            for rule in self.rules.values().cloned().collect::<Vec<_>>() {
+                self.add_modes(&rule);
+            }
+            self.clear_dispatch_cache();
+        }
+        ids.len()
+    }
+
+    /// Merge the modes `rule` declares into those of its arity. A parameter
+    /// is `+` if any rule declares it so, and otherwise `-` if any does.
+    fn add_modes(&mut self, rule: &Rule) {
+        if rule.params.iter().all(|param| param.mode.is_none()) {
+            return;
+        }
+        let modes = self
+            .modes
+            .entry(rule.params.len())
+            .or_insert_with(|| vec![None; rule.params.len()]);
+        for (mode, param) in modes.iter_mut().zip(&rule.params) {
+            match param.mode {
+                Some(Mode::In) => *mode = Some(Mode::In),
+                Some(Mode::Out) if mode.is_none() => *mode = Some(Mode::Out),
+                _ => {}
+            }
+        }
+    }
+
+    /// Modes of the parameters of the rules taking `arity` arguments, if
+    /// any declare them.
+    pub fn modes(&self, arity: usize) -> Option<&[Option<Mode>]> {
+        self.modes.get(&arity).map(Vec::as_slice)
+    }
+
+    /// Whether ground calls to the predicate are remembered across queries,
+    /// because any of its rules is annotated `@cached`.
+    pub fn is_cached(&self) -> bool {
+        self.cached_rules > 0
+    }
+
+    /// The numbers of parameters the rules take, in increasing order.
+    pub fn arities(&self) -> impl Iterator<Item = usize> + '_ {
+        self.arities.keys().copied()
+    }
+
+    /// Whether any of the rules takes `arity` parameters.
+    pub fn has_arity(&self, arity: usize) -> bool {
+        self.arities.contains_key(&arity)
+    }
+
+    /// Return all of the rules, in the order they were added.
+    pub fn rules(&self) -> Rules {
+        let mut ids: Vec<&u64> = self.rules.keys().collect();
+        ids.sort();
+        ids.into_iter().map(|id| self.rules[id].clone()).collect()
     }
 
     #[allow(clippy::ptr_arg)]
@@ -138,6 +419,128 @@ impl GenericRule {
             .collect()
     }
 
+    /// Whether the rules applicable to `arity` arguments, and their order,
+    /// depend only on the classes of the arguments; if so, which arguments
+    /// some rule specializes.
+    pub fn class_dispatch(&self, arity: usize) -> Option<Vec<bool>> {
+        if let Some(class_dispatch) = self.class_dispatches.get(&arity) {
+            return class_dispatch.clone();
+        }
+        let mut specialized = vec![false; arity];
+        for rule in self
+            .rules
+            .values()
+            .filter(|rule| rule.params.len() == arity)
+        {
+            if !rule.dispatches_by_class() {
+                return None;
+            }
+            for (i, param) in rule.params.iter().enumerate() {
+                specialized[i] |= param.specializer.is_some();
+            }
+        }
+        Some(specialized)
+    }
+
+    /// The rules found applicable to arguments with classes `key` before,
+    /// most specific first, or else the generation of the cache, to pass to
+    /// `cache_dispatch` along with the rules once they're found.
+    pub fn cached_dispatch(&self, key: &[Option<Symbol>]) -> Result<Rules, u64> {
+        // Nothing panics while holding the lock, so even if it's poisoned,
+        // the cache is whole.
+        let cache = self
+            .dispatch_cache
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        cache.entries.get(key).cloned().ok_or(cache.generation)
+    }
+
+    /// Remember the rules applicable to arguments with classes `key`, until
+    /// rules are added or removed, unless they have been since `generation`.
+    pub fn cache_dispatch(&self, generation: u64, key: DispatchKey, rules: Rules) {
+        let mut cache = self
+            .dispatch_cache
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if cache.generation == generation {
+            cache.entries.insert(key, rules);
+        }
+    }
+
+    /// Forget the rules cached by `cache_dispatch`, and what `freeze`
+    /// precomputed. Clones of the rule stop sharing a cache, since their
+    /// rules may differ.
+    fn clear_dispatch_cache(&mut self) {
+        self.dispatch_cache = Arc::new(Mutex::new(DispatchCache::new()));
+        self.class_dispatches.clear();
+    }
+
+    /// Precompute `class_dispatch` for each arity of the rules, and the
+    /// sorted rules for the arguments they expect, until rules are added or
+    /// removed. `constants` are the registered classes.
+    pub fn freeze(&mut self, constants: &Bindings) {
+        self.class_dispatches.clear();
+        for arity in self.arities().collect::<Vec<_>>() {
+            let class_dispatch = self.class_dispatch(arity);
+            if let Some((key, rules)) = class_dispatch
+                .as_ref()
+                .and_then(|specialized| self.expected_dispatch(specialized, constants))
+            {
+                let mut cache = self
+                    .dispatch_cache
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner);
+                cache.entries.insert(key, rules);
+            }
+            self.class_dispatches.insert(arity, class_dispatch);
+        }
+    }
+
+    /// The classes that make every rule of the arity applicable, and those
+    /// rules sorted as a query would sort them, if the rules that specialize
+    /// each argument all specialize it on the same registered class. Which
+    /// rules apply to other classes, and how they sort, takes the host.
+    fn expected_dispatch(
+        &self,
+        specialized: &[bool],
+        constants: &Bindings,
+    ) -> Option<(DispatchKey, Rules)> {
+        let mut rules: Rules = self
+            .rules()
+            .into_iter()
+            .filter(|rule| rule.params.len() == specialized.len())
+            .collect();
+        let mut key: DispatchKey = vec![None; specialized.len()];
+        for rule in &rules {
+            for (class, param) in key.iter_mut().zip(&rule.params) {
+                let tag = match param.specializer.as_ref().map(Term::value) {
+                    Some(Value::Pattern(Pattern::Instance(InstanceLiteral { tag, .. }))) => tag,
+                    _ => continue,
+                };
+                match class {
+                    Some(class) if *class != *tag => return None,
+                    _ => *class = Some(tag.clone()),
+                }
+            }
+        }
+        if !key
+            .iter()
+            .flatten()
+            .all(|class| constants.contains_key(class))
+        {
+            return None;
+        }
+        // The more specific of two rules is the one that specializes the
+        // first argument the other doesn't; the sort keeps ties in order.
+        rules.sort_by_key(|rule| {
+            rule.params
+                .iter()
+                .map(|param| param.specializer.is_none())
+                .collect::<Vec<_>>()
+        });
+        Some((key, rules))
+    }
+
     fn next_rule_id(&mut self) -> u64 {
         let v = self.next_rule_id;
         self.next_rule_id += 1;
@@ -204,4 +607,103 @@ mod tests {
         let index13 = index1.index.get(&Some(value!(3))).unwrap();
         assert_eq!(args, keys(index13));
     }
+
+    #[test]
+    fn test_remove_rules() {
+        let polar = Polar::new();
+        polar
+            .load_str(r#"f(1); f(1, "x"); f(1, "y"); f(2, "y"); f(x, "z") if x = 3;"#)
+            .unwrap();
+
+        let mut kb = polar.kb.write().unwrap();
+        let generic_rule = kb.rules.get_mut(&sym!("f")).unwrap();
+        let removed = generic_rule.remove_rules(|rule| {
+            rule.params[0].parameter == term!(1) || rule.params[1].parameter == term!("z")
+        });
+        assert_eq!(removed, 4);
+        assert_eq!(generic_rule.rules().len(), 1);
+        assert_eq!(generic_rule.arities().collect::<Vec<_>>(), vec![2]);
+
+        // Indices left empty are removed too.
+        let keys: HashSet<_> = generic_rule.index.index.keys().cloned().collect();
+        assert_eq!(keys, vec![Some(value!(2))].into_iter().collect());
+        let args = vec![term!(sym!("a")), term!(sym!("b"))];
+        assert_eq!(generic_rule.get_applicable_rules(&args).len(), 1);
+    }
+
+    #[test]
+    fn test_class_dispatch() {
+        let polar = Polar::new();
+        polar
+            .load_str(
+                r#"f(x: A, y) if x = y;
+                   f(_x: B, _y: C);
+                   g(x, x: A);
+                   h(_x: A{a: 1});
+                   k(x: A) when x.b = 2 if x.a = 1;
+                   m(1, _x: A);
+                   n(_x, _y);
+                   n(_x: A, _y);
+                   n(_x: A, _y: C);"#,
+            )
+            .unwrap();
+        let kb = polar.kb.read().unwrap();
+        let dispatch = |name: &str, arity| kb.rules[&sym!(name)].class_dispatch(arity);
+        assert_eq!(dispatch("f", 2), Some(vec![true, true]));
+        // No rule of the arity specializes anything.
+        assert_eq!(dispatch("f", 1), Some(vec![false]));
+        // Repeated variables, fields, guards and literals depend on more than classes.
+        assert_eq!(dispatch("g", 2), None);
+        assert_eq!(dispatch("h", 1), None);
+        assert_eq!(dispatch("k", 1), None);
+        assert_eq!(dispatch("m", 2), None);
+
+        // Freezing precomputes the same, until rules change.
+        let mut generic_rule = kb.rules[&sym!("f")].clone();
+        generic_rule.freeze(&kb.constants);
+        assert_eq!(generic_rule.class_dispatches.len(), 1);
+        assert_eq!(generic_rule.class_dispatch(2), Some(vec![true, true]));
+        // Along with the sorted rules for the classes they specialize, if
+        // they're registered.
+        let key = vec![Some(sym!("A")), Some(sym!("C"))];
+        assert!(generic_rule.cached_dispatch(&key).is_err());
+        let mut n = kb.rules[&sym!("n")].clone();
+        n.freeze(&kb.constants);
+        assert!(n.cached_dispatch(&key).is_err());
+        let constants = ["A", "C"].map(|name| (sym!(name), term!(true))).into();
+        n.freeze(&constants);
+        let sources: Vec<String> = n
+            .cached_dispatch(&key)
+            .unwrap()
+            .iter()
+            .map(|rule| rule.to_polar())
+            .collect();
+        assert_eq!(
+            sources,
+            vec!["n(_x: A{}, _y: C{});", "n(_x: A{}, _y);", "n(_x, _y);"]
+        );
+        generic_rule.add_rule(Arc::new(
+            crate::parser::parse_rules(0, "f(1, 2);").unwrap().remove(0),
+        ));
+        assert!(generic_rule.class_dispatches.is_empty());
+        assert_eq!(generic_rule.class_dispatch(2), None);
+    }
+
+    #[test]
+    fn test_rename_vars() {
+        let rule = crate::parser::parse_rules(0, "f(x, [y, *rest]) if x = y and g(rest);")
+            .unwrap()
+            .pop()
+            .unwrap();
+        let counter = Counter::with_start(7);
+        let renamed = rule.rename_vars(&counter);
+        assert_eq!(
+            renamed.to_polar(),
+            "f(_x_7, [_y_8, *_rest_9]) if _x_7 = _y_8 and g(_rest_9);"
+        );
+
+        // Renaming again with the same counter never reuses a name.
+        let renamed = rule.body.rename_vars(&counter);
+        assert_eq!(renamed.to_polar(), "_x_10 = _y_11 and g(_rest_12)");
+    }
 }
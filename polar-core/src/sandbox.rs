@@ -0,0 +1,63 @@
+//! Limits for evaluating policies from untrusted sources, e.g., ones users
+//! submit to a hosted playground, set together with `Polar::set_sandbox`
+//! rather than one by one.
+
+use serde::{Deserialize, Serialize};
+
+use crate::parser::ParserLimits;
+
+/// Everything limited when running untrusted policies. The default is the
+/// strictest useful preset; loosen individual fields from there, e.g.,
+///
+/// ```
+/// use polar_core::sandbox::SandboxConfig;
+///
+/// let config = SandboxConfig {
+///     max_results: Some(1_000),
+///     ..SandboxConfig::default()
+/// };
+/// ```
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SandboxConfig {
+    /// Limits on the policies and queries parsed.
+    pub parser_limits: ParserLimits,
+    /// Goals a query may execute, including those of its sub-queries.
+    pub max_goals: Option<u64>,
+    /// Milliseconds a query may run for each time the host asks for its
+    /// next event.
+    pub query_timeout_ms: u64,
+    /// Goals and choice points a query may have pending at once.
+    pub max_stack: usize,
+    /// Variable bindings a query may hold at once.
+    pub max_bindings: Option<usize>,
+    /// Results a query may produce.
+    pub max_results: Option<usize>,
+    /// Whether `debug()` stops the query with a `QueryEvent::Debug`. If not,
+    /// it does nothing.
+    pub debug_events: bool,
+    /// Whether queries may ask the host anything, e.g., to look up an
+    /// attribute or check an instance's class. If not, they fail with an
+    /// error when they would.
+    pub host_calls: bool,
+}
+
+impl Default for SandboxConfig {
+    fn default() -> Self {
+        Self {
+            parser_limits: ParserLimits {
+                max_source_len: Some(64 * 1024),
+                max_depth: Some(32),
+                max_rules: Some(500),
+                max_collection_len: Some(1_000),
+            },
+            max_goals: Some(100_000),
+            query_timeout_ms: 1_000,
+            max_stack: 1_000,
+            max_bindings: Some(10_000),
+            max_results: Some(100),
+            debug_events: false,
+            host_calls: false,
+        }
+    }
+}
@@ -1,5 +1,15 @@
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Source ids are unique across knowledge bases, so that terms from an
+/// overlay's rules can't be mistaken for terms from the base's sources.
+static NEXT_SOURCE_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Return a new source id.
+pub fn new_source_id() -> u64 {
+    NEXT_SOURCE_ID.fetch_add(1, Ordering::Relaxed)
+}
 
 #[derive(Debug, Clone, Hash)]
 pub enum SourceInfo {
@@ -33,8 +43,80 @@ impl SourceInfo {
 pub struct Source {
     pub filename: Option<String>,
     pub src: String,
+    /// Where generated source came from, if it was generated.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_map: Option<SourceMap>,
+}
+
+impl Source {
+    /// The line and character column of the byte `offset` in this source,
+    /// counting from 0, ignoring any source map. Columns count characters,
+    /// so a tab or multi-byte character is one column.
+    pub fn line_column(&self, offset: usize) -> (usize, usize) {
+        crate::lexer::loc_to_pos(&self.src, offset)
+    }
+
+    /// The lines of this source, without their `\n` or `\r\n` endings.
+    pub fn lines(&self) -> impl Iterator<Item = &str> {
+        self.src.lines()
+    }
+
+    /// Where the character at byte `offset` came from: its line and column
+    /// in this source, or in the template this source was generated from.
+    pub fn position(&self, offset: usize) -> Position<'_> {
+        let (row, column) = self.line_column(offset);
+        let mapping = self.source_map.as_ref().and_then(|map| {
+            map.mappings
+                .iter()
+                .filter(|mapping| mapping.line <= row)
+                .max_by_key(|mapping| mapping.line)
+                .map(|mapping| (map, mapping))
+        });
+        match mapping {
+            Some((map, mapping)) => Position {
+                filename: Some(&map.file),
+                row: mapping.original_line,
+                column: mapping.original_column + if mapping.line == row { column } else { 0 },
+            },
+            None => Position {
+                filename: self.filename.as_deref(),
+                row,
+                column,
+            },
+        }
+    }
 }
 
+/// A position in a policy file, with `row` and `column` counted from 0.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Position<'a> {
+    pub filename: Option<&'a str>,
+    pub row: usize,
+    pub column: usize,
+}
+
+/// Maps lines of a generated policy back to the template they were
+/// generated from, e.g., a file of rules expanded per resource, so that
+/// errors and traces point into the template.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct SourceMap {
+    /// The template.
+    pub file: String,
+    pub mappings: Vec<SourceMapping>,
+}
+
+/// The generated line `line` starts at `original_line`, `original_column`
+/// in the template, counting from 0. The lines after it that have no
+/// mapping of their own were generated by the same part of the template,
+/// and map to the same position.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SourceMapping {
+    pub line: usize,
+    pub original_line: usize,
+    pub original_column: usize,
+}
+
+#[derive(Clone)]
 pub struct Sources {
     /// Map from term ID to `Source`.
     sources: HashMap<u64, Source>,
@@ -48,6 +130,7 @@ impl Default for Sources {
             Source {
                 filename: None,
                 src: "<Unknown>".to_string(),
+                source_map: None,
             },
         );
         Self { sources }
@@ -63,3 +146,50 @@ impl Sources {
         self.sources.get(&src_id).cloned()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_source_map_positions() {
+        let mut source = Source {
+            filename: Some("generated.polar".to_string()),
+            src: "f(1);\ng(1);\ng(2);\nh(1);".to_string(),
+            source_map: None,
+        };
+        let position = |source: &Source, offset| {
+            let Position {
+                filename,
+                row,
+                column,
+            } = source.position(offset);
+            (filename.map(str::to_string), row, column)
+        };
+        let generated = |row, column| (Some("generated.polar".to_string()), row, column);
+        let template = |row, column| (Some("template.polar".to_string()), row, column);
+        assert_eq!(position(&source, 8), generated(1, 2));
+
+        source.source_map = Some(SourceMap {
+            file: "template.polar".to_string(),
+            mappings: vec![
+                SourceMapping {
+                    line: 3,
+                    original_line: 9,
+                    original_column: 0,
+                },
+                SourceMapping {
+                    line: 1,
+                    original_line: 4,
+                    original_column: 8,
+                },
+            ],
+        });
+        // Lines before the first mapping aren't mapped.
+        assert_eq!(position(&source, 2), generated(0, 2));
+        assert_eq!(position(&source, 8), template(4, 10));
+        // A line without a mapping maps to the start of the one before it.
+        assert_eq!(position(&source, 14), template(4, 8));
+        assert_eq!(position(&source, 20), template(9, 2));
+    }
+}
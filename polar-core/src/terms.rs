@@ -1,29 +1,161 @@
+use super::bindings::Bindings;
+use super::builtins::compare_terms;
+use super::counter::Counter;
+use super::folder::{fold_list, fold_term, Folder};
+use super::rewrites::Renamer;
 use super::sources::SourceInfo;
 pub use super::{error, formatting::ToPolarString};
+use indexmap::IndexMap;
 use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
 use std::collections::hash_map::DefaultHasher;
 use std::collections::{BTreeMap, HashSet};
 use std::hash::{Hash, Hasher};
 use std::sync::Arc;
 
+pub use super::datetime::DateTime;
 pub use super::numerics::Numeric;
 use super::visitor::{walk_term, Visitor};
 
-#[derive(Debug, Clone, Serialize, Deserialize, Default, Eq, PartialEq, Hash)]
+/// Dictionary fields, in the order they were written or inserted.
+pub type Fields = IndexMap<Symbol, Term>;
+
+/// Key of the rest variable in a dictionary pattern, e.g., `{id: 1, **rest}`.
+/// It can't be written as a field name in Polar.
+pub const REST_FIELD: &str = "**";
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default, Eq, PartialEq)]
 pub struct Dictionary {
-    pub fields: BTreeMap<Symbol, Term>,
+    pub fields: Fields,
 }
 
 impl Dictionary {
     pub fn new() -> Self {
         Self {
-            fields: BTreeMap::new(),
+            fields: Fields::new(),
         }
     }
 
     pub fn is_empty(&self) -> bool {
         self.fields.is_empty()
     }
+
+    /// The variable that captures the fields besides the listed ones.
+    pub fn rest_var(&self) -> Option<&Term> {
+        self.fields
+            .get(&Symbol::new(REST_FIELD))
+            .filter(|rest| matches!(rest.value(), Value::RestVariable(_)))
+    }
+
+    /// The fields besides the rest variable.
+    pub fn listed_fields(&self) -> impl DoubleEndedIterator<Item = (&Symbol, &Term)> {
+        self.fields
+            .iter()
+            .filter(|(k, v)| k.0 != REST_FIELD || !matches!(v.value(), Value::RestVariable(_)))
+    }
+}
+
+// Dictionaries with the same fields are equal regardless of field order,
+// so they must hash the same regardless of field order, too.
+impl Hash for Dictionary {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        let mut fields: Vec<_> = self.fields.iter().collect();
+        fields.sort_by_key(|(name, _)| *name);
+        fields.hash(state);
+    }
+}
+
+/// An unordered collection of distinct terms.
+///
+/// Elements are kept sorted in canonical term order with duplicates removed,
+/// so that ground membership is a binary search and equal sets compare equal.
+/// Build sets with [`Set::new`] (or `From<TermList>`) to keep that invariant.
+#[derive(Debug, Clone, Serialize, Deserialize, Default, Eq, PartialEq, Hash)]
+#[serde(from = "TermList", into = "TermList")]
+pub struct Set {
+    pub elements: TermList,
+}
+
+impl Set {
+    pub fn new(mut elements: TermList) -> Self {
+        elements.sort_by(compare_terms);
+        elements.dedup_by(|a, b| compare_terms(a, b) == Ordering::Equal);
+        Self { elements }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.elements.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.elements.len()
+    }
+
+    /// Whether every element is ground, so that `contains` is exact.
+    pub fn is_ground(&self) -> bool {
+        self.elements.iter().all(|e| e.is_ground())
+    }
+
+    /// Whether every element of this set is in `other`.
+    pub fn is_subset(&self, other: &Set) -> bool {
+        self.elements.iter().all(|e| other.contains(e))
+    }
+
+    /// Whether a ground set contains a ground term.
+    pub fn contains(&self, term: &Term) -> bool {
+        self.elements
+            .binary_search_by(|e| compare_terms(e, term))
+            .is_ok()
+    }
+}
+
+impl From<TermList> for Set {
+    fn from(elements: TermList) -> Self {
+        Self::new(elements)
+    }
+}
+
+impl From<Set> for TermList {
+    fn from(set: Set) -> Self {
+        set.elements
+    }
+}
+
+/// The integers from `start` to `end`, inclusive, e.g., `1..10`. The bounds
+/// are terms, so they can be variables bound by the time the range is used.
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq, Hash)]
+pub struct Range {
+    pub start: Term,
+    pub end: Term,
+}
+
+/// How strings are ordered by `<`, `<=`, `>`, `>=` and `sort`. Equality is
+/// always exact, so `==` and unification aren't affected.
+#[derive(Debug, Default, Copy, Clone, Serialize, Deserialize, Eq, PartialEq)]
+pub enum StringCollation {
+    /// By bytes, i.e., by code point. The default.
+    #[default]
+    Binary,
+    /// By code point after lowercasing, so `"a" < "B"`.
+    CaseInsensitive,
+    /// By the Unicode Collation Algorithm with the CLDR root collation, so
+    /// that, e.g., accented letters sort next to unaccented ones.
+    #[cfg(feature = "unicode-collation")]
+    Unicode,
+}
+
+impl StringCollation {
+    pub fn compare(self, left: &str, right: &str) -> Ordering {
+        match self {
+            Self::Binary => left.cmp(right),
+            Self::CaseInsensitive => left
+                .chars()
+                .flat_map(char::to_lowercase)
+                .cmp(right.chars().flat_map(char::to_lowercase)),
+            #[cfg(feature = "unicode-collation")]
+            Self::Unicode => feruca::Collator::default().collate(left, right),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq, Hash)]
@@ -37,6 +169,10 @@ pub struct ExternalInstance {
     pub instance_id: u64,
     pub constructor: Option<Term>,
     pub repr: Option<String>,
+    /// The name of the instance's class, if the host knows it. Lets the VM
+    /// reuse the rules found applicable to other instances of the class.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub class_tag: Option<Symbol>,
 }
 
 // Context stored somewhere by id.
@@ -56,6 +192,10 @@ pub fn has_rest_var(list: &TermList) -> bool {
     !list.is_empty() && matches!(list.last().unwrap().value(), Value::RestVariable(_))
 }
 
+/// Name of the variable that stands in for a term which contains itself
+/// through its bindings.
+pub const CYCLE_MARKER: &str = "<cycle>";
+
 #[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub struct Symbol(pub String);
 
@@ -111,6 +251,10 @@ pub enum Operator {
     And,
     ForAll,
     Assign,
+    FindAll,
+    /// `match value { pattern => body; ... }`, with arguments
+    /// `[value, pattern, body, ...]`. Rewritten into an `or` before evaluation.
+    Match,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq, Hash)]
@@ -136,6 +280,15 @@ pub enum Value {
     Pattern(Pattern),
     Call(Call),
     List(TermList),
+    Set(Set),
+    /// A fixed-length sequence, which unifies positionally but can't be
+    /// iterated or destructured with a rest variable.
+    Tuple(TermList),
+    Range(Range),
+    /// An instant, written as an ISO-8601 literal, e.g., `@2024-05-01`.
+    DateTime(DateTime),
+    /// No value, e.g., of an attribute the host has none for.
+    Nil,
     Variable(Symbol),
     RestVariable(Symbol),
     Expression(Operation),
@@ -197,12 +350,19 @@ impl Value {
         match self {
             Value::Call(_)
             | Value::ExternalInstance(_)
+            | Value::Pattern(_)
             | Value::Variable(_)
             | Value::RestVariable(_) => false,
-            Value::Number(_) | Value::String(_) | Value::Boolean(_) => true,
-            Value::Pattern(_) => panic!("unexpected value type"),
+            Value::Number(_)
+            | Value::String(_)
+            | Value::Boolean(_)
+            | Value::DateTime(_)
+            | Value::Nil => true,
             Value::Dictionary(Dictionary { fields }) => fields.values().all(|t| t.is_ground()),
             Value::List(terms) => terms.iter().all(|t| t.is_ground()),
+            Value::Set(set) => set.is_ground(),
+            Value::Tuple(terms) => terms.iter().all(|t| t.is_ground()),
+            Value::Range(Range { start, end }) => start.is_ground() && end.is_ground(),
             Value::Expression(Operation { operator: _, args }) => {
                 args.iter().all(|t| t.is_ground())
             }
@@ -344,6 +504,102 @@ impl Term {
         walk_term(&mut VariableVisitor::new(vars), self);
     }
 
+    /// Get the set of all variables and rest-variables that occur in a term.
+    pub fn free_variables(&self) -> HashSet<Symbol> {
+        struct FreeVariableVisitor {
+            vars: HashSet<Symbol>,
+        }
+
+        impl Visitor for FreeVariableVisitor {
+            fn visit_variable(&mut self, v: &Symbol) {
+                self.vars.insert(v.clone());
+            }
+
+            fn visit_rest_variable(&mut self, r: &Symbol) {
+                self.vars.insert(r.clone());
+            }
+        }
+
+        let mut visitor = FreeVariableVisitor {
+            vars: HashSet::new(),
+        };
+        walk_term(&mut visitor, self);
+        visitor.vars
+    }
+
+    /// Return true if any variable occurs anywhere in the term.
+    ///
+    /// This only looks for variables, and walks into calls to do so:
+    /// `f(x)` has free variables but `f(1)` does not. `is_ground`, by
+    /// contrast, returns false for any call or external instance.
+    pub fn has_free_variables(&self) -> bool {
+        !self.free_variables().is_empty()
+    }
+
+    /// Rename every variable in the term to a fresh one numbered by `counter`.
+    ///
+    /// Occurrences of the same variable are renamed consistently.
+    pub fn rename_vars(&self, counter: &Counter) -> Self {
+        Renamer::with_counter(counter).fold_term(self.clone())
+    }
+
+    /// Substitute the values in `bindings` for the variables they bind.
+    ///
+    /// Substitution is applied recursively to the substituted values. A bound
+    /// rest-variable at the end of a list is spliced into the list. Variables
+    /// without a binding are left in place.
+    pub fn apply(&self, bindings: &Bindings) -> Self {
+        struct Substituter<'a> {
+            bindings: &'a Bindings,
+            expanding: Vec<Symbol>,
+        }
+
+        impl<'a> Substituter<'a> {
+            fn substitute(&mut self, t: Term, var: &Symbol) -> Term {
+                match self.bindings.get(var) {
+                    // Don't expand a variable inside its own binding.
+                    Some(value) if !self.expanding.contains(var) => {
+                        self.expanding.push(var.clone());
+                        let value = self.fold_term(value.clone());
+                        self.expanding.pop();
+                        value
+                    }
+                    _ => t,
+                }
+            }
+        }
+
+        impl<'a> Folder for Substituter<'a> {
+            fn fold_term(&mut self, t: Term) -> Term {
+                match t.value() {
+                    Value::Variable(v) | Value::RestVariable(v) => {
+                        let v = v.clone();
+                        self.substitute(t, &v)
+                    }
+                    _ => fold_term(t, self),
+                }
+            }
+
+            fn fold_list(&mut self, l: TermList) -> TermList {
+                let rest = has_rest_var(&l);
+                let mut list = fold_list(l, self);
+                if rest {
+                    if let Some(Value::List(tail)) = list.last().map(|t| t.value().clone()) {
+                        list.pop();
+                        list.extend(tail);
+                    }
+                }
+                list
+            }
+        }
+
+        Substituter {
+            bindings,
+            expanding: vec![],
+        }
+        .fold_term(self.clone())
+    }
+
     pub fn hash_value(&self) -> u64 {
         let mut hasher = DefaultHasher::new();
         self.hash(&mut hasher);
@@ -390,4 +646,44 @@ mod tests {
             "b:2"
         );
     }
+
+    #[test]
+    fn test_apply_bindings() {
+        let var = |name: &str| term!(sym!(name));
+        let list = |terms: Vec<Term>| term!(Value::List(terms));
+        let dict = |key: &str, value: Term| term!(btreemap! {sym!(key) => value});
+
+        let term = list(vec![
+            var("x"),
+            dict("a", var("y")),
+            term!(Value::RestVariable(sym!("rest"))),
+        ]);
+        let vars = term.free_variables();
+        assert_eq!(vars.len(), 3);
+        assert!(vars.contains(&sym!("rest")));
+        assert!(term.has_free_variables());
+
+        let mut bindings = Bindings::new();
+        bindings.insert(sym!("x"), term!(1));
+        bindings.insert(sym!("y"), list(vec![var("x")]));
+        bindings.insert(sym!("rest"), term!([2, 3]));
+        let applied = term.apply(&bindings);
+        assert_eq!(
+            applied,
+            list(vec![
+                term!(1),
+                dict("a", list(vec![term!(1)])),
+                term!(2),
+                term!(3)
+            ])
+        );
+        assert!(!applied.has_free_variables());
+
+        // Unbound variables are left in place, and
+        // self-referential bindings don't loop.
+        let mut bindings = Bindings::new();
+        bindings.insert(sym!("x"), list(vec![var("x")]));
+        assert_eq!(var("x").apply(&bindings), list(vec![var("x")]));
+        assert_eq!(var("z").apply(&bindings), var("z"));
+    }
 }
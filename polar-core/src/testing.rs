@@ -0,0 +1,153 @@
+//! [proptest](https://docs.rs/proptest) strategies for generating Polar
+//! terms, rules and programs.
+//!
+//! Everything generated here prints with [`ToPolarString`] to source that
+//! parses back to an equal value, so these strategies can drive round-trip
+//! tests (parse → print → parse) as well as VM invariants.
+//!
+//! Only available with the `testing` feature.
+//!
+//! [`ToPolarString`]: crate::formatting::ToPolarString
+
+use proptest::collection::{btree_map, vec};
+use proptest::prelude::*;
+
+use crate::rules::{Mode, Parameter, Rule};
+use crate::terms::*;
+
+/// Words the lexer treats specially, which can't be used as names.
+const RESERVED: &[&str] = &[
+    "and", "cut", "debug", "false", "forall", "if", "in", "inf", "isa", "match", "matches", "mod",
+    "nan", "new", "nil", "not", "or", "print", "rem", "true", "when",
+];
+
+/// A lowercase identifier usable as a variable, predicate or field name.
+pub fn arb_symbol() -> impl Strategy<Value = Symbol> {
+    "[a-z][a-z0-9_]{0,7}"
+        .prop_filter("reserved word", |s| !RESERVED.contains(&s.as_str()))
+        .prop_map(Symbol)
+}
+
+/// A variable term.
+pub fn arb_variable() -> impl Strategy<Value = Term> {
+    arb_symbol().prop_map(|name| Term::new_from_test(Value::Variable(name)))
+}
+
+/// A ground scalar: a non-negative integer, a short string, which may need
+/// escaping, a boolean or `nil`.
+pub fn arb_scalar() -> impl Strategy<Value = Term> {
+    prop_oneof![
+        (0..i64::MAX).prop_map(|i| Term::new_from_test(value!(i))),
+        r#"[a-zA-Z0-9 "\\\n\té💯]{0,12}"#.prop_map(|s| Term::new_from_test(Value::String(s))),
+        any::<bool>().prop_map(|b| Term::new_from_test(value!(b))),
+        Just(Term::new_from_test(Value::Nil)),
+    ]
+}
+
+/// A term built from scalars and variables, nested in lists, sets, tuples,
+/// ranges, dictionaries and calls up to `depth` levels deep.
+pub fn arb_term_with_depth(depth: u32) -> impl Strategy<Value = Term> {
+    let leaf = prop_oneof![arb_scalar(), arb_variable()];
+    leaf.prop_recursive(depth, 32, 4, |inner| {
+        prop_oneof![
+            vec(inner.clone(), 0..4).prop_map(|terms| Term::new_from_test(Value::List(terms))),
+            vec(inner.clone(), 1..4)
+                .prop_map(|terms| Term::new_from_test(Value::Set(Set::new(terms)))),
+            vec(inner.clone(), 1..4).prop_map(|terms| Term::new_from_test(Value::Tuple(terms))),
+            (
+                prop_oneof![arb_scalar(), arb_variable()],
+                prop_oneof![arb_scalar(), arb_variable()]
+            )
+                .prop_map(|(start, end)| Term::new_from_test(Value::Range(Range { start, end }))),
+            btree_map(arb_symbol(), inner.clone(), 0..4).prop_map(|fields| {
+                Term::new_from_test(Value::Dictionary(Dictionary {
+                    fields: fields.into_iter().collect(),
+                }))
+            }),
+            (arb_symbol(), vec(inner, 0..4)).prop_map(|(name, args)| {
+                Term::new_from_test(Value::Call(Call {
+                    name,
+                    args,
+                    kwargs: None,
+                }))
+            }),
+        ]
+    })
+}
+
+/// A term nested at most three levels deep.
+pub fn arb_term() -> impl Strategy<Value = Term> {
+    arb_term_with_depth(3)
+}
+
+/// A single condition in a rule body: a predicate call, a unification or
+/// a comparison.
+pub fn arb_condition() -> impl Strategy<Value = Term> {
+    let operand = || prop_oneof![arb_scalar(), arb_variable()];
+    let operator = prop_oneof![
+        Just(Operator::Unify),
+        Just(Operator::Eq),
+        Just(Operator::Neq),
+        Just(Operator::Lt),
+        Just(Operator::Leq),
+        Just(Operator::Gt),
+        Just(Operator::Geq),
+    ];
+    prop_oneof![
+        (arb_symbol(), vec(arb_term(), 0..4)).prop_map(|(name, args)| {
+            Term::new_from_test(Value::Call(Call {
+                name,
+                args,
+                kwargs: None,
+            }))
+        }),
+        (operator, operand(), operand()).prop_map(|(operator, left, right)| {
+            Term::new_from_test(Value::Expression(Operation {
+                operator,
+                args: vec![left, right],
+            }))
+        }),
+    ]
+}
+
+/// A rule parameter without a specializer, and with a mode if it's a
+/// variable.
+pub fn arb_parameter() -> impl Strategy<Value = Parameter> {
+    let mode = prop_oneof![Just(None), Just(Some(Mode::In)), Just(Some(Mode::Out))];
+    prop_oneof![
+        (arb_variable(), mode).prop_map(|(parameter, mode)| Parameter {
+            parameter,
+            specializer: None,
+            mode,
+        }),
+        arb_scalar().prop_map(|parameter| Parameter {
+            parameter,
+            specializer: None,
+            mode: None,
+        }),
+    ]
+}
+
+/// A rule whose body is a conjunction of up to four conditions.
+pub fn arb_rule() -> impl Strategy<Value = Rule> {
+    (
+        arb_symbol(),
+        vec(arb_parameter(), 0..4),
+        vec(arb_condition(), 0..4),
+    )
+        .prop_map(|(name, params, conditions)| Rule {
+            name,
+            params,
+            guard: None,
+            body: Term::new_from_test(Value::Expression(Operation {
+                operator: Operator::And,
+                args: conditions,
+            })),
+            annotations: vec![],
+        })
+}
+
+/// A program of up to `max_rules` rules.
+pub fn arb_program(max_rules: usize) -> impl Strategy<Value = Vec<Rule>> {
+    vec(arb_rule(), 0..=max_rules)
+}
@@ -31,3 +31,188 @@ pub struct TraceResult {
     pub trace: Rc<Trace>,
     pub formatted: String,
 }
+
+/// The rules that justified a result: the head of each rule applied, with
+/// the arguments it was called with, e.g.,
+///
+/// ```text
+/// allow(User{name: "alice"}, "read", Doc{id: 1})
+///   has_role(User{name: "alice"}, "reader", Doc{id: 1})
+/// ```
+///
+/// Much smaller than a `Trace`, which has every goal and rule body, so cheap
+/// enough to keep for every decision.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Proof {
+    /// Rule applications, each before the ones its body made.
+    pub steps: Vec<ProofStep>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ProofStep {
+    pub name: Symbol,
+    /// The arguments of the call the rule was applied to, as bound when
+    /// the result was found.
+    pub args: Vec<Term>,
+    /// Number of rule applications this one is nested in.
+    pub depth: usize,
+}
+
+impl Proof {
+    /// The proof of a result from its trace, with the calls' arguments
+    /// dereferenced by `vm`.
+    pub fn from_trace(trace: &Trace, vm: &crate::vm::PolarVirtualMachine) -> Self {
+        let mut proof = Proof::default();
+        proof.visit(trace, None, 0, vm);
+        proof
+    }
+
+    fn visit(
+        &mut self,
+        trace: &Trace,
+        call: Option<&Call>,
+        depth: usize,
+        vm: &crate::vm::PolarVirtualMachine,
+    ) {
+        let (call, depth) = match &trace.node {
+            Node::Term(term) => match term.value() {
+                Value::Call(call) => (Some(call), depth),
+                _ => (call, depth),
+            },
+            Node::Rule(rule) => {
+                let args = match call {
+                    Some(call) if call.name == rule.name => {
+                        call.args.iter().map(|arg| vm.deep_deref(arg)).collect()
+                    }
+                    _ => rule.params.iter().map(|p| p.parameter.clone()).collect(),
+                };
+                self.steps.push(ProofStep {
+                    name: rule.name.clone(),
+                    args,
+                    depth,
+                });
+                (None, depth + 1)
+            }
+        };
+        for child in &trace.children {
+            self.visit(child, call, depth, vm);
+        }
+    }
+}
+
+impl std::fmt::Display for Proof {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for step in &self.steps {
+            let args = step
+                .args
+                .iter()
+                .map(|arg| arg.to_polar())
+                .collect::<Vec<_>>()
+                .join(", ");
+            writeln!(f, "{}{}({})", "  ".repeat(step.depth), step.name, args)?;
+        }
+        Ok(())
+    }
+}
+
+/// A structured view of a query's goal stack and choice points at a point in time.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct StackSnapshot {
+    /// Pending goals, in the order they will be run.
+    pub goals: Vec<String>,
+    /// Queries currently being evaluated, outermost first.
+    pub queries: Vec<Term>,
+    /// Choice points, oldest first.
+    pub choices: Vec<ChoiceSnapshot>,
+}
+
+/// A choice point: what the VM will resume with when it next backtracks.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ChoiceSnapshot {
+    /// Number of alternatives not yet tried.
+    pub alternatives: usize,
+    /// Names of the rules those alternatives will apply, if they are rule applications.
+    pub rules: Vec<Symbol>,
+    /// Goals that run after the chosen alternative, in the order they will be run.
+    pub goals: Vec<String>,
+}
+
+/// A goal executed by the VM, as reported to a `GoalSink`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct GoalRecord {
+    /// The kind of goal, e.g., `Query` or `Unify`.
+    pub kind: String,
+    /// The goal, formatted.
+    pub summary: String,
+    /// Number of queries being evaluated when the goal ran.
+    pub depth: usize,
+    /// Microseconds since goal dumping started.
+    pub timestamp_us: u64,
+}
+
+/// Receives every goal a query executes, for performance analysis.
+pub trait GoalSink {
+    fn goal(&mut self, record: GoalRecord);
+}
+
+impl<F: FnMut(GoalRecord)> GoalSink for F {
+    fn goal(&mut self, record: GoalRecord) {
+        self(record)
+    }
+}
+
+/// A goal executed by the VM, as reported to the hook set with
+/// `Polar::set_instrumentation`. Cheaper to build than a `GoalRecord`,
+/// since nothing is formatted.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GoalSummary<'a> {
+    /// The kind of goal, e.g., `Query` or `Unify`.
+    pub kind: &'static str,
+    /// The innermost rule being evaluated when the goal ran, if any.
+    pub rule: Option<&'a Symbol>,
+    /// Number of queries being evaluated when the goal ran.
+    pub depth: usize,
+}
+
+/// Called with every goal executed by every query of a `Polar`.
+pub type Instrumentation = Arc<dyn Fn(&GoalSummary) + Send + Sync>;
+
+/// What a query decided, as reported to a `DecisionLogger` once the query
+/// is done, fails with an error, or is dropped.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Decision {
+    /// The query, as written.
+    pub query: String,
+    /// Number of results returned; an authorization query allows if any.
+    pub results: usize,
+    /// Whether the query ran to the end, rather than being dropped early,
+    /// e.g., after its first result.
+    pub completed: bool,
+    /// The error that ended the query, if any.
+    pub error: Option<String>,
+    /// Version of the policy the query was evaluated against, if it has one.
+    pub policy_version: Option<String>,
+    pub duration_ms: u64,
+    /// The rules that justified the last result, if any, with the arguments
+    /// they were called with when it was found.
+    pub proof: Option<Proof>,
+    /// How the last result was derived, formatted, if the logger wants traces.
+    pub trace: Option<String>,
+}
+
+/// Records the decision of every query of a `Polar`, e.g., for an audit log.
+pub trait DecisionLogger: Send + Sync {
+    fn log(&self, decision: &Decision);
+
+    /// Whether decisions should include full traces, which are formatted
+    /// for every query.
+    fn wants_traces(&self) -> bool {
+        false
+    }
+}
+
+impl<F: Fn(&Decision) + Send + Sync> DecisionLogger for F {
+    fn log(&self, decision: &Decision) {
+        self(decision)
+    }
+}
@@ -89,6 +89,9 @@ macro_rules! walk_fields {
 pub fn walk_rule<V: Visitor>(visitor: &mut V, rule: &Rule) {
     visitor.visit_symbol(&rule.name);
     walk_elements!(visitor, visit_param, &rule.params);
+    if let Some(guard) = &rule.guard {
+        visitor.visit_term(guard);
+    }
     visitor.visit_term(&rule.body);
 }
 
@@ -97,11 +100,18 @@ pub fn walk_term<V: Visitor>(visitor: &mut V, term: &Term) {
         Value::Number(n) => visitor.visit_number(n),
         Value::String(s) => visitor.visit_string(s),
         Value::Boolean(b) => visitor.visit_boolean(b),
+        Value::DateTime(_) | Value::Nil => {}
         Value::ExternalInstance(e) => visitor.visit_external_instance(e),
         Value::Dictionary(d) => visitor.visit_dictionary(d),
         Value::Pattern(p) => visitor.visit_pattern(p),
         Value::Call(c) => visitor.visit_call(c),
         Value::List(l) => visitor.visit_list(l),
+        Value::Set(s) => visitor.visit_list(&s.elements),
+        Value::Tuple(t) => visitor.visit_list(t),
+        Value::Range(Range { start, end }) => {
+            visitor.visit_term(start);
+            visitor.visit_term(end);
+        }
         Value::Variable(v) => visitor.visit_variable(v),
         Value::RestVariable(r) => visitor.visit_rest_variable(r),
         Value::Expression(o) => visitor.visit_operation(o),
@@ -231,18 +241,19 @@ mod tests {
             instance_id: 1,
             constructor: None,
             repr: None,
+            class_tag: None,
         }));
         let instance_pattern = term!(value!(Pattern::Instance(InstanceLiteral {
             tag: sym!("d"),
             fields: Dictionary {
-                fields: btreemap! {
+                fields: indexmap! {
                     sym!("e") => term!(call!("f", [2])),
                     sym!("g") => term!(op!(Add, term!(3), term!(4))),
                 }
             }
         })));
         let dict_pattern = term!(Value::Pattern(Pattern::Dictionary(Dictionary {
-            fields: btreemap! {
+            fields: indexmap! {
                 sym!("i") => term!("j"),
                 sym!("k") => term!("l"),
             },
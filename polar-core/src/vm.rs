@@ -1,31 +1,39 @@
-use std::cell::RefCell;
-use std::collections::BTreeMap;
+use std::cell::{Cell, RefCell};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet};
 use std::collections::{HashMap, HashSet};
 use std::fmt::Write;
 use std::rc::Rc;
 use std::string::ToString;
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, PoisonError, RwLock};
+
+use serde::{Deserialize, Serialize};
 
 #[cfg(target_arch = "wasm32")]
 use wasm_bindgen::prelude::*;
 
-use super::visitor::{walk_term, Visitor};
+use super::visitor::{walk_operation, walk_term, Visitor};
 use crate::bindings::{BindingManager, BindingStack, Bindings, Bsp, FollowerId, VariableState};
+use crate::builtins::{builtin, Builtin};
+use crate::collector::Collector;
 use crate::counter::Counter;
 use crate::debugger::{DebugEvent, Debugger};
 use crate::error::{self, PolarResult};
 use crate::events::*;
+use crate::facts::{FactSource, FactSources};
 use crate::folder::Folder;
-use crate::formatting::ToPolarString;
+use crate::formatting::{format_annotations, ToPolarString};
 use crate::inverter::Inverter;
 use crate::kb::*;
-use crate::lexer::loc_to_pos;
 use crate::messages::*;
+use crate::metrics::RuleMetrics;
 use crate::numerics::*;
 use crate::partial::{simplify_bindings, simplify_partial, sub_this, IsaConstraintCheck};
+use crate::replay::is_external;
 use crate::rewrites::Renamer;
 use crate::rules::*;
 use crate::runnable::Runnable;
+use crate::sandbox::SandboxConfig;
 use crate::sources::*;
 use crate::terms::*;
 use crate::traces::*;
@@ -36,7 +44,9 @@ pub const QUERY_TIMEOUT_S: std::time::Duration = std::time::Duration::from_secs(
 #[cfg(target_arch = "wasm32")]
 pub const QUERY_TIMEOUT_S: f64 = 30_000.0;
 
-#[derive(Debug, Clone)]
+/// Goals serialize for suspending queries, except those holding runnables,
+/// builtins, or state shared with them, which can't be.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[must_use = "ignored goals are never accomplished"]
 #[allow(clippy::large_enum_variant)]
 pub enum Goal {
@@ -73,6 +83,15 @@ pub enum Goal {
         instance: Term,
         field: Term,
     },
+    LookupMany {
+        call_id: u64,
+        instance: Term,
+        attributes: Vec<Symbol>,
+    },
+    /// Ask the host for the ground lookups among a conjunction's conjuncts.
+    LookupConcurrent {
+        lookups: TermList,
+    },
     IsaExternal {
         instance: Term,
         literal: InstanceLiteral,
@@ -101,24 +120,57 @@ pub enum Goal {
         args: TermList,
         applicable_rules: Rules,
         unfiltered_rules: Rules,
+        dispatch: Option<Rc<Dispatch>>,
     },
     SortRules {
         args: TermList,
         rules: Rules,
         outer: usize,
         inner: usize,
+        dispatch: Option<Rc<Dispatch>>,
     },
     TraceRule {
         trace: Rc<Trace>,
     },
     TraceStackPush,
     TraceStackPop,
+    /// Start enforcing the `@budget` of a rule being entered.
+    PushBudget {
+        rule: Symbol,
+        budget: Budget,
+    },
+    /// Stop enforcing the innermost budget, once its rule is done.
+    PopBudget,
+    /// Count a rule as tried, or as having succeeded, in the rule metrics.
+    CountRule {
+        rule: Arc<Rule>,
+        success: bool,
+    },
+    /// Remember whether a ground call to a `@cached` predicate succeeded.
+    Memoize {
+        call: Call,
+        generation: u64,
+        success: bool,
+    },
+    /// Bind `item` to `start`, or on backtracking to each later integer up
+    /// to `end`, with one choice point at a time.
+    EnumerateRange {
+        item: Term,
+        range: Term,
+        start: i64,
+        end: i64,
+    },
+    /// Query for the best conjunct to run next, then schedule the rest.
+    ScheduleConjuncts {
+        conjuncts: TermList,
+    },
     Unify {
         left: Term,
         right: Term,
     },
 
     /// Run the `runnable`.
+    #[serde(skip)]
     Run {
         runnable: Box<dyn Runnable>,
     },
@@ -130,12 +182,32 @@ pub enum Goal {
 
     /// TODO hack.
     /// Add a new constraint
+    #[serde(skip)]
     AddConstraintsBatch {
         add_constraints: Rc<RefCell<Bindings>>,
     },
+
+    /// Run a builtin once the goals before it have bound its arguments.
+    #[serde(skip)]
+    CallBuiltin {
+        builtin: Builtin,
+        args: TermList,
+    },
+
+    /// Unify `term` with the list of values gathered by a `Collector`.
+    #[serde(skip)]
+    UnifyCollected {
+        collected: Rc<RefCell<TermList>>,
+        term: Term,
+    },
+
+    /// Emit a warning message, unless the query already has.
+    Warn {
+        message: String,
+    },
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Choice {
     pub alternatives: Vec<GoalStack>,
     bsp: Bsp,              // binding stack pointer
@@ -143,6 +215,7 @@ pub struct Choice {
     queries: Queries,      // query stack snapshot
     trace: Vec<Rc<Trace>>, // trace snapshot
     trace_stack: TraceStack,
+    budgets: Vec<ActiveBudget>, // budget stack snapshot
 }
 
 pub type Choices = Vec<Choice>;
@@ -150,7 +223,119 @@ pub type Choices = Vec<Choice>;
 pub type Goals = Vec<Goal>;
 pub type TraceStack = Vec<Rc<Vec<Rc<Trace>>>>;
 
-#[derive(Clone, Debug, Default)]
+/// The budget of a rule being run, and how far into the query it was
+/// entered.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ActiveBudget {
+    id: u64,
+    rule: Symbol,
+    budget: Budget,
+    goals_at_entry: u64,
+    ms_at_entry: u64,
+    /// The goals executed and ms elapsed when the rule returned, if it has,
+    /// so that backtracking into it doesn't charge it for the work done
+    /// since.
+    paused_at: Option<(u64, u64)>,
+}
+
+impl Goal {
+    /// The name of the goal's variant.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Goal::Backtrack => "Backtrack",
+            Goal::Cut { .. } => "Cut",
+            Goal::Debug { .. } => "Debug",
+            Goal::Halt => "Halt",
+            Goal::Isa { .. } => "Isa",
+            Goal::IsMoreSpecific { .. } => "IsMoreSpecific",
+            Goal::IsSubspecializer { .. } => "IsSubspecializer",
+            Goal::Lookup { .. } => "Lookup",
+            Goal::LookupExternal { .. } => "LookupExternal",
+            Goal::LookupMany { .. } => "LookupMany",
+            Goal::LookupConcurrent { .. } => "LookupConcurrent",
+            Goal::IsaExternal { .. } => "IsaExternal",
+            Goal::MakeExternal { .. } => "MakeExternal",
+            Goal::NextExternal { .. } => "NextExternal",
+            Goal::UnifyExternal { .. } => "UnifyExternal",
+            Goal::CheckError => "CheckError",
+            Goal::Noop => "Noop",
+            Goal::Query { .. } => "Query",
+            Goal::PopQuery { .. } => "PopQuery",
+            Goal::FilterRules { .. } => "FilterRules",
+            Goal::SortRules { .. } => "SortRules",
+            Goal::TraceRule { .. } => "TraceRule",
+            Goal::TraceStackPush => "TraceStackPush",
+            Goal::TraceStackPop => "TraceStackPop",
+            Goal::PushBudget { .. } => "PushBudget",
+            Goal::PopBudget => "PopBudget",
+            Goal::CountRule { .. } => "CountRule",
+            Goal::Memoize { .. } => "Memoize",
+            Goal::EnumerateRange { .. } => "EnumerateRange",
+            Goal::ScheduleConjuncts { .. } => "ScheduleConjuncts",
+            Goal::Unify { .. } => "Unify",
+            Goal::Run { .. } => "Run",
+            Goal::AddConstraint { .. } => "AddConstraint",
+            Goal::AddConstraintsBatch { .. } => "AddConstraintsBatch",
+            Goal::CallBuiltin { .. } => "CallBuiltin",
+            Goal::UnifyCollected { .. } => "UnifyCollected",
+            Goal::Warn { .. } => "Warn",
+        }
+    }
+}
+
+/// How often to report progress, and when the query started.
+struct ProgressClock {
+    interval: u64,
+    #[cfg(not(target_arch = "wasm32"))]
+    started: std::time::Instant,
+    #[cfg(target_arch = "wasm32")]
+    started: f64,
+}
+
+impl ProgressClock {
+    fn elapsed_ms(&self) -> u64 {
+        #[cfg(not(target_arch = "wasm32"))]
+        let elapsed_ms = self.started.elapsed().as_millis() as u64;
+        #[cfg(target_arch = "wasm32")]
+        let elapsed_ms = (js_sys::Date::now() - self.started) as u64;
+        elapsed_ms
+    }
+}
+
+/// A host's `GoalSink`, and when it started receiving goals.
+struct GoalDump {
+    sink: Rc<RefCell<dyn GoalSink>>,
+    #[cfg(not(target_arch = "wasm32"))]
+    started: std::time::Instant,
+    #[cfg(target_arch = "wasm32")]
+    started: f64,
+}
+
+impl GoalDump {
+    fn record(&self, goal: &Goal, depth: usize) {
+        #[cfg(not(target_arch = "wasm32"))]
+        let timestamp_us = self.started.elapsed().as_micros() as u64;
+        #[cfg(target_arch = "wasm32")]
+        let timestamp_us = ((js_sys::Date::now() - self.started) * 1_000.0) as u64;
+        self.sink.borrow_mut().goal(GoalRecord {
+            kind: goal.kind().to_owned(),
+            summary: goal.to_string(),
+            depth,
+            timestamp_us,
+        });
+    }
+}
+
+/// Where to cache the rules a call turns out to dispatch to, once they're
+/// filtered and sorted.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Dispatch {
+    name: Symbol,
+    key: DispatchKey,
+    generation: u64,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct GoalStack(Vec<Rc<Goal>>);
 
 impl GoalStack {
@@ -175,8 +360,178 @@ impl std::ops::DerefMut for GoalStack {
 
 pub type Queries = TermList;
 
-// TODO(ap): don't panic.
+/// State of a dry run, in which external calls are recorded instead of being
+/// sent to the host, and answered with placeholder "unknown" instances.
+#[derive(Debug, Default)]
+struct DryRun {
+    /// Lookups recorded so far, in the order they were first made.
+    lookups: Vec<ExternalLookup>,
+    /// Instance ID of each unknown → the receiver and path of lookups it stands for.
+    unknowns: HashMap<u64, (Term, Vec<Symbol>)>,
+    /// Call IDs of iterations that have already produced their unknown element.
+    iterated: HashSet<u64>,
+}
+
+/// An external lookup, identified by the instance it's made on, the attribute,
+/// and any arguments.
+type CallKey = (
+    Term,
+    Symbol,
+    Option<Vec<Term>>,
+    Option<BTreeMap<Symbol, Term>>,
+);
+
+/// Results of external lookups made during a query, so that backtracking
+/// doesn't ask the host for the same lookup again.
+#[derive(Debug, Default)]
+struct CallCache {
+    /// Lookup → the host's answer, `None` if it had no result.
+    results: HashMap<CallKey, Option<Term>>,
+    /// Call ID → the lookup it's waiting on an answer for.
+    pending: HashMap<u64, CallKey>,
+    /// Call ID → the instance and attributes of a bulk lookup awaiting an answer.
+    pending_many: HashMap<u64, (Term, Vec<Symbol>)>,
+    /// Call IDs of lookups asked for ahead of time by an `ExternalCalls`
+    /// event, whose answers only go in the cache.
+    concurrent: HashSet<u64>,
+    /// Instance ID → fields the host snapshotted, which answer lookups of
+    /// those attributes without asking it.
+    snapshots: HashMap<u64, BTreeMap<Symbol, Term>>,
+}
+
+/// The state of a suspended VM: everything needed to resume it, with a
+/// knowledge base holding the same rules, in this or another process.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SuspendedVm {
+    goals: GoalStack,
+    binding_manager: BindingManager,
+    choices: Choices,
+    queries: Queries,
+    tracing: bool,
+    trace_stack: TraceStack,
+    trace: Vec<Rc<Trace>>,
+    budgets: Vec<ActiveBudget>,
+    csp: Bsp,
+    call_id_symbols: HashMap<u64, Symbol>,
+    attribute_calls: HashSet<u64>,
+    query_constants: Bindings,
+    query_vars: HashSet<Symbol>,
+    query_contains_partial: bool,
+    inverting: bool,
+    external_error: Option<String>,
+    /// The contents of the `CallCache`.
+    cached_calls: Vec<(CallKey, Option<Term>)>,
+    pending_calls: HashMap<u64, CallKey>,
+    pending_lookups: HashMap<u64, (Term, Vec<Symbol>)>,
+    concurrent_calls: HashSet<u64>,
+    instance_snapshots: HashMap<u64, BTreeMap<Symbol, Term>>,
+    warned: HashSet<String>,
+    goals_executed: u64,
+    choices_created: u64,
+    peak_choice_points: usize,
+    policy_version: Option<String>,
+    /// An ID greater than any the query has used, so that resuming it doesn't
+    /// reuse call or instance IDs.
+    next_id: u64,
+}
+
+/// The variable a lookup or arithmetic operation outputs to, its last
+/// argument.
+fn output_var(term: &Term) -> Option<Symbol> {
+    match term.value() {
+        Value::Expression(Operation {
+            operator:
+                Operator::Dot
+                | Operator::Add
+                | Operator::Sub
+                | Operator::Mul
+                | Operator::Div
+                | Operator::Mod
+                | Operator::Rem,
+            args,
+        }) if args.len() == 3 => match args[2].value() {
+            Value::Variable(var) => Some(var.clone()),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Modes of the parameters of the rules `call` would apply in `kbs`, with
+/// `None` for those without one.
+fn call_modes(kbs: &[&KnowledgeBase], call: &Call) -> Vec<Option<Mode>> {
+    let arity = call.args.len();
+    let mut modes = vec![None; arity];
+    for kb in kbs {
+        let declared = kb
+            .rules
+            .get(&call.name)
+            .and_then(|generic_rule| generic_rule.modes(arity));
+        for (mode, declared) in modes.iter_mut().zip(declared.unwrap_or_default()) {
+            if *declared == Some(Mode::In) || mode.is_none() {
+                *mode = *declared;
+            }
+        }
+    }
+    modes
+}
+
+/// Whether `call` runs a builtin, which it does only if there are no rules
+/// by the same name in `kbs`.
+fn calls_builtin(kbs: &[&KnowledgeBase], call: &Call) -> bool {
+    builtin(&call.name, call.args.len()).is_some()
+        && !kbs.iter().any(|kb| kb.rules.contains_key(&call.name))
+}
+
+/// Goals that run `conjuncts` in the order `schedule_conjuncts` picks, except
+/// for those whose effect depends on where they're written, e.g., `cut` and
+/// `print`, which stay put: only the conjuncts between them are reordered.
+fn conjunct_segments(conjuncts: TermList) -> Goals {
+    let mut goals = vec![];
+    let mut segment = vec![];
+    let flush = |segment: &mut TermList, goals: &mut Goals| match segment.len() {
+        0 => (),
+        1 => goals.push(Goal::Query {
+            term: segment.pop().unwrap(),
+        }),
+        _ => goals.push(Goal::ScheduleConjuncts {
+            conjuncts: std::mem::take(segment),
+        }),
+    };
+    for conjunct in conjuncts {
+        let fixed = matches!(
+            conjunct.value(),
+            Value::Expression(Operation {
+                operator: Operator::Cut
+                    | Operator::Print
+                    | Operator::Debug
+                    | Operator::Assign
+                    | Operator::New,
+                ..
+            })
+        );
+        if fixed {
+            flush(&mut segment, &mut goals);
+            goals.push(Goal::Query { term: conjunct });
+        } else {
+            segment.push(conjunct);
+        }
+    }
+    flush(&mut segment, &mut goals);
+    goals
+}
+
 pub fn compare(op: Operator, left: &Term, right: &Term) -> PolarResult<bool> {
+    compare_collated(op, left, right, StringCollation::Binary)
+}
+
+/// Like `compare`, but order strings by `collation`.
+pub fn compare_collated(
+    op: Operator,
+    left: &Term,
+    right: &Term,
+    collation: StringCollation,
+) -> PolarResult<bool> {
     // Coerce booleans to integers.
     fn to_int(x: bool) -> Numeric {
         if x {
@@ -186,24 +541,48 @@ pub fn compare(op: Operator, left: &Term, right: &Term) -> PolarResult<bool> {
         }
     }
 
-    fn compare<T: PartialOrd>(op: Operator, left: T, right: T) -> bool {
-        match op {
+    fn not_comparison(op: Operator) -> error::PolarError {
+        let msg = format!("`{}` is not a comparison operator", op.to_polar());
+        error::OperationalError::InvalidState(msg).into()
+    }
+
+    fn compare<T: PartialOrd>(op: Operator, left: T, right: T) -> PolarResult<bool> {
+        Ok(match op {
             Operator::Lt => left < right,
             Operator::Leq => left <= right,
             Operator::Gt => left > right,
             Operator::Geq => left >= right,
             Operator::Eq => left == right,
             Operator::Neq => left != right,
-            _ => panic!("`{}` is not a comparison operator", op.to_polar()),
-        }
+            _ => return Err(not_comparison(op)),
+        })
     }
 
     match (left.value(), right.value()) {
-        (Value::Boolean(l), Value::Boolean(r)) => Ok(compare(op, &to_int(*l), &to_int(*r))),
-        (Value::Boolean(l), Value::Number(r)) => Ok(compare(op, &to_int(*l), r)),
-        (Value::Number(l), Value::Boolean(r)) => Ok(compare(op, l, &to_int(*r))),
-        (Value::Number(l), Value::Number(r)) => Ok(compare(op, l, r)),
-        (Value::String(l), Value::String(r)) => Ok(compare(op, l, r)),
+        (Value::Boolean(l), Value::Boolean(r)) => compare(op, &to_int(*l), &to_int(*r)),
+        (Value::Boolean(l), Value::Number(r)) => compare(op, &to_int(*l), r),
+        (Value::Number(l), Value::Boolean(r)) => compare(op, l, &to_int(*r)),
+        (Value::Number(l), Value::Number(r)) => compare(op, l, r),
+        (Value::DateTime(l), Value::DateTime(r)) => compare(op, l, r),
+        // Only `nil` equals `nil`.
+        (Value::Nil, _) | (_, Value::Nil) if matches!(op, Operator::Eq | Operator::Neq) => {
+            let both = left.value() == right.value();
+            Ok(if op == Operator::Eq { both } else { !both })
+        }
+        (Value::String(l), Value::String(r)) => match op {
+            Operator::Eq | Operator::Neq => compare(op, l, r),
+            _ => compare(op, collation.compare(l, r), Ordering::Equal),
+        },
+        // Sets are ordered by inclusion.
+        (Value::Set(l), Value::Set(r)) => Ok(match op {
+            Operator::Eq => l == r,
+            Operator::Neq => l != r,
+            Operator::Leq => l.is_subset(r),
+            Operator::Lt => l.is_subset(r) && l != r,
+            Operator::Geq => r.is_subset(l),
+            Operator::Gt => r.is_subset(l) && l != r,
+            _ => return Err(not_comparison(op)),
+        }),
         _ => Err(error::RuntimeError::Unsupported {
             msg: format!("{} {} {}", left.to_polar(), op.to_polar(), right.to_polar()),
         }
@@ -211,6 +590,11 @@ pub fn compare(op: Operator, left: &Term, right: &Term) -> PolarResult<bool> {
     }
 }
 
+/// Error for an answer to a call the VM isn't waiting on.
+fn unknown_call_id(call_id: u64) -> error::PolarError {
+    error::OperationalError::InvalidState(format!("Unexpected call id {}", call_id)).into()
+}
+
 #[derive(Clone)]
 pub struct PolarVirtualMachine {
     /// Stacks.
@@ -221,7 +605,10 @@ pub struct PolarVirtualMachine {
 
     pub tracing: bool,
     pub trace_stack: TraceStack, // Stack of traces higher up the tree.
+    budgets: Vec<ActiveBudget>,  // Budgets of the rules being run, innermost last.
     pub trace: Vec<Rc<Trace>>,   // Traces for the current level of the trace tree.
+    /// The derivation of the latest result, kept whether or not tracing.
+    last_result_trace: Option<Rc<Trace>>,
 
     // Errors from outside the vm.
     pub external_error: Option<String>,
@@ -238,6 +625,16 @@ pub struct PolarVirtualMachine {
     /// Maximum size of goal stack
     stack_limit: usize,
 
+    /// Most goals to execute, shared with any sub-VMs, and bindings to hold,
+    /// if limited.
+    goal_limit: Option<u64>,
+    binding_limit: Option<usize>,
+
+    /// Whether `debug()` emits `Debug` events, and whether to ask the host
+    /// questions; both off when sandboxed.
+    debug_events: bool,
+    host_calls: bool,
+
     /// Binding stack constant below here.
     csp: usize,
 
@@ -245,10 +642,18 @@ pub struct PolarVirtualMachine {
     pub debugger: Debugger,
 
     /// Rules and types.
-    pub kb: Arc<RwLock<KnowledgeBase>>,
+    pub kb: SharedKb,
+
+    /// The knowledge base's counters for IDs and fresh variables, which
+    /// don't need its lock to use.
+    id_counter: Counter,
+    gensym_counter: Counter,
 
     /// Call ID -> result variable name table.
     call_id_symbols: HashMap<u64, Symbol>,
+    /// Call IDs of attribute lookups, which are `nil` if the host answers
+    /// them with no value, rather than failing.
+    attribute_calls: HashSet<u64>,
 
     /// Logging flag.
     log: bool,
@@ -256,18 +661,83 @@ pub struct PolarVirtualMachine {
     polar_log_stderr: bool,
     polar_log_mute: bool,
 
+    /// Constants bound for this query only, which rules see like the
+    /// knowledge base's constants.
+    query_constants: Bindings,
+
+    /// Variables of the top-level query. Negating them while unbound
+    /// constrains the query's results rather than being unsafe.
+    query_vars: HashSet<Symbol>,
+
     // Other flags.
     pub query_contains_partial: bool,
     pub inverting: bool,
 
     /// Output messages.
     pub messages: MessageQueue,
+
+    /// Dry-run state, shared with any sub-VMs.
+    dry_run: Option<Rc<RefCell<DryRun>>>,
+
+    /// Emit a `Heartbeat` event after every this many goals; 0 disables heartbeats.
+    heartbeat_interval: u64,
+
+    /// How strings are ordered by comparisons and `sort`.
+    pub string_collation: StringCollation,
+
+    /// Run the conjuncts of `and`s in the order their bindings make best,
+    /// rather than as written.
+    pub reorder_conjunctions: bool,
+
+    /// Emit `ExternalLookupMany` events when several attributes of one
+    /// instance are about to be looked up.
+    bulk_lookups: bool,
+
+    /// Emit `ExternalCalls` events for the ground lookups in conjunctions.
+    concurrent_calls: bool,
+
+    /// Goals executed so far, shared with any sub-VMs.
+    goals_executed: Rc<Cell<u64>>,
+
+    /// Choice points created so far, shared with any sub-VMs.
+    choices_created: Rc<Cell<u64>>,
+
+    /// The most choice points on any VM's stack at once, shared with any sub-VMs.
+    peak_choice_points: Rc<Cell<usize>>,
+
+    /// When to emit `Progress` events, if at all; shared with any sub-VMs.
+    progress: Option<Rc<ProgressClock>>,
+
+    /// External lookup results, shared with any sub-VMs.
+    call_cache: Rc<RefCell<CallCache>>,
+
+    /// Where to report every executed goal, if anywhere; shared with any sub-VMs.
+    goal_dump: Option<Rc<GoalDump>>,
+
+    /// Called with a summary of every executed goal, if set.
+    pub instrumentation: Option<Instrumentation>,
+
+    /// Where to count rule hits and successes, if the KB keeps metrics.
+    rule_metrics: Option<Arc<RuleMetrics>>,
+
+    /// Version of the policy when the query started, which its results are
+    /// stamped with even if the policy is reloaded while it runs.
+    policy_version: Option<String>,
+
+    /// Rules consulted along with, and before, those of `kb`.
+    overlay: Option<SharedKb>,
+
+    /// Host storage answering calls to some predicates, before any rules.
+    pub fact_sources: Arc<FactSources>,
+
+    /// Warnings already emitted, shared with any sub-VMs.
+    warned: Rc<RefCell<HashSet<String>>>,
 }
 
 impl Default for PolarVirtualMachine {
     fn default() -> Self {
         PolarVirtualMachine::new(
-            Arc::new(RwLock::new(KnowledgeBase::default())),
+            Arc::new(RwLock::new(KnowledgeBase::default())).into(),
             false,
             vec![],
             // Messages will not be exposed, only use default() for testing.
@@ -287,45 +757,91 @@ extern "C" {
 impl PolarVirtualMachine {
     /// Make a new virtual machine with an initial list of goals.
     /// Reverse the goal list for the sanity of callers.
-    pub fn new(
-        kb: Arc<RwLock<KnowledgeBase>>,
-        tracing: bool,
-        goals: Goals,
-        messages: MessageQueue,
-    ) -> Self {
-        let constants = kb
-            .read()
-            .expect("cannot acquire KB read lock")
-            .constants
-            .clone();
+    pub fn new(kb: SharedKb, tracing: bool, goals: Goals, messages: MessageQueue) -> Self {
+        let (constants, rule_metrics, policy_version, id_counter, gensym_counter) = {
+            // Copying these can't go wrong even if the lock is poisoned. Goals
+            // that read the rules fail instead.
+            let locked;
+            let kb: &KnowledgeBase = match &kb {
+                SharedKb::Locked(kb) => {
+                    locked = kb.read().unwrap_or_else(PoisonError::into_inner);
+                    &locked
+                }
+                SharedKb::Frozen { kb, .. } => kb,
+            };
+            (
+                kb.constants.clone(),
+                kb.rule_metrics.clone(),
+                kb.version.clone(),
+                kb.id_counter(),
+                kb.gensym_counter(),
+            )
+        };
         let mut vm = Self {
             goals: GoalStack::new_reversed(goals),
             binding_manager: BindingManager::new(),
             query_start_time: None,
             query_timeout: QUERY_TIMEOUT_S,
             stack_limit: MAX_STACK_SIZE,
+            goal_limit: None,
+            binding_limit: None,
+            debug_events: true,
+            host_calls: true,
             csp: 0,
             choices: vec![],
             queries: vec![],
             tracing,
             trace_stack: vec![],
+            budgets: vec![],
             trace: vec![],
+            last_result_trace: None,
             external_error: None,
             debugger: Debugger::default(),
             kb,
+            id_counter,
+            gensym_counter,
             call_id_symbols: HashMap::new(),
+            attribute_calls: HashSet::new(),
             log: std::env::var("RUST_LOG").is_ok(),
             polar_log: std::env::var("POLAR_LOG").is_ok(),
             polar_log_stderr: std::env::var("POLAR_LOG")
                 .map(|pl| pl == "now")
                 .unwrap_or(false),
             polar_log_mute: false,
+            query_constants: Bindings::new(),
+            query_vars: HashSet::new(),
             query_contains_partial: false,
             inverting: false,
             messages,
+            dry_run: None,
+            heartbeat_interval: 0,
+            string_collation: StringCollation::default(),
+            reorder_conjunctions: false,
+            bulk_lookups: false,
+            concurrent_calls: false,
+            goals_executed: Rc::new(Cell::new(0)),
+            choices_created: Rc::new(Cell::new(0)),
+            peak_choice_points: Rc::new(Cell::new(0)),
+            progress: None,
+            call_cache: Rc::new(RefCell::new(CallCache::default())),
+            goal_dump: None,
+            instrumentation: None,
+            rule_metrics,
+            policy_version,
+            overlay: None,
+            fact_sources: Arc::new(FactSources::new()),
+            warned: Rc::new(RefCell::new(HashSet::new())),
         };
         vm.bind_constants(constants);
         vm.query_contains_partial();
+        vm.query_vars = vm
+            .goals
+            .iter()
+            .flat_map(|goal| match goal.as_ref() {
+                Goal::Query { term } => term.free_variables(),
+                _ => HashSet::new(),
+            })
+            .collect();
         vm
     }
 
@@ -370,18 +886,353 @@ impl PolarVirtualMachine {
 
     #[cfg(test)]
     pub fn new_test(kb: Arc<RwLock<KnowledgeBase>>, tracing: bool, goals: Goals) -> Self {
-        PolarVirtualMachine::new(kb, tracing, goals, MessageQueue::new())
+        PolarVirtualMachine::new(kb.into(), tracing, goals, MessageQueue::new())
     }
 
     /// Clone self, replacing the goal stack and retaining only the current bindings.
     pub fn clone_with_goals(&self, goals: Goals) -> Self {
         let mut vm = Self::new(self.kb.clone(), self.tracing, goals, self.messages.clone());
         vm.binding_manager.clone_from(&self.binding_manager);
+        vm.query_vars = self.query_vars.clone();
+        vm.query_constants = self.query_constants.clone();
         vm.query_contains_partial = self.query_contains_partial;
+        vm.budgets = self.budgets.clone();
         vm.debugger = self.debugger.clone();
+        vm.dry_run = self.dry_run.clone();
+        vm.query_timeout = self.query_timeout;
+        vm.stack_limit = self.stack_limit;
+        vm.goal_limit = self.goal_limit;
+        vm.binding_limit = self.binding_limit;
+        vm.debug_events = self.debug_events;
+        vm.host_calls = self.host_calls;
+        vm.heartbeat_interval = self.heartbeat_interval;
+        vm.string_collation = self.string_collation;
+        vm.reorder_conjunctions = self.reorder_conjunctions;
+        vm.bulk_lookups = self.bulk_lookups;
+        vm.concurrent_calls = self.concurrent_calls;
+        vm.goals_executed = self.goals_executed.clone();
+        vm.choices_created = self.choices_created.clone();
+        vm.peak_choice_points = self.peak_choice_points.clone();
+        vm.progress = self.progress.clone();
+        vm.call_cache = self.call_cache.clone();
+        vm.goal_dump = self.goal_dump.clone();
+        vm.instrumentation = self.instrumentation.clone();
+        vm.policy_version = self.policy_version.clone();
+        vm.overlay = self.overlay.clone();
+        vm.fact_sources = self.fact_sources.clone();
+        vm.warned = self.warned.clone();
         vm
     }
 
+    /// Capture the state of the VM, to resume it later with `resume`.
+    /// Fails for dry-run queries, and for those stopped with state that
+    /// can't be serialized, e.g., in the middle of a `findall`.
+    pub fn suspend(&self) -> PolarResult<SuspendedVm> {
+        if self.dry_run.is_some() || self.overlay.is_some() {
+            return Err(error::OperationalError::Unimplemented(
+                "suspending a dry-run or overlaid query".to_owned(),
+            )
+            .into());
+        }
+        let cache = self.call_cache.borrow();
+        Ok(SuspendedVm {
+            goals: self.goals.clone(),
+            binding_manager: self.binding_manager.clone(),
+            choices: self.choices.clone(),
+            queries: self.queries.clone(),
+            tracing: self.tracing,
+            trace_stack: self.trace_stack.clone(),
+            trace: self.trace.clone(),
+            budgets: self.budgets.clone(),
+            csp: self.csp,
+            call_id_symbols: self.call_id_symbols.clone(),
+            attribute_calls: self.attribute_calls.clone(),
+            query_constants: self.query_constants.clone(),
+            query_vars: self.query_vars.clone(),
+            query_contains_partial: self.query_contains_partial,
+            inverting: self.inverting,
+            external_error: self.external_error.clone(),
+            cached_calls: cache
+                .results
+                .iter()
+                .map(|(key, result)| (key.clone(), result.clone()))
+                .collect(),
+            pending_calls: cache.pending.clone(),
+            pending_lookups: cache.pending_many.clone(),
+            concurrent_calls: cache.concurrent.clone(),
+            instance_snapshots: cache.snapshots.clone(),
+            warned: self.warned.borrow().clone(),
+            goals_executed: self.goals_executed.get(),
+            choices_created: self.choices_created.get(),
+            peak_choice_points: self.peak_choice_points.get(),
+            policy_version: self.policy_version.clone(),
+            next_id: self.id_counter.next(),
+        })
+    }
+
+    /// Restore the state captured by `suspend`. The query's timeout starts
+    /// over.
+    pub fn resume(&mut self, state: SuspendedVm) {
+        self.goals = state.goals;
+        self.binding_manager = state.binding_manager;
+        self.choices = state.choices;
+        self.queries = state.queries;
+        self.tracing = state.tracing;
+        self.trace_stack = state.trace_stack;
+        self.trace = state.trace;
+        self.budgets = state.budgets;
+        self.csp = state.csp;
+        self.call_id_symbols = state.call_id_symbols;
+        self.attribute_calls = state.attribute_calls;
+        self.query_constants = state.query_constants;
+        self.query_vars = state.query_vars;
+        self.query_contains_partial = state.query_contains_partial;
+        self.inverting = state.inverting;
+        self.external_error = state.external_error;
+        self.call_cache = Rc::new(RefCell::new(CallCache {
+            results: state.cached_calls.into_iter().collect(),
+            pending: state.pending_calls,
+            pending_many: state.pending_lookups,
+            concurrent: state.concurrent_calls,
+            snapshots: state.instance_snapshots,
+        }));
+        self.warned = Rc::new(RefCell::new(state.warned));
+        self.goals_executed.set(state.goals_executed);
+        self.choices_created.set(state.choices_created);
+        self.peak_choice_points.set(state.peak_choice_points);
+        self.policy_version = state.policy_version;
+        self.id_counter.advance_to(state.next_id);
+    }
+
+    /// Consult the rules of `overlay` along with, and before, those of the
+    /// knowledge base.
+    pub fn set_overlay(&mut self, overlay: Arc<RwLock<KnowledgeBase>>) {
+        self.overlay = Some(overlay.into());
+    }
+
+    /// Emit a `QueryEvent::Progress` after every `interval` goals; 0 disables them.
+    pub fn set_progress_interval(&mut self, interval: u64) {
+        self.progress = (interval > 0).then(|| {
+            Rc::new(ProgressClock {
+                interval,
+                #[cfg(not(target_arch = "wasm32"))]
+                started: std::time::Instant::now(),
+                #[cfg(target_arch = "wasm32")]
+                started: js_sys::Date::now(),
+            })
+        });
+    }
+
+    /// Report every goal this VM and its sub-VMs execute to `sink`.
+    pub fn set_goal_sink(&mut self, sink: Rc<RefCell<dyn GoalSink>>) {
+        self.goal_dump = Some(Rc::new(GoalDump {
+            sink,
+            #[cfg(not(target_arch = "wasm32"))]
+            started: std::time::Instant::now(),
+            #[cfg(target_arch = "wasm32")]
+            started: js_sys::Date::now(),
+        }));
+    }
+
+    /// Don't cache the host's answer to external call `call_id`, e.g., because
+    /// the method it calls has side effects. Must be called before the answer.
+    pub fn uncache_call(&mut self, call_id: u64) {
+        self.call_cache.borrow_mut().pending.remove(&call_id);
+    }
+
+    /// Answer lookups of the attributes in `fields` on the instance
+    /// `instance_id` from their values there, rather than asking the host.
+    /// Adds to any fields snapshotted before.
+    pub fn snapshot_instance(&mut self, instance_id: u64, fields: BTreeMap<Symbol, Term>) {
+        self.call_cache
+            .borrow_mut()
+            .snapshots
+            .entry(instance_id)
+            .or_default()
+            .extend(fields);
+    }
+
+    /// How the latest result was derived, whether or not the VM is tracing.
+    pub fn trace_for_last_result(&self) -> Option<TraceResult> {
+        self.draw_trace(self.last_result_trace.as_ref())
+    }
+
+    /// The rules that justified the latest result, built from its trace with
+    /// the current bindings, so it's only accurate until the query continues.
+    pub fn proof_for_last_result(&self) -> Option<Proof> {
+        self.last_result_trace
+            .as_ref()
+            .map(|trace| Proof::from_trace(trace, self))
+    }
+
+    fn draw_trace(&self, trace: Option<&Rc<Trace>>) -> Option<TraceResult> {
+        trace.map(|trace| TraceResult {
+            formatted: trace.draw(self),
+            trace: trace.clone(),
+        })
+    }
+
+    /// Emit a `QueryEvent::Heartbeat` after every `interval` goals, so that
+    /// hosts can yield or cancel during long queries. 0 disables heartbeats.
+    pub fn set_heartbeat_interval(&mut self, interval: u64) {
+        self.heartbeat_interval = interval;
+    }
+
+    /// Ask the host for several attributes of an instance at once, with
+    /// `QueryEvent::ExternalLookupMany`, when a query is about to look them up.
+    pub fn enable_bulk_lookups(&mut self) {
+        self.bulk_lookups = true;
+    }
+
+    /// Ask the host for the lookups in a conjunction whose instances and
+    /// arguments are ground all at once, with `QueryEvent::ExternalCalls`,
+    /// before running its conjuncts.
+    pub fn enable_concurrent_calls(&mut self) {
+        self.concurrent_calls = true;
+    }
+
+    /// Run in dry-run mode: record external calls instead of asking the host
+    /// for their results, and answer every external question in the affirmative.
+    pub fn enable_dry_run(&mut self) {
+        self.dry_run = Some(Rc::new(RefCell::new(DryRun::default())));
+    }
+
+    /// External lookups recorded so far by a dry run, or `None` if this
+    /// isn't a dry run.
+    pub fn dry_run_lookups(&self) -> Option<Vec<ExternalLookup>> {
+        self.dry_run
+            .as_ref()
+            .map(|dry_run| dry_run.borrow().lookups.clone())
+    }
+
+    /// Return true if `instance_id` is a placeholder made by a dry run.
+    fn is_unknown(&self, instance_id: u64) -> bool {
+        matches!(&self.dry_run, Some(dry_run) if dry_run.borrow().unknowns.contains_key(&instance_id))
+    }
+
+    /// Answer an external event during a dry run. Return the event if it
+    /// still has to be passed on to the host.
+    fn answer_dry_run(&mut self, event: QueryEvent) -> PolarResult<Option<QueryEvent>> {
+        let dry_run = match &self.dry_run {
+            Some(dry_run) => dry_run.clone(),
+            None => return Ok(Some(event)),
+        };
+
+        // Make a placeholder for the unknown result of a lookup on `instance`.
+        let unknown = |vm: &Self, instance: &Term, attribute: Option<Symbol>| {
+            let mut dry_run = dry_run.borrow_mut();
+            let (receiver, mut path) = match instance.value() {
+                Value::ExternalInstance(ExternalInstance { instance_id, .. }) => dry_run
+                    .unknowns
+                    .get(instance_id)
+                    .cloned()
+                    .unwrap_or_else(|| (instance.clone(), vec![])),
+                _ => (instance.clone(), vec![]),
+            };
+            if let Some(attribute) = attribute {
+                path.push(attribute);
+            }
+            let instance_id = vm.new_id();
+            let repr = path.iter().fold(receiver.to_polar(), |repr, attribute| {
+                format!("{}.{}", repr, attribute)
+            });
+            dry_run
+                .unknowns
+                .insert(instance_id, (receiver.clone(), path.clone()));
+            (
+                receiver,
+                path,
+                Term::new_temporary(Value::ExternalInstance(ExternalInstance {
+                    instance_id,
+                    constructor: None,
+                    repr: Some(repr),
+                    class_tag: None,
+                })),
+            )
+        };
+
+        match event {
+            QueryEvent::ExternalCall {
+                call_id,
+                instance,
+                attribute,
+                args,
+                kwargs,
+            } => {
+                let (receiver, mut path, value) = unknown(self, &instance, Some(attribute.clone()));
+                path.pop();
+                let lookup = ExternalLookup {
+                    receiver,
+                    path,
+                    attribute,
+                    args,
+                    kwargs,
+                };
+                let mut dry_run = dry_run.borrow_mut();
+                if !dry_run.lookups.contains(&lookup) {
+                    dry_run.lookups.push(lookup);
+                }
+                drop(dry_run);
+                self.external_call_result(call_id, Some(value))?;
+            }
+            // Iterating over an unknown produces a single unknown element.
+            QueryEvent::NextExternal { call_id, iterable } => {
+                if dry_run.borrow_mut().iterated.insert(call_id) {
+                    let (_, _, value) = unknown(self, &iterable, None);
+                    self.external_call_result(call_id, Some(value))?;
+                } else {
+                    self.external_call_result(call_id, None)?;
+                }
+            }
+            QueryEvent::ExternalIsSubSpecializer { call_id, .. } => {
+                self.external_question_result(call_id, false)?;
+            }
+            QueryEvent::ExternalIsa { call_id, .. }
+            | QueryEvent::ExternalIsaWithPath { call_id, .. }
+            | QueryEvent::ExternalIsSubclass { call_id, .. }
+            | QueryEvent::ExternalUnify { call_id, .. }
+            | QueryEvent::ExternalOp { call_id, .. } => {
+                self.external_question_result(call_id, true)?;
+            }
+            event => return Ok(Some(event)),
+        }
+        Ok(None)
+    }
+
+    /// Apply the limits of `config` that are enforced while running.
+    pub fn sandbox(&mut self, config: &SandboxConfig) {
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            self.query_timeout = std::time::Duration::from_millis(config.query_timeout_ms);
+        }
+        #[cfg(target_arch = "wasm32")]
+        {
+            self.query_timeout = config.query_timeout_ms as f64;
+        }
+        self.stack_limit = config.max_stack;
+        self.goal_limit = config.max_goals;
+        self.binding_limit = config.max_bindings;
+        self.debug_events = config.debug_events;
+        self.host_calls = config.host_calls;
+    }
+
+    /// Fail if a sandboxed query has executed too many goals, or holds too
+    /// many bindings.
+    fn check_sandbox_limits(&self, goals_executed: u64) -> PolarResult<()> {
+        let exceeded = |limit: &str, max: u64| {
+            Err(error::RuntimeError::SandboxLimitExceeded {
+                limit: limit.to_string(),
+                max,
+            }
+            .into())
+        };
+        match (self.goal_limit, self.binding_limit) {
+            (Some(max), _) if goals_executed > max => exceeded("goals executed", max),
+            (_, Some(max)) if self.binding_manager.bsp() > max => exceeded("bindings", max as u64),
+            _ => Ok(()),
+        }
+    }
+
     #[cfg(test)]
     fn set_stack_limit(&mut self, limit: usize) {
         self.stack_limit = limit;
@@ -393,17 +1244,16 @@ impl PolarVirtualMachine {
     }
 
     pub fn new_id(&self) -> u64 {
-        self.kb
-            .read()
-            .expect("cannot acquire KB read lock")
-            .new_id()
+        self.id_counter.next()
     }
 
     pub fn id_counter(&self) -> Counter {
-        self.kb
-            .read()
-            .expect("cannot acquire KB read lock")
-            .id_counter()
+        self.id_counter.clone()
+    }
+
+    /// Generate a new symbol.
+    pub(crate) fn gensym(&self, prefix: &str) -> Symbol {
+        gensym(&self.gensym_counter, prefix)
     }
 
     fn new_call_id(&mut self, symbol: &Symbol) -> u64 {
@@ -413,7 +1263,7 @@ impl PolarVirtualMachine {
     }
 
     fn new_call_var(&mut self, var_prefix: &str, initial_value: Value) -> (u64, Term) {
-        let sym = self.kb.read().unwrap().gensym(var_prefix);
+        let sym = self.gensym(var_prefix);
         self.bind(&sym, Term::new_temporary(initial_value)).unwrap();
         let call_id = self.new_call_id(&sym);
         (call_id, Term::new_temporary(Value::Variable(sym)))
@@ -426,12 +1276,25 @@ impl PolarVirtualMachine {
             self.print(&format!("{}", goal));
         }
 
+        if let Some(goal_dump) = &self.goal_dump {
+            goal_dump.record(&goal, self.queries.len());
+        }
+
+        if let Some(instrumentation) = &self.instrumentation {
+            instrumentation(&GoalSummary {
+                kind: goal.kind(),
+                rule: self.current_rule(),
+                depth: self.queries.len(),
+            });
+        }
+
         self.check_timeout()?;
 
         match goal.as_ref() {
             Goal::Backtrack => self.backtrack()?,
             Goal::Cut { choice_index } => self.cut(*choice_index),
-            Goal::Debug { message } => return Ok(self.debug(&message)),
+            Goal::Debug { message } if self.debug_events => return Ok(self.debug(message)),
+            Goal::Debug { .. } => (),
             Goal::Halt => return Ok(self.halt()),
             Goal::Isa { left, right } => self.isa(&left, &right)?,
             Goal::IsMoreSpecific { left, right, args } => {
@@ -449,6 +1312,12 @@ impl PolarVirtualMachine {
                 instance,
                 field,
             } => return self.lookup_external(*call_id, instance, field),
+            Goal::LookupMany {
+                call_id,
+                instance,
+                attributes,
+            } => return Ok(self.lookup_many(*call_id, instance, attributes)),
+            Goal::LookupConcurrent { lookups } => return Ok(self.lookup_concurrent(lookups)),
             Goal::IsaExternal { instance, literal } => return self.isa_external(instance, literal),
             Goal::UnifyExternal {
                 left_instance_id,
@@ -473,13 +1342,15 @@ impl PolarVirtualMachine {
                 applicable_rules,
                 unfiltered_rules,
                 args,
-            } => self.filter_rules(applicable_rules, unfiltered_rules, args)?,
+                dispatch,
+            } => self.filter_rules(applicable_rules, unfiltered_rules, args, dispatch)?,
             Goal::SortRules {
                 rules,
                 outer,
                 inner,
                 args,
-            } => self.sort_rules(rules, args, *outer, *inner)?,
+                dispatch,
+            } => self.sort_rules(rules, args, *outer, *inner, dispatch)?,
             Goal::TraceStackPush => {
                 self.trace_stack.push(Rc::new(self.trace.clone()));
                 self.trace = vec![];
@@ -493,6 +1364,39 @@ impl PolarVirtualMachine {
                 self.trace.push(Rc::new(trace.clone()));
                 self.maybe_break(DebugEvent::Pop)?;
             }
+            Goal::PushBudget { rule, budget } => {
+                let ms_at_entry = if budget.ms.is_some() {
+                    self.query_elapsed_ms()
+                } else {
+                    0
+                };
+                self.budgets.push(ActiveBudget {
+                    id: self.new_id(),
+                    rule: rule.clone(),
+                    budget: *budget,
+                    goals_at_entry: self.goals_executed.get(),
+                    ms_at_entry,
+                    paused_at: None,
+                });
+            }
+            Goal::PopBudget => self.pause_budget(),
+            Goal::CountRule { rule, success } => {
+                if let Some(metrics) = &self.rule_metrics {
+                    metrics.count(rule, *success);
+                }
+            }
+            Goal::Memoize {
+                call,
+                generation,
+                success,
+            } => self.kb.read()?.memoize(*generation, call.clone(), *success),
+            Goal::EnumerateRange {
+                item,
+                range,
+                start,
+                end,
+            } => self.enumerate_range(item, range, *start, *end)?,
+            Goal::ScheduleConjuncts { conjuncts } => self.schedule_conjuncts(conjuncts)?,
             Goal::TraceRule { trace } => {
                 if let Node::Rule(rule) = &trace.node {
                     self.log_with(
@@ -513,6 +1417,22 @@ impl PolarVirtualMachine {
                 )?
             }
             Goal::Run { runnable } => return self.run_runnable(runnable.clone_runnable()),
+            Goal::CallBuiltin { builtin, args } => {
+                let goals = builtin(self, args)?;
+                self.append_goals(goals)?
+            }
+            Goal::UnifyCollected { collected, term } => {
+                let list = Term::new_temporary(Value::List(collected.borrow().clone()));
+                self.push_goal(Goal::Unify {
+                    left: term.clone(),
+                    right: list,
+                })?
+            }
+            Goal::Warn { message } => {
+                if self.warned.borrow_mut().insert(message.clone()) {
+                    self.messages.push(MessageKind::Warning, message.clone());
+                }
+            }
         }
         Ok(QueryEvent::None)
     }
@@ -545,18 +1465,25 @@ impl PolarVirtualMachine {
     /// Do not modify the goals stack.  This function defers execution of the
     /// choice until a backtrack occurs.  To immediately execute the choice on
     /// top of the current stack, use `choose`.
-    fn push_choice<I>(&mut self, alternatives: I)
+    fn push_choice<I>(&mut self, alternatives: I) -> PolarResult<()>
     where
         I: IntoIterator<Item = Goals>,
         I::IntoIter: std::iter::DoubleEndedIterator,
     {
+        if self.choices.len() >= self.stack_limit {
+            return Err(error::RuntimeError::StackOverflow {
+                msg: format!("Too many choices! MAX_CHOICES = {}", self.stack_limit),
+            }
+            .into());
+        }
+
         // Make sure that alternatives are executed in order of first to last.
         let alternatives = alternatives
             .into_iter()
             .rev()
             .map(GoalStack::new_reversed)
             .collect();
-        assert!(self.choices.len() < self.stack_limit, "too many choices");
+        self.choices_created.set(self.choices_created.get() + 1);
         self.choices.push(Choice {
             alternatives,
             bsp: self.bsp(),
@@ -564,7 +1491,11 @@ impl PolarVirtualMachine {
             queries: self.queries.clone(),
             trace: self.trace.clone(),
             trace_stack: self.trace_stack.clone(),
+            budgets: self.budgets.clone(),
         });
+        let peak = self.peak_choice_points.get().max(self.choices.len());
+        self.peak_choice_points.set(peak);
+        Ok(())
     }
 
     /// Push a choice onto the choice stack, and execute immediately by
@@ -581,7 +1512,7 @@ impl PolarVirtualMachine {
     {
         let mut alternatives_iter = alternatives.into_iter();
         if let Some(alternative) = alternatives_iter.next() {
-            self.push_choice(alternatives_iter);
+            self.push_choice(alternatives_iter)?;
             self.append_goals(alternative)?;
             Ok(())
         } else {
@@ -606,7 +1537,7 @@ impl PolarVirtualMachine {
         alternative.insert(0, cut_consequent);
 
         // If the conditional succeeds, cut the alternative and backtrack to this choice point.
-        self.push_choice(vec![consequent]);
+        self.push_choice(vec![consequent])?;
         let cut_alternative = Goal::Cut {
             choice_index: self.choices.len(),
         };
@@ -671,6 +1602,24 @@ impl PolarVirtualMachine {
         self.csp = self.bsp();
     }
 
+    /// Bind a constant for this query only. Like the knowledge base's
+    /// constants, it's visible in rules and left out of results.
+    pub fn bind_query_constant(&mut self, name: Symbol, value: Term) -> PolarResult<()> {
+        if self.bsp() != self.csp {
+            return Err(error::RuntimeError::Unsupported {
+                msg: format!(
+                    "cannot bind constant {} after other variables have been bound",
+                    name
+                ),
+            }
+            .into());
+        }
+        self.bind(&name, value.clone())?;
+        self.csp = self.bsp();
+        self.query_constants.insert(name, value);
+        Ok(())
+    }
+
     /// Retrieve the current non-constant bindings as a hash map.
     pub fn bindings(&self, include_temps: bool) -> Bindings {
         self.binding_manager.bindings_after(include_temps, self.csp)
@@ -706,7 +1655,7 @@ impl PolarVirtualMachine {
     }
 
     /// Recursively dereference variables in a term, including subterms, except operations.
-    fn deep_deref(&self, term: &Term) -> Term {
+    pub fn deep_deref(&self, term: &Term) -> Term {
         self.binding_manager.deep_deref(term)
     }
 
@@ -718,17 +1667,18 @@ impl PolarVirtualMachine {
     }
 
     /// Generate a fresh set of variables for a rule.
-    fn rename_rule_vars(&self, rule: &Rule) -> Rule {
-        let kb = &*self.kb.read().unwrap();
-        let mut renamer = Renamer::new(&kb);
-        renamer.fold_rule(rule.clone())
+    fn rename_rule_vars(&self, rule: &Rule) -> PolarResult<Rule> {
+        let kb = &*self.kb.read()?;
+        let mut renamer = Renamer::new(kb).keeping(&self.query_constants);
+        Ok(renamer.fold_rule(rule.clone()))
     }
 
-    /// Push or print a message to the output stream.
+    /// Push or print a message to the output stream. A host's message sink
+    /// takes precedence over `POLAR_LOG_STDERR`.
     #[cfg(not(target_arch = "wasm32"))]
     fn print<S: Into<String>>(&self, message: S) {
         let message = message.into();
-        if self.polar_log_stderr {
+        if self.polar_log_stderr && !self.messages.has_sink() {
             eprintln!("{}", message);
         } else {
             self.messages.push(MessageKind::Print, message);
@@ -739,7 +1689,7 @@ impl PolarVirtualMachine {
     #[cfg(target_arch = "wasm32")]
     fn print<S: Into<String>>(&self, message: S) {
         let message = message.into();
-        if self.polar_log_stderr {
+        if self.polar_log_stderr && !self.messages.has_sink() {
             console_error(&message);
         } else {
             self.messages.push(MessageKind::Print, message);
@@ -783,18 +1733,53 @@ impl PolarVirtualMachine {
         }
     }
 
+    /// The source of `term`, whether it's from the knowledge base or the overlay.
     pub fn source(&self, term: &Term) -> Option<Source> {
-        term.get_source_id()
-            .and_then(|id| self.kb.read().unwrap().sources.get_source(id))
+        let id = term.get_source_id()?;
+        self.kb
+            .get_source(id)
+            .or_else(|| self.overlay.as_ref()?.get_source(id))
     }
 
-    /// Get the query stack as a string for printing in error messages.
-    pub fn stack_trace(&self) -> String {
+    /// Return a snapshot of the goal stack and choice points.
+    pub fn stack_snapshot(&self) -> StackSnapshot {
+        fn goal_strings(goals: &GoalStack) -> Vec<String> {
+            goals.iter().rev().map(|g| g.to_string()).collect()
+        }
+
+        let choices = self
+            .choices
+            .iter()
+            .map(|choice| ChoiceSnapshot {
+                alternatives: choice.alternatives.len(),
+                rules: choice
+                    .alternatives
+                    .iter()
+                    .filter_map(|alternative| match alternative.last().map(|g| g.as_ref()) {
+                        Some(Goal::TraceRule { trace }) => match &trace.node {
+                            Node::Rule(rule) => Some(rule.name.clone()),
+                            _ => None,
+                        },
+                        _ => None,
+                    })
+                    .collect(),
+                goals: goal_strings(&choice.goals),
+            })
+            .collect();
+
+        StackSnapshot {
+            goals: goal_strings(&self.goals),
+            queries: self.queries.clone(),
+            choices,
+        }
+    }
+
+    /// Build linear stack from trace tree. Not just using query stack because it doesn't
+    /// know about rules, query stack should really use this too.
+    fn trace_path(&self) -> Vec<Rc<Trace>> {
         let mut trace_stack = self.trace_stack.clone();
         let mut trace = self.trace.clone();
 
-        // Build linear stack from trace tree. Not just using query stack because it doesn't
-        // know about rules, query stack should really use this too.
         let mut stack = vec![];
         while let Some(t) = trace.last() {
             stack.push(t.clone());
@@ -805,12 +1790,88 @@ impl PolarVirtualMachine {
         }
 
         stack.reverse();
+        stack
+    }
 
-        let mut st = String::new();
-        let _ = write!(st, "trace (most recent evaluation last):");
+    /// The name of the innermost rule being evaluated, if any.
+    fn current_rule(&self) -> Option<&Symbol> {
+        self.trace_stack
+            .iter()
+            .rev()
+            .filter_map(|level| level.last())
+            .find_map(|t| match &t.node {
+                Node::Rule(rule) => Some(&rule.name),
+                _ => None,
+            })
+    }
 
-        let mut rule = None;
-        for t in stack {
+    /// The rules currently being evaluated, outermost first, each with the
+    /// call that invoked it.
+    pub fn rule_stack(&self) -> Vec<error::StackFrame> {
+        // Longer arguments are cut short, so that frames stay readable.
+        const MAX_ARG_LEN: usize = 40;
+
+        let mut frames = vec![];
+        let mut call = None;
+        for t in self.trace_path() {
+            match &t.node {
+                Node::Term(term) if matches!(term.value(), Value::Call(_)) => {
+                    call = Some(term.clone())
+                }
+                Node::Rule(rule) => {
+                    let call = match call.take() {
+                        Some(call) => call,
+                        None => continue,
+                    };
+                    let args = match call.value() {
+                        Value::Call(Call { args, .. }) => args
+                            .iter()
+                            .map(|arg| {
+                                let arg = self.deep_deref(arg).to_polar();
+                                if arg.chars().count() > MAX_ARG_LEN {
+                                    let arg: String = arg.chars().take(MAX_ARG_LEN).collect();
+                                    format!("{}...", arg)
+                                } else {
+                                    arg
+                                }
+                            })
+                            .collect(),
+                        _ => unreachable!(),
+                    };
+                    let source = self.source(&call);
+                    let position = source.as_ref().map(|s| s.position(call.offset()));
+                    frames.push(error::StackFrame {
+                        rule: rule.name.0.clone(),
+                        args,
+                        file: position.and_then(|p| p.filename.map(str::to_string)),
+                        line: position.map(|p| p.row + 1),
+                        column: position.map(|p| p.column + 1),
+                    });
+                }
+                _ => {}
+            }
+        }
+        frames
+    }
+
+    /// Record the rules being evaluated on a runtime error that doesn't
+    /// already have them.
+    fn add_rule_stack(&self, mut error: error::PolarError) -> error::PolarError {
+        if matches!(error.kind, error::ErrorKind::Runtime(_)) && error.stack.is_empty() {
+            error.stack = self.rule_stack();
+        }
+        error
+    }
+
+    /// Get the query stack as a string for printing in error messages.
+    pub fn stack_trace(&self) -> String {
+        let stack = self.trace_path();
+
+        let mut st = String::new();
+        let _ = write!(st, "trace (most recent evaluation last):");
+
+        let mut rule = None;
+        for t in stack {
             match &t.node {
                 Node::Rule(r) => {
                     rule = Some(r.clone());
@@ -828,9 +1889,14 @@ impl PolarVirtualMachine {
                         } else {
                             let _ = write!(st, "in query ");
                         }
-                        let (row, column) = loc_to_pos(&source.src, t.offset());
-                        let _ = write!(st, "at line {}, column {}", row + 1, column + 1);
-                        if let Some(filename) = source.filename {
+                        let position = source.position(t.offset());
+                        let _ = write!(
+                            st,
+                            "at line {}, column {}",
+                            position.row + 1,
+                            position.column + 1
+                        );
+                        if let Some(filename) = position.filename {
                             let _ = write!(st, " in file {}", filename);
                         }
                         let _ = writeln!(st);
@@ -842,6 +1908,105 @@ impl PolarVirtualMachine {
         st
     }
 
+    /// Goals executed so far, including by sub-VMs.
+    pub fn goals_executed(&self) -> u64 {
+        self.goals_executed.get()
+    }
+
+    /// The most choice points on the stack of this VM, or any of its
+    /// sub-VMs, at once.
+    pub fn peak_choice_points(&self) -> usize {
+        self.peak_choice_points.get()
+    }
+
+    /// Version of the policy when the query started, if it has one.
+    pub fn policy_version(&self) -> Option<String> {
+        self.policy_version.clone()
+    }
+
+    /// Milliseconds since the query started running.
+    pub fn query_elapsed_ms(&self) -> u64 {
+        #[cfg(not(target_arch = "wasm32"))]
+        let elapsed_ms = self
+            .query_start_time
+            .map_or(0, |start| start.elapsed().as_millis() as u64);
+        #[cfg(target_arch = "wasm32")]
+        let elapsed_ms = self
+            .query_start_time
+            .map_or(0, |start| (js_sys::Date::now() - start) as u64);
+        elapsed_ms
+    }
+
+    /// Stop enforcing the innermost budget, and pause it in the choices made
+    /// while its rule ran, in case backtracking re-enters the rule.
+    fn pause_budget(&mut self) {
+        if let Some(active) = self.budgets.pop() {
+            let ms = if active.budget.ms.is_some() {
+                self.query_elapsed_ms()
+            } else {
+                0
+            };
+            let paused = ActiveBudget {
+                paused_at: Some((self.goals_executed.get(), ms)),
+                ..active
+            };
+            for choice in self.choices.iter_mut() {
+                for budget in choice.budgets.iter_mut().filter(|b| b.id == paused.id) {
+                    *budget = paused.clone();
+                }
+            }
+        }
+    }
+
+    /// Resume the budgets of rules that backtracking re-entered, leaving out
+    /// the work done while they were paused.
+    fn resume_budgets(&mut self) {
+        if self.budgets.iter().all(|b| b.paused_at.is_none()) {
+            return;
+        }
+        let goals = self.goals_executed.get();
+        let ms = if self.budgets.iter().any(|b| b.budget.ms.is_some()) {
+            self.query_elapsed_ms()
+        } else {
+            0
+        };
+        for budget in self.budgets.iter_mut() {
+            if let Some((paused_goals, paused_ms)) = budget.paused_at.take() {
+                budget.goals_at_entry += goals - paused_goals;
+                budget.ms_at_entry += ms.saturating_sub(paused_ms);
+            }
+        }
+    }
+
+    /// Fail if a rule being run has done more work than its budget allows.
+    fn check_budgets(&self, goals_executed: u64) -> PolarResult<()> {
+        let needs_time = self.budgets.iter().any(|b| b.budget.ms.is_some());
+        let elapsed_ms = if needs_time {
+            self.query_elapsed_ms()
+        } else {
+            0
+        };
+        for active in &self.budgets {
+            let goals = goals_executed - active.goals_at_entry;
+            let ms = elapsed_ms.saturating_sub(active.ms_at_entry);
+            let msg = match active.budget {
+                Budget {
+                    goals: Some(max), ..
+                } if goals > max => format!("executed {} goals, more than {}", goals, max),
+                Budget { ms: Some(max), .. } if needs_time && ms > max => {
+                    format!("ran for {} ms, more than {}", ms, max)
+                }
+                _ => continue,
+            };
+            return Err(error::RuntimeError::BudgetExceeded {
+                rule: active.rule.0.clone(),
+                msg,
+            }
+            .into());
+        }
+        Ok(())
+    }
+
     #[cfg(not(target_arch = "wasm32"))]
     fn check_timeout(&self) -> PolarResult<()> {
         // TODO (dhatch): How do we reliably not do this when debugging.
@@ -907,6 +2072,7 @@ impl PolarVirtualMachine {
                     queries,
                     trace,
                     trace_stack,
+                    budgets,
                 }) => {
                     self.binding_manager.backtrack(bsp);
                     if let Some(mut alternative) = alternatives.pop() {
@@ -915,11 +2081,13 @@ impl PolarVirtualMachine {
                             self.queries = queries;
                             self.trace = trace;
                             self.trace_stack = trace_stack;
+                            self.budgets = budgets;
                         } else {
                             self.goals.clone_from(&goals);
                             self.queries.clone_from(&queries);
                             self.trace.clone_from(&trace);
                             self.trace_stack.clone_from(&trace_stack);
+                            self.budgets.clone_from(&budgets);
                             self.choices.push(Choice {
                                 alternatives,
                                 bsp,
@@ -927,8 +2095,10 @@ impl PolarVirtualMachine {
                                 queries,
                                 trace,
                                 trace_stack,
+                                budgets,
                             })
                         }
+                        self.resume_budgets();
                         self.goals.append(&mut alternative);
                         break;
                     }
@@ -964,7 +2134,11 @@ impl PolarVirtualMachine {
         self.goals.clear();
         self.choices.clear();
         assert!(self.is_halted());
-        QueryEvent::Done { result: true }
+        QueryEvent::Done {
+            result: true,
+            policy_version: None,
+            stats: None,
+        }
     }
 
     /// Comparison operator that essentially performs partial unification.
@@ -1032,17 +2206,32 @@ impl PolarVirtualMachine {
             (Value::Dictionary(left), Value::Pattern(Pattern::Dictionary(right))) => {
                 // Check that the left is more specific than the right.
                 let left_fields: HashSet<&Symbol> = left.fields.keys().collect();
-                let right_fields: HashSet<&Symbol> = right.fields.keys().collect();
+                let right_fields: HashSet<&Symbol> =
+                    right.listed_fields().map(|(k, _)| k).collect();
                 if !right_fields.is_subset(&left_fields) {
                     return self.push_goal(Goal::Backtrack);
                 }
 
+                // Capture the fields not in the pattern in its rest variable.
+                if let Some(rest) = right.rest_var() {
+                    let fields = left
+                        .fields
+                        .iter()
+                        .filter(|(k, _)| !right_fields.contains(k))
+                        .map(|(k, v)| (k.clone(), v.clone()))
+                        .collect();
+                    self.push_goal(Goal::Unify {
+                        left: rest.clone(),
+                        right: rest.clone_with_value(Value::Dictionary(Dictionary { fields })),
+                    })?;
+                }
+
                 // For each field on the right, isa its value against the corresponding value on
                 // the left.
-                for (k, v) in right.fields.iter() {
+                for (k, v) in right.listed_fields() {
                     let left = left
                         .fields
-                        .get(&k)
+                        .get(k)
                         .expect("left fields should be a superset of right fields")
                         .clone();
                     self.push_goal(Goal::Isa {
@@ -1053,6 +2242,15 @@ impl PolarVirtualMachine {
             }
 
             (_, Value::Pattern(Pattern::Dictionary(right))) => {
+                if right.rest_var().is_some() {
+                    return Err(self.set_error_context(
+                        left,
+                        error::RuntimeError::Unsupported {
+                            msg: "cannot capture the rest of the fields of an instance".to_string(),
+                        },
+                    ));
+                }
+
                 // For each field in the dict, look up the corresponding field on the instance and
                 // then isa them.
                 for (field, right_value) in right.fields.iter() {
@@ -1069,6 +2267,15 @@ impl PolarVirtualMachine {
                     };
                     self.append_goals(vec![lookup, isa])?;
                 }
+                // Ask for all of the fields at once first, if the host wants.
+                if self.bulk_lookups && right.fields.len() > 1 {
+                    let call_id = self.new_id();
+                    self.push_goal(Goal::LookupMany {
+                        call_id,
+                        instance: left.clone(),
+                        attributes: right.fields.keys().cloned().collect(),
+                    })?;
+                }
             }
 
             (_, Value::Pattern(Pattern::Instance(right_literal))) => {
@@ -1098,6 +2305,15 @@ impl PolarVirtualMachine {
     fn isa_expr(&mut self, left: &Term, right: &Term) -> PolarResult<()> {
         match right.value() {
             Value::Pattern(Pattern::Dictionary(fields)) => {
+                if fields.rest_var().is_some() {
+                    return Err(self.set_error_context(
+                        right,
+                        error::RuntimeError::Unsupported {
+                            msg: "cannot capture the rest of the fields of a partial".to_string(),
+                        },
+                    ));
+                }
+
                 // Produce a constraint like left.field = value
                 let to_unify = |(field, value): (&Symbol, &Term)| -> Term {
                     let value = self.deref(value);
@@ -1107,7 +2323,7 @@ impl PolarVirtualMachine {
                     term!(unify)
                 };
 
-                let constraints = fields.fields.iter().rev().map(to_unify).collect::<Vec<_>>();
+                let constraints = fields.fields.iter().map(to_unify).collect::<Vec<_>>();
                 for op in constraints {
                     self.add_constraint(&op)?;
                 }
@@ -1157,7 +2373,7 @@ impl PolarVirtualMachine {
                 ));
 
                 // Construct field constraints.
-                let field_constraints = fields.fields.iter().rev().map(|(f, v)| {
+                let field_constraints = fields.fields.iter().map(|(f, v)| {
                     let v = self.deref(v);
                     let field = right.clone_with_value(value!(f.0.as_ref()));
                     let left = left.clone_with_value(value!(op!(Dot, left.clone(), field)));
@@ -1236,36 +2452,59 @@ impl PolarVirtualMachine {
         instance: &Term,
         field: &Term,
     ) -> PolarResult<QueryEvent> {
-        let (field_name, args, kwargs): (
-            Symbol,
-            Option<Vec<Term>>,
-            Option<BTreeMap<Symbol, Term>>,
-        ) = match self.deref(field).value() {
-            Value::Call(Call { name, args, kwargs }) => (
-                name.clone(),
-                Some(args.iter().map(|arg| self.deep_deref(arg)).collect()),
-                match kwargs {
-                    Some(unwrapped) => Some(
-                        unwrapped
-                            .iter()
-                            .map(|(k, v)| (k.to_owned(), self.deep_deref(v)))
-                            .collect(),
-                    ),
-                    None => None,
-                },
-            ),
-            Value::String(field) => (Symbol(field.clone()), None, None),
-            v => {
-                return Err(self.type_error(
-                    &field,
-                    format!("cannot look up field {:?} on an external instance", v),
-                ))
-            }
-        };
+        let (field_name, args, kwargs) = self.lookup_field(field).ok_or_else(|| {
+            self.type_error(
+                field,
+                format!(
+                    "cannot look up field {:?} on an external instance",
+                    self.deref(field).value()
+                ),
+            )
+        })?;
 
         // add an empty choice point; lookups return only one value
         // but we'll want to cut if we get back nothing
-        self.push_choice(vec![]);
+        self.push_choice(vec![])?;
+        if args.is_none() && kwargs.is_none() {
+            self.attribute_calls.insert(call_id);
+        }
+
+        // Answer attribute lookups from a snapshot of the instance.
+        let instance = self.deep_deref(instance);
+        if let (None, None, Some(value)) =
+            (&args, &kwargs, self.snapshotted(&instance, &field_name))
+        {
+            self.log_with(
+                || format!("LOOKUP: {}.{} (snapshot)", instance, field_name),
+                &[],
+            );
+            self.external_call_result(call_id, Some(value))?;
+            return Ok(QueryEvent::None);
+        }
+
+        // Reuse the answer to an identical earlier lookup.
+        let key = (
+            instance.clone(),
+            field_name.clone(),
+            args.clone(),
+            kwargs.clone(),
+        );
+        let cacheable = std::iter::once(&instance)
+            .chain(key.2.iter().flatten())
+            .chain(key.3.iter().flat_map(|kwargs| kwargs.values()))
+            .all(|term| term.free_variables().is_empty());
+        if cacheable {
+            let cached = self.call_cache.borrow().results.get(&key).cloned();
+            if let Some(result) = cached {
+                self.log_with(
+                    || format!("LOOKUP: {}.{} (cached)", instance, field_name),
+                    &[],
+                );
+                self.external_call_result(call_id, result)?;
+                return Ok(QueryEvent::None);
+            }
+            self.call_cache.borrow_mut().pending.insert(call_id, key);
+        }
 
         self.log_with(
             || {
@@ -1290,13 +2529,207 @@ impl PolarVirtualMachine {
 
         Ok(QueryEvent::ExternalCall {
             call_id,
-            instance: self.deep_deref(instance),
+            instance,
             attribute: field_name,
             args,
             kwargs,
         })
     }
 
+    /// The attribute name, arguments and keyword arguments of the lookup of
+    /// `field`, with the arguments dereferenced, if it's a field name or a
+    /// method call.
+    #[allow(clippy::type_complexity)]
+    fn lookup_field(
+        &self,
+        field: &Term,
+    ) -> Option<(Symbol, Option<Vec<Term>>, Option<BTreeMap<Symbol, Term>>)> {
+        match self.deref(field).value() {
+            Value::Call(Call { name, args, kwargs }) => Some((
+                name.clone(),
+                Some(args.iter().map(|arg| self.deep_deref(arg)).collect()),
+                kwargs.as_ref().map(|kwargs| {
+                    kwargs
+                        .iter()
+                        .map(|(k, v)| (k.to_owned(), self.deep_deref(v)))
+                        .collect()
+                }),
+            )),
+            Value::String(field) => Some((Symbol(field.clone()), None, None)),
+            _ => None,
+        }
+    }
+
+    /// Return an `ExternalCalls` event asking for the lookups among
+    /// `conjuncts`, and those of nested conjunctions, on external instances
+    /// that are ground, uncached and distinct, if there are several. Their
+    /// answers go in the lookup cache for the `Goal::LookupExternal`s the
+    /// conjuncts run.
+    fn lookup_concurrent(&mut self, conjuncts: &[Term]) -> QueryEvent {
+        if self.dry_run.is_some() {
+            return QueryEvent::None;
+        }
+        let mut conjuncts: Vec<&Term> = conjuncts.iter().rev().collect();
+        let mut keys: Vec<CallKey> = vec![];
+        while let Some(conjunct) = conjuncts.pop() {
+            let (instance, field) = match conjunct.value() {
+                Value::Expression(Operation {
+                    operator: Operator::And,
+                    args,
+                }) => {
+                    conjuncts.extend(args.iter().rev());
+                    continue;
+                }
+                Value::Expression(Operation {
+                    operator: Operator::Dot,
+                    args,
+                }) if args.len() == 3 => (self.deep_deref(&args[0]), &args[1]),
+                _ => continue,
+            };
+            if !matches!(instance.value(), Value::ExternalInstance(_)) {
+                continue;
+            }
+            let (attribute, args, kwargs) = match self.lookup_field(field) {
+                Some(lookup) => lookup,
+                None => continue,
+            };
+            let ground = args
+                .iter()
+                .flatten()
+                .chain(kwargs.iter().flat_map(|kwargs| kwargs.values()))
+                .all(|term| term.free_variables().is_empty());
+            if ground && self.snapshotted(&instance, &attribute).is_none() {
+                let key = (instance, attribute, args, kwargs);
+                let cache = self.call_cache.borrow();
+                if !cache.results.contains_key(&key)
+                    && !cache.pending.values().any(|pending| pending == &key)
+                    && !keys.contains(&key)
+                {
+                    keys.push(key);
+                }
+            }
+        }
+        if keys.len() < 2 {
+            return QueryEvent::None;
+        }
+
+        let calls: Vec<ExternalCallRequest> = keys
+            .into_iter()
+            .map(|key| {
+                let call_id = self.new_id();
+                let mut cache = self.call_cache.borrow_mut();
+                cache.pending.insert(call_id, key.clone());
+                cache.concurrent.insert(call_id);
+                let (instance, attribute, args, kwargs) = key;
+                ExternalCallRequest {
+                    call_id,
+                    instance,
+                    attribute,
+                    args,
+                    kwargs,
+                }
+            })
+            .collect();
+        self.log_with(
+            || {
+                let lookups: Vec<String> = calls
+                    .iter()
+                    .map(|call| format!("{}.{}", call.instance, call.attribute))
+                    .collect();
+                format!("LOOKUP CONCURRENT: {}", lookups.join(", "))
+            },
+            &[],
+        );
+        QueryEvent::ExternalCalls { calls }
+    }
+
+    /// Return an `ExternalLookupMany` event asking for the attributes of an
+    /// external instance that aren't cached yet, if there are several. The
+    /// answers go in the lookup cache for the `Goal::LookupExternal`s that follow.
+    fn lookup_many(&mut self, call_id: u64, instance: &Term, attributes: &[Symbol]) -> QueryEvent {
+        if self.dry_run.is_some() {
+            return QueryEvent::None;
+        }
+        let instance = self.deep_deref(instance);
+        let instance_id = match instance.value() {
+            Value::ExternalInstance(ExternalInstance { instance_id, .. }) => *instance_id,
+            _ => return QueryEvent::None,
+        };
+        let attributes: Vec<Symbol> = {
+            let cache = self.call_cache.borrow();
+            attributes
+                .iter()
+                .filter(|attribute| {
+                    let key = (instance.clone(), (*attribute).clone(), None, None);
+                    !cache.results.contains_key(&key)
+                        && !cache
+                            .snapshots
+                            .get(&instance_id)
+                            .is_some_and(|fields| fields.contains_key(attribute))
+                })
+                .cloned()
+                .collect()
+        };
+        if attributes.len() < 2 {
+            return QueryEvent::None;
+        }
+
+        self.log_with(
+            || {
+                let names: Vec<&str> = attributes.iter().map(|a| a.0.as_str()).collect();
+                format!("LOOKUP MANY: {}.{{{}}}", instance, names.join(", "))
+            },
+            &[],
+        );
+        self.call_cache
+            .borrow_mut()
+            .pending_many
+            .insert(call_id, (instance, attributes.clone()));
+        QueryEvent::ExternalLookupMany {
+            call_id,
+            instance_id,
+            attributes,
+        }
+    }
+
+    /// The snapshotted value of `attribute` on `instance`, if any.
+    fn snapshotted(&self, instance: &Term, attribute: &Symbol) -> Option<Term> {
+        match instance.value() {
+            Value::ExternalInstance(ExternalInstance { instance_id, .. }) => self
+                .call_cache
+                .borrow()
+                .snapshots
+                .get(instance_id)
+                .and_then(|fields| fields.get(attribute))
+                .cloned(),
+            _ => None,
+        }
+    }
+
+    /// Handle the host's answer to an `ExternalLookupMany` event. Attributes
+    /// missing from `results` are looked up one at a time as usual.
+    pub fn lookup_many_result(
+        &mut self,
+        call_id: u64,
+        results: BTreeMap<Symbol, Term>,
+    ) -> PolarResult<()> {
+        let (instance, attributes) = self
+            .call_cache
+            .borrow_mut()
+            .pending_many
+            .remove(&call_id)
+            .ok_or_else(|| unknown_call_id(call_id))?;
+        let mut cache = self.call_cache.borrow_mut();
+        for (attribute, value) in results {
+            if attributes.contains(&attribute) {
+                self.log_with(|| format!("=> {}: {}", attribute, value), &[]);
+                let key = (instance.clone(), attribute, None, None);
+                cache.results.insert(key, Some(value));
+            }
+        }
+        Ok(())
+    }
+
     pub fn isa_external(
         &mut self,
         instance: &Term,
@@ -1320,7 +2753,7 @@ impl PolarVirtualMachine {
         self.push_choice(vec![vec![Goal::NextExternal {
             call_id,
             iterable: iterable.clone(),
-        }]]);
+        }]])?;
 
         Ok(QueryEvent::NextExternal {
             call_id,
@@ -1392,67 +2825,503 @@ impl PolarVirtualMachine {
             }
         };
 
-        self.queries.push(term.clone());
-        self.push_goal(Goal::PopQuery { term: term.clone() })?;
-        self.trace.push(Rc::new(Trace {
-            node: Node::Term(term.clone()),
-            children: vec![],
-        }));
-
-        match &term.value() {
-            Value::Call(predicate) => {
-                self.query_for_predicate(predicate.clone())?;
+        self.queries.push(term.clone());
+        self.push_goal(Goal::PopQuery { term: term.clone() })?;
+        self.trace.push(Rc::new(Trace {
+            node: Node::Term(term.clone()),
+            children: vec![],
+        }));
+
+        match &term.value() {
+            Value::Call(predicate) if predicate.kwargs.is_some() => {
+                return Err(self.set_error_context(
+                    term,
+                    error::RuntimeError::Unsupported {
+                        msg: format!(
+                            "keyword arguments in rule calls, such as {}",
+                            term.to_polar()
+                        ),
+                    },
+                ));
+            }
+            Value::Call(predicate) => {
+                self.query_for_predicate(predicate.clone())?;
+            }
+            Value::Expression(_) => {
+                return self.query_for_operation(&term);
+            }
+            _ => {
+                let term = self.deref(term);
+                self.query_for_value(&term)?;
+            }
+        }
+        Ok(QueryEvent::None)
+    }
+
+    /// Answer predicate from its fact source, if it has one; otherwise, run
+    /// its rules.
+    fn query_for_predicate(&mut self, predicate: Call) -> PolarResult<()> {
+        let key = (predicate.name.clone(), predicate.args.len());
+        if let Some(source) = self.fact_sources.get(&key).cloned() {
+            return self.query_fact_source(predicate, &*source);
+        }
+        if let Some(call) = self.memoizable(&predicate)? {
+            return self.query_memoized(call);
+        }
+        let goals = self.rule_goals(predicate)?;
+        self.append_goals(goals)
+    }
+
+    /// `predicate` with its arguments dereferenced, if it's a ground call to
+    /// a `@cached` predicate, and the query sees only the knowledge base's
+    /// rules, facts and constants.
+    fn memoizable(&self, predicate: &Call) -> PolarResult<Option<Call>> {
+        if self.overlay.is_some()
+            || !self.fact_sources.is_empty()
+            || !self.query_constants.is_empty()
+            || self.dry_run.is_some()
+            || self.inverting
+            || predicate.kwargs.is_some()
+        {
+            return Ok(None);
+        }
+        let kb = self.kb.read()?;
+        if !kb
+            .rules
+            .get(&predicate.name)
+            .is_some_and(GenericRule::is_cached)
+        {
+            return Ok(None);
+        }
+        // Instance ids aren't stable across queries, so calls with instances
+        // can't be remembered.
+        struct HasInstance(bool);
+        impl Visitor for HasInstance {
+            fn visit_instance_id(&mut self, _: &u64) {
+                self.0 = true;
+            }
+        }
+        let args: TermList = predicate.args.iter().map(|t| self.deep_deref(t)).collect();
+        let mut has_instance = HasInstance(false);
+        args.iter()
+            .for_each(|arg| walk_term(&mut has_instance, arg));
+        Ok(
+            (!has_instance.0 && args.iter().all(Term::is_ground)).then(|| Call {
+                name: predicate.name.clone(),
+                args,
+                kwargs: None,
+            }),
+        )
+    }
+
+    /// Answer a ground call to a `@cached` predicate from the knowledge
+    /// base's memo, or else run it until it first succeeds, remembering
+    /// whether it does. Either way it succeeds at most once.
+    fn query_memoized(&mut self, call: Call) -> PolarResult<()> {
+        let memoized = self.kb.read()?.memoized(&call);
+        match memoized {
+            Ok(success) => {
+                self.log_with(|| format!("MEMO: {} (cached)", call.to_polar()), &[]);
+                if !success {
+                    self.push_goal(Goal::Backtrack)?;
+                }
+                Ok(())
+            }
+            Err(generation) => {
+                // Remember a failure once every alternative has failed.
+                let choice_index = self.choices.len();
+                self.push_choice(vec![vec![
+                    Goal::Memoize {
+                        call: call.clone(),
+                        generation,
+                        success: false,
+                    },
+                    Goal::Backtrack,
+                ]])?;
+                let mut goals = self.rule_goals(call.clone())?;
+                goals.push(Goal::Memoize {
+                    call,
+                    generation,
+                    success: true,
+                });
+                goals.push(Goal::Cut { choice_index });
+                self.append_goals(goals)
+            }
+        }
+    }
+
+    /// Select applicable rules for predicate.
+    /// Sort applicable rules by specificity.
+    /// Return goals that choose among the applicable rules, or that call the
+    /// builtin by the predicate's name if there are no rules.
+    fn rule_goals(&mut self, predicate: Call) -> PolarResult<Goals> {
+        let kb = self.kb.read()?;
+        let overlay = self.overlay.as_ref().map(SharedKb::read).transpose()?;
+        let generic_rules: Vec<&GenericRule> = overlay
+            .iter()
+            .map(|overlay| &**overlay)
+            .chain(std::iter::once(&*kb))
+            .filter_map(|kb| kb.rules.get(&predicate.name))
+            .collect();
+        if generic_rules.is_empty() {
+            // Builtins may read the knowledge bases themselves.
+            drop(overlay);
+            drop(kb);
+            return match builtin(&predicate.name, predicate.args.len()) {
+                Some(builtin) => builtin(self, &predicate.args),
+                None => Ok(vec![Goal::Backtrack]),
+            };
+        }
+        let goals = if let Some(message) = self.arity_warning(&predicate, &generic_rules) {
+            vec![Goal::Warn { message }, Goal::Backtrack]
+        } else {
+            self.check_modes(&predicate, &generic_rules)?;
+
+            // Pre-filter rules, overlay rules first.
+            let args = predicate.args.iter().map(|t| self.deep_deref(t)).collect();
+
+            // Reuse the rules found applicable to arguments of the same classes.
+            let mut dispatch = None;
+            if let (None, [generic_rule]) = (&overlay, generic_rules.as_slice()) {
+                if let Some(key) = self.dispatch_key(generic_rule, &args) {
+                    match generic_rule.cached_dispatch(&key) {
+                        Ok(rules) => {
+                            self.log_with(|| format!("DISPATCH: {} (cached)", predicate.name), &[]);
+                            self.polar_log_mute = true;
+                            let sorted = rules.len();
+                            return Ok(vec![
+                                Goal::TraceStackPush,
+                                Goal::SortRules {
+                                    rules,
+                                    args: predicate.args,
+                                    outer: sorted,
+                                    inner: sorted,
+                                    dispatch: None,
+                                },
+                                Goal::TraceStackPop,
+                            ]);
+                        }
+                        Err(generation) => {
+                            dispatch = Some(Rc::new(Dispatch {
+                                name: predicate.name.clone(),
+                                key,
+                                generation,
+                            }))
+                        }
+                    }
+                }
+            }
+
+            let pre_filter = generic_rules
+                .iter()
+                .flat_map(|generic_rule| {
+                    assert_eq!(generic_rule.name, predicate.name);
+                    generic_rule.get_applicable_rules(&args)
+                })
+                .collect();
+
+            self.polar_log_mute = true;
+
+            // Filter rules by applicability.
+            vec![
+                Goal::TraceStackPush,
+                Goal::FilterRules {
+                    applicable_rules: vec![],
+                    unfiltered_rules: pre_filter,
+                    args: predicate.args,
+                    dispatch,
+                },
+                Goal::TraceStackPop,
+            ]
+        };
+        drop(overlay);
+        drop(kb);
+        Ok(goals)
+    }
+
+    /// The classes of `args` that determine which of `generic_rule`'s rules
+    /// apply to them, if the rules dispatch by class and every argument a
+    /// rule specializes has a known class.
+    fn dispatch_key(&self, generic_rule: &GenericRule, args: &TermList) -> Option<DispatchKey> {
+        if self.dry_run.is_some() {
+            return None;
+        }
+        let specialized = generic_rule.class_dispatch(args.len())?;
+        args.iter()
+            .zip(specialized)
+            .map(|(arg, specialized)| {
+                if !specialized {
+                    return Some(None);
+                }
+                let class = match arg.value() {
+                    Value::ExternalInstance(ExternalInstance { class_tag, .. }) => {
+                        return class_tag.clone().map(Some)
+                    }
+                    Value::Number(Numeric::Integer(_)) => "Integer",
+                    Value::Number(Numeric::Float(_)) => "Float",
+                    Value::String(_) => "String",
+                    Value::Boolean(_) => "Boolean",
+                    Value::DateTime(_) => "DateTime",
+                    Value::Nil => "Nil",
+                    Value::List(_) => "List",
+                    Value::Dictionary(_) => "Dictionary",
+                    _ => return None,
+                };
+                Some(Some(Symbol::new(class)))
+            })
+            .collect()
+    }
+
+    /// Try each fact `source` answers for `predicate`, then the predicate's
+    /// rules, if any.
+    fn query_fact_source(&mut self, predicate: Call, source: &dyn FactSource) -> PolarResult<()> {
+        let args: TermList = predicate.args.iter().map(|t| self.deep_deref(t)).collect();
+        let facts = source.facts(&args)?;
+        let call = Term::new_temporary(Value::List(predicate.args.clone()));
+        let mut alternatives: Vec<Goals> = facts
+            .into_iter()
+            .map(|fact| {
+                vec![Goal::Unify {
+                    left: call.clone(),
+                    right: Term::new_temporary(Value::List(fact)),
+                }]
+            })
+            .collect();
+        let has_rules = self.kb.read()?.rules.contains_key(&predicate.name)
+            || self
+                .overlay
+                .as_ref()
+                .map(SharedKb::read)
+                .transpose()?
+                .is_some_and(|overlay| overlay.rules.contains_key(&predicate.name));
+        if has_rules {
+            alternatives.push(self.rule_goals(predicate)?);
+        }
+        self.choose(alternatives)
+    }
+
+    /// Query for the conjunct that's best to run given the current bindings,
+    /// and schedule the rest to run after it. Ties go to the one written
+    /// first.
+    fn schedule_conjuncts(&mut self, conjuncts: &[Term]) -> PolarResult<()> {
+        // Constraints on partially known variables depend on the order
+        // they're added in, so keep it.
+        let partial = conjuncts.iter().any(|conjunct| {
+            conjunct
+                .free_variables()
+                .iter()
+                .any(|var| self.binding_manager.is_constrained(var))
+        });
+        if partial {
+            return self.append_goals(
+                conjuncts
+                    .iter()
+                    .map(|term| Goal::Query { term: term.clone() }),
+            );
+        }
+        let best = {
+            let kb = self.kb.read()?;
+            let overlay = self.overlay.as_ref().map(SharedKb::read).transpose()?;
+            let kbs: Vec<&KnowledgeBase> =
+                overlay.as_deref().into_iter().chain(Some(&*kb)).collect();
+
+            // Conjuncts that use what a lookup, arithmetic operation or call
+            // with `-` parameters still to run outputs wait for it, as they
+            // would as written.
+            let outputs_of = |conjunct: &Term| -> Vec<Symbol> {
+                let mut outputs: Vec<Symbol> = output_var(conjunct).into_iter().collect();
+                if let Value::Call(call) = conjunct.value() {
+                    for (arg, mode) in call.args.iter().zip(call_modes(&kbs, call)) {
+                        match (arg.value(), mode) {
+                            (Value::Variable(var), Some(Mode::Out))
+                                if !matches!(self.variable_state(var), VariableState::Bound(_)) =>
+                            {
+                                outputs.push(var.clone())
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+                outputs
+            };
+            let outputs: HashSet<Symbol> = conjuncts.iter().flat_map(outputs_of).collect();
+            let waits = |conjunct: &Term| {
+                let own = outputs_of(conjunct);
+                conjunct
+                    .free_variables()
+                    .iter()
+                    .any(|var| outputs.contains(var) && !own.contains(var))
+            };
+            conjuncts
+                .iter()
+                .enumerate()
+                .min_by_key(|(i, conjunct)| {
+                    (waits(conjunct), self.conjunct_cost(&kbs, conjunct), *i)
+                })
+                .map(|(i, _)| i)
+                .ok_or_else(|| {
+                    error::OperationalError::InvalidState("no conjuncts to schedule".to_string())
+                })?
+        };
+        let mut rest = conjuncts.to_vec();
+        let term = rest.remove(best);
+        let mut goals = vec![Goal::Query { term }];
+        if !rest.is_empty() {
+            goals.push(Goal::ScheduleConjuncts { conjuncts: rest });
+        }
+        self.append_goals(goals)
+    }
+
+    /// How soon to run `conjunct`: ground conjuncts first, since they only
+    /// check; then those narrowed by something bound, e.g., calls with a
+    /// bound argument and lookups on bound instances; then calls and
+    /// unifications with nothing bound; and last, operations that need
+    /// their inputs bound to run at all, e.g., comparisons, negations and
+    /// calls to rules with unbound `+` parameters.
+    fn conjunct_cost(&self, kbs: &[&KnowledgeBase], conjunct: &Term) -> u8 {
+        let unbound = |term: &Term| {
+            term.free_variables()
+                .iter()
+                .any(|var| !matches!(self.variable_state(var), VariableState::Bound(_)))
+        };
+        if !unbound(conjunct) {
+            return 0;
+        }
+        let narrowed = |args: &[Term]| {
+            if args.iter().any(|arg| !unbound(arg)) {
+                1
+            } else {
+                2
             }
-            Value::Expression(_) => {
-                return self.query_for_operation(&term);
+        };
+        let inputs_bound = |inputs: &[Term]| if inputs.iter().any(unbound) { 3 } else { 1 };
+        let is_expression = |arg: &Term| matches!(arg.value(), Value::Expression(_));
+        match conjunct.value() {
+            // Builtins output to their last argument.
+            Value::Call(call) if calls_builtin(kbs, call) => {
+                inputs_bound(&call.args[..call.args.len().saturating_sub(1)])
             }
-            _ => {
-                let term = self.deref(term);
-                self.query_for_value(&term)?;
+            Value::Call(call) => {
+                let inputs: TermList = call
+                    .args
+                    .iter()
+                    .zip(call_modes(kbs, call))
+                    .filter(|(_, mode)| *mode == Some(Mode::In))
+                    .map(|(arg, _)| arg.clone())
+                    .collect();
+                if inputs.iter().any(unbound) {
+                    3
+                } else {
+                    narrowed(&call.args)
+                }
             }
+            Value::Expression(Operation { operator, args }) => match operator {
+                Operator::Unify if args.iter().any(is_expression) => {
+                    let inputs: TermList =
+                        args.iter().filter(|a| is_expression(a)).cloned().collect();
+                    inputs_bound(&inputs)
+                }
+                Operator::Unify | Operator::And | Operator::Or => narrowed(args),
+                // Lookups and arithmetic output to their last argument.
+                Operator::Dot
+                | Operator::Add
+                | Operator::Sub
+                | Operator::Mul
+                | Operator::Div
+                | Operator::Mod
+                | Operator::Rem
+                    if args.len() == 3 =>
+                {
+                    inputs_bound(&args[..2])
+                }
+                Operator::In => inputs_bound(&args[1..]),
+                Operator::Isa => inputs_bound(&args[..1]),
+                _ => 3,
+            },
+            _ => 3,
         }
-        Ok(QueryEvent::None)
     }
 
-    /// Select applicable rules for predicate.
-    /// Sort applicable rules by specificity.
-    /// Create a choice over the applicable rules.
-    fn query_for_predicate(&mut self, predicate: Call) -> PolarResult<()> {
-        assert!(predicate.kwargs.is_none());
-        let goals = match self.kb.read().unwrap().rules.get(&predicate.name) {
-            None => vec![Goal::Backtrack],
-            Some(generic_rule) => {
-                assert_eq!(generic_rule.name, predicate.name);
-
-                // Pre-filter rules.
-                let args = predicate.args.iter().map(|t| self.deep_deref(t)).collect();
-                let pre_filter = generic_rule.get_applicable_rules(&args);
-
-                self.polar_log_mute = true;
+    /// Find the variables that make negating `term` unsound: those that are
+    /// unbound now but occur in goals still to be run, which could bind them
+    /// after the negation has already been decided. Variables that only occur
+    /// in `term` are existential, as in `forall` and rewritten lookups, so
+    /// they're safe. Variables of the top-level query are exempt, since
+    /// negating them produces constraints on the query's results.
+    fn unsafe_negation_vars(&self, term: &Term) -> Vec<Symbol> {
+        // Unbound variables unified with one another count as one.
+        let negated: Vec<(Symbol, Vec<Symbol>)> = term
+            .free_variables()
+            .into_iter()
+            .filter_map(|var| {
+                let aliases = self.binding_manager.unbound_aliases(&var)?;
+                (!aliases.iter().any(|v| self.query_vars.contains(v))).then_some((var, aliases))
+            })
+            .collect();
+        if negated.is_empty() {
+            return vec![];
+        }
 
-                // Filter rules by applicability.
-                vec![
-                    Goal::TraceStackPush,
-                    Goal::FilterRules {
-                        applicable_rules: vec![],
-                        unfiltered_rules: pre_filter,
-                        args: predicate.args,
-                    },
-                    Goal::TraceStackPop,
-                ]
+        // Collect variables that pending goals could bind. Negations and
+        // collections never export bindings, so don't look inside them.
+        struct BindableVars(HashSet<Symbol>);
+        impl Visitor for BindableVars {
+            fn visit_variable(&mut self, v: &Symbol) {
+                self.0.insert(v.clone());
             }
-        };
-        self.append_goals(goals)
+            fn visit_rest_variable(&mut self, r: &Symbol) {
+                self.0.insert(r.clone());
+            }
+            fn visit_operation(&mut self, o: &Operation) {
+                if !matches!(
+                    o.operator,
+                    Operator::Not | Operator::ForAll | Operator::FindAll
+                ) {
+                    walk_operation(self, o)
+                }
+            }
+        }
+        let mut bindable = BindableVars(HashSet::new());
+        for goal in self.goals.iter() {
+            match goal.as_ref() {
+                Goal::Query { term } => walk_term(&mut bindable, term),
+                Goal::ScheduleConjuncts { conjuncts } => {
+                    conjuncts.iter().for_each(|t| walk_term(&mut bindable, t))
+                }
+                _ => (),
+            }
+        }
+        let pending: HashSet<Symbol> = bindable
+            .0
+            .iter()
+            .filter_map(|var| self.binding_manager.unbound_aliases(var))
+            .flatten()
+            .collect();
+        let mut vars: Vec<Symbol> = negated
+            .into_iter()
+            .filter(|(_, aliases)| aliases.iter().any(|v| pending.contains(v)))
+            .map(|(var, _)| var)
+            .collect();
+        vars.sort();
+        vars
     }
 
     fn query_for_operation(&mut self, term: &Term) -> PolarResult<QueryEvent> {
-        let operation = term.value().as_expression().unwrap();
+        let operation = term.value().as_expression()?;
         let mut args = operation.args.clone();
         match operation.operator {
             Operator::And => {
                 // Query for each conjunct.
                 self.push_goal(Goal::TraceStackPop)?;
-                self.append_goals(args.into_iter().map(|term| Goal::Query { term }))?;
+                let lookups = (self.concurrent_calls && args.len() > 1).then(|| args.clone());
+                if self.reorder_conjunctions && !self.query_contains_partial && args.len() > 1 {
+                    self.append_goals(conjunct_segments(args))?;
+                } else {
+                    self.append_goals(args.into_iter().map(|term| Goal::Query { term }))?;
+                }
+                if let Some(lookups) = lookups {
+                    self.push_goal(Goal::LookupConcurrent { lookups })?;
+                }
                 self.push_goal(Goal::TraceStackPush)?;
             }
             Operator::Or => {
@@ -1461,8 +3330,18 @@ impl PolarVirtualMachine {
             }
             Operator::Not => {
                 // Query in a sub-VM and invert the results.
-                assert_eq!(args.len(), 1);
+                self.check_arity(term, &args, 1)?;
                 let term = args.pop().unwrap();
+                let vars = self.unsafe_negation_vars(&term);
+                if !vars.is_empty() {
+                    return Err(self.set_error_context(
+                        &term,
+                        error::RuntimeError::UnsafeNegation {
+                            negated: term.to_polar(),
+                            vars,
+                        },
+                    ));
+                }
                 let add_constraints = Rc::new(RefCell::new(Bindings::new()));
                 let inverter = Box::new(Inverter::new(
                     self,
@@ -1476,8 +3355,11 @@ impl PolarVirtualMachine {
                     vec![Goal::Backtrack],
                 )?;
             }
+            Operator::Match => {
+                return Err(self.invalid_state(term, "match expression was not rewritten"));
+            }
             Operator::Assign => {
-                assert_eq!(args.len(), 2);
+                self.check_arity(term, &args, 2)?;
                 let right = args.pop().unwrap();
                 let left = args.pop().unwrap();
                 match (left.value(), right.value()) {
@@ -1506,7 +3388,7 @@ impl PolarVirtualMachine {
 
             Operator::Unify => {
                 // Push a `Unify` goal
-                assert_eq!(args.len(), 2);
+                self.check_arity(term, &args, 2)?;
                 let right = args.pop().unwrap();
                 let left = args.pop().unwrap();
                 self.push_goal(Goal::Unify { left, right })?
@@ -1566,20 +3448,25 @@ impl PolarVirtualMachine {
                 );
             }
             Operator::New => {
-                assert_eq!(args.len(), 2);
+                self.check_arity(term, &args, 2)?;
                 let result = args.pop().unwrap();
-                assert!(
-                    matches!(result.value(), Value::Variable(_)),
-                    "Must have result variable as second arg."
-                );
+                if !matches!(result.value(), Value::Variable(_)) {
+                    return Err(self.invalid_state(term, "expected a result variable"));
+                }
                 let constructor = args.pop().unwrap();
 
+                // Arguments constructed by nested `new`s are bound by now.
+                let constructor = self.deep_deref(&constructor);
                 let instance_id = self.new_id();
                 let instance =
                     constructor.clone_with_value(Value::ExternalInstance(ExternalInstance {
                         instance_id,
                         constructor: Some(constructor.clone()),
                         repr: Some(constructor.to_polar()),
+                        class_tag: match constructor.value() {
+                            Value::Call(Call { name, .. }) => Some(name.clone()),
+                            _ => None,
+                        },
                     }));
 
                 // A goal is used here in case the result is already bound to some external
@@ -1624,13 +3511,32 @@ impl PolarVirtualMachine {
             }
             Operator::Isa => {
                 // TODO (dhatch): Use query op helper.
-                assert_eq!(args.len(), 2);
+                self.check_arity(term, &args, 2)?;
                 let right = args.pop().unwrap();
                 let left = args.pop().unwrap();
                 self.push_goal(Goal::Isa { left, right })?
             }
+            Operator::FindAll => {
+                // Collect the template's values in a sub-VM, then unify the result with them.
+                self.check_arity(term, &args, 3)?;
+                let result = args.pop().unwrap();
+                let condition = args.pop().unwrap();
+                let template = args.pop().unwrap();
+                let collected = Rc::new(RefCell::new(vec![]));
+                let collector =
+                    Box::new(Collector::new(self, condition, template, collected.clone()));
+                self.append_goals(vec![
+                    Goal::Run {
+                        runnable: collector,
+                    },
+                    Goal::UnifyCollected {
+                        collected,
+                        term: result,
+                    },
+                ])?;
+            }
             Operator::ForAll => {
-                assert_eq!(args.len(), 2);
+                self.check_arity(term, &args, 2)?;
                 let action = args.pop().unwrap();
                 let condition = args.pop().unwrap();
                 // For all is implemented as !(condition, !action).
@@ -1657,7 +3563,7 @@ impl PolarVirtualMachine {
     }
 
     /// Query for a value.  Succeeds if the value is 'truthy' or backtracks.
-    /// Currently only defined for boolean values.
+    /// Currently only defined for boolean values and `nil`, which is falsy.
     fn query_for_value(&mut self, term: &Term) -> PolarResult<()> {
         if let Value::Boolean(value) = term.value() {
             if !value {
@@ -1665,6 +3571,9 @@ impl PolarVirtualMachine {
                 self.push_goal(Goal::Backtrack)?;
             }
 
+            Ok(())
+        } else if let Value::Nil = term.value() {
+            self.push_goal(Goal::Backtrack)?;
             Ok(())
         } else {
             Err(self.type_error(
@@ -1695,10 +3604,12 @@ impl PolarVirtualMachine {
     where
         F: Fn(&mut Self, &Term) -> PolarResult<QueryEvent>,
     {
-        let Operation { operator: op, args } = term.value().as_expression().unwrap();
+        let Operation { operator: op, args } = term.value().as_expression()?;
 
         let mut args = args.clone();
-        assert!(args.len() >= 2);
+        if args.len() < 2 {
+            return Err(self.invalid_state(term, "expected at least two arguments"));
+        }
         let left = &args[0];
         let right = &args[1];
 
@@ -1707,7 +3618,7 @@ impl PolarVirtualMachine {
             | (_, Value::Expression(_))
             | (Value::RestVariable(_), _)
             | (_, Value::RestVariable(_)) => {
-                panic!("invalid query");
+                return Err(self.invalid_state(term, "operands must be evaluated first"));
             }
             _ => {}
         };
@@ -1752,13 +3663,23 @@ impl PolarVirtualMachine {
 
     /// Evaluate comparison operations.
     fn comparison_op_helper(&mut self, term: &Term) -> PolarResult<QueryEvent> {
-        let Operation { operator: op, args } = term.value().as_expression().unwrap();
+        let Operation { operator: op, args } = term.value().as_expression()?;
 
-        assert_eq!(args.len(), 2);
+        self.check_arity(term, args, 2)?;
         let left = &args[0];
         let right = &args[1];
 
         match (left.value(), right.value()) {
+            // Compare with `nil` here rather than asking the host, so that
+            // instances needn't know about it.
+            (Value::ExternalInstance(_), Value::Nil) | (Value::Nil, Value::ExternalInstance(_))
+                if matches!(op, Operator::Eq | Operator::Neq) =>
+            {
+                if *op == Operator::Eq {
+                    self.push_goal(Goal::Backtrack)?;
+                }
+                Ok(QueryEvent::None)
+            }
             (Value::ExternalInstance(_), _) | (_, Value::ExternalInstance(_)) => {
                 // Generate a symbol for the external result and bind to `false` (default).
                 let (call_id, answer) =
@@ -1778,7 +3699,7 @@ impl PolarVirtualMachine {
                 })
             }
             _ => {
-                if !compare(*op, left, right)? {
+                if !compare_collated(*op, left, right, self.string_collation)? {
                     self.push_goal(Goal::Backtrack)?;
                 }
                 Ok(QueryEvent::None)
@@ -1790,13 +3711,15 @@ impl PolarVirtualMachine {
     // like we do for dots; e.g., `+(a, b, c)` → `c = +(a, b)`.
     /// Evaluate arithmetic operations.
     fn arithmetic_op_helper(&mut self, term: &Term) -> PolarResult<QueryEvent> {
-        let Operation { operator: op, args } = term.value().as_expression().unwrap();
+        let Operation { operator: op, args } = term.value().as_expression()?;
 
-        assert_eq!(args.len(), 3);
+        self.check_arity(term, args, 3)?;
         let left = &args[0];
         let right = &args[1];
         let result = &args[2];
-        assert!(matches!(result.value(), Value::Variable(_)));
+        if !matches!(result.value(), Value::Variable(_)) {
+            return Err(self.invalid_state(term, "expected a result variable"));
+        }
 
         match (left.value(), right.value()) {
             (Value::Number(left), Value::Number(right)) => {
@@ -1830,6 +3753,14 @@ impl PolarVirtualMachine {
                     ))
                 }
             }
+            // `+` concatenates strings.
+            (Value::String(left), Value::String(right)) if *op == Operator::Add => {
+                self.push_goal(Goal::Unify {
+                    left: term.clone_with_value(Value::String(format!("{}{}", left, right))),
+                    right: result.clone(),
+                })?;
+                Ok(QueryEvent::None)
+            }
             (_, _) => Err(self.set_error_context(
                 &term,
                 error::RuntimeError::Unsupported {
@@ -1841,11 +3772,10 @@ impl PolarVirtualMachine {
 
     /// Push appropriate goals for lookups on dictionaries and instances.
     fn dot_op_helper(&mut self, term: &Term) -> PolarResult<QueryEvent> {
-        let Operation { operator: op, args } = term.value().as_expression().unwrap();
-        assert_eq!(*op, Operator::Dot, "expected a dot operation");
+        let Operation { args, .. } = term.value().as_expression()?;
 
         let mut args = args.clone();
-        assert_eq!(args.len(), 3);
+        self.check_arity(term, &args, 3)?;
         let object = &args[0];
         let field = &args[1];
         let value = &args[2];
@@ -1861,20 +3791,24 @@ impl PolarVirtualMachine {
                     value: args.remove(2),
                 })?
             }
+            // Attributes of `nil` are `nil`, so that lookups chained through
+            // a missing value are too, e.g., `x.manager.name`.
+            Value::Nil if matches!(field.value(), Value::String(_) | Value::Variable(_)) => self
+                .push_goal(Goal::Unify {
+                    left: object.clone(),
+                    right: args.remove(2),
+                })?,
             // Push an `ExternalLookup` goal for external instances and built-ins.
             Value::Dictionary(_)
             | Value::ExternalInstance(_)
+            | Value::Nil
             | Value::List(_)
             | Value::Number(_)
             | Value::String(_) => {
-                let value = value
-                    .value()
-                    .as_symbol()
-                    .map_err(|mut e| {
-                        e.add_stack_trace(self);
-                        e
-                    })
-                    .expect("bad lookup value");
+                let value = value.value().as_symbol().map_err(|mut e| {
+                    e.add_stack_trace(self);
+                    e
+                })?;
                 let call_id = self.new_call_id(value);
                 self.append_goals(vec![
                     Goal::LookupExternal {
@@ -1912,10 +3846,37 @@ impl PolarVirtualMachine {
         Ok(QueryEvent::None)
     }
 
+    /// Enumerate a range lazily: bind `item` to the start, with an
+    /// alternative to enumerate the rest of the range in its place, so that
+    /// neither the goal nor the choice stack grows with the range.
+    fn enumerate_range(
+        &mut self,
+        item: &Term,
+        range: &Term,
+        start: i64,
+        end: i64,
+    ) -> PolarResult<()> {
+        let first = vec![Goal::Unify {
+            left: item.clone(),
+            right: range.clone_with_value(Value::Number(start.into())),
+        }];
+        if start < end {
+            let rest = vec![Goal::EnumerateRange {
+                item: item.clone(),
+                range: range.clone(),
+                start: start + 1,
+                end,
+            }];
+            self.choose(vec![first, rest])
+        } else {
+            self.append_goals(first)
+        }
+    }
+
     fn in_op_helper(&mut self, term: &Term) -> PolarResult<QueryEvent> {
-        let Operation { args, .. } = term.value().as_expression().unwrap();
+        let Operation { args, .. } = term.value().as_expression()?;
 
-        assert_eq!(args.len(), 2);
+        self.check_arity(term, args, 2)?;
         let item = &args[0];
         let iterable = &args[1];
 
@@ -1932,6 +3893,49 @@ impl PolarVirtualMachine {
                 // Nothing is in an empty dict.
                 self.backtrack()?;
             }
+            (_, Value::Nil) => {
+                // Nothing is in `nil`.
+                self.backtrack()?;
+            }
+            (_, Value::Set(_)) => {
+                let item = self.deep_deref(item);
+                let set = match self.deep_deref(iterable).value() {
+                    Value::Set(set) => set.clone(),
+                    _ => return Err(self.invalid_state(term, "dereferenced set is not a set")),
+                };
+                if item.is_ground() && set.is_ground() {
+                    // Ground membership is a binary search, not a choice per element.
+                    if !set.contains(&item) {
+                        self.backtrack()?;
+                    }
+                } else {
+                    self.choose(
+                        set.elements
+                            .into_iter()
+                            .map(|term| {
+                                vec![Goal::Unify {
+                                    left: item.clone(),
+                                    right: term,
+                                }]
+                            })
+                            .collect::<Vec<Goals>>(),
+                    )?;
+                }
+            }
+
+            (_, Value::Range(range)) => {
+                let (start, end) = self.range_bounds(iterable, range)?;
+                let item = self.deep_deref(item);
+                match item.value() {
+                    Value::Variable(_) if start <= end => {
+                        self.enumerate_range(&item, iterable, start, end)?
+                    }
+                    Value::Number(Numeric::Integer(i)) if start <= *i && *i <= end => {}
+                    Value::Number(Numeric::Float(f))
+                        if f.fract() == 0.0 && start as f64 <= *f && *f <= end as f64 => {}
+                    _ => self.backtrack()?,
+                }
+            }
 
             (_, Value::List(terms)) => {
                 // Unify item with each element of the list, skipping non-matching ground terms.
@@ -1976,7 +3980,9 @@ impl PolarVirtualMachine {
                 )?;
             }
             (_, Value::String(s)) => {
-                // Unify item with each element of the string
+                // Unify item with each character of the string. This is
+                // element-wise iteration like lists, not a substring test:
+                // `"bc" in "abc"` fails.
                 let item_is_ground = item.is_ground();
                 self.choose(
                     s.chars()
@@ -2023,6 +4029,21 @@ impl PolarVirtualMachine {
         Ok(QueryEvent::None)
     }
 
+    /// The bounds of a range, which must be integers.
+    fn range_bounds(&self, iterable: &Term, range: &Range) -> PolarResult<(i64, i64)> {
+        let bound = |t: &Term| match self.deep_deref(t).value() {
+            Value::Number(Numeric::Integer(i)) => Ok(*i),
+            _ => Err(self.type_error(
+                iterable,
+                format!(
+                    "range bounds must be integers, got: {}",
+                    iterable.to_polar()
+                ),
+            )),
+        };
+        Ok((bound(&range.start)?, bound(&range.end)?))
+    }
+
     /// Unify `left` and `right` terms.
     ///
     /// Outcomes of a unification are:
@@ -2108,29 +4129,66 @@ impl PolarVirtualMachine {
                 right: r.clone(),
             })?,
 
-            (Value::Dictionary(left), Value::Dictionary(right)) => {
-                // Check that the set of keys are the same.
-                let left_fields: HashSet<&Symbol> = left.fields.keys().collect();
-                let right_fields: HashSet<&Symbol> = right.fields.keys().collect();
-                if left_fields != right_fields {
-                    self.push_goal(Goal::Backtrack)?;
-                    return Ok(());
+            // Unify ranges bound-wise.
+            (Value::Range(l), Value::Range(r)) => self.append_goals(vec![
+                Goal::Unify {
+                    left: l.start.clone(),
+                    right: r.start.clone(),
+                },
+                Goal::Unify {
+                    left: l.end.clone(),
+                    right: r.end.clone(),
+                },
+            ])?,
+
+            // Unify tuples of the same length element-wise.
+            (Value::Tuple(l), Value::Tuple(r)) => {
+                if l.len() == r.len() {
+                    self.append_goals(l.iter().zip(r).map(|(l, r)| Goal::Unify {
+                        left: l.clone(),
+                        right: r.clone(),
+                    }))?
+                } else {
+                    self.push_goal(Goal::Backtrack)?
                 }
+            }
 
-                // For each value, push a unify goal.
-                for (k, v) in left.fields.iter() {
-                    let right = right
-                        .fields
-                        .get(&k)
-                        .expect("fields should be equal")
-                        .clone();
-                    self.push_goal(Goal::Unify {
-                        left: v.clone(),
-                        right,
-                    })?
+            // Ground sets unify if they are equal. Otherwise, ground elements
+            // common to both sides are matched, and each remaining element
+            // must be a member of the other set.
+            (Value::Set(_), Value::Set(_)) => {
+                let (l, r) = (self.deep_deref(left), self.deep_deref(right));
+                match (l.value(), r.value()) {
+                    (Value::Set(l), Value::Set(r)) if l.is_ground() && r.is_ground() => {
+                        if l != r {
+                            self.push_goal(Goal::Backtrack)?;
+                        }
+                    }
+                    (Value::Set(l), Value::Set(r)) => {
+                        let unmatched = |set: &Set, other: &Set| -> TermList {
+                            set.elements
+                                .iter()
+                                .filter(|e| !(e.is_ground() && other.contains(e)))
+                                .cloned()
+                                .collect()
+                        };
+                        let member_of = |item: Term, set: &Term| Goal::Query {
+                            term: op!(In, item, set.clone()).into_term(),
+                        };
+                        let (l_rest, r_rest) = (unmatched(l, r), unmatched(r, l));
+                        let goals: Goals = l_rest
+                            .into_iter()
+                            .map(|item| member_of(item, right))
+                            .chain(r_rest.into_iter().map(|item| member_of(item, left)))
+                            .collect();
+                        self.append_goals(goals)?
+                    }
+                    _ => return Err(self.invalid_state(left, "dereferenced set is not a set")),
                 }
             }
 
+            (Value::Dictionary(left), Value::Dictionary(right)) => self.unify_dicts(left, right)?,
+
             // Unify integers by value.
             (Value::Number(left), Value::Number(right)) => {
                 if left != right {
@@ -2145,6 +4203,16 @@ impl PolarVirtualMachine {
                 }
             }
 
+            // `nil` only unifies with `nil`.
+            (Value::Nil, Value::Nil) => (),
+
+            // Unify datetimes by instant.
+            (Value::DateTime(left), Value::DateTime(right)) => {
+                if left != right {
+                    self.push_goal(Goal::Backtrack)?;
+                }
+            }
+
             // Unify bools by value.
             (Value::Boolean(left), Value::Boolean(right)) => {
                 if left != right {
@@ -2192,6 +4260,11 @@ impl PolarVirtualMachine {
                 }
             }
 
+            // In a dry run, unknowns unify with anything.
+            (Value::ExternalInstance(ExternalInstance { instance_id, .. }), _)
+            | (_, Value::ExternalInstance(ExternalInstance { instance_id, .. }))
+                if self.is_unknown(*instance_id) => {}
+
             // Anything else fails.
             (_, _) => self.push_goal(Goal::Backtrack)?,
         }
@@ -2221,6 +4294,59 @@ impl PolarVirtualMachine {
         }
     }
 
+    /// Unify two dictionaries. Without rest variables, they must have the
+    /// same keys. A rest variable, as in `{id: id, **rest}`, is unified with
+    /// the fields of the other dictionary besides the listed ones.
+    fn unify_dicts(&mut self, left: &Dictionary, right: &Dictionary) -> PolarResult<()> {
+        let left_fields: HashSet<&Symbol> = left.listed_fields().map(|(k, _)| k).collect();
+        let right_fields: HashSet<&Symbol> = right.listed_fields().map(|(k, _)| k).collect();
+        // The listed fields of `dict` whose keys aren't in `keys`.
+        let extra = |dict: &Dictionary, keys: &HashSet<&Symbol>| -> Fields {
+            dict.listed_fields()
+                .filter(|(k, _)| !keys.contains(k))
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect()
+        };
+        let dict = |fields: Fields| Term::new_temporary(Value::Dictionary(Dictionary { fields }));
+
+        let rests = match (left.rest_var(), right.rest_var()) {
+            (None, None) if left_fields == right_fields => vec![],
+            (Some(rest), None) if left_fields.is_subset(&right_fields) => {
+                vec![(rest.clone(), dict(extra(right, &left_fields)))]
+            }
+            (None, Some(rest)) if right_fields.is_subset(&left_fields) => {
+                vec![(dict(extra(left, &right_fields)), rest.clone())]
+            }
+            (Some(left_rest), Some(right_rest)) if left_fields == right_fields => {
+                vec![(left_rest.clone(), right_rest.clone())]
+            }
+            // Each rest has the other's extra fields, and a common rest.
+            (Some(left_rest), Some(right_rest)) => {
+                let rest = Value::RestVariable(self.gensym("rest"));
+                let rest = Term::new_temporary(rest);
+                let mut left_extra = extra(right, &left_fields);
+                left_extra.insert(Symbol::new(REST_FIELD), rest.clone());
+                let mut right_extra = extra(left, &right_fields);
+                right_extra.insert(Symbol::new(REST_FIELD), rest);
+                vec![
+                    (left_rest.clone(), dict(left_extra)),
+                    (dict(right_extra), right_rest.clone()),
+                ]
+            }
+            _ => return self.push_goal(Goal::Backtrack),
+        };
+
+        let common = left
+            .listed_fields()
+            .filter(|(k, _)| right_fields.contains(k))
+            .map(|(k, v)| (v.clone(), right.fields[k].clone()));
+        self.append_goals(
+            common
+                .chain(rests)
+                .map(|(left, right)| Goal::Unify { left, right }),
+        )
+    }
+
     /// Unify two list that end with a rest-variable with eachother.
     /// A helper method for `unify_lists`.
     #[allow(clippy::ptr_arg)]
@@ -2299,6 +4425,22 @@ impl PolarVirtualMachine {
         }
     }
 
+    /// Remember the sorted, applicable `rules` for calls like the one they
+    /// were found for, if it dispatches by class.
+    fn cache_dispatch(&self, dispatch: &Option<Rc<Dispatch>>, rules: &Rules) -> PolarResult<()> {
+        if let Some(Dispatch {
+            name,
+            key,
+            generation,
+        }) = dispatch.as_deref()
+        {
+            if let Some(generic_rule) = self.kb.read()?.rules.get(name) {
+                generic_rule.cache_dispatch(*generation, key.clone(), rules.clone());
+            }
+        }
+        Ok(())
+    }
+
     /// Filter rules to just those applicable to a list of arguments,
     /// then sort them by specificity.
     #[allow(clippy::ptr_arg)]
@@ -2307,6 +4449,7 @@ impl PolarVirtualMachine {
         applicable_rules: &Rules,
         unfiltered_rules: &Rules,
         args: &TermList,
+        dispatch: &Option<Rc<Dispatch>>,
     ) -> PolarResult<()> {
         if unfiltered_rules.is_empty() {
             // The rules have been filtered. Sort them.
@@ -2316,6 +4459,7 @@ impl PolarVirtualMachine {
                 args: args.clone(),
                 outer: 1,
                 inner: 1,
+                dispatch: dispatch.clone(),
             })
         } else {
             // Check one rule for applicability.
@@ -2326,6 +4470,7 @@ impl PolarVirtualMachine {
                 args: args.clone(),
                 applicable_rules: applicable_rules.clone(),
                 unfiltered_rules: unfiltered_rules.clone(),
+                dispatch: dispatch.clone(),
             };
             if rule.params.len() != args.len() {
                 return self.push_goal(inapplicable); // wrong arity
@@ -2337,6 +4482,7 @@ impl PolarVirtualMachine {
                 args: args.clone(),
                 applicable_rules,
                 unfiltered_rules,
+                dispatch: dispatch.clone(),
             };
 
             // The prefilter already checks applicability for ground rules.
@@ -2346,7 +4492,7 @@ impl PolarVirtualMachine {
 
             // Rename the variables in the rule (but not the args).
             // This avoids clashes between arg vars and rule vars.
-            let Rule { params, .. } = self.rename_rule_vars(&rule);
+            let Rule { params, .. } = self.rename_rule_vars(&rule)?;
             let mut check_applicability = vec![];
             for (arg, param) in args.iter().zip(params.iter()) {
                 check_applicability.push(Goal::Unify {
@@ -2380,8 +4526,10 @@ impl PolarVirtualMachine {
         args: &TermList,
         outer: usize,
         inner: usize,
+        dispatch: &Option<Rc<Dispatch>>,
     ) -> PolarResult<()> {
         if rules.is_empty() {
+            self.cache_dispatch(dispatch, rules)?;
             return self.push_goal(Goal::Backtrack);
         }
 
@@ -2394,6 +4542,7 @@ impl PolarVirtualMachine {
             args: args.clone(),
             outer: outer + 1,
             inner: outer + 1,
+            dispatch: dispatch.clone(),
         };
         // Because `outer` starts as `1`, if there is only one rule in the `Rules`, this check
         // fails and we jump down to the evaluation of that lone rule.
@@ -2412,6 +4561,7 @@ impl PolarVirtualMachine {
                     outer,
                     inner: inner - 1,
                     args: args.clone(),
+                    dispatch: dispatch.clone(),
                 };
 
                 // If the comparison fails, break out of the inner loop.
@@ -2424,6 +4574,7 @@ impl PolarVirtualMachine {
         } else {
             // We're done; the rules are sorted.
             // Make alternatives for calling them.
+            self.cache_dispatch(dispatch, rules)?;
 
             self.polar_log_mute = false;
             self.log_with(
@@ -2447,7 +4598,25 @@ impl PolarVirtualMachine {
                     }),
                 });
                 goals.push(Goal::TraceStackPush);
-                let Rule { body, params, .. } = self.rename_rule_vars(rule);
+                if self.rule_metrics.is_some() {
+                    goals.push(Goal::CountRule {
+                        rule: rule.clone(),
+                        success: false,
+                    });
+                }
+                let budget = rule.budget();
+                if let Some(budget) = budget {
+                    goals.push(Goal::PushBudget {
+                        rule: rule.name.clone(),
+                        budget,
+                    });
+                }
+                let Rule {
+                    body,
+                    params,
+                    guard,
+                    ..
+                } = self.rename_rule_vars(rule)?;
 
                 // Unify the arguments with the formal parameters.
                 for (arg, param) in args.iter().zip(params.iter()) {
@@ -2463,8 +4632,31 @@ impl PolarVirtualMachine {
                     }
                 }
 
+                // Check the guard once the specializers have matched, binding
+                // its variables for the body. A rule whose guard fails gives
+                // no results, as if it weren't applicable.
+                if let Some(guard) = guard {
+                    goals.push(Goal::Query { term: guard });
+                }
+
+                // Warn about the call once the rule applies.
+                if let Some(message) = rule.deprecation() {
+                    goals.push(Goal::Warn {
+                        message: self.deprecation_warning(rule, &message),
+                    });
+                }
+
                 // Query for the body clauses.
                 goals.push(Goal::Query { term: body.clone() });
+                if self.rule_metrics.is_some() {
+                    goals.push(Goal::CountRule {
+                        rule: rule.clone(),
+                        success: true,
+                    });
+                }
+                if budget.is_some() {
+                    goals.push(Goal::PopBudget);
+                }
                 goals.push(Goal::TraceStackPop);
 
                 alternatives.push(goals)
@@ -2476,6 +4668,87 @@ impl PolarVirtualMachine {
         Ok(())
     }
 
+    /// A warning for a call that no rule applies to because every rule by
+    /// its name takes a different number of arguments, which is more likely
+    /// a mistake than a query meant to fail.
+    fn arity_warning(&self, predicate: &Call, generic_rules: &[&GenericRule]) -> Option<String> {
+        let arity = predicate.args.len();
+        if generic_rules
+            .iter()
+            .any(|generic_rule| generic_rule.has_arity(arity))
+        {
+            return None;
+        }
+        let arities: BTreeSet<usize> = generic_rules
+            .iter()
+            .flat_map(|generic_rule| generic_rule.arities())
+            .collect();
+        if arities.is_empty() {
+            return None;
+        }
+        let defined = arities
+            .iter()
+            .map(|arity| arity.to_string())
+            .collect::<Vec<_>>()
+            .join(" or ");
+        let mut warning = format!(
+            "Wrong number of arguments to {}: called with {}, but defined with {}",
+            predicate.name,
+            predicate.args.len(),
+            defined
+        );
+        if let Some(call) = self.queries.last() {
+            warning.push_str("\n  called by ");
+            warning.push_str(&self.term_source(call, true));
+        }
+        Some(warning)
+    }
+
+    /// Error if `predicate` leaves an argument unbound that the rules it
+    /// calls declare `+`. Partially known arguments count as bound.
+    fn check_modes(&self, predicate: &Call, generic_rules: &[&GenericRule]) -> PolarResult<()> {
+        let arity = predicate.args.len();
+        let required = |i: usize| {
+            generic_rules.iter().any(|generic_rule| {
+                matches!(generic_rule.modes(arity), Some(modes) if modes[i] == Some(Mode::In))
+            })
+        };
+        let unbound: Vec<String> = predicate
+            .args
+            .iter()
+            .enumerate()
+            .filter(|(i, arg)| {
+                required(*i)
+                    && matches!(self.deref(arg).value(), Value::Variable(var)
+                        if matches!(self.variable_state(var), VariableState::Unbound))
+            })
+            .map(|(_, arg)| arg.to_polar())
+            .collect();
+        if unbound.is_empty() {
+            return Ok(());
+        }
+        let call = Term::new_temporary(Value::Call(predicate.clone()));
+        let error = error::RuntimeError::ModeViolation {
+            call: call.to_polar(),
+            unbound,
+        };
+        Err(self.set_error_context(self.queries.last().unwrap_or(&call), error))
+    }
+
+    /// Warning for a call to a deprecated rule from the current query.
+    fn deprecation_warning(&self, rule: &Rule, message: &str) -> String {
+        let mut warning = format!("Rule {} is deprecated", rule.name);
+        if !message.is_empty() {
+            warning.push_str(": ");
+            warning.push_str(message);
+        }
+        if let Some(call) = self.queries.last() {
+            warning.push_str("\n  called by ");
+            warning.push_str(&self.term_source(call, true));
+        }
+        warning
+    }
+
     /// Succeed if `left` is more specific than `right` with respect to `args`.
     #[allow(clippy::ptr_arg)]
     fn is_more_specific(&mut self, left: &Rule, right: &Rule, args: &TermList) -> PolarResult<()> {
@@ -2488,7 +4761,7 @@ impl PolarVirtualMachine {
                     // that aren't the same and you can compare them and ask which one is more specific
                     // to the relevant argument, you're done.
                     if left_spec != right_spec {
-                        let answer = self.kb.read().unwrap().gensym("is_subspecializer");
+                        let answer = self.gensym("is_subspecializer");
                         // Bind answer to false as a starting point in case is subspecializer doesn't
                         // bind any result.
                         // This is done here for safety to avoid a bug where `answer` is unbound by
@@ -2521,6 +4794,10 @@ impl PolarVirtualMachine {
                 (None, None) => (),
             }
         }
+        // If the specializers don't decide it, a guarded rule is more specific than an unguarded one.
+        if left.guard.is_some() && right.guard.is_none() {
+            return Ok(());
+        }
         // Fail on any of the above branches that do not return
         self.push_goal(Goal::Backtrack)
     }
@@ -2569,8 +4846,9 @@ impl PolarVirtualMachine {
                 Value::Pattern(Pattern::Dictionary(left)),
                 Value::Pattern(Pattern::Dictionary(right)),
             ) => {
-                let left_fields: HashSet<&Symbol> = left.fields.keys().collect();
-                let right_fields: HashSet<&Symbol> = right.fields.keys().collect();
+                let left_fields: HashSet<&Symbol> = left.listed_fields().map(|(k, _)| k).collect();
+                let right_fields: HashSet<&Symbol> =
+                    right.listed_fields().map(|(k, _)| k).collect();
 
                 // The dictionary with more fields is taken as more specific.
                 // The assumption here is that rules have already been filtered
@@ -2578,7 +4856,7 @@ impl PolarVirtualMachine {
                 if left_fields.len() != right_fields.len() {
                     self.rebind_external_answer(
                         &answer,
-                        Term::new_temporary(Value::Boolean(right_fields.len() < left.fields.len())),
+                        Term::new_temporary(Value::Boolean(right_fields.len() < left_fields.len())),
                     );
                 }
                 Ok(QueryEvent::None)
@@ -2594,23 +4872,26 @@ impl PolarVirtualMachine {
         }
     }
 
-    pub fn term_source(&self, term: &Term, include_info: bool) -> String {
-        let source = self.source(term);
-        let span = term.span();
+    /// The text of `term` in the source it was parsed from, if any.
+    pub fn source_text(&self, term: &Term) -> Option<String> {
+        match (self.source(term), term.span()) {
+            (Some(source), Some((left, right))) => source.src.get(left..right).map(str::to_string),
+            _ => None,
+        }
+    }
 
-        let mut source_string = match (&source, &span) {
-            (Some(source), Some((left, right))) => {
-                source.src.chars().take(*right).skip(*left).collect()
-            }
-            _ => term.to_polar(),
-        };
+    pub fn term_source(&self, term: &Term, include_info: bool) -> String {
+        let mut source_string = self.source_text(term).unwrap_or_else(|| term.to_polar());
 
         if include_info {
-            if let Some(source) = source {
-                let offset = term.offset();
-                let (row, column) = crate::lexer::loc_to_pos(&source.src, offset);
-                source_string.push_str(&format!(" at line {}, column {}", row + 1, column));
-                if let Some(filename) = source.filename {
+            if let Some(source) = self.source(term) {
+                let position = source.position(term.offset());
+                source_string.push_str(&format!(
+                    " at line {}, column {}",
+                    position.row + 1,
+                    position.column
+                ));
+                if let Some(filename) = position.filename {
                     source_string.push_str(&format!(" in file {}", filename));
                 }
             }
@@ -2621,7 +4902,8 @@ impl PolarVirtualMachine {
 
     pub fn rule_source(&self, rule: &Rule) -> String {
         let head = format!(
-            "{}({})",
+            "{}{}({})",
+            format_annotations(&rule.annotations),
             rule.name,
             rule.params.iter().fold(String::new(), |mut acc, p| {
                 if !acc.is_empty() {
@@ -2635,6 +4917,10 @@ impl PolarVirtualMachine {
                 acc
             })
         );
+        let head = match &rule.guard {
+            Some(guard) => head + " when " + &self.term_source(guard, false),
+            None => head,
+        };
         match rule.body.value() {
             Value::Expression(Operation {
                 operator: Operator::And,
@@ -2654,7 +4940,27 @@ impl PolarVirtualMachine {
         error.set_context(source.as_ref(), Some(term))
     }
 
-    fn type_error(&self, term: &Term, msg: String) -> error::PolarError {
+    /// Error for a term the VM can't have been given by the parser or the
+    /// rewriter, e.g., an operation with the wrong number of arguments.
+    fn invalid_state(&self, term: &Term, msg: &str) -> error::PolarError {
+        self.set_error_context(
+            term,
+            error::OperationalError::InvalidState(format!("{} in {}", msg, term.to_polar())),
+        )
+    }
+
+    fn check_arity(&self, term: &Term, args: &[Term], arity: usize) -> PolarResult<()> {
+        if args.len() == arity {
+            Ok(())
+        } else {
+            Err(self.invalid_state(
+                term,
+                &format!("expected {} arguments, got {}", arity, args.len()),
+            ))
+        }
+    }
+
+    pub fn type_error(&self, term: &Term, msg: String) -> error::PolarError {
         let stack_trace = self.stack_trace();
         let error = error::RuntimeError::TypeError {
             msg,
@@ -2696,21 +5002,61 @@ impl Runnable for PolarVirtualMachine {
 
         if self.goals.is_empty() {
             if self.choices.is_empty() {
-                return Ok(QueryEvent::Done { result: true });
+                return Ok(QueryEvent::Done {
+                    result: true,
+                    policy_version: None,
+                    stats: None,
+                });
             } else {
                 self.backtrack()?;
             }
         }
 
         while let Some(goal) = self.goals.pop() {
-            match self.next(goal.clone())? {
+            match self
+                .next(goal.clone())
+                .map_err(|e| self.add_rule_stack(e))?
+            {
                 QueryEvent::None => (),
                 event => {
-                    self.external_error = None;
-                    return Ok(event);
+                    if let Some(event) = self.answer_dry_run(event)? {
+                        if !self.host_calls && is_external(&event) {
+                            return Err(error::RuntimeError::Unsupported {
+                                msg: "the sandbox doesn't allow asking the host anything"
+                                    .to_string(),
+                            }
+                            .into());
+                        }
+                        self.external_error = None;
+                        return Ok(event);
+                    }
                 }
             }
             self.maybe_break(DebugEvent::Goal(goal.clone()))?;
+
+            let goals_executed = self.goals_executed.get() + 1;
+            self.goals_executed.set(goals_executed);
+            if !self.budgets.is_empty() {
+                self.check_budgets(goals_executed)?;
+            }
+            self.check_sandbox_limits(goals_executed)?;
+            // Only pause with goals left, so the next `run` resumes here
+            // rather than backtracking past a result.
+            if let Some(progress) = self.progress.as_ref().filter(|progress| {
+                goals_executed.is_multiple_of(progress.interval) && !self.goals.is_empty()
+            }) {
+                return Ok(QueryEvent::Progress {
+                    goals_executed,
+                    choice_points: self.choices_created.get(),
+                    elapsed_ms: progress.elapsed_ms(),
+                });
+            }
+            if self.heartbeat_interval > 0
+                && goals_executed.is_multiple_of(self.heartbeat_interval)
+                && !self.goals.is_empty()
+            {
+                return Ok(QueryEvent::Heartbeat { goals_executed });
+            }
         }
 
         if self.log {
@@ -2723,11 +5069,7 @@ impl Runnable for PolarVirtualMachine {
         }
 
         let trace = if self.tracing {
-            let trace = self.trace.first().cloned();
-            trace.map(|trace| TraceResult {
-                formatted: trace.draw(&self),
-                trace,
-            })
+            self.draw_trace(self.trace.first())
         } else {
             None
         };
@@ -2748,12 +5090,20 @@ impl Runnable for PolarVirtualMachine {
                 .collect();
         }
 
-        Ok(QueryEvent::Result { bindings, trace })
+        self.last_result_trace = self.trace.first().cloned();
+        Ok(QueryEvent::Result {
+            bindings,
+            trace,
+            policy_version: self.policy_version(),
+        })
     }
 
     /// Handle response to a predicate posed to the application, e.g., `ExternalIsa`.
     fn external_question_result(&mut self, call_id: u64, answer: bool) -> PolarResult<()> {
-        let var = self.call_id_symbols.remove(&call_id).expect("bad call id");
+        let var = self
+            .call_id_symbols
+            .remove(&call_id)
+            .ok_or_else(|| unknown_call_id(call_id))?;
         self.rebind_external_answer(&var, Term::new_temporary(Value::Boolean(answer)));
         Ok(())
     }
@@ -2768,23 +5118,53 @@ impl Runnable for PolarVirtualMachine {
         // TODO: Open question if we need to pass errors back down to rust.
         // For example what happens if the call asked for a field that doesn't exist?
 
+        // Errors aren't cached, so that repeating the lookup raises them again.
+        // Nor are instances returned by method calls: the host makes a new one,
+        // e.g., a fresh generator, each time, and reusing it isn't the same.
+        let mut cache = self.call_cache.borrow_mut();
+        if let Some(key) = cache.pending.remove(&call_id) {
+            let new_instance = key.2.is_some()
+                && matches!(
+                    term.as_ref().map(Term::value),
+                    Some(Value::ExternalInstance(_))
+                );
+            if self.external_error.is_none() && !new_instance {
+                cache.results.insert(key, term.clone());
+            }
+        }
+        if cache.concurrent.remove(&call_id) {
+            // Nothing waits on a call asked for ahead of time but the cache.
+            return Ok(());
+        }
+        drop(cache);
+
+        // An attribute with no value is `nil`, unless asking for it failed.
+        let term = match term {
+            None if self.attribute_calls.remove(&call_id) && self.external_error.is_none() => {
+                Some(Term::new_temporary(Value::Nil))
+            }
+            term => {
+                self.attribute_calls.remove(&call_id);
+                term
+            }
+        };
         if let Some(value) = term {
             self.log_with(|| format!("=> {}", value.to_string()), &[]);
 
-            self.rebind_external_answer(
-                &self
-                    .call_id_symbols
-                    .get(&call_id)
-                    .expect("unregistered external call ID")
-                    .clone(),
-                value,
-            );
+            let var = self
+                .call_id_symbols
+                .get(&call_id)
+                .ok_or_else(|| unknown_call_id(call_id))?
+                .clone();
+            self.rebind_external_answer(&var, value);
         } else {
             self.log("=> No more results.", &[]);
 
             // No more results. Clean up, cut out the retry alternative,
             // and backtrack.
-            self.call_id_symbols.remove(&call_id).expect("bad call ID");
+            self.call_id_symbols
+                .remove(&call_id)
+                .ok_or_else(|| unknown_call_id(call_id))?;
 
             let check_error = if let Some(goal) = self.goals.last() {
                 matches!(*(*goal), Goal::CheckError)
@@ -2901,7 +5281,7 @@ mod tests {
         let mut vm = PolarVirtualMachine::new_test(Arc::new(RwLock::new(kb)), false, vec![goal]);
         assert_query_events!(vm, [
             QueryEvent::Result{hashmap!()},
-            QueryEvent::Done { result: true }
+            QueryEvent::Done { result: true, .. }
         ]);
 
         assert!(vm.is_halted());
@@ -2915,19 +5295,19 @@ mod tests {
 
         assert_query_events!(vm, [
             QueryEvent::Result{hashmap!{}},
-            QueryEvent::Done { result: true }
+            QueryEvent::Done { result: true, .. }
         ]);
 
         // Querying for f(1), f(2)
         vm.push_goal(query!(f1.clone(), f2.clone())).unwrap();
         assert_query_events!(vm, [
             QueryEvent::Result{hashmap!{}},
-            QueryEvent::Done { result: true }
+            QueryEvent::Done { result: true, .. }
         ]);
 
         // Querying for f(3)
         vm.push_goal(query!(op!(And, f3.clone()))).unwrap();
-        assert_query_events!(vm, [QueryEvent::Done { result: true }]);
+        assert_query_events!(vm, [QueryEvent::Done { result: true, .. }]);
 
         // Querying for f(1), f(2), f(3)
         let parts = vec![f1, f2, f3];
@@ -2939,7 +5319,7 @@ mod tests {
                 })),
             })
             .unwrap();
-            assert_query_events!(vm, [QueryEvent::Done { result: true }]);
+            assert_query_events!(vm, [QueryEvent::Done { result: true, .. }]);
         }
     }
 
@@ -2951,13 +5331,13 @@ mod tests {
 
         assert_query_events!(vm, [
             QueryEvent::Result{hashmap!{}},
-            QueryEvent::Done { result: true }
+            QueryEvent::Done { result: true, .. }
         ]);
 
         let q = op!(Unify, term!(1), term!(2));
         vm.push_goal(query!(q)).unwrap();
 
-        assert_query_events!(vm, [QueryEvent::Done { result: true }]);
+        assert_query_events!(vm, [QueryEvent::Done { result: true, .. }]);
     }
 
     #[test]
@@ -2981,7 +5361,7 @@ mod tests {
         );
         assert!(matches!(
             vm.run(None).unwrap(),
-            QueryEvent::Done { result: true }
+            QueryEvent::Done { result: true, .. }
         ));
         assert!(vm.is_halted());
 
@@ -2996,7 +5376,7 @@ mod tests {
         );
         assert!(matches!(
             vm.run(None).unwrap(),
-            QueryEvent::Done { result: true }
+            QueryEvent::Done { result: true, .. }
         ));
         assert!(vm.is_halted());
 
@@ -3008,7 +5388,7 @@ mod tests {
         .unwrap();
         assert!(matches!(
             vm.run(None).unwrap(),
-            QueryEvent::Done { result: true }
+            QueryEvent::Done { result: true, .. }
         ));
         assert!(vm.is_halted());
 
@@ -3020,7 +5400,7 @@ mod tests {
         .unwrap();
         assert!(matches!(
             vm.run(None).unwrap(),
-            QueryEvent::Done { result: true }
+            QueryEvent::Done { result: true, .. }
         ));
         assert!(vm.is_halted());
 
@@ -3032,7 +5412,7 @@ mod tests {
         .unwrap();
         assert!(matches!(
             vm.run(None).unwrap(),
-            QueryEvent::Done { result: true }
+            QueryEvent::Done { result: true, .. }
         ));
         assert!(vm.is_halted());
 
@@ -3044,7 +5424,7 @@ mod tests {
         .unwrap();
         assert!(matches!(
             vm.run(None).unwrap(),
-            QueryEvent::Done { result: true }
+            QueryEvent::Done { result: true, .. }
         ));
         assert!(vm.is_halted());
 
@@ -3056,7 +5436,7 @@ mod tests {
         .unwrap();
         assert!(matches!(
             vm.run(None).unwrap(),
-            QueryEvent::Done { result: true }
+            QueryEvent::Done { result: true, .. }
         ));
         assert!(vm.is_halted());
 
@@ -3068,7 +5448,7 @@ mod tests {
         .unwrap();
         assert!(matches!(
             vm.run(None).unwrap(),
-            QueryEvent::Done { result: true }
+            QueryEvent::Done { result: true, .. }
         ));
         assert!(vm.is_halted());
 
@@ -3080,7 +5460,7 @@ mod tests {
         .unwrap();
         assert!(matches!(
             vm.run(None).unwrap(),
-            QueryEvent::Done { result: true }
+            QueryEvent::Done { result: true, .. }
         ));
         assert!(vm.is_halted());
 
@@ -3092,7 +5472,7 @@ mod tests {
         .unwrap();
         assert_query_events!(vm, [
             QueryEvent::Result{hashmap!{sym!("rest") => term!([2])}},
-            QueryEvent::Done { result: true }
+            QueryEvent::Done { result: true, .. }
         ]);
     }
 
@@ -3113,7 +5493,7 @@ mod tests {
             right: dict_pattern.clone(),
         })
         .unwrap();
-        assert_query_events!(vm, [QueryEvent::Result { hashmap!() }, QueryEvent::Done { result: true }]);
+        assert_query_events!(vm, [QueryEvent::Result { hashmap!() }, QueryEvent::Done { result: true, .. }]);
 
         // Dicts with identical keys and different values DO NOT isa.
         let different_dict_pattern = term!(pattern!(btreemap! {
@@ -3125,7 +5505,7 @@ mod tests {
             right: different_dict_pattern,
         })
         .unwrap();
-        assert_query_events!(vm, [QueryEvent::Done { result: true }]);
+        assert_query_events!(vm, [QueryEvent::Done { result: true, .. }]);
 
         let empty_dict = term!(btreemap! {});
         let empty_dict_pattern = term!(pattern!(btreemap! {}));
@@ -3135,7 +5515,7 @@ mod tests {
             right: empty_dict_pattern.clone(),
         })
         .unwrap();
-        assert_query_events!(vm, [QueryEvent::Result { hashmap!() }, QueryEvent::Done { result: true }]);
+        assert_query_events!(vm, [QueryEvent::Result { hashmap!() }, QueryEvent::Done { result: true, .. }]);
 
         // Non-empty dicts should isa against an empty dict.
         vm.push_goal(Goal::Isa {
@@ -3143,7 +5523,7 @@ mod tests {
             right: empty_dict_pattern,
         })
         .unwrap();
-        assert_query_events!(vm, [QueryEvent::Result { hashmap!() }, QueryEvent::Done { result: true }]);
+        assert_query_events!(vm, [QueryEvent::Result { hashmap!() }, QueryEvent::Done { result: true, .. }]);
 
         // Empty dicts should NOT isa against a non-empty dict.
         vm.push_goal(Goal::Isa {
@@ -3151,7 +5531,7 @@ mod tests {
             right: dict_pattern.clone(),
         })
         .unwrap();
-        assert_query_events!(vm, [QueryEvent::Done { result: true }]);
+        assert_query_events!(vm, [QueryEvent::Done { result: true, .. }]);
 
         let subset_dict_pattern = term!(pattern!(btreemap! {sym!("x") => term!(1)}));
         // Superset dict isa subset dict.
@@ -3160,7 +5540,7 @@ mod tests {
             right: subset_dict_pattern,
         })
         .unwrap();
-        assert_query_events!(vm, [QueryEvent::Result { hashmap!() }, QueryEvent::Done { result: true }]);
+        assert_query_events!(vm, [QueryEvent::Result { hashmap!() }, QueryEvent::Done { result: true, .. }]);
 
         // Subset dict isNOTa superset dict.
         let subset_dict = term!(btreemap! {sym!("x") => term!(1)});
@@ -3169,7 +5549,7 @@ mod tests {
             right: dict_pattern,
         })
         .unwrap();
-        assert_query_events!(vm, [QueryEvent::Done { result: true }]);
+        assert_query_events!(vm, [QueryEvent::Done { result: true, .. }]);
     }
 
     #[test]
@@ -3189,7 +5569,7 @@ mod tests {
             right,
         })
         .unwrap();
-        assert_query_events!(vm, [QueryEvent::Result { hashmap!() }, QueryEvent::Done { result: true }]);
+        assert_query_events!(vm, [QueryEvent::Result { hashmap!() }, QueryEvent::Done { result: true, .. }]);
 
         // Dicts with identical keys and different values DO NOT unify.
         let right = term!(btreemap! {
@@ -3201,7 +5581,7 @@ mod tests {
             right,
         })
         .unwrap();
-        assert_query_events!(vm, [QueryEvent::Done { result: true }]);
+        assert_query_events!(vm, [QueryEvent::Done { result: true, .. }]);
 
         // Empty dicts unify.
         vm.push_goal(Goal::Unify {
@@ -3209,7 +5589,7 @@ mod tests {
             right: term!(btreemap! {}),
         })
         .unwrap();
-        assert_query_events!(vm, [QueryEvent::Result { hashmap!() }, QueryEvent::Done { result: true }]);
+        assert_query_events!(vm, [QueryEvent::Result { hashmap!() }, QueryEvent::Done { result: true, .. }]);
 
         // Empty dict should not unify against a non-empty dict.
         vm.push_goal(Goal::Unify {
@@ -3217,14 +5597,14 @@ mod tests {
             right: term!(btreemap! {}),
         })
         .unwrap();
-        assert_query_events!(vm, [QueryEvent::Done { result: true }]);
+        assert_query_events!(vm, [QueryEvent::Done { result: true, .. }]);
 
         // Subset match should fail.
         let right = term!(btreemap! {
             sym!("x") => term!(1),
         });
         vm.push_goal(Goal::Unify { left, right }).unwrap();
-        assert_query_events!(vm, [QueryEvent::Done { result: true }]);
+        assert_query_events!(vm, [QueryEvent::Done { result: true, .. }]);
     }
 
     #[test]
@@ -3242,14 +5622,14 @@ mod tests {
             })
         });
         vm.push_goal(Goal::Unify { left, right }).unwrap();
-        assert_query_events!(vm, [QueryEvent::Result { hashmap!{sym!("result") => term!(1)} }, QueryEvent::Done { result: true }]);
+        assert_query_events!(vm, [QueryEvent::Result { hashmap!{sym!("result") => term!(1)} }, QueryEvent::Done { result: true, .. }]);
     }
 
     #[test]
     fn lookup() {
         let mut vm = PolarVirtualMachine::default();
 
-        let fields = btreemap! {
+        let fields = indexmap! {
             sym!("x") => term!(1),
         };
         let dict = Dictionary { fields };
@@ -3272,7 +5652,7 @@ mod tests {
         })
         .unwrap();
 
-        assert_query_events!(vm, [QueryEvent::Done { result: true }]);
+        assert_query_events!(vm, [QueryEvent::Done { result: true, .. }]);
 
         // Lookup with unbound value
         vm.push_goal(Goal::Lookup {
@@ -3382,6 +5762,8 @@ mod tests {
         let rule = Rule {
             name: Symbol::new("foo"),
             params: vec![],
+            guard: None,
+            annotations: vec![],
             body: Term::new_from_test(Value::Expression(Operation {
                 operator: Operator::And,
                 args: vec![
@@ -3395,7 +5777,7 @@ mod tests {
             })),
         };
 
-        let renamed_rule = vm.rename_rule_vars(&rule);
+        let renamed_rule = vm.rename_rule_vars(&rule).unwrap();
         let renamed_terms = unwrap_and(&renamed_rule.body);
         assert_eq!(renamed_terms[1].value(), renamed_terms[2].value());
         let x_value = match &renamed_terms[1].value() {
@@ -3431,6 +5813,7 @@ mod tests {
             instance_id: 1,
             constructor: None,
             repr: None,
+            class_tag: None,
         });
         let query = query!(call!("bar", [sym!("x")]));
         let mut vm = PolarVirtualMachine::new_test(kb.clone(), false, vec![query]);
@@ -3502,6 +5885,7 @@ mod tests {
             instance_id: 1,
             constructor: None,
             repr: None,
+            class_tag: None,
         });
 
         let mut vm = PolarVirtualMachine::new_test(
@@ -3561,15 +5945,16 @@ mod tests {
             instance_id: 1,
             constructor: None,
             repr: None,
+            class_tag: None,
         }));
         let left = term!(value!(Pattern::Instance(InstanceLiteral {
             tag: sym!("Any"),
             fields: Dictionary {
-                fields: btreemap! {}
+                fields: indexmap! {}
             }
         })));
         let right = term!(Value::Pattern(Pattern::Dictionary(Dictionary {
-            fields: btreemap! {sym!("a") => term!("a")},
+            fields: indexmap! {sym!("a") => term!("a")},
         })));
 
         let answer = vm.kb.read().unwrap().gensym("is_subspecializer");
@@ -3620,6 +6005,25 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_poisoned_kb() {
+        let kb = Arc::new(RwLock::new(KnowledgeBase::new()));
+        let _ = std::panic::catch_unwind(|| {
+            let _kb = kb.write().unwrap();
+            panic!("while loading");
+        });
+        assert!(kb.is_poisoned());
+
+        let mut vm = PolarVirtualMachine::new_test(kb, false, vec![query!(call!("f", [1]))]);
+        assert!(matches!(
+            vm.run(None),
+            Err(error::PolarError {
+                kind: error::ErrorKind::Operational(error::OperationalError::InvalidState(_)),
+                ..
+            })
+        ));
+    }
+
     #[test]
     fn test_prefiltering() {
         let bar_rule = GenericRule::new(
@@ -3677,7 +6081,7 @@ mod tests {
         .unwrap();
         assert_query_events!(vm, [
             QueryEvent::Debug { message } if &message[..] == "consequent" && vm.is_halted(),
-            QueryEvent::Done { result: true }
+            QueryEvent::Done { result: true, .. }
         ]);
 
         // Check alternative path when conditional fails.
@@ -3689,7 +6093,7 @@ mod tests {
         .unwrap();
         assert_query_events!(vm, [
             QueryEvent::Debug { message } if &message[..] == "alternative" && vm.is_halted(),
-            QueryEvent::Done { result: true }
+            QueryEvent::Done { result: true, .. }
         ]);
 
         // Ensure bindings are cleaned up after conditional.
@@ -3707,7 +6111,7 @@ mod tests {
         .unwrap();
         assert_query_events!(vm, [
             QueryEvent::Debug { message } if &message[..] == "consequent" && vm.bindings(true).is_empty() && vm.is_halted(),
-            QueryEvent::Done { result: true }
+            QueryEvent::Done { result: true, .. }
         ]);
     }
 }
@@ -2,9 +2,9 @@ use super::formatting::source_lines;
 use super::kb::*;
 use super::rules::*;
 use super::terms::*;
-use super::visitor::{walk_rule, walk_term, Visitor};
+use super::visitor::{walk_operation, walk_rule, walk_term, Visitor};
 
-use std::collections::{hash_map::Entry, HashMap};
+use std::collections::{hash_map::Entry, BTreeMap, BTreeSet, HashMap, HashSet};
 
 fn common_misspellings(t: &str) -> Option<String> {
     let misspelled_type = match t {
@@ -118,3 +118,288 @@ pub fn check_singletons(rule: &Rule, kb: &KnowledgeBase) -> Vec<String> {
     walk_rule(&mut visitor, rule);
     visitor.warnings()
 }
+
+/// Record the predicates called by a rule, and whether each call is made
+/// under a negation (or another construct that doesn't export bindings,
+/// like `forall` or a list comprehension).
+#[derive(Default)]
+struct CallVisitor {
+    negated: usize,
+    calls: Vec<(Symbol, bool, Term)>,
+}
+
+impl Visitor for CallVisitor {
+    fn visit_term(&mut self, t: &Term) {
+        match t.value() {
+            // Arguments of a call are data, not further calls.
+            Value::Call(call) => self
+                .calls
+                .push((call.name.clone(), self.negated > 0, t.clone())),
+            _ => walk_term(self, t),
+        }
+    }
+
+    fn visit_operation(&mut self, o: &Operation) {
+        match o.operator {
+            // Method calls on the left of a lookup aren't predicates.
+            Operator::Dot => self.visit_term(&o.args[0]),
+            Operator::Not | Operator::ForAll | Operator::FindAll => {
+                self.negated += 1;
+                walk_operation(self, o);
+                self.negated -= 1;
+            }
+            _ => walk_operation(self, o),
+        }
+    }
+}
+
+/// A dependency between two predicates: the callee, and the negated call, if any.
+type Dependencies = BTreeMap<Symbol, BTreeMap<Symbol, Option<Term>>>;
+
+fn dependencies(kb: &KnowledgeBase) -> Dependencies {
+    let mut deps = Dependencies::new();
+    for (name, generic_rule) in kb.rules.iter() {
+        let callees = deps.entry(name.clone()).or_default();
+        for rule in generic_rule.rules() {
+            let mut visitor = CallVisitor::default();
+            walk_rule(&mut visitor, &rule);
+            for (callee, negated, term) in visitor.calls {
+                if !kb.rules.contains_key(&callee) {
+                    continue;
+                }
+                let entry = callees.entry(callee).or_insert(None);
+                if negated && entry.is_none() {
+                    *entry = Some(term);
+                }
+            }
+        }
+    }
+    deps
+}
+
+/// Partition the predicates into strongly connected components (Tarjan's algorithm).
+fn components(deps: &Dependencies) -> Vec<BTreeSet<Symbol>> {
+    struct Tarjan<'a> {
+        deps: &'a Dependencies,
+        index: HashMap<&'a Symbol, usize>,
+        low: HashMap<&'a Symbol, usize>,
+        stack: Vec<&'a Symbol>,
+        on_stack: HashSet<&'a Symbol>,
+        components: Vec<BTreeSet<Symbol>>,
+    }
+
+    impl<'a> Tarjan<'a> {
+        fn visit(&mut self, name: &'a Symbol) {
+            let index = self.index.len();
+            self.index.insert(name, index);
+            self.low.insert(name, index);
+            self.stack.push(name);
+            self.on_stack.insert(name);
+
+            for callee in self.deps[name].keys() {
+                if !self.index.contains_key(callee) {
+                    self.visit(callee);
+                    let low = self.low[name].min(self.low[callee]);
+                    self.low.insert(name, low);
+                } else if self.on_stack.contains(callee) {
+                    let low = self.low[name].min(self.index[callee]);
+                    self.low.insert(name, low);
+                }
+            }
+
+            if self.low[name] == self.index[name] {
+                let mut component = BTreeSet::new();
+                while let Some(member) = self.stack.pop() {
+                    self.on_stack.remove(member);
+                    component.insert(member.clone());
+                    if member == name {
+                        break;
+                    }
+                }
+                self.components.push(component);
+            }
+        }
+    }
+
+    let mut tarjan = Tarjan {
+        deps,
+        index: HashMap::new(),
+        low: HashMap::new(),
+        stack: vec![],
+        on_stack: HashSet::new(),
+        components: vec![],
+    };
+    for name in deps.keys() {
+        if !tarjan.index.contains_key(name) {
+            tarjan.visit(name);
+        }
+    }
+    tarjan.components
+}
+
+/// Warn about predicates that are defined recursively through a negation,
+/// e.g., `p() if not q(); q() if p();`. Such policies have no well-defined
+/// meaning. Only cycles that include one of the `loaded` predicates are
+/// reported, so that loading more rules doesn't repeat earlier warnings.
+pub fn check_negation_cycles(kb: &KnowledgeBase, loaded: &HashSet<Symbol>) -> Vec<String> {
+    let deps = dependencies(kb);
+    let mut warnings = vec![];
+    for component in components(&deps) {
+        if !component.iter().any(|name| loaded.contains(name)) {
+            continue;
+        }
+        for caller in component.iter() {
+            for (callee, negated) in deps[caller].iter() {
+                let term = match negated {
+                    Some(term) if component.contains(callee) => term,
+                    _ => continue,
+                };
+                let mut msg = format!(
+                    "Rule {} depends on itself through the negation of {}",
+                    caller, callee
+                );
+                if component.len() > 1 {
+                    let members = component
+                        .iter()
+                        .map(|name| name.0.as_str())
+                        .collect::<Vec<_>>();
+                    msg.push_str(&format!(" (cycle: {})", members.join(", ")));
+                }
+                msg.push_str("; recursion through negation has no well-defined meaning");
+                if let Some(ref source) = term
+                    .get_source_id()
+                    .and_then(|id| kb.sources.get_source(id))
+                {
+                    msg.push('\n');
+                    msg.push_str(&source_lines(source, term.offset(), 0));
+                }
+                warnings.push(msg);
+            }
+        }
+    }
+    warnings
+}
+
+/// True if every parameter of `rule` is a plain variable. Destructuring a
+/// parameter in the head is how a recursive rule usually works on smaller values.
+fn has_plain_head(rule: &Rule) -> bool {
+    rule.params
+        .iter()
+        .all(|param| matches!(param.parameter.value(), Value::Variable(_)))
+}
+
+/// True if `call` passes only the rule's own head variables, or constants,
+/// so that a recursive call can't be working on smaller values than the rule.
+fn is_non_decreasing(rule: &Rule, call: &Call) -> bool {
+    if !has_plain_head(rule) {
+        return false;
+    }
+    let head_vars = rule
+        .params
+        .iter()
+        .filter_map(|param| match param.parameter.value() {
+            Value::Variable(v) => Some(v),
+            _ => None,
+        })
+        .collect::<HashSet<_>>();
+    call.args.iter().all(|arg| match arg.value() {
+        Value::Variable(v) => head_vars.contains(v),
+        value => value.is_ground(),
+    })
+}
+
+/// Warn about recursion that is likely to never terminate: rules whose first
+/// goal calls the rule itself without destructuring any parameters (left
+/// recursion), and recursive cycles in which
+/// no call passes anything but the caller's own head variables or constants.
+/// Only cycles that include one of the `loaded` predicates are reported.
+pub fn check_recursion(kb: &KnowledgeBase, loaded: &HashSet<Symbol>) -> Vec<String> {
+    let warning = |mut msg: String, term: &Term| {
+        if let Some(ref source) = term
+            .get_source_id()
+            .and_then(|id| kb.sources.get_source(id))
+        {
+            msg.push('\n');
+            msg.push_str(&source_lines(source, term.offset(), 0));
+        }
+        msg
+    };
+
+    let mut warnings = vec![];
+    for component in components(&dependencies(kb)) {
+        if !component.iter().any(|name| loaded.contains(name)) {
+            continue;
+        }
+
+        // Every call from a rule in the cycle to a predicate in the cycle.
+        let mut recursive_calls = vec![];
+        for name in component.iter() {
+            for rule in kb.rules[name].rules() {
+                let mut visitor = CallVisitor::default();
+                walk_rule(&mut visitor, &rule);
+                recursive_calls.extend(
+                    visitor
+                        .calls
+                        .into_iter()
+                        .filter(|(callee, _, _)| component.contains(callee))
+                        .map(|(_, negated, term)| (rule.clone(), negated, term)),
+                );
+            }
+        }
+        // Cycles through negation are reported by `check_negation_cycles`.
+        if recursive_calls.is_empty() || recursive_calls.iter().any(|(_, negated, _)| *negated) {
+            continue;
+        }
+        let recursive_calls = recursive_calls
+            .into_iter()
+            .map(|(rule, _, term)| (rule, term))
+            .collect::<Vec<_>>();
+
+        let left_recursive = recursive_calls.iter().filter(|(rule, term)| {
+            has_plain_head(rule)
+                && matches!(term.value(), Value::Call(call) if call.name == rule.name)
+                && matches!(rule.body.value(), Value::Expression(Operation {
+                    operator: Operator::And,
+                    args,
+                }) if args.first() == Some(term))
+        });
+        let mut found = false;
+        for (rule, term) in left_recursive {
+            found = true;
+            warnings.push(warning(
+                format!(
+                    "Rule {} is left-recursive: its first goal calls {} again before \
+                     doing anything else, so it may never terminate",
+                    rule.name, rule.name
+                ),
+                term,
+            ));
+        }
+        if found {
+            continue;
+        }
+
+        let non_decreasing = recursive_calls
+            .iter()
+            .all(|(rule, term)| match term.value() {
+                Value::Call(call) => is_non_decreasing(rule, call),
+                _ => false,
+            });
+        if non_decreasing {
+            let names = component
+                .iter()
+                .map(|name| name.0.as_str())
+                .collect::<Vec<_>>()
+                .join(", ");
+            warnings.push(warning(
+                format!(
+                    "Recursion through {} never passes a smaller value than it was \
+                     called with, so it may never terminate",
+                    names
+                ),
+                &recursive_calls[0].1,
+            ));
+        }
+    }
+    warnings
+}
@@ -7,12 +7,21 @@ use permute::permute;
 use std::cell::RefCell;
 use std::collections::{BTreeMap, HashMap};
 use std::iter::FromIterator;
+use std::ops::ControlFlow;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
 
 use polar_core::{
+    coverage::AuthorizationTypes,
     error::*,
     events::*,
     messages::*,
-    polar::{Polar, Query},
+    parser::ParserLimits,
+    plan::GoalPlan,
+    polar::{compare_terms, InlineQueries, LoadOptions, Polar, Query},
+    replay::Recording,
+    sandbox::SandboxConfig,
+    sources::{SourceMap, SourceMapping},
     sym, term,
     terms::*,
     traces::*,
@@ -75,7 +84,9 @@ where
         }
         match event {
             QueryEvent::Done { .. } => break,
-            QueryEvent::Result { bindings, trace } => {
+            QueryEvent::Result {
+                bindings, trace, ..
+            } => {
                 results.push((
                     bindings
                         .into_iter()
@@ -381,6 +392,69 @@ fn test_trace() -> TestResult {
     Ok(())
 }
 
+#[test]
+fn test_trace_for_last_result() -> TestResult {
+    let p = Polar::new();
+    p.load_str(
+        r#"f(x) if x = 1 and x = 1;
+           f(y) if y = 1;"#,
+    )?;
+    let traced: Vec<String> = query_results!(p.new_query("f(1)", true)?)
+        .into_iter()
+        .map(|(_, trace)| trace.unwrap().formatted)
+        .collect();
+
+    // Without tracing, each result's trace is available on request.
+    let mut q = p.new_query("f(1)", false)?;
+    assert!(q.trace_for_last_result().is_none());
+    for expected in traced {
+        match q.next_event()? {
+            QueryEvent::Result { trace, .. } => assert!(trace.is_none()),
+            event => panic!("unexpected event {:?}", event),
+        }
+        assert_eq!(q.trace_for_last_result().unwrap().formatted, expected);
+    }
+    assert!(matches!(q.next_event()?, QueryEvent::Done { .. }));
+    Ok(())
+}
+
+#[test]
+fn test_proof_for_last_result() -> TestResult {
+    let p = Polar::new();
+    p.load_str(
+        r#"allow(actor, action, resource) if has_role(actor, role) and can(role, action);
+           has_role("alice", "reader");
+           has_role("alice", "writer");
+           can("reader", "read");
+           can("writer", "write");"#,
+    )?;
+    let mut q = p.new_query("allow(\"alice\", action, \"doc\")", false)?;
+    assert!(q.proof_for_last_result().is_none());
+    let mut proofs = vec![];
+    while let QueryEvent::Result { .. } = q.next_event()? {
+        proofs.push(q.proof_for_last_result().unwrap().to_string());
+    }
+    assert_eq!(
+        proofs,
+        vec![
+            "allow(\"alice\", \"read\", \"doc\")\n  \
+             has_role(\"alice\", \"reader\")\n  \
+             can(\"reader\", \"read\")\n",
+            "allow(\"alice\", \"write\", \"doc\")\n  \
+             has_role(\"alice\", \"writer\")\n  \
+             can(\"writer\", \"write\")\n",
+        ]
+    );
+
+    let proof = q.proof_for_last_result().unwrap();
+    assert_eq!(
+        proof.steps.iter().map(|s| s.depth).collect::<Vec<_>>(),
+        vec![0, 1, 1]
+    );
+    assert_eq!(proof.steps[1].name, sym!("has_role"));
+    Ok(())
+}
+
 #[test]
 fn test_nested_rule() -> TestResult {
     let mut p = Polar::new();
@@ -416,7 +490,7 @@ fn test_bad_functions() -> TestResult {
 
 #[test]
 fn test_functions_reorder() -> TestResult {
-    // TODO (dhatch): Reorder f(x), h(x), g(x)
+    // Reordering conjuncts is covered by `test_reorder_conjunctions`.
     let parts = vec![
         "f(1)",
         "f(2)",
@@ -518,6 +592,46 @@ fn test_no_applicable_rules() -> TestResult {
     Ok(())
 }
 
+#[test]
+fn test_arity_mismatch_warning() -> TestResult {
+    let p = Polar::new();
+    let results = |src| -> Result<usize, PolarError> {
+        let mut q = p.new_query(src, false)?;
+        let mut results = 0;
+        while let QueryEvent::Result { .. } = q.next_event()? {
+            results += 1;
+        }
+        Ok(results)
+    };
+
+    // Undefined rules don't warn.
+    assert_eq!(results("f()")?, 0);
+    assert!(p.next_message().is_none());
+
+    p.load_str("f(_);")?;
+    assert_eq!(results("f()")?, 0);
+    let msg = p.next_message().unwrap();
+    assert!(matches!(&msg.kind, MessageKind::Warning));
+    assert_eq!(
+        msg.msg,
+        "Wrong number of arguments to f: called with 0, but defined with 1\n  called by f() at line 1, column 0"
+    );
+    assert!(p.next_message().is_none());
+
+    p.load_str("f(_, _, _);")?;
+    assert_eq!(results("f(1, 2)")?, 0);
+    assert!(p
+        .next_message()
+        .unwrap()
+        .msg
+        .starts_with("Wrong number of arguments to f: called with 2, but defined with 1 or 3\n"));
+
+    // Calls with a defined arity don't warn.
+    assert_eq!(results("f(1, 2, 3) and f(1)")?, 1);
+    assert!(p.next_message().is_none());
+    Ok(())
+}
+
 /// From Aït-Kaci's WAM tutorial (1999), page 34.
 #[test]
 fn test_ait_kaci_34() -> TestResult {
@@ -602,6 +716,49 @@ fn test_not() -> TestResult {
     // Negate Or with unbound variable.
     p.load_str("j(x,y) if not (y = 2 or x = 1);")?;
     qeval(&mut p, "j(2, y)");
+
+    // Negation stops at the first solution of what it negates.
+    let mut q = p.new_query("not x in 1..1000000", false)?;
+    q.enable_stats();
+    let stats = loop {
+        if let QueryEvent::Done { stats, .. } = q.next_event()? {
+            break stats.expect("stats");
+        }
+    };
+    assert_eq!(stats.results, 0);
+    assert!(stats.goals_executed < 100, "{:?}", stats);
+    Ok(())
+}
+
+#[test]
+fn test_unsafe_negation() -> TestResult {
+    let mut p = Polar::new();
+    p.load_str(
+        r#"g(1);
+           unsafe(x) if not g(y) and y = x;
+           safe(x) if y = x and not g(y);
+           local(x) if not (g(y) and y = x) and not g(y);
+           not_g(x) if not g(x);
+           caller() if not_g(y) and y = 2;"#,
+    )?;
+    let err = p.new_query("unsafe(2)", false)?.next_event().unwrap_err();
+    assert!(
+        matches!(&err.kind, ErrorKind::Runtime(RuntimeError::UnsafeNegation { vars, .. }) if vars.len() == 1),
+        "{}",
+        err
+    );
+    assert!(err
+        .to_string()
+        .starts_with("[R0011] Unsafe negation: `not g(_y_"));
+    qeval(&mut p, "safe(2)");
+    qnull(&mut p, "safe(1)");
+    qnull(&mut p, "local(2)");
+    // Including variables the caller binds after the rule returns.
+    qruntime!(&mut p, "caller()", RuntimeError::UnsafeNegation { .. });
+
+    // Variables of the query itself may be negated while unbound.
+    qnull(&mut p, "not g(x)");
+    qeval(&mut p, "not g(2)");
     Ok(())
 }
 
@@ -767,6 +924,108 @@ fn test_dict_specializers() -> TestResult {
     Ok(())
 }
 
+/// Nested dictionary patterns look up each level of nesting on external
+/// instances.
+#[test]
+fn test_nested_dict_specializers() -> TestResult {
+    let mut p = Polar::new();
+    p.load_str(
+        r#"f(_: {role: "admin", org: {tier: "enterprise"}});
+           g(_: {org: Org{tier: "enterprise"}});"#,
+    )?;
+    qeval(
+        &mut p,
+        r#"f({role: "admin", org: {tier: "enterprise", x: 1}})"#,
+    );
+    qnull(&mut p, r#"f({role: "admin", org: {tier: "free"}})"#);
+    qnull(&mut p, r#"f({role: "admin", org: "enterprise"})"#);
+
+    let lookups = |p: &mut Polar, tier: &'static str| {
+        let mut seen = vec![];
+        let results = query_results!(
+            p.new_query("f(new User()) and g(new User())", false)
+                .unwrap(),
+            |_, instance: Term, attribute: Symbol, _, _| {
+                seen.push(format!("{}.{}", instance.to_polar(), attribute.0));
+                Some(match attribute.0.as_str() {
+                    "role" => term!("admin"),
+                    "org" => term!(Value::ExternalInstance(ExternalInstance {
+                        instance_id: 99,
+                        constructor: None,
+                        repr: None,
+                        class_tag: None,
+                    })),
+                    "tier" => term!(tier),
+                    _ => unreachable!(),
+                })
+            }
+        );
+        (results.len(), seen)
+    };
+    let (n, seen) = lookups(&mut p, "enterprise");
+    assert_eq!(n, 1);
+    // Each `new User()` is a separate instance, but both share the same
+    // org, whose `tier` is only looked up once.
+    assert_eq!(
+        seen,
+        vec!["User().org", "^{id: 99}.tier", "User().role", "User().org"]
+    );
+    assert_eq!(lookups(&mut p, "free").0, 0);
+    Ok(())
+}
+
+/// A `**rest` variable in a dict captures the fields besides the listed ones.
+#[test]
+fn test_dict_rest_vars() -> TestResult {
+    let mut p = Polar::new();
+    p.load_str(
+        r#"f({id: id, **rest}, id, rest);
+           g(_: {id: 1, **rest}, rest);"#,
+    )?;
+    qvar(
+        &mut p,
+        "f({id: 1, a: 2, b: 3}, 1, rest)",
+        "rest",
+        vec![value!(
+            btreemap! {sym!("a") => term!(2), sym!("b") => term!(3)}
+        )],
+    );
+    qvar(
+        &mut p,
+        "f({id: 1}, 1, rest)",
+        "rest",
+        vec![value!(btreemap! {})],
+    );
+    qnull(&mut p, "f({a: 2}, _, _)");
+    qvar(
+        &mut p,
+        "g({id: 1, a: 2}, rest)",
+        "rest",
+        vec![value!(btreemap! {sym!("a") => term!(2)})],
+    );
+    qnull(&mut p, "g({id: 2, a: 2}, _)");
+
+    // Dicts without rest variables still need the same fields.
+    qnull(&mut p, "{a: 1} = {a: 1, b: 2}");
+    qeval(&mut p, "{a: 1, **_} = {a: 1, b: 2}");
+    qnull(&mut p, "{a: 1, b: 2, **_} = {a: 1}");
+    qvar(
+        &mut p,
+        "{b: 2, **x} = {a: 1, **y} and y = {b: 2, c: 3}",
+        "x",
+        vec![value!(
+            btreemap! {sym!("a") => term!(1), sym!("c") => term!(3)}
+        )],
+    );
+    qvar(
+        &mut p,
+        "{a: 1, **x} = {a: y, **z} and z = {b: 2}",
+        "x",
+        vec![value!(btreemap! {sym!("b") => term!(2)})],
+    );
+    Ok(())
+}
+
 #[test]
 fn test_non_instance_specializers() -> TestResult {
     let mut p = Polar::new();
@@ -948,6 +1207,91 @@ fn test_load_str_with_query() -> TestResult {
     Ok(())
 }
 
+#[test]
+fn test_load_options() -> TestResult {
+    let policy = r#"f(1);
+                    ?= f(1);
+                    ?= f(2);
+                    ?= new Foo().bar = 1;"#;
+    let options = |inline_queries| LoadOptions {
+        inline_queries,
+        ..LoadOptions::default()
+    };
+
+    // Collected queries are left for the host to run.
+    let p = Polar::new();
+    p.load_with_options(policy, None, &options(InlineQueries::Collect))?;
+    let mut collected = 0;
+    while p.next_inline_query(false).is_some() {
+        collected += 1;
+    }
+    assert_eq!(collected, 3);
+
+    let p = Polar::new();
+    p.load_with_options(policy, None, &options(InlineQueries::Skip))?;
+    assert!(p.next_inline_query(false).is_none());
+
+    // Executed queries fail the load together, and undo it.
+    let mut p = Polar::new();
+    let filename = Some("inline.polar".to_string());
+    let err = p
+        .load_with_options(policy, filename.clone(), &options(InlineQueries::Execute))
+        .unwrap_err();
+    match err.kind {
+        ErrorKind::Runtime(RuntimeError::InlineQueriesFailed { failures }) => assert_eq!(
+            failures,
+            vec![
+                "f(2) at line 3, column 23 in file inline.polar: no results",
+                "new Foo().bar = 1 at line 4, column 23 in file inline.polar: needs the host to answer it",
+            ]
+        ),
+        _ => panic!("unexpected error {:?}", err),
+    }
+    assert!(p.next_inline_query(false).is_none());
+    qnull(&mut p, "f(1)");
+    p.load_with_options(policy, filename, &options(InlineQueries::Skip))?;
+    qeval(&mut p, "f(1)");
+    p.load_with_options("g(1); ?= g(1);", None, &options(InlineQueries::Execute))?;
+    Ok(())
+}
+
+#[test]
+fn test_check_coverage() -> TestResult {
+    let p = Polar::new();
+    p.load_str(r#"allow(_: User, "read", _: Document);"#)?;
+    // Nothing to cover until types are declared.
+    p.check_coverage()?;
+
+    let types: AuthorizationTypes = serde_json::from_str(
+        r#"{"actors": ["User"],
+            "resources": {"Document": ["read", "write"], "Folder": ["list"]},
+            "unhandled": [["Folder", "list"]]}"#,
+    )
+    .unwrap();
+    p.declare_authorization_types(types);
+    let err = p.check_coverage().unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "[R0016] Incomplete policy coverage:\n\
+         No allow rule covers action \"write\" on resource Document"
+    );
+
+    // Checked on load if asked, once every rule is loaded.
+    let options = LoadOptions {
+        check_coverage: true,
+        ..LoadOptions::default()
+    };
+    assert!(matches!(
+        p.load_with_options("f(1);", None, &options)
+            .unwrap_err()
+            .kind,
+        ErrorKind::Runtime(RuntimeError::IncompleteCoverage { .. })
+    ));
+    p.load_with_options(r#"allow(_: User, "write", _: Document);"#, None, &options)?;
+    p.check_coverage()?;
+    Ok(())
+}
+
 /// Test using a constructor with positional + kwargs.
 #[test]
 fn test_make_external() -> TestResult {
@@ -1187,6 +1531,12 @@ fn test_arithmetic() -> TestResult {
     qnull(&mut p, "1/0 < 0");
     qeval(&mut p, "1/0 > 0");
     qeval(&mut p, "1/0 > 1e100");
+
+    // Unary minus.
+    qvar(&mut p, "y = 2 and x = -y", "x", values![-2]);
+    qvar(&mut p, "y = 2.5 and x = -y * 2", "x", values![-5.0]);
+    qeval(&mut p, "x = 3 and -x < 0 and -(-x) == x");
+    qeval(&mut p, "[1, -2] = [1, -2]");
     Ok(())
 }
 
@@ -1380,6 +1730,13 @@ fn test_anonymous_vars() {
     let mut p = Polar::new();
     qeval(&mut p, "[1,2,3] = [_,_,_]");
     qnull(&mut p, "[1,2,3] = [__,__,__]");
+
+    // Each `_` in a rule is a distinct variable, and none is a singleton.
+    p.load_str("f(_, _); g(x) if h(_, x) and h(x, _); h(1, 2); h(2, 3);")
+        .unwrap();
+    assert!(p.next_message().is_none());
+    qeval(&mut p, "f(1, 2)");
+    qvar(&mut p, "g(x)", "x", values![2]);
 }
 
 #[test]
@@ -1410,575 +1767,3848 @@ fn test_singleton_vars() -> TestResult {
 }
 
 #[test]
-fn test_print() -> TestResult {
-    // TODO: If POLAR_LOG is on this test will fail.
+fn test_message_sink() -> TestResult {
     let p = Polar::new();
-    p.load_str("f(x,y,z) if print(x, y, z);")?;
-    let message_handler = |output: &Message| {
-        assert!(matches!(&output.kind, MessageKind::Print));
-        assert_eq!(&output.msg, "1, 2, 3");
-    };
-    let q = p.new_query("f(1, 2, 3)", false)?;
-    let _results = query_results!(q, @msgs message_handler);
+    let received = Arc::new(Mutex::new(vec![]));
+    let sink = received.clone();
+    p.set_message_sink(Some(Arc::new(move |message: Message| {
+        sink.lock().unwrap().push(message.msg)
+    })));
+
+    // Warnings and output of queries go to the sink, not the queue.
+    p.load_str("f(x) if f(x);")?;
+    let mut q = p.new_query(r#"print("hello")"#, false)?;
+    assert!(matches!(q.next_event()?, QueryEvent::Result { .. }));
+    assert!(p.next_message().is_none());
+    assert!(q.next_message().is_none());
+    {
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 2);
+        assert!(received[0].starts_with("Rule f is left-recursive"));
+        assert_eq!(received[1], r#""hello""#);
+    }
+
+    // A query can have its own sink.
+    let query_received = Arc::new(Mutex::new(vec![]));
+    let sink = query_received.clone();
+    let mut q = p.new_query(r#"print("query")"#, false)?;
+    q.set_message_sink(Arc::new(move |message: Message| {
+        sink.lock().unwrap().push(message.msg)
+    }));
+    assert!(matches!(q.next_event()?, QueryEvent::Result { .. }));
+    assert_eq!(*query_received.lock().unwrap(), vec![r#""query""#]);
+    assert_eq!(received.lock().unwrap().len(), 2);
+
+    // Without a sink, messages are buffered again.
+    p.set_message_sink(None);
+    let mut q = p.new_query(r#"print("buffered")"#, false)?;
+    assert!(matches!(q.next_event()?, QueryEvent::Result { .. }));
+    assert_eq!(p.next_message().unwrap().msg, r#""buffered""#);
     Ok(())
 }
 
 #[test]
-fn test_unknown_specializer_suggestions() -> TestResult {
+fn test_negation_cycle_warnings() -> TestResult {
     let p = Polar::new();
-    p.load_str("f(s: string) if s;")?;
+    p.load_str(
+        r#"win(x) if move(x, y) and not win(y);
+           move(1, 2);"#,
+    )?;
     let msg = p.next_message().unwrap();
     assert!(matches!(&msg.kind, MessageKind::Warning));
     assert_eq!(
         &msg.msg,
-        "Unknown specializer string, did you mean String?\n001: f(s: string) if s;\n          ^"
+        "Rule win depends on itself through the negation of win; \
+         recursion through negation has no well-defined meaning\n\
+         001: win(x) if move(x, y) and not win(y);\n                                  ^"
     );
+    assert!(p.next_message().is_none());
+
+    // Mutual recursion through negation is reported once per negated call.
+    p.load_str(
+        r#"p() if not q();
+           q() if r();
+           r() if p();"#,
+    )?;
+    let msg = p.next_message().unwrap();
+    assert!(msg
+        .msg
+        .starts_with("Rule p depends on itself through the negation of q (cycle: p, q, r)"));
+    assert!(p.next_message().is_none());
+
+    // Negation of a predicate that doesn't depend on the caller is fine,
+    // and loading more rules doesn't repeat earlier warnings.
+    p.load_str(
+        r#"s() if not t();
+           t() if u();
+           u();"#,
+    )?;
+    assert!(p.next_message().is_none());
     Ok(())
 }
 
 #[test]
-fn test_rest_vars() -> TestResult {
-    let mut p = Polar::new();
-    qvar(&mut p, "[1,2,3] = [*rest]", "rest", vec![value!([1, 2, 3])]);
-    qvar(&mut p, "[1,2,3] = [1,*rest]", "rest", vec![value!([2, 3])]);
-    qvar(&mut p, "[1,2,3] = [1,2,*rest]", "rest", vec![value!([3])]);
-    qvar(&mut p, "[1,2,3] = [1,2,3,*rest]", "rest", vec![value!([])]);
-    qnull(&mut p, "[1,2,3] = [1,2,3,4,*_rest]");
+fn test_recursion_warnings() -> TestResult {
+    let p = Polar::new();
+    p.load_str("f(x) if f(x);")?;
+    let msg = p.next_message().unwrap();
+    assert!(matches!(&msg.kind, MessageKind::Warning));
+    assert!(msg.msg.starts_with("Rule f is left-recursive"));
+    assert!(p.next_message().is_none());
 
     p.load_str(
-        r#"member(x, [x, *_rest]);
-           member(x, [_first, *rest]) if member(x, rest);"#,
+        r#"ancestor(x, z) if ancestor(x, y) and parent(y, z);
+           ancestor(x, y) if parent(x, y);"#,
     )?;
-    qeval(&mut p, "member(1, [1,2,3])");
-    qeval(&mut p, "member(3, [1,2,3])");
-    qeval(&mut p, "not member(4, [1,2,3])");
-    qvar(&mut p, "member(x, [1,2,3])", "x", values![1, 2, 3]);
+    let msg = p.next_message().unwrap();
+    assert!(msg.msg.starts_with("Rule ancestor is left-recursive"));
+    assert!(p.next_message().is_none());
 
+    // Mutual recursion that passes the same arguments around.
     p.load_str(
-        r#"append([], x, x);
-           append([first, *rest], x, [first, *tail]) if append(rest, x, tail);"#,
+        r#"even(x) if x = 0 or odd(x);
+           odd(x) if x > 0 and even(x);"#,
     )?;
-    qeval(&mut p, "append([], [], [])");
-    qeval(&mut p, "append([], [1,2,3], [1,2,3])");
-    qeval(&mut p, "append([1], [2,3], [1,2,3])");
-    qeval(&mut p, "append([1,2], [3], [1,2,3])");
-    qeval(&mut p, "append([1,2,3], [], [1,2,3])");
-    qeval(&mut p, "not append([1,2,3], [4], [1,2,3])");
+    let msg = p.next_message().unwrap();
+    assert!(msg
+        .msg
+        .starts_with("Recursion through even, odd never passes a smaller value"));
+    assert!(p.next_message().is_none());
 
-    let a = &var(&mut p, "[*_] in [*a] and [*b] in [*_] and b = 1", "a")[0];
-    assert!(matches!(a, Value::List(b) if matches!(b[0].value(), Value::RestVariable(_))));
+    // Recursion on smaller or different values is fine.
+    p.load_str(
+        r#"length([], 0);
+           length([_, *t], n) if length(t, m) and n = m + 1;
+           path(x, y) if edge(x, y);
+           path(x, y) if edge(x, z) and path(z, y);
+           countdown(0);
+           countdown(n) if n > 0 and countdown(n - 1);"#,
+    )?;
+    assert!(p.next_message().is_none());
     Ok(())
 }
 
 #[test]
-fn test_in_op() -> TestResult {
-    let mut p = Polar::new();
-    p.load_str("f(x, y) if x in y;")?;
-    qeval(&mut p, "f(1, [1,2,3])");
-    qvar(&mut p, "f(x, [1,2,3])", "x", values![1, 2, 3]);
-
-    // Failure.
-    qnull(&mut p, "4 in [1,2,3]");
-    qeval(&mut p, "4 in [1,2,3] or 1 in [1,2,3]");
-
-    // Make sure we scan the whole list.
-    let q = p.new_query("1 in [1, 2, x, 1]", false)?;
-    let results = query_results!(q);
-    assert_eq!(results.len(), 3);
-    assert!(results[0].0.is_empty());
-    assert_eq!(
-        results[1].0.get(&Symbol("x".to_string())).unwrap().clone(),
-        value!(1)
-    );
-    assert!(results[2].0.is_empty());
-
-    // This returns 3 results, with 1 binding each.
-    let q = p.new_query("f(1, [x,y,z])", false)?;
-    let results = query_results!(q);
-    assert_eq!(results.len(), 3);
-    assert_eq!(results[0].0[&sym!("x")], value!(1));
-    assert_eq!(results[1].0[&sym!("y")], value!(1));
-    assert_eq!(results[2].0[&sym!("z")], value!(1));
+fn test_reorder_conjunctions() -> TestResult {
+    let facts = "f(1); f(2); f(3); g(1); g(2); h(2); h(3);";
+    let conjuncts = vec!["x > 1", "not g(x)", "h(x)", "f(x)", "d.a = x"];
+    for permutation in permute(conjuncts) {
+        let mut p = Polar::new();
+        p.set_reorder_conjunctions(true);
+        p.load_str(facts)?;
+        p.load_str(&format!(
+            "k(x) if d = {{a: x}} and {};",
+            permutation.join(" and ")
+        ))?;
+        assert_eq!(
+            var(&mut p, "k(x)", "x"),
+            values![3],
+            "k(x) failed for {:?}",
+            &permutation
+        );
+    }
 
-    qeval(&mut p, "f({a:1}, [{a:1}, b, c])");
+    // Written order is the default, in which `x in l` runs before `l` is
+    // bound, and so only constrains `x`.
+    let rule = "m(x) if x in l and l = [1, 2];";
+    let mut p = Polar::new();
+    p.load_str(rule)?;
+    let results = var(&mut p, "m(x)", "x");
+    assert!(matches!(&results[..], [Value::Expression(_)]));
+    p.set_reorder_conjunctions(true);
+    qvar(&mut p, "m(x)", "x", values![1, 2]);
 
-    // Negation.
-    qeval(&mut p, "not (4 in [1,2,3])");
-    qnull(&mut p, "not (1 in [1,2,3])");
-    qnull(&mut p, "not (2 in [1,2,3])");
-    qnull(&mut p, "not (3 in [1,2,3])");
+    // Lookups run before what uses their results, even when unbound.
+    let mut p = Polar::new();
+    p.set_reorder_conjunctions(true);
+    p.load_str("n(x) if x.a = 1;")?;
+    qeval(&mut p, "n({a: 1})");
+    let results = var(&mut p, "n(x)", "x");
+    assert!(matches!(&results[..], [Value::Expression(_)]));
 
-    // Nothing is in an empty list.
-    qnull(&mut p, "x in []");
-    qnull(&mut p, "1 in []");
-    qnull(&mut p, "\"foo\" in []");
-    qnull(&mut p, "[] in []");
-    qeval(&mut p, "not x in []");
-    qeval(&mut p, "not 1 in []");
-    qeval(&mut p, "not \"foo\" in []");
-    qeval(&mut p, "not [] in []");
+    // Cuts stay where they're written.
+    let mut p = Polar::new();
+    p.set_reorder_conjunctions(true);
+    p.load_str(facts)?;
+    p.load_str("c(x, y) if f(x) and cut and h(y) and y > x;")?;
+    assert_eq!(var(&mut p, "c(x, y)", "y"), values![2, 3]);
     Ok(())
 }
 
 #[test]
-fn test_matches() {
-    let mut p = Polar::new();
-    qnull(&mut p, "1 matches 2");
-    qeval(&mut p, "1 matches 1");
-    // This doesn't fail because `y` is parsed as an unknown specializer
-    // qnull(&mut p, "x = 1 and y = 2 and x matches y");
-    qeval(&mut p, "x = {foo: 1} and x matches {foo: 1}");
-    qeval(&mut p, "x = {foo: 1, bar: 2} and x matches {foo: 1}");
-    qnull(&mut p, "x = {foo: 1} and x matches {foo: 1, bar: 2}");
-    qnull(&mut p, "x = {foo: 1} and x matches {foo: 2}");
+fn test_for_each_result() -> TestResult {
+    let p = Polar::new();
+    p.load_str("f(1); f(2); f(3); g(x) if f(x) and x.y = x;")?;
+
+    // Stopping from the callback ends the query.
+    let mut seen = vec![];
+    let mut q = p.new_query("f(x)", false)?;
+    let event = q.for_each_result(|bindings| {
+        seen.push(bindings[&sym!("x")].clone());
+        if seen.len() == 2 {
+            ControlFlow::Break(())
+        } else {
+            ControlFlow::Continue(())
+        }
+    })?;
+    assert!(event.is_none());
+    assert_eq!(seen, vec![term!(1), term!(2)]);
+    assert!(q.next().is_none());
+
+    // Events for the host are returned to answer before continuing.
+    let mut results = 0;
+    let mut q = p.new_query("g(x)", false)?;
+    while let Some(event) = q.for_each_result(|_| {
+        results += 1;
+        ControlFlow::Continue(())
+    })? {
+        match event {
+            QueryEvent::ExternalCall { call_id, .. } => q.call_result(call_id, Some(term!(2)))?,
+            event => panic!("unexpected event {:?}", event),
+        }
+    }
+    assert_eq!(results, 1);
+    Ok(())
 }
 
 #[test]
-fn test_keyword_bug() {
-    qparse!("g(a) if a.new(b);", ParseError::ReservedWord { .. });
-    qparse!("f(a) if a.in(b);", ParseError::ReservedWord { .. });
-    qparse!("cut(a) if a;", ParseError::ReservedWord { .. });
-    qparse!("debug(a) if a;", ParseError::ReservedWord { .. });
+fn test_query_stats() -> TestResult {
+    let p = Polar::new();
+    p.load_str("f(1); f(2); g(x) if f(x) and x.y = 1;")?;
+
+    let mut q = p.new_query("g(x)", false)?;
+    q.enable_stats();
+    let mut results = 0;
+    let stats = loop {
+        match q.next_event()? {
+            QueryEvent::ExternalCall { call_id, .. } => q.call_result(call_id, Some(term!(1)))?,
+            QueryEvent::Result { .. } => results += 1,
+            QueryEvent::Done { stats, .. } => break stats.expect("stats"),
+            event => panic!("unexpected event {:?}", event),
+        }
+    };
+    assert_eq!(results, 2);
+    assert_eq!(stats.results, 2);
+    assert_eq!(stats.external_calls, 2);
+    assert!(stats.goals_executed > 0);
+    assert!(stats.peak_choice_points > 0);
+
+    // Off by default.
+    let mut q = p.new_query("f(x)", false)?;
+    loop {
+        match q.next_event()? {
+            QueryEvent::Result { .. } => (),
+            QueryEvent::Done { stats, .. } => break assert!(stats.is_none()),
+            event => panic!("unexpected event {:?}", event),
+        }
+    }
+    Ok(())
 }
 
-/// Test that rule heads work correctly when unification or specializers are used.
 #[test]
-fn test_unify_rule_head() -> TestResult {
-    qparse!("f(Foo{a: 1});", ParseError::UnrecognizedToken { .. });
-    qparse!(
-        "f(new Foo(a: Foo{a: 1}));",
-        ParseError::UnrecognizedToken { .. }
-    );
-    qparse!("f(x: new Foo(a: 1));", ParseError::ReservedWord { .. });
-    qparse!(
-        "f(x: Foo{a: new Foo(a: 1)});",
-        ParseError::ReservedWord { .. }
-    );
-
+fn test_deduplicate_results() -> TestResult {
     let p = Polar::new();
-    p.register_constant(sym!("Foo"), term!(true));
     p.load_str(
-        r#"f(_: Foo{a: 1}, x) if x = 1;
-           g(_: Foo{a: Foo{a: 1}}, x) if x = 1;"#,
+        r#"can_read(r, via) if r in ["a", "b"] and via = "list";
+           can_read("a", via) if via = "owner";"#,
     )?;
+    let run = |query: &str, dedup: Option<Option<Vec<Symbol>>>| -> Result<Vec<Term>, PolarError> {
+        let mut q = p.new_query(query, false)?;
+        if let Some(variables) = dedup {
+            q.deduplicate_results(variables);
+        }
+        let mut results = vec![];
+        while let QueryEvent::Result { bindings, .. } = q.next_event()? {
+            results.push(bindings[&sym!("r")].clone());
+        }
+        Ok(results)
+    };
 
-    let q = p.new_query("f(new Foo(a: 1), x)", false)?;
-    let (results, _externals) = query_results_with_externals(q);
-    assert_eq!(results[0].0[&sym!("x")], value!(1));
-
-    let q = p.new_query("g(new Foo(a: new Foo(a: 1)), x)", false)?;
-    let (results, _externals) = query_results_with_externals(q);
-    assert_eq!(results[0].0[&sym!("x")], value!(1));
+    // Off by default.
+    assert_eq!(
+        run("can_read(r, via)", None)?,
+        vec![term!("a"), term!("b"), term!("a")]
+    );
+    // Results that differ in any variable are kept by default.
+    assert_eq!(
+        run("can_read(r, via)", Some(None))?,
+        vec![term!("a"), term!("b"), term!("a")]
+    );
+    assert_eq!(run("r in [1, 2, 1]", Some(None))?, vec![term!(1), term!(2)]);
+    // Skipping many duplicates in a row doesn't recurse.
+    assert_eq!(
+        run("x in 1..100000 and r = 1", Some(Some(vec![sym!("r")])))?,
+        vec![term!(1)]
+    );
+    // Only the selected variables distinguish results.
+    assert_eq!(
+        run("can_read(r, via)", Some(Some(vec![sym!("r")])))?,
+        vec![term!("a"), term!("b")]
+    );
     Ok(())
 }
 
-/// Test that cut commits to all choice points before the cut, not just the last.
 #[test]
-fn test_cut() -> TestResult {
+fn test_rule_budgets() -> TestResult {
     let mut p = Polar::new();
     p.load_str(
-        r#"a(x) if x = 1 or x = 2;
-           b(x) if x = 3 or x = 4;
-           bcut(x) if x = 3 or x = 4 and cut;
-           c(a, b) if a(a) and b(b) and cut;
-           c_no_cut(a, b) if a(a) and b(b);
-           c_partial_cut(a, b) if a(a) and bcut(b);
-           c_another_partial_cut(a, b) if a(a) and cut and b(b);"#,
+        r#"count(n, n);
+           @budget(goals: 1000)
+           count(i, n) if i < n and count(i + 1, n);
+           allow(n) if count(0, n);"#,
     )?;
+    qeval(&mut p, "allow(5)");
+    let err = p.new_query("allow(100)", false)?.next_event().unwrap_err();
+    assert!(matches!(
+        err.kind,
+        ErrorKind::Runtime(RuntimeError::BudgetExceeded { ref rule, .. }) if rule == "count"
+    ));
+    assert!(err.to_string().contains("Rule count exceeded its budget"));
+
+    // Work done after a rule returns isn't charged to it when backtracking
+    // re-enters it.
+    p.clear_rules();
+    p.load_str(
+        r#"@budget(goals: 40)
+           f(x) if x in [1, 2, 3];
+           g(0);
+           g(n) if n > 0 and g(n - 1);
+           allow(x) if f(x) and g(50) and x = 3;"#,
+    )?;
+    qvar(&mut p, "allow(x)", "x", values![3]);
 
-    // Ensure we return multiple results without a cut.
-    qvars(
-        &mut p,
-        "c_no_cut(a, b)",
-        &["a", "b"],
-        values![[1, 3], [1, 4], [2, 3], [2, 4]],
-    );
-
-    // Ensure that only one result is returned when cut is at the end.
-    qvars(&mut p, "c(a, b)", &["a", "b"], values![[1, 3]]);
-
-    // Make sure that cut in `bcut` does not affect `c_partial_cut`.
-    // If it did, only one result would be returned, [1, 3].
-    qvars(
-        &mut p,
-        "c_partial_cut(a, b)",
-        &["a", "b"],
-        values![[1, 3], [2, 3]],
-    );
-
-    // Make sure cut only affects choice points before it.
-    qvars(
-        &mut p,
-        "c_another_partial_cut(a, b)",
-        &["a", "b"],
-        values![[1, 3], [1, 4]],
-    );
-
-    p.load_str("f(x) if (x = 1 and cut) or x = 2;")?;
-    qvar(&mut p, "f(x)", "x", values![1]);
-    qeval(&mut p, "f(1)");
-    qeval(&mut p, "f(2)");
+    // Other rules have no budget.
+    p.clear_rules();
+    p.load_str(
+        r#"count(n, n);
+           count(i, n) if i < n and count(i + 1, n);"#,
+    )?;
+    qeval(&mut p, "count(0, 100)");
     Ok(())
 }
 
 #[test]
-fn test_forall() -> TestResult {
-    let mut p = Polar::new();
-    p.load_str("all_ones(l) if forall(item in l, item = 1);")?;
+fn test_deprecated_rules() -> TestResult {
+    let p = Polar::new();
+    p.load_str(
+        r#"@deprecated("use new_allow instead")
+           allow(x) if new_allow(x);
+           new_allow(x) if x in [1, 2, 3];
+           old_only(x) if allow(x);"#,
+    )?;
+    while p.next_message().is_some() {}
 
-    qnull(&mut p, "all_ones([2])");
+    let results = |src| -> Result<usize, PolarError> {
+        let mut q = p.new_query(src, false)?;
+        let mut results = 0;
+        while let QueryEvent::Result { .. } = q.next_event()? {
+            results += 1;
+        }
+        Ok(results)
+    };
 
-    qeval(&mut p, "all_ones([1])");
-    qeval(&mut p, "all_ones([1, 1, 1])");
-    qnull(&mut p, "all_ones([1, 2, 1])");
+    // Each call site warns once per query, with its location.
+    assert_eq!(results("allow(x)")?, 3);
+    let msg = p.next_message().unwrap();
+    assert!(matches!(&msg.kind, MessageKind::Warning));
+    assert_eq!(
+        msg.msg,
+        "Rule allow is deprecated: use new_allow instead\n  called by allow(x) at line 1, column 0"
+    );
+    assert!(p.next_message().is_none());
 
-    p.load_str("not_ones(l) if forall(item in l, item != 1);")?;
-    qnull(&mut p, "not_ones([1])");
-    qeval(&mut p, "not_ones([2, 3, 4])");
+    assert_eq!(results("old_only(2)")?, 1);
+    assert!(p
+        .next_message()
+        .unwrap()
+        .msg
+        .ends_with("called by allow(x) at line 4, column 26"));
 
-    qnull(&mut p, "forall(x = 2 or x = 3, x != 2)");
-    qnull(&mut p, "forall(x = 2 or x = 3, x != 3)");
-    qeval(&mut p, "forall(x = 2 or x = 3, x = 2 or x = 3)");
-    qeval(&mut p, "forall(x = 1, x = 1)");
-    qeval(&mut p, "forall(x in [2, 3, 4], x > 1)");
+    // Calls that don't apply the rule don't warn.
+    assert_eq!(results("new_allow(4)")?, 0);
+    assert!(p.next_message().is_none());
+    Ok(())
+}
 
+#[test]
+fn test_annotations() -> TestResult {
+    let p = Polar::new();
     p.load_str(
-        r#"g(1);
-           g(2);
-           g(3);"#,
+        r#"@audit @tag("pii") allow(x) if x = 1;
+           allow(x) if x = 2;
+           @audit deny(x) if x = 3;"#,
     )?;
-    qeval(&mut p, "forall(g(x), x in [1, 2, 3])");
 
-    p.load_str(
-        r#"allow(_: {x: 1}, y) if y = 1;
-           allow(_: {y: 1}, y) if y = 2;
-           allow(_: {z: 1}, y) if y = 3;"#,
-    )?;
-    qeval(
-        &mut p,
-        "forall(allow({x: 1, y: 1, z: 1}, y), y in [1, 2, 3])",
+    let audited: Vec<String> = p
+        .annotated_rules("audit")
+        .iter()
+        .map(|rule| rule.to_polar())
+        .collect();
+    assert_eq!(
+        audited,
+        vec![
+            r#"@audit @tag("pii") allow(x) if x = 1;"#,
+            "@audit deny(x) if x = 3;"
+        ]
     );
-    Ok(())
-}
+    assert!(p.annotated_rules("priority").is_empty());
 
-#[test]
-fn test_emoji_policy() -> TestResult {
-    let mut p = Polar::new();
-    p.load_str(
+    // Traces show the annotations of the rules they pass through.
+    let q = p.new_query("allow(1)", true)?;
+    let results = query_results!(q);
+    let trace = results[0].1.as_ref().unwrap();
+    let expected = indoc!(
         r#"
-                    👩‍🔧("👩‍🦰");
-                    allow(👩, "🛠", "🚙") if 👩‍🔧(👩);
-                "#,
-    )?;
-    qeval(&mut p, r#"allow("👩‍🦰","🛠","🚙")"#);
-    qnull(&mut p, r#"allow("🧟","🛠","🚙")"#);
+        allow(1) [
+          @audit @tag("pii") allow(x) if x = 1; [
+              x = 1 []
+          ]
+        ]
+        "#
+    );
+    assert_eq!(trace.formatted, expected);
     Ok(())
 }
 
 #[test]
-/// Check that boolean expressions evaluate without requiring "= true".
-fn test_boolean_expression() {
-    let mut p = Polar::new();
-    qeval(&mut p, "a = {t: true, f: false} and a.t"); // Succeeds because t is true.
-    qnull(&mut p, "a = {t: true, f: false} and a.f"); // Fails because `f` is not true.
-    qnull(&mut p, "a = {t: true, f: false} and a.f and a.t"); // Fails because `f` is not true.
-    qeval(&mut p, "a = {t: true, f: false} and (a.f or a.t)"); // Succeeds because `t` is true.
+fn test_error_codes() {
+    use std::error::Error;
 
-    qeval(&mut p, "true");
-    qnull(&mut p, "false");
-    qeval(&mut p, "a = true and a");
-    qnull(&mut p, "a = false and a");
+    let p = Polar::new();
+    let err = p.load_str("f(x)").unwrap_err();
+    assert_eq!(err.code(), "P0004");
+    assert!(err
+        .to_string()
+        .starts_with("[P0004] hit the end of the file"));
+    let source = err.source().unwrap();
+    assert!(source.is::<ParseError>());
+    assert!(source.source().is_none());
+
+    let mut q = p.new_query("1 in 2", false).unwrap();
+    let err = q.next_event().unwrap_err();
+    assert!(matches!(
+        err.kind,
+        ErrorKind::Runtime(RuntimeError::TypeError { .. })
+    ));
+    assert_eq!(err.code(), "R0004");
+    assert!(err.to_string().contains("[R0004] Type error: "));
+
+    let formatted = serde_json::to_value(&err).unwrap();
+    assert_eq!(formatted["code"], "R0004");
 }
 
 #[test]
-fn test_float_parsing() {
-    let mut p = Polar::new();
-    qvar(&mut p, "x=1+1", "x", values![2]);
-    qvar(&mut p, "x=1+1.5", "x", values![2.5]);
-    qvar(&mut p, "x=1.e+5", "x", values![1e5]);
-    qvar(&mut p, "x=1e+5", "x", values![1e5]);
-    qvar(&mut p, "x=1e5", "x", values![1e5]);
-    qvar(&mut p, "x=1e-5", "x", values![1e-5]);
-    qvar(&mut p, "x=1.e-5", "x", values![1e-5]);
-    qvar(&mut p, "x=1.0e+15", "x", values![1e15]);
-    qvar(&mut p, "x=1.0E+15", "x", values![1e15]);
-    qvar(&mut p, "x=1.0e-15", "x", values![1e-15]);
+fn test_error_serialization() {
+    let p = Polar::new();
+    let err = p
+        .load("f(x) if\n  g(x) 1;", Some("policy.polar".to_string()))
+        .unwrap_err();
+    let json = serde_json::to_value(&err).unwrap();
+    assert_eq!(json["code"], "P0005");
+    assert_eq!(
+        json["message"],
+        "[P0005] did not expect to find the token '1'"
+    );
+    assert_eq!(json["file"], "policy.polar");
+    assert_eq!(json["line"], 2);
+    assert_eq!(json["column"], 8);
+    assert_eq!(json["snippet"], "002:   g(x) 1;\n            ^");
+    assert!(json["kind"]["Parse"]["UnrecognizedToken"].is_object());
+
+    // Errors without a location leave it out.
+    let json = serde_json::to_value(&PolarError::from(OperationalError::Unknown)).unwrap();
+    assert_eq!(json["code"], "O0002");
+    assert!(json["line"].is_null());
+    assert!(json["snippet"].is_null());
 }
 
+/// Columns count characters, not bytes, on any line ending.
 #[test]
-fn test_assignment() {
-    let mut p = Polar::new();
-    qeval(&mut p, "x := 5 and x == 5");
-    qruntime!(
-        "x := 5 and x := 6",
-        RuntimeError::TypeError { msg: s, .. },
-        s == "Can only assign to unbound variables, x is not unbound."
+fn test_error_positions() {
+    let p = Polar::new();
+    let err = p.load_str("f(\"日本\") if\r\n\tg(x) 1;\r\n").unwrap_err();
+    let json = serde_json::to_value(&err).unwrap();
+    assert_eq!(json["line"], 2);
+    assert_eq!(json["column"], 7);
+    assert_eq!(json["snippet"], "002: \tg(x) 1;\n     \t     ^");
+
+    let err = p.load_str("f(\"日本\", x) if 1 2;").unwrap_err();
+    assert!(err.to_string().ends_with("at line 1, column 17"), "{}", err);
+    let json = serde_json::to_value(&err).unwrap();
+    assert_eq!(
+        json["snippet"],
+        format!("001: f(\"日本\", x) if 1 2;\n{}^", " ".repeat(21))
     );
-    qnull(&mut p, "x := 5 and x > 6");
-    qeval(&mut p, "x := y and y = 6 and x = 6");
-
-    // confirm old syntax -> parse error
-    qparse!("f(x) := g(x);", ParseError::UnrecognizedToken { .. });
 }
 
 #[test]
-fn test_rule_index() -> TestResult {
-    let mut p = Polar::new();
-    p.load_str(
-        r#"f(1, 1, "x");
-           f(1, 1, "y");
-           f(1, x, "y") if x = 2;
-           f(1, 2, {b: "y"});
-           f(1, 3, {c: "z"});"#,
+fn test_error_rule_stack() -> TestResult {
+    let p = Polar::new();
+    p.load(
+        r#"f(x) if g(x, "a long string that will be cut short in the stack");
+           g(x, _) if h([x]);
+           h([y]) if 1 in y;"#,
+        Some("stack.polar".to_string()),
     )?;
-    // Exercise the index.
-    qeval(&mut p, r#"f(1, 1, "x")"#);
-    qeval(&mut p, r#"f(1, 1, "y")"#);
-    qvar(&mut p, r#"f(1, x, "y")"#, "x", values![1, 2]);
-    qnull(&mut p, r#"f(1, 1, "z")"#);
-    qnull(&mut p, r#"f(1, 2, "x")"#);
-    qeval(&mut p, r#"f(1, 2, {b: "y"})"#);
-    qeval(&mut p, r#"f(1, 3, {c: "z"})"#);
+    let mut q = p.new_query("f(1)", false)?;
+    let err = q.next_event().unwrap_err();
+    let frames: Vec<String> = err.stack.iter().map(|frame| frame.to_string()).collect();
+    assert_eq!(
+        frames,
+        vec![
+            "f(1) at line 1, column 1",
+            "g(1, \"a long string that will be cut short in...) \
+             at line 1, column 9 in file stack.polar",
+            "h([1]) at line 2, column 23 in file stack.polar",
+        ]
+    );
+    let json = serde_json::to_value(&err).unwrap();
+    assert_eq!(json["stack"][2]["rule"], "h");
+    assert_eq!(json["stack"][2]["args"][0], "[1]");
+
+    // Errors outside of any rule have no frames.
+    let mut q = p.new_query("1 in 2", false)?;
+    assert!(q.next_event().unwrap_err().stack.is_empty());
     Ok(())
 }
 
 #[test]
-fn test_fib() -> TestResult {
-    let mut p = Polar::new();
-    p.load_str(
-        r#"fib(0, 1) if cut;
-           fib(1, 1) if cut;
-           fib(n, a+b) if fib(n-1, a) and fib(n-2, b);"#,
-    )?;
-    qvar(&mut p, r#"fib(0, x)"#, "x", values![1]);
-    qvar(&mut p, r#"fib(1, x)"#, "x", values![1]);
-    qvar(&mut p, r#"fib(2, x)"#, "x", values![2]);
-    qvar(&mut p, r#"fib(3, x)"#, "x", values![3]);
-    qvar(&mut p, r#"fib(4, x)"#, "x", values![5]);
-    qvar(&mut p, r#"fib(5, x)"#, "x", values![8]);
+fn test_source_map() -> TestResult {
+    // Generated from a template whose line 5 expands to one rule per
+    // resource, and whose line 8 is copied as is.
+    let generated = r#"f(x) if g(x, "doc");
+                       g(x, "doc") if h([x]);
+                       g(x, "img") if h([x]);
+                       h([y]) if 1 in y;"#;
+    let mapping = |line, original_line, original_column| SourceMapping {
+        line,
+        original_line,
+        original_column,
+    };
+    let options = LoadOptions {
+        source_map: Some(SourceMap {
+            file: "template.polar".to_string(),
+            mappings: vec![mapping(0, 2, 0), mapping(1, 4, 2), mapping(3, 7, 0)],
+        }),
+        ..LoadOptions::default()
+    };
+    let p = Polar::new();
+    p.load_with_options(generated, Some("generated.polar".to_string()), &options)?;
+    let mut q = p.new_query("f(1)", false)?;
+    let err = q.next_event().unwrap_err();
+    let frames: Vec<String> = err.stack.iter().map(|frame| frame.to_string()).collect();
+    assert_eq!(
+        frames,
+        vec![
+            "f(1) at line 1, column 1",
+            "g(1, \"doc\") at line 3, column 9 in file template.polar",
+            "h([1]) at line 5, column 41 in file template.polar",
+        ]
+    );
+    assert!(err
+        .to_string()
+        .contains("at line 8, column 34 in file template.polar"));
+
+    let err = Polar::new()
+        .load_with_options("f(x) if g(x);\ng(x) if x = ;", None, &options)
+        .unwrap_err();
+    let context = err.context.unwrap();
+    assert_eq!((context.row, context.column), (4, 14));
+    assert!(context.to_string().ends_with("in file template.polar"));
     Ok(())
 }
 
 #[test]
-fn test_duplicated_rule() -> TestResult {
-    let mut p = Polar::new();
-    p.load_str(
-        r#"f(1);
-           f(1);"#,
-    )?;
-    qvar(&mut p, "f(x)", "x", values![1, 1]);
+fn test_malformed_terms_are_errors() -> TestResult {
+    let p = Polar::new();
+    p.load_str("f(x) if x = 1;")?;
+    let malformed = |operator, args| term!(Value::Expression(Operation { operator, args }));
+    for term in vec![
+        malformed(Operator::Unify, vec![term!(1)]),
+        malformed(Operator::Not, vec![]),
+        malformed(Operator::Dot, vec![term!(1)]),
+        malformed(Operator::Lt, vec![term!(1)]),
+        malformed(Operator::FindAll, vec![term!(sym!("x"))]),
+    ] {
+        let mut q = p.new_query_from_term(term, false);
+        let err = q.next_event().unwrap_err();
+        assert!(
+            matches!(
+                err.kind,
+                ErrorKind::Operational(OperationalError::InvalidState(_))
+            ),
+            "{}",
+            err
+        );
+    }
+
+    let call = term!(Value::Call(Call {
+        name: sym!("f"),
+        args: vec![],
+        kwargs: Some(btreemap! {sym!("x") => term!(1)}),
+    }));
+    let mut q = p.new_query_from_term(call, false);
+    assert!(matches!(
+        q.next_event().unwrap_err().kind,
+        ErrorKind::Runtime(RuntimeError::Unsupported { .. })
+    ));
+
+    // Answers to calls the query didn't make are rejected.
+    let mut q = p.new_query("f(x)", false)?;
+    assert!(q.call_result(1234, None).is_err());
+    assert!(q.question_result(1234, true).is_err());
     Ok(())
 }
 
 #[test]
-fn test_numeric_applicability() -> TestResult {
-    let mut p = Polar::new();
-    let eps = f64::EPSILON;
-    let nan1 = f64::NAN;
-    let nan2 = f64::from_bits(f64::NAN.to_bits() | 1);
-    assert!(eps.is_normal() && nan1.is_nan() && nan2.is_nan());
-    p.register_constant(sym!("eps"), term!(eps));
-    p.register_constant(sym!("nan1"), term!(nan1));
-    p.register_constant(sym!("nan2"), term!(nan2));
-    p.load_str(
-        r#"f(0);
-           f(1);
-           f(9007199254740991); # (1 << 53) - 1
-           f(9007199254740992); # (1 << 53)
-           f(9223372036854775807); # i64::MAX
-           f(-9223372036854775807); # i64::MIN + 1
-           f(9223372036854776000.0); # i64::MAX as f64
-           f(nan1); # NaN"#,
-    )?;
-    qeval(&mut p, "f(0)");
-    qeval(&mut p, "f(0.0)");
-    qnull(&mut p, "f(eps)");
-    qeval(&mut p, "f(1)");
-    qeval(&mut p, "f(1.0)");
-    qnull(&mut p, "f(1.0000000000000002)");
-    qnull(&mut p, "f(9007199254740990)");
-    qnull(&mut p, "f(9007199254740990.0)");
-    qeval(&mut p, "f(9007199254740991)");
-    qeval(&mut p, "f(9007199254740991.0)");
-    qeval(&mut p, "f(9007199254740992)");
-    qeval(&mut p, "f(9007199254740992.0)");
-    qeval(&mut p, "f(9223372036854775807)");
-    qeval(&mut p, "f(-9223372036854775807)");
-    qeval(&mut p, "f(9223372036854776000.0)");
-    qnull(&mut p, "f(nan1)");
-    qnull(&mut p, "f(nan2)");
+fn test_parser_limits() -> TestResult {
+    let p = Polar::new();
+    p.set_parser_limits(ParserLimits {
+        max_source_len: Some(100),
+        max_depth: Some(4),
+        max_rules: Some(2),
+        max_collection_len: Some(3),
+    });
+    let limit = |result: Result<(), PolarError>| match result.unwrap_err().kind {
+        ErrorKind::Parse(ParseError::LimitExceeded { limit, .. }) => limit,
+        kind => panic!("unexpected error {:?}", kind),
+    };
+
+    p.load_str("f([1, [2, {a: 3}]]); g([1, 2, 3], {a: 1, b: 2, c: 3});")?;
+    assert_eq!(limit(p.load_str("f([[[[1]]]]);")), "nesting depth");
+    assert_eq!(limit(p.load_str("f([1, 2, 3, 4]);")), "collection length");
+    assert_eq!(
+        limit(p.load_str("f({a: 1, b: 2, c: 3, d: 4});")),
+        "collection length"
+    );
+    // Arguments aren't collections.
+    p.load_str("f(1, 2, 3, 4);")?;
+    assert_eq!(limit(p.load_str("f(1); f(2); f(3);")), "number of rules");
+    assert_eq!(limit(p.load_str(&"#".repeat(101))), "source length");
+
+    // Queries are limited too.
+    assert_eq!(
+        limit(p.new_query("x = [1, 2, 3, 4]", false).map(|_| ())),
+        "collection length"
+    );
+    let err = p.load_str("f(1);\nf([1, 2,\n 3, 4]);").unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "[P0011] exceeded the parser's limit on collection length of 3 at line 2, column 3"
+    );
     Ok(())
 }
 
 #[test]
-fn test_external_unify() -> TestResult {
+fn test_max_results() -> TestResult {
     let p = Polar::new();
-    p.load_str(
-        r#"selfEq(x) if eq(x, x);
-           eq(x, x);"#,
-    )?;
-
-    let q = p.new_query("selfEq(new Foo())", false)?;
-    let (results, _externals) = query_results_with_externals(q);
-    assert_eq!(results.len(), 1);
+    p.load_str("f(x) if x in [1, 2, 3];")?;
+    let count = |src| -> Result<usize, PolarError> {
+        let mut results = 0;
+        for event in p.new_query(src, false)? {
+            if let QueryEvent::Result { .. } = event? {
+                results += 1;
+            }
+        }
+        Ok(results)
+    };
 
-    let q = p.new_query("eq(new Foo(), new Foo())", false)?;
-    let (results, _externals) = query_results_with_externals(q);
-    assert!(results.is_empty());
+    assert_eq!(count("f(x)")?, 3);
+    p.set_max_results(Some(3));
+    assert_eq!(count("f(x)")?, 3);
+    p.set_max_results(Some(2));
+    let err = count("f(x)").unwrap_err();
+    assert!(matches!(
+        err.kind,
+        ErrorKind::Runtime(RuntimeError::TooManyResults { max: 2 })
+    ));
+    assert!(err
+        .to_string()
+        .starts_with("[R0012] Query produced more than"));
+    assert_eq!(count("f(1) or f(2)")?, 2);
+    p.set_max_results(None);
+    assert_eq!(count("f(x)")?, 3);
     Ok(())
 }
 
 #[test]
-fn test_list_results() -> TestResult {
+fn test_string_collation() -> TestResult {
     let mut p = Polar::new();
-    p.load_str(
-        r#"delete([x, *xs], x, ys) if delete(xs, x, ys);
-           delete([x, *xs], z, [x, *ys]) if
-               x != z and delete(xs, z, ys);
-           delete([], _, []);"#,
-    )?;
-    qeval(&mut p, "delete([1,2,3,2,1],2,[1,3,1])");
+    qeval(&mut p, r#""B" < "a""#);
     qvar(
         &mut p,
-        "delete([1,2,3,2,1],2,result)",
-        "result",
-        vec![value!([1, 3, 1])],
+        r#"sort(["b", "B", "a", "A"], x)"#,
+        "x",
+        vec![value!(["A", "B", "a", "b"])],
     );
 
-    qvar(&mut p, "[1,2] = [1,*ys]", "ys", vec![value!([2])]);
-    qvar(
-        &mut p,
-        "[1,2,*xs] = [1,*ys] and [1,2,3] = [1,*ys]",
-        "xs",
-        vec![value!([3])],
-    );
+    p.set_string_collation(StringCollation::CaseInsensitive);
+    qeval(&mut p, r#""a" < "B""#);
+    qeval(&mut p, r#""a" <= "A" and "A" <= "a""#);
+    qnull(&mut p, r#""a" < "A""#);
+    // Equality is still exact.
+    qnull(&mut p, r#""a" == "A""#);
+    qnull(&mut p, r#""a" = "A""#);
+    // Sorting is stable, so equal strings keep their order.
     qvar(
         &mut p,
-        "[1,2,*xs] = [1,*ys] and [1,2,3] = [1,*ys]",
-        "ys",
-        vec![value!([2, 3])],
+        r#"sort(["b", "B", "a", "A"], x)"#,
+        "x",
+        vec![value!(["a", "A", "b", "B"])],
     );
-    qvar(
+    qeval(
         &mut p,
-        "[1,2,*xs] = [1,*ys] and [1,2,3] = [1,*ys]",
-        "ys",
-        vec![value!([2, 3])],
+        r#"sort([["b", 1], ["A", 2]], [["A", 2], ["b", 1]])"#,
     );
-    qeval(&mut p, "xs = [2] and [1,2] = [1, *xs]");
-    qnull(&mut p, "[1, 2] = [2, *ys]");
     Ok(())
 }
 
+#[cfg(feature = "unicode-collation")]
 #[test]
-fn test_expressions_in_lists() -> TestResult {
+fn test_unicode_string_collation() -> TestResult {
     let mut p = Polar::new();
-    p.load_str(
-        r#"scope(actor: Dictionary, "read", "Person", filters) if
-               filters = ["id", "=", actor.id];"#,
-    )?;
-    qeval(
-        &mut p,
-        r#"scope({id: 1}, "read", "Person", ["id", "=", 1])"#,
-    );
-    qnull(
-        &mut p,
-        r#"scope({id: 2}, "read", "Person", ["id", "=", 1])"#,
-    );
-    qnull(
-        &mut p,
-        r#"scope({id: 1}, "read", "Person", ["not_id", "=", 1])"#,
-    );
-    qeval(&mut p, r#"d = {x: 1} and [d.x, 1+1] = [1, 2]"#);
+    qeval(&mut p, r#""z" < "é""#);
+    p.set_string_collation(StringCollation::Unicode);
+    qeval(&mut p, r#""é" < "z""#);
     qvar(
         &mut p,
-        r#"d = {x: 1} and [d.x, 1+1] = [1, *rest]"#,
-        "rest",
-        vec![value!([2])],
+        r#"sort(["zebra", "éclair", "apple"], x)"#,
+        "x",
+        vec![value!(["apple", "éclair", "zebra"])],
     );
     Ok(())
 }
 
 #[test]
-fn test_list_matches() {
-    let mut p = Polar::new();
-    qeval(&mut p, "[] matches []");
-    qnull(&mut p, "[1] matches []");
-    qnull(&mut p, "[] matches [1]");
-    qnull(&mut p, "[1, 2] matches [1, 2, 3]");
-    qnull(&mut p, "[2, 1] matches [1, 2]");
-    qeval(&mut p, "[1, 2, 3] matches [1, 2, 3]");
-    qnull(&mut p, "[1, 2, 3] matches [1, 2]");
+fn test_normalize_strings() -> TestResult {
+    let composed = "caf\u{e9}";
+    let decomposed = "cafe\u{301}";
+    let matches = |p: &Polar| -> Result<(bool, bool), PolarError> {
+        let mut q = p.new_query("f(x)", false)?;
+        q.bind(sym!("x"), term!(decomposed))?;
+        let bound = q.next_event()?;
+        let query = format!("g(\"{}\")", decomposed);
+        let looked_up = query_results!(p.new_query(&query, false)?, |_, _, _, _, _| Some(term!(
+            decomposed
+        )));
+        Ok((
+            matches!(bound, QueryEvent::Result { .. }),
+            !looked_up.is_empty(),
+        ))
+    };
+    let policy = format!(
+        "f(\"{}\"); g(s) if new Menu().item = s and s = \"{}\";",
+        composed, composed
+    );
 
-    qnull(&mut p, "[x] matches []");
-    qnull(&mut p, "[] matches [x]");
-    qnull(&mut p, "[1, 2, x] matches [1, 2]");
-    qnull(&mut p, "[1, x] matches [1, 2, 3]");
-    qnull(&mut p, "[2, x] matches [1, 2]");
-    qvar(&mut p, "[1, 2, x] matches [1, 2, 3]", "x", values![3]);
-    qnull(&mut p, "[1, 2, 3] matches [1, x]");
+    let p = Polar::new();
+    p.load_str(&policy)?;
+    assert_eq!(matches(&p)?, (false, false));
 
-    qvar(&mut p, "[] matches [*ys]", "ys", vec![value!([])]);
-    qvar(&mut p, "[*xs] matches []", "xs", vec![value!([])]);
-    qvar(&mut p, "[*xs] matches [1]", "xs", vec![value!([1])]);
-    qvar(&mut p, "[1] matches [*ys]", "ys", vec![value!([1])]);
-    qeval(&mut p, "[xs] matches [*ys]");
-    qeval(&mut p, "[*xs] matches [ys]");
-    qeval(&mut p, "[*xs] matches [*ys]");
-    qvar(&mut p, "[1,2,3] matches [1,2,*xs]", "xs", vec![value!([3])]);
-    qvar(
-        &mut p,
-        "[1,2,*xs] matches [1,2,3,*ys]",
-        "xs",
-        vec![value!([3, Value::RestVariable(Symbol::new("ys"))])],
-    );
+    let p = Polar::new();
+    p.set_normalize_strings(true);
+    p.load_str(&policy)?;
+    assert_eq!(matches(&p)?, (true, true));
+    Ok(())
 }
 
 #[test]
-#[allow(clippy::unnecessary_wraps)]
-fn error_on_binding_expressions_and_patterns_to_variables() -> TestResult {
-    qruntime!(
-        "x matches y",
-        RuntimeError::TypeError { msg: m, .. },
-        m == "cannot unify patterns directly `x` = `y{}`"
+fn test_print() -> TestResult {
+    // TODO: If POLAR_LOG is on this test will fail.
+    let p = Polar::new();
+    p.load_str("f(x,y,z) if print(x, y, z);")?;
+    let message_handler = |output: &Message| {
+        assert!(matches!(&output.kind, MessageKind::Print));
+        assert_eq!(&output.msg, "1, 2, 3");
+    };
+    let q = p.new_query("f(1, 2, 3)", false)?;
+    let _results = query_results!(q, @msgs message_handler);
+    Ok(())
+}
+
+#[test]
+fn test_unknown_specializer_suggestions() -> TestResult {
+    let p = Polar::new();
+    p.load_str("f(s: string) if s;")?;
+    let msg = p.next_message().unwrap();
+    assert!(matches!(&msg.kind, MessageKind::Warning));
+    assert_eq!(
+        &msg.msg,
+        "Unknown specializer string, did you mean String?\n001: f(s: string) if s;\n          ^"
     );
     Ok(())
 }
 
 #[test]
-fn test_builtin_iterables() {
+fn test_rest_vars() -> TestResult {
     let mut p = Polar::new();
+    qvar(&mut p, "[1,2,3] = [*rest]", "rest", vec![value!([1, 2, 3])]);
+    qvar(&mut p, "[1,2,3] = [1,*rest]", "rest", vec![value!([2, 3])]);
+    qvar(&mut p, "[1,2,3] = [1,2,*rest]", "rest", vec![value!([3])]);
+    qvar(&mut p, "[1,2,3] = [1,2,3,*rest]", "rest", vec![value!([])]);
+    qnull(&mut p, "[1,2,3] = [1,2,3,4,*_rest]");
 
-    qnull(&mut p, r#"x in """#);
-    qvar(
-        &mut p,
-        "x in \"abc\"",
-        "x",
-        vec![value!("a"), value!("b"), value!("c")],
-    );
-    qnull(&mut p, "x in {}");
+    p.load_str(
+        r#"member(x, [x, *_rest]);
+           member(x, [_first, *rest]) if member(x, rest);"#,
+    )?;
+    qeval(&mut p, "member(1, [1,2,3])");
+    qeval(&mut p, "member(3, [1,2,3])");
+    qeval(&mut p, "not member(4, [1,2,3])");
+    qvar(&mut p, "member(x, [1,2,3])", "x", values![1, 2, 3]);
+
+    p.load_str(
+        r#"append([], x, x);
+           append([first, *rest], x, [first, *tail]) if append(rest, x, tail);"#,
+    )?;
+    qeval(&mut p, "append([], [], [])");
+    qeval(&mut p, "append([], [1,2,3], [1,2,3])");
+    qeval(&mut p, "append([1], [2,3], [1,2,3])");
+    qeval(&mut p, "append([1,2], [3], [1,2,3])");
+    qeval(&mut p, "append([1,2,3], [], [1,2,3])");
+    qeval(&mut p, "not append([1,2,3], [4], [1,2,3])");
+
+    let a = &var(&mut p, "[*_] in [*a] and [*b] in [*_] and b = 1", "a")[0];
+    assert!(matches!(a, Value::List(b) if matches!(b[0].value(), Value::RestVariable(_))));
+    Ok(())
+}
+
+#[test]
+fn test_cyclic_bindings() -> TestResult {
+    let mut p = Polar::new();
+    let cycle = Value::Variable(sym!("<cycle>"));
     qvar(
         &mut p,
-        "x in {a: 1, b: 2}",
+        "x = [x]",
         "x",
-        vec![value!(["a", 1]), value!(["b", 2])],
+        vec![value!([value!([cycle.clone()])])],
     );
-    qeval(&mut p, r#"["a", 1] in {a: 1, b: 2}"#);
     qvar(
         &mut p,
-        "[x, _] in {a: 1, b: 2}",
-        "x",
-        vec![value!("a"), value!("b")],
+        "x = {a: y} and y = [1, x]",
+        "y",
+        vec![value!([
+            value!(1),
+            value!(btreemap! {sym!("a") => term!(value!([value!(1), cycle]))})
+        ])],
     );
-    qeval(&mut p, r#"["a", 1] in {a: 1, b: 2}"#);
-    qvar(
-        &mut p,
-        "[_, x] in {a: 1, b: 2}",
-        "x",
-        vec![value!(1), value!(2)],
+    Ok(())
+}
+
+#[test]
+fn test_in_op() -> TestResult {
+    let mut p = Polar::new();
+    p.load_str("f(x, y) if x in y;")?;
+    qeval(&mut p, "f(1, [1,2,3])");
+    qvar(&mut p, "f(x, [1,2,3])", "x", values![1, 2, 3]);
+
+    // Failure.
+    qnull(&mut p, "4 in [1,2,3]");
+    qeval(&mut p, "4 in [1,2,3] or 1 in [1,2,3]");
+
+    // Make sure we scan the whole list.
+    let q = p.new_query("1 in [1, 2, x, 1]", false)?;
+    let results = query_results!(q);
+    assert_eq!(results.len(), 3);
+    assert!(results[0].0.is_empty());
+    assert_eq!(
+        results[1].0.get(&Symbol("x".to_string())).unwrap().clone(),
+        value!(1)
     );
+    assert!(results[2].0.is_empty());
 
-    qeval(&mut p, r#""b" in "abc""#);
-    qnull(&mut p, r#""d" in "abc""#);
-    qeval(&mut p, r#"forall(x in "abc", x in "abacus")"#);
-    qnull(&mut p, r#"forall(x in "abcd", x in "abacus")"#);
+    // This returns 3 results, with 1 binding each.
+    let q = p.new_query("f(1, [x,y,z])", false)?;
+    let results = query_results!(q);
+    assert_eq!(results.len(), 3);
+    assert_eq!(results[0].0[&sym!("x")], value!(1));
+    assert_eq!(results[1].0[&sym!("y")], value!(1));
+    assert_eq!(results[2].0[&sym!("z")], value!(1));
+
+    qeval(&mut p, "f({a:1}, [{a:1}, b, c])");
+
+    // Negation.
+    qeval(&mut p, "not (4 in [1,2,3])");
+    qnull(&mut p, "not (1 in [1,2,3])");
+    qnull(&mut p, "not (2 in [1,2,3])");
+    qnull(&mut p, "not (3 in [1,2,3])");
+
+    // Nothing is in an empty list.
+    qnull(&mut p, "x in []");
+    qnull(&mut p, "1 in []");
+    qnull(&mut p, "\"foo\" in []");
+    qnull(&mut p, "[] in []");
+    qeval(&mut p, "not x in []");
+    qeval(&mut p, "not 1 in []");
+    qeval(&mut p, "not \"foo\" in []");
+    qeval(&mut p, "not [] in []");
+    Ok(())
+}
+
+#[test]
+fn test_matches() {
+    let mut p = Polar::new();
+    qnull(&mut p, "1 matches 2");
+    qeval(&mut p, "1 matches 1");
+    // This doesn't fail because `y` is parsed as an unknown specializer
+    // qnull(&mut p, "x = 1 and y = 2 and x matches y");
+    qeval(&mut p, "x = {foo: 1} and x matches {foo: 1}");
+    qeval(&mut p, "x = {foo: 1, bar: 2} and x matches {foo: 1}");
+    qnull(&mut p, "x = {foo: 1} and x matches {foo: 1, bar: 2}");
+    qnull(&mut p, "x = {foo: 1} and x matches {foo: 2}");
+}
+
+#[test]
+fn test_keyword_bug() {
+    qparse!("g(a) if a.new(b);", ParseError::ReservedWord { .. });
+    qparse!("f(a) if a.in(b);", ParseError::ReservedWord { .. });
+    qparse!("cut(a) if a;", ParseError::ReservedWord { .. });
+    qparse!("debug(a) if a;", ParseError::ReservedWord { .. });
+}
+
+/// Test that rule heads work correctly when unification or specializers are used.
+#[test]
+fn test_unify_rule_head() -> TestResult {
+    qparse!("f(x: new Foo(a: 1));", ParseError::ReservedWord { .. });
+    qparse!(
+        "f(x: Foo{a: new Foo(a: 1)});",
+        ParseError::ReservedWord { .. }
+    );
+
+    let p = Polar::new();
+    p.register_constant(sym!("Foo"), term!(true));
+    p.load_str(
+        r#"f(_: Foo{a: 1}, x) if x = 1;
+           g(_: Foo{a: Foo{a: 1}}, x) if x = 1;"#,
+    )?;
+
+    let q = p.new_query("f(new Foo(a: 1), x)", false)?;
+    let (results, _externals) = query_results_with_externals(q);
+    assert_eq!(results[0].0[&sym!("x")], value!(1));
+
+    let q = p.new_query("g(new Foo(a: new Foo(a: 1)), x)", false)?;
+    let (results, _externals) = query_results_with_externals(q);
+    assert_eq!(results[0].0[&sym!("x")], value!(1));
+    Ok(())
+}
+
+#[test]
+fn test_instance_literal_terms() -> TestResult {
+    let p = Polar::new();
+    p.register_constant(sym!("Foo"), term!(true));
+    p.load_str(
+        r#"f(x) if x = Foo{a: 1};
+           g([Foo{a: 2}, {foo: Foo{a: 3}}]);
+           h(x) if Foo{a: x}.a = 4;
+           k(y) if match (Foo{a: 5}) { Foo{a: 5} => y = "five"; _ => y = "other"; };
+           m(Foo{a: 6});"#,
+    )?;
+    let count = |src| -> Result<usize, PolarError> {
+        let (results, _externals) = query_results_with_externals(p.new_query(src, false)?);
+        Ok(results.len())
+    };
+
+    let q = p.new_query("f(x)", false)?;
+    let (results, externals) = query_results_with_externals(q);
+    let id = match &results[0].0[&sym!("x")] {
+        Value::ExternalInstance(ExternalInstance { instance_id, .. }) => *instance_id,
+        x => panic!("expected an instance, got {:?}", x),
+    };
+    assert_eq!(
+        externals.constructor(id).to_polar(),
+        "Foo(a: 1)",
+        "constructed through MakeExternal"
+    );
+
+    assert_eq!(count("f(x) and x.a = 1")?, 1);
+    assert_eq!(count("g([x, {foo: y}]) and x.a = 2 and y.a = 3")?, 1);
+    assert_eq!(count("h(4)")?, 1);
+    assert_eq!(count("h(5)")?, 0);
+    assert_eq!(count(r#"k("five")"#)?, 1);
+    // In a rule head, the instance is constructed when the rule is applied.
+    assert_eq!(count("m(x) and x.a = 6")?, 1);
+    Ok(())
+}
+
+#[test]
+fn test_nested_instance_literals() -> TestResult {
+    let p = Polar::new();
+    p.load_str(
+        r#"outer(x) if x = Outer{b: Inner{n: Leaf{}}, a: [Inner{n: 1}, Inner{n: 2}]};
+           outer_head(Outer{a: Inner{n: 3}});"#,
+    )?;
+    // Each constructed class, with the classes of the instances passed to it.
+    let constructed = |src| -> Result<Vec<String>, PolarError> {
+        fn instances(term: &Term, classes: &HashMap<u64, String>, found: &mut Vec<String>) {
+            match term.value() {
+                Value::ExternalInstance(instance) => {
+                    found.push(classes[&instance.instance_id].clone())
+                }
+                Value::List(terms) => terms.iter().for_each(|t| instances(t, classes, found)),
+                Value::Call(call) => call
+                    .kwargs
+                    .iter()
+                    .flat_map(|kwargs| kwargs.values())
+                    .for_each(|t| instances(t, classes, found)),
+                _ => (),
+            }
+        }
+
+        let mut q = p.new_query(src, false)?;
+        let (mut classes, mut constructed) = (HashMap::new(), vec![]);
+        loop {
+            match q.next_event()? {
+                QueryEvent::Done { .. } => break,
+                QueryEvent::MakeExternal {
+                    instance_id,
+                    constructor,
+                } => {
+                    let class = match constructor.value() {
+                        Value::Call(call) => call.name.0.clone(),
+                        _ => panic!("expected a call"),
+                    };
+                    let mut args = vec![];
+                    instances(&constructor, &classes, &mut args);
+                    constructed.push(format!("{}({})", class, args.join(", ")));
+                    classes.insert(instance_id, class);
+                }
+                QueryEvent::Result { .. } => (),
+                event => panic!("unexpected event {:?}", event),
+            }
+        }
+        Ok(constructed)
+    };
+    // Inner literals are constructed first, in order of field name, and
+    // passed to the constructors of the literals containing them.
+    assert_eq!(
+        constructed("outer(x)")?,
+        vec![
+            "Inner()",
+            "Inner()",
+            "Leaf()",
+            "Inner(Leaf)",
+            "Outer(Inner, Inner, Inner)"
+        ]
+    );
+    assert_eq!(
+        constructed("outer_head(x)")?,
+        vec!["Inner()", "Outer(Inner)"]
+    );
+
+    let q = p.new_query("outer(x)", false)?;
+    let (results, _) = query_results_with_externals(q);
+    assert_eq!(
+        results[0].0[&sym!("x")].to_polar(),
+        "Outer(a: [Inner(n: 1), Inner(n: 2)], b: Inner(n: Leaf()))"
+    );
+    Ok(())
+}
+
+/// Test that expressions in rule heads are evaluated as part of the body.
+#[test]
+fn test_rule_head_expressions() -> TestResult {
+    let mut p = Polar::new();
+    p.load_str(
+        r#"successor(x, x + 1);
+           double(x, x * 2) if x > 0;
+           half(x * 2, y) if y = x;
+           field({a: x}, x.b);"#,
+    )?;
+    qvar(&mut p, "successor(1, y)", "y", values![2]);
+    qeval(&mut p, "successor(1, 2)");
+    qnull(&mut p, "successor(1, 3)");
+    qvar(&mut p, "double(3, y)", "y", values![6]);
+    qnull(&mut p, "double(-3, _)");
+    qvar(&mut p, "half(x, 4)", "x", values![8]);
+    qvar(&mut p, "field({a: {b: 1}}, y)", "y", values![1]);
+    Ok(())
+}
+
+#[test]
+fn test_rule_guards() -> TestResult {
+    let mut p = Polar::new();
+    p.load_str(
+        r#"size(x, "big") when x > 10 if cut;
+           size(x, "small") when x <= 10;
+           size(_, "any");
+           level(x, y) when x.level = y;"#,
+    )?;
+    // Guarded rules sort ahead of unguarded ones with the same specializers,
+    // and a rule whose guard fails gives no results.
+    qvar(&mut p, "size(20, s)", "s", values!["big"]);
+    qvar(&mut p, "size(5, s)", "s", values!["small", "any"]);
+    qvar(&mut p, "level({level: 3}, y)", "y", values![3]);
+    qnull(&mut p, "level({level: 3}, 4)");
+    Ok(())
+}
+
+#[test]
+fn test_list_comprehensions() -> TestResult {
+    let mut p = Polar::new();
+    p.load_str(
+        r#"doubled(xs, ys) if ys = [x * 2 | x in xs, x > 1];
+           names(people, ns) if ns = [p.name | p in people];
+           f(1);
+           f(2);"#,
+    )?;
+    qvar(&mut p, "doubled([1, 2, 3], ys)", "ys", vec![value!([4, 6])]);
+    qvar(&mut p, "doubled([], ys)", "ys", vec![value!([])]);
+    qvar(
+        &mut p,
+        r#"names([{name: "a"}, {name: "b"}], ns)"#,
+        "ns",
+        vec![value!(["a", "b"])],
+    );
+    qeval(&mut p, "[[x, y] | f(x), f(y), x != y] = [[1, 2], [2, 1]]");
+    qnull(&mut p, "[x | x in [1, 2]] = [2, 1]");
+    Ok(())
+}
+
+#[test]
+fn test_dict_of() -> TestResult {
+    let mut p = Polar::new();
+    p.load_str(
+        r#"role_permissions(roles, perms) if
+               dict_of([[r.name, r.perm] | r in roles], perms);"#,
+    )?;
+    qvar(
+        &mut p,
+        r#"role_permissions([{name: "admin", perm: "write"}, {name: "guest", perm: "read"}], perms)"#,
+        "perms",
+        vec![value!(btreemap! {
+            sym!("admin") => term!("write"),
+            sym!("guest") => term!("read"),
+        })],
+    );
+    qvar(
+        &mut p,
+        r#"dict_of([["a", 1], ["a", 2]], d)"#,
+        "d",
+        vec![value!(btreemap! {sym!("a") => term!(2)})],
+    );
+    qeval(&mut p, "dict_of([], {})");
+    qruntime!("dict_of([[1, 2]], d)", RuntimeError::TypeError { .. });
+    qruntime!("dict_of(x, d)", RuntimeError::TypeError { .. });
+    Ok(())
+}
+
+#[test]
+fn test_sort() -> TestResult {
+    let mut p = Polar::new();
+    p.load_str(
+        r#"highest_priority(roles, role) if
+               sort_by(roles, "priority", [role, *_]);"#,
+    )?;
+    qvar(
+        &mut p,
+        "sort([3, 1.5, 2], xs)",
+        "xs",
+        vec![value!([1.5, 2, 3])],
+    );
+    qvar(
+        &mut p,
+        r#"sort(["b", 1, "a", true], xs)"#,
+        "xs",
+        vec![value!([true, 1, "a", "b"])],
+    );
+    qvar(&mut p, "sort([], xs)", "xs", vec![value!([])]);
+    qvar(
+        &mut p,
+        r#"sort_by([{n: "a", p: 2}, {n: "b", p: 1}, {n: "c", p: 2}], "p", xs) and
+           ns = [x.n | x in xs]"#,
+        "ns",
+        vec![value!(["b", "a", "c"])],
+    );
+    qvar(
+        &mut p,
+        r#"highest_priority([{name: "user", priority: 2}, {name: "admin", priority: 1}], role)
+           and name = role.name"#,
+        "name",
+        values!["admin"],
+    );
+    qruntime!("sort(1, xs)", RuntimeError::TypeError { .. });
+    qruntime!("sort_by([], 1, xs)", RuntimeError::TypeError { .. });
+    Ok(())
+}
+
+#[test]
+fn test_compare_terms() {
+    let instance = |instance_id| {
+        term!(Value::ExternalInstance(ExternalInstance {
+            instance_id,
+            constructor: None,
+            repr: None,
+            class_tag: None,
+        }))
+    };
+    let sorted = vec![
+        term!(Value::Nil),
+        term!(false),
+        term!(true),
+        term!(-1),
+        term!(1.5),
+        term!(2),
+        term!(f64::NAN),
+        term!("a"),
+        term!("b"),
+        term!([1]),
+        term!([1, 2]),
+        term!(btreemap! { sym!("a") => term!(1) }),
+        term!(btreemap! { sym!("a") => term!(2) }),
+        instance(1),
+        instance(2),
+        term!(sym!("x")),
+    ];
+    let mut shuffles: Vec<Vec<Term>> = (0..sorted.len())
+        .map(|i| {
+            let mut terms = sorted.clone();
+            terms.rotate_left(i);
+            terms
+        })
+        .collect();
+    shuffles.push(sorted.iter().rev().cloned().collect());
+    for mut terms in shuffles {
+        terms.sort_by(compare_terms);
+        assert_eq!(
+            terms.iter().map(|t| t.to_polar()).collect::<Vec<_>>(),
+            sorted.iter().map(|t| t.to_polar()).collect::<Vec<_>>()
+        );
+    }
+
+    // Equal numbers of different representations compare equal.
+    assert_eq!(
+        compare_terms(&term!(1), &term!(1.0)),
+        std::cmp::Ordering::Equal
+    );
+    assert_eq!(
+        compare_terms(&term!(f64::NAN), &term!(f64::NAN)),
+        std::cmp::Ordering::Equal
+    );
+
+    let p = Polar::new();
+    let mut terms = vec![term!("b"), term!(1), term!(true), term!("a")];
+    p.sort_terms(&mut terms);
+    assert_eq!(terms, vec![term!(true), term!(1), term!("a"), term!("b")]);
+}
+
+#[test]
+fn test_group_by() -> TestResult {
+    let mut p = Polar::new();
+    p.load_str(
+        r#"permission_summary(perms, summary) if
+               group_by([[p.resource, p.action] | p in perms], summary);"#,
+    )?;
+    qvar(
+        &mut p,
+        r#"permission_summary([{resource: "repo", action: "read"},
+                               {resource: "org", action: "read"},
+                               {resource: "repo", action: "write"}], s)"#,
+        "s",
+        vec![value!(btreemap! {
+            sym!("repo") => term!(["read", "write"]),
+            sym!("org") => term!(["read"]),
+        })],
+    );
+    qvar(
+        &mut p,
+        r#"group_by([{t: "a", n: 1}, {t: "b", n: 2}, {t: "a", n: 3}], "t", g) and
+           ns = [x.n | x in g.a]"#,
+        "ns",
+        vec![value!([1, 3])],
+    );
+    qeval(&mut p, "group_by([], {})");
+    qruntime!("group_by([[1, 2]], g)", RuntimeError::TypeError { .. });
+    Ok(())
+}
+
+#[test]
+fn test_distinct() -> TestResult {
+    let mut p = Polar::new();
+    p.load_str(
+        r#"role(_, "reader");
+           role(_, "writer");
+           role(_, "reader");
+           roles(user, rs) if distinct([r | role(user, r)], rs);"#,
+    )?;
+    qvar(
+        &mut p,
+        "distinct([1, 2, 1, 1.0, \"a\", 2], xs)",
+        "xs",
+        vec![value!([1, 2, "a"])],
+    );
+    qvar(
+        &mut p,
+        "roles(\"leina\", rs)",
+        "rs",
+        vec![value!(["reader", "writer"])],
+    );
+    qvar(
+        &mut p,
+        "distinct(role(\"leina\", r))",
+        "r",
+        values!["reader", "writer"],
+    );
+    qvar(
+        &mut p,
+        "role(\"leina\", r)",
+        "r",
+        values!["reader", "writer", "reader"],
+    );
+    qnull(&mut p, "distinct(role(\"leina\", \"admin\"))");
+    qruntime!("distinct(1)", RuntimeError::TypeError { .. });
+    Ok(())
+}
+
+#[test]
+fn test_list_builtins() -> TestResult {
+    let mut p = Polar::new();
+    qvar(&mut p, "length([1, 2, 3], n)", "n", values![3]);
+    qeval(&mut p, "length([], 0)");
+    qvar(
+        &mut p,
+        "append([1], [2, 3], x)",
+        "x",
+        vec![value!([1, 2, 3])],
+    );
+    qeval(&mut p, "append([], [], [])");
+    qvar(
+        &mut p,
+        "append(a, b, [1, 2])",
+        "a",
+        vec![value!([]), value!([1]), value!([1, 2])],
+    );
+    qvar(
+        &mut p,
+        "append([1], b, [1, 2, 3])",
+        "b",
+        vec![value!([2, 3])],
+    );
+    qnull(&mut p, "append([2], b, [1, 2])");
+    qvar(&mut p, r#"nth(["a", "b"], 1, x)"#, "x", values!["b"]);
+    qnull(&mut p, r#"nth(["a", "b"], 2, x)"#);
+    qnull(&mut p, r#"nth(["a", "b"], -1, x)"#);
+    qvar(
+        &mut p,
+        r#"nth(["a", "b", "a"], i, "a")"#,
+        "i",
+        values![0, 2],
+    );
+    qvar(
+        &mut p,
+        "reverse([1, 2, 3], x)",
+        "x",
+        vec![value!([3, 2, 1])],
+    );
+    qruntime!("length(x, n)", RuntimeError::TypeError { .. });
+    qruntime!("append([1], 2, x)", RuntimeError::TypeError { .. });
+    qruntime!(r#"nth([1], "0", x)"#, RuntimeError::TypeError { .. });
+
+    // Rules by the same name take precedence.
+    p.load_str("length(_, -1);")?;
+    qvar(&mut p, "length([1, 2, 3], n)", "n", values![-1]);
+    Ok(())
+}
+
+#[test]
+fn test_zip_and_enumerate() -> TestResult {
+    let mut p = Polar::new();
+    p.load_str(
+        r#"sensitive_columns(columns, labels, sensitive) if
+               zip(columns, labels, pairs) and
+               sensitive = [c | [c, "pii"] in pairs];"#,
+    )?;
+    qeval(
+        &mut p,
+        r#"zip([1, 2, 3], ["a", "b"], [[1, "a"], [2, "b"]])"#,
+    );
+    qvar(&mut p, "zip([], [1], z)", "z", vec![value!([])]);
+    qeval(&mut p, r#"enumerate(["a", "b"], [[0, "a"], [1, "b"]])"#);
+    qvar(
+        &mut p,
+        r#"sensitive_columns(["name", "email", "id"], ["pii", "pii", "public"], s)"#,
+        "s",
+        vec![value!(["name", "email"])],
+    );
+    qruntime!("zip([1], 2, z)", RuntimeError::TypeError { .. });
+    Ok(())
+}
+
+#[test]
+fn test_dict_keys_and_values() -> TestResult {
+    let mut p = Polar::new();
+    p.load_str(
+        r#"has_owner(attrs) if has_key(attrs, "owner");
+           missing_owner(attrs) if not has_key(attrs, "owner");"#,
+    )?;
+    qeval(&mut p, r#"has_owner({owner: "alice", team: "eng"})"#);
+    qnull(&mut p, r#"has_owner({team: "eng"})"#);
+    qeval(&mut p, r#"missing_owner({team: "eng"})"#);
+    qnull(&mut p, r#"has_key({}, "a")"#);
+    qvar(
+        &mut p,
+        "has_key({a: 1, b: 2}, k)",
+        "k",
+        vec![value!("a"), value!("b")],
+    );
+    qvar(
+        &mut p,
+        "keys({b: 2, a: 1}, ks)",
+        "ks",
+        vec![value!(["b", "a"])],
+    );
+    qvar(
+        &mut p,
+        "values({b: 2, a: 1}, vs)",
+        "vs",
+        vec![value!([2, 1])],
+    );
+    qeval(&mut p, "keys({}, []) and values({}, [])");
+    qruntime!("has_key([1], \"a\")", RuntimeError::TypeError { .. });
+    qruntime!("has_key({a: 1}, 1)", RuntimeError::TypeError { .. });
+    qruntime!("keys(1, ks)", RuntimeError::TypeError { .. });
+    Ok(())
+}
+
+#[test]
+fn test_equals_ignore_case() -> TestResult {
+    let mut p = Polar::new();
+    p.load_str(r#"same_user(a, b) if equals_ignore_case(a.email, b.email);"#)?;
+    qeval(
+        &mut p,
+        r#"same_user({email: "Alice@Example.com"}, {email: "alice@example.COM"})"#,
+    );
+    qnull(
+        &mut p,
+        r#"same_user({email: "alice@example.com"}, {email: "bob@example.com"})"#,
+    );
+    qeval(&mut p, r#"equals_ignore_case("STRASSE", "strasse")"#);
+    qeval(&mut p, r#"equals_ignore_case("ÉCOLE", "école")"#);
+    qnull(&mut p, r#"equals_ignore_case("a", "aa")"#);
+    qnull(&mut p, r#"not equals_ignore_case("", "")"#);
+    qruntime!(
+        r#"equals_ignore_case(x, "a")"#,
+        RuntimeError::TypeError { .. }
+    );
+    qruntime!(
+        r#"equals_ignore_case("1", 1)"#,
+        RuntimeError::TypeError { .. }
+    );
+    Ok(())
+}
+
+#[test]
+fn test_string_builtins() -> TestResult {
+    let mut p = Polar::new();
+    p.load_str(
+        r#"can_read(user, path) if
+               starts_with(path, "/home/" + user.name + "/") and
+               not contains(path, "/../");"#,
+    )?;
+    qeval(
+        &mut p,
+        r#"can_read({name: "alice"}, "/home/alice/notes.txt")"#,
+    );
+    qnull(
+        &mut p,
+        r#"can_read({name: "alice"}, "/home/alicia/notes.txt")"#,
+    );
+    qnull(&mut p, r#"can_read({name: "alice"}, "/home/alice/../bob")"#);
+
+    qvar(&mut p, r#"x = "ab" + "cd""#, "x", values!["abcd"]);
+    qvar(&mut p, r#"string_length("héllo", n)"#, "n", values![5]);
+    qeval(&mut p, r#"string_length("", 0)"#);
+    qeval(&mut p, r#"ends_with("report.pdf", ".pdf")"#);
+    qnull(&mut p, r#"ends_with("report.pdf", ".doc")"#);
+    qeval(&mut p, r#"starts_with("abc", "") and contains("abc", "b")"#);
+    qnull(&mut p, r#"contains("abc", "d")"#);
+    qruntime!(r#"starts_with(x, "a")"#, RuntimeError::TypeError { .. });
+    qruntime!(r#"string_length(1, n)"#, RuntimeError::TypeError { .. });
+    qruntime!(r#"x = "a" - "b""#, RuntimeError::Unsupported { .. });
+    qruntime!(r#"x = "a" + 1"#, RuntimeError::Unsupported { .. });
+    Ok(())
+}
+
+#[test]
+fn test_matches_regex() -> TestResult {
+    let mut p = Polar::new();
+    p.load_str(
+        r#"allow(_user, "GET", path) if matches_regex(path, "^/api/v[0-9]+/docs/[a-z0-9-]+$");"#,
+    )?;
+    qeval(&mut p, r#"allow("alice", "GET", "/api/v2/docs/q3-report")"#);
+    qnull(
+        &mut p,
+        r#"allow("alice", "GET", "/api/v2/docs/q3-report/edit")"#,
+    );
+    qnull(&mut p, r#"allow("alice", "GET", "/api/vx/docs/a")"#);
+
+    // Unanchored patterns match anywhere.
+    qeval(&mut p, r#"matches_regex("a/b/c", "b/")"#);
+    qnull(&mut p, r#"matches_regex("abc", "^b")"#);
+    qruntime!(
+        r#"matches_regex("abc", "(")"#,
+        RuntimeError::TypeError { .. }
+    );
+    qruntime!(r#"matches_regex(1, "a")"#, RuntimeError::TypeError { .. });
+    Ok(())
+}
+
+#[test]
+fn test_dict_merge_and_without() -> TestResult {
+    let mut p = Polar::new();
+    p.load_str(
+        r#"defaults({read: true, write: false, admin: false});
+           permissions(overrides, perms) if
+               defaults(d) and merge(d, overrides, perms);"#,
+    )?;
+    qvar(
+        &mut p,
+        "permissions({write: true}, perms)",
+        "perms",
+        vec![value!(btreemap! {
+            sym!("read") => term!(true),
+            sym!("write") => term!(true),
+            sym!("admin") => term!(false),
+        })],
+    );
+    qeval(&mut p, "merge({}, {}, {})");
+    qeval(&mut p, "merge({a: 1}, {b: 2}, {a: 1, b: 2})");
+    qvar(
+        &mut p,
+        r#"without({a: 1, b: 2}, "a", d)"#,
+        "d",
+        vec![value!(btreemap! {sym!("b") => term!(2)})],
+    );
+    qeval(&mut p, r#"without({a: 1}, "b", {a: 1})"#);
+    qeval(&mut p, r#"without({a: 1, b: 2, c: 3}, ["a", "c"], {b: 2})"#);
+    qruntime!("merge({a: 1}, [1], d)", RuntimeError::TypeError { .. });
+    qruntime!("without({a: 1}, 1, d)", RuntimeError::TypeError { .. });
+    Ok(())
+}
+
+#[test]
+fn test_sets() -> TestResult {
+    let mut p = Polar::new();
+    p.load_str(
+        r#"admin_roles({"owner", "admin"});
+           is_admin(roles) if admin_roles(admins) and
+               intersection(roles, admins, common) and
+               set([], none) and common != none;"#,
+    )?;
+    qeval(&mut p, r#""a" in {"b", "a", "c"}"#);
+    qnull(&mut p, r#""d" in {"b", "a", "c"}"#);
+    qvar(
+        &mut p,
+        "x in {3, 1, 2}",
+        "x",
+        vec![value!(1), value!(2), value!(3)],
+    );
+    qvar(
+        &mut p,
+        "x = 2 and y = {x, 1, 2}",
+        "y",
+        vec![value!(Value::Set(Set::new(vec![term!(1), term!(2)])))],
+    );
+    qeval(&mut p, "{1, 2} = {2, 1, 1}");
+    qnull(&mut p, "{1, 2} = {1, 3}");
+    qnull(&mut p, "{1, 2} = [1, 2]");
+    qeval(&mut p, "{x, 2} = {1, 2} and x = 1");
+    qvar(&mut p, "{x, 1} = {2, y}", "x", vec![value!(2)]);
+    qvar(&mut p, "{x, 1} = {1}", "x", vec![value!(1)]);
+    qvar(&mut p, "{x, y} = {1, 2}", "x", vec![value!(1), value!(2)]);
+    qeval(
+        &mut p,
+        "{1} < {1, 2} and {1, 2} <= {2, 1} and {1, 2} >= {1}",
+    );
+    qnull(&mut p, "{1, 3} <= {1, 2}");
+    qnull(&mut p, "{1, 2} < {1, 2}");
+    qeval(&mut p, "{1, 2} != {1}");
+    qeval(&mut p, r#"is_admin({"owner", "viewer"})"#);
+    qnull(&mut p, r#"is_admin({"viewer"})"#);
+    qeval(&mut p, r#"is_admin(["viewer", "admin"])"#);
+    qeval(&mut p, "union({1, 2}, {2, 3}, {1, 2, 3})");
+    qeval(&mut p, "intersection({1, 2}, {2, 3}, {2})");
+    qeval(&mut p, "difference({1, 2}, {2, 3}, {1})");
+    qeval(&mut p, "set([2, 1, 2], {1, 2})");
+    qeval(&mut p, "set([], s) and not 1 in s");
+    qruntime!("union({1}, 2, s)", RuntimeError::TypeError { .. });
+
+    // Sets from the host have the same membership semantics.
+    let roles = Set::new(
+        (0..1000)
+            .map(|i| term!(Value::String(format!("role{}", i))))
+            .collect(),
+    );
+    let mut q = p.new_query_from_term(
+        term!(Value::Expression(Operation {
+            operator: Operator::In,
+            args: vec![term!("role500"), term!(Value::Set(roles))],
+        })),
+        false,
+    );
+    assert!(matches!(q.next_event()?, QueryEvent::Result { .. }));
+    assert!(matches!(q.next_event()?, QueryEvent::Done { .. }));
+    Ok(())
+}
+
+#[test]
+fn test_tuples() -> TestResult {
+    let mut p = Polar::new();
+    p.load_str(
+        r#"edge((0, 0), (0, 1));
+           edge((0, 1), (1, 1));
+           allowed(("doc", "read"));
+           allowed(("doc", "write"));"#,
+    )?;
+    qvar(&mut p, "edge((0, 0), (x, y))", "y", vec![value!(1)]);
+    qvar(
+        &mut p,
+        r#"allowed(("doc", action))"#,
+        "action",
+        vec![value!("read"), value!("write")],
+    );
+    qnull(&mut p, r#"allowed(["doc", "read"])"#);
+    qeval(&mut p, "(1, 2) = (1, 2) and (x,) = (1,) and x = 1");
+    qnull(&mut p, "(1, 2) = (1, 2, 3)");
+    qnull(&mut p, "(1, 2) = [1, 2]");
+    qeval(&mut p, "(1) = 1");
+    qvar(
+        &mut p,
+        "x = (1, [2, 3])",
+        "x",
+        vec![Value::Tuple(vec![term!(1), term!([2, 3])])],
+    );
+    qruntime!("x in (1, 2)", RuntimeError::TypeError { .. });
+    assert!(matches!(
+        p.load_str("f((a, *b));").unwrap_err().kind,
+        ErrorKind::Parse(_)
+    ));
+    Ok(())
+}
+
+#[test]
+fn test_ranges() -> TestResult {
+    let mut p = Polar::new();
+    p.load_str("adult(age) if age in 18..150;")?;
+    qvar(&mut p, "x in 1..4", "x", values![1, 2, 3, 4]);
+    qvar(&mut p, "x in -1..1", "x", values![-1, 0, 1]);
+    qvar(&mut p, "n = 3 and x in n..(n + 1)", "x", values![3, 4]);
+    qnull(&mut p, "x in 2..1");
+    qeval(&mut p, "5 in 1..10 and 5.0 in 1..10");
+    qnull(&mut p, "0 in 1..10");
+    qnull(&mut p, "2.5 in 1..10");
+    qnull(&mut p, "\"a\" in 1..10");
+    qeval(&mut p, "adult(30)");
+    qnull(&mut p, "adult(12)");
+    qeval(&mut p, "x = 1..2 and x = 1..y and y = 2");
+
+    // Comparisons filter the enumeration, before or after it.
+    qvar(&mut p, "x > 7 and x in 1..9", "x", values![8, 9]);
+    qvar(&mut p, "x in 1..9 and x mod 4 = 0", "x", values![4, 8]);
+
+    // Enumeration is lazy, so large ranges are cheap to take from.
+    let mut q = p.new_query("x in 1..9223372036854775807", false)?;
+    assert!(matches!(q.next_event()?, QueryEvent::Result { .. }));
+    // And the stacks don't grow with how much of the range is enumerated.
+    qeval(&mut p, "x in 1..20000 and x = 20000");
+
+    qruntime!("x in 1..2.5", RuntimeError::TypeError { .. });
+    qruntime!("x in 1..y", RuntimeError::TypeError { .. });
+    Ok(())
+}
+
+#[test]
+fn test_mode_declarations() -> TestResult {
+    let mut p = Polar::new();
+    p.load_str(
+        r#"next(+x, -y) if y = x + 1;
+           next(x, -y) if x = 10 and y = 0;
+           source(1);
+           k(y) if next(x, y) and source(x);"#,
+    )?;
+    qvar(&mut p, "next(1, y)", "y", values![2]);
+    qvar(&mut p, "x = 2 and next(x, y)", "y", values![3]);
+    qeval(&mut p, "next(1, 2)");
+
+    let err = p.new_query("next(x, 2)", false)?.next_event().unwrap_err();
+    assert!(
+        matches!(&err.kind, ErrorKind::Runtime(RuntimeError::ModeViolation { unbound, .. }) if unbound == &["x"]),
+        "{}",
+        err
+    );
+    assert!(err.to_string().starts_with(
+        "[R0017] Mode violation: next(x, 2) is called with x unbound, but its rules declare it +"
+    ));
+    qruntime!(&mut p, "k(y)", RuntimeError::ModeViolation { .. });
+
+    // Reordering runs calls after what binds their `+` arguments.
+    p.set_reorder_conjunctions(true);
+    qvar(&mut p, "k(y)", "y", values![2]);
+    qvar(&mut p, "next(x, y) and x = 3", "y", values![4]);
+    Ok(())
+}
+
+#[test]
+fn test_match_expression() -> TestResult {
+    let mut p = Polar::new();
+    p.load_str(
+        r#"allow(user, action, doc) if
+               match action {
+                   "read" => doc.public = true or user.name = doc.owner;
+                   "write" => user.name = doc.owner;
+                   _ => user.name = "root";
+               };
+           kind(x, k) if match x { {admin: true} => k = "admin"; 1 => k = "one"; _ => k = x; };"#,
+    )?;
+    let doc = r#"{public: false, owner: "alice"}"#;
+    let allowed = |p: &mut Polar, user: &str, action: &str| {
+        !query_results!(p
+            .new_query(
+                &format!(r#"allow({{name: "{}"}}, "{}", {})"#, user, action, doc),
+                false
+            )
+            .unwrap())
+        .is_empty()
+    };
+    assert!(allowed(&mut p, "alice", "read"));
+    assert!(!allowed(&mut p, "bob", "read"));
+    assert!(allowed(&mut p, "alice", "write"));
+    assert!(!allowed(&mut p, "bob", "write"));
+    // A later branch is not tried once an earlier pattern has matched.
+    assert!(!allowed(&mut p, "root", "write"));
+    assert!(allowed(&mut p, "root", "delete"));
+    assert!(!allowed(&mut p, "alice", "delete"));
+
+    qvar(
+        &mut p,
+        "kind({admin: true, x: 1}, k)",
+        "k",
+        vec![value!("admin")],
+    );
+    qvar(&mut p, "kind(1, k)", "k", vec![value!("one")]);
+    qvar(&mut p, "kind(2, k)", "k", vec![value!(2)]);
+    qnull(&mut p, "match 1 { 2 => true; }");
+    Ok(())
+}
+
+/// Test that cut commits to all choice points before the cut, not just the last.
+#[test]
+fn test_cut() -> TestResult {
+    let mut p = Polar::new();
+    p.load_str(
+        r#"a(x) if x = 1 or x = 2;
+           b(x) if x = 3 or x = 4;
+           bcut(x) if x = 3 or x = 4 and cut;
+           c(a, b) if a(a) and b(b) and cut;
+           c_no_cut(a, b) if a(a) and b(b);
+           c_partial_cut(a, b) if a(a) and bcut(b);
+           c_another_partial_cut(a, b) if a(a) and cut and b(b);"#,
+    )?;
+
+    // Ensure we return multiple results without a cut.
+    qvars(
+        &mut p,
+        "c_no_cut(a, b)",
+        &["a", "b"],
+        values![[1, 3], [1, 4], [2, 3], [2, 4]],
+    );
+
+    // Ensure that only one result is returned when cut is at the end.
+    qvars(&mut p, "c(a, b)", &["a", "b"], values![[1, 3]]);
+
+    // Make sure that cut in `bcut` does not affect `c_partial_cut`.
+    // If it did, only one result would be returned, [1, 3].
+    qvars(
+        &mut p,
+        "c_partial_cut(a, b)",
+        &["a", "b"],
+        values![[1, 3], [2, 3]],
+    );
+
+    // Make sure cut only affects choice points before it.
+    qvars(
+        &mut p,
+        "c_another_partial_cut(a, b)",
+        &["a", "b"],
+        values![[1, 3], [1, 4]],
+    );
+
+    p.load_str("f(x) if (x = 1 and cut) or x = 2;")?;
+    qvar(&mut p, "f(x)", "x", values![1]);
+    qeval(&mut p, "f(1)");
+    qeval(&mut p, "f(2)");
+    Ok(())
+}
+
+#[test]
+fn test_forall() -> TestResult {
+    let mut p = Polar::new();
+    p.load_str("all_ones(l) if forall(item in l, item = 1);")?;
+
+    qnull(&mut p, "all_ones([2])");
+
+    qeval(&mut p, "all_ones([1])");
+    qeval(&mut p, "all_ones([1, 1, 1])");
+    qnull(&mut p, "all_ones([1, 2, 1])");
+
+    p.load_str("not_ones(l) if forall(item in l, item != 1);")?;
+    qnull(&mut p, "not_ones([1])");
+    qeval(&mut p, "not_ones([2, 3, 4])");
+
+    qnull(&mut p, "forall(x = 2 or x = 3, x != 2)");
+    qnull(&mut p, "forall(x = 2 or x = 3, x != 3)");
+    qeval(&mut p, "forall(x = 2 or x = 3, x = 2 or x = 3)");
+    qeval(&mut p, "forall(x = 1, x = 1)");
+    qeval(&mut p, "forall(x in [2, 3, 4], x > 1)");
+
+    p.load_str(
+        r#"g(1);
+           g(2);
+           g(3);"#,
+    )?;
+    qeval(&mut p, "forall(g(x), x in [1, 2, 3])");
+
+    p.load_str(
+        r#"allow(_: {x: 1}, y) if y = 1;
+           allow(_: {y: 1}, y) if y = 2;
+           allow(_: {z: 1}, y) if y = 3;"#,
+    )?;
+    qeval(
+        &mut p,
+        "forall(allow({x: 1, y: 1, z: 1}, y), y in [1, 2, 3])",
+    );
+    Ok(())
+}
+
+#[test]
+fn test_emoji_policy() -> TestResult {
+    let mut p = Polar::new();
+    p.load_str(
+        r#"
+                    👩‍🔧("👩‍🦰");
+                    allow(👩, "🛠", "🚙") if 👩‍🔧(👩);
+                "#,
+    )?;
+    qeval(&mut p, r#"allow("👩‍🦰","🛠","🚙")"#);
+    qnull(&mut p, r#"allow("🧟","🛠","🚙")"#);
+    Ok(())
+}
+
+#[test]
+/// Check that boolean expressions evaluate without requiring "= true".
+fn test_boolean_expression() {
+    let mut p = Polar::new();
+    qeval(&mut p, "a = {t: true, f: false} and a.t"); // Succeeds because t is true.
+    qnull(&mut p, "a = {t: true, f: false} and a.f"); // Fails because `f` is not true.
+    qnull(&mut p, "a = {t: true, f: false} and a.f and a.t"); // Fails because `f` is not true.
+    qeval(&mut p, "a = {t: true, f: false} and (a.f or a.t)"); // Succeeds because `t` is true.
+
+    qeval(&mut p, "true");
+    qnull(&mut p, "false");
+    qeval(&mut p, "a = true and a");
+    qnull(&mut p, "a = false and a");
+}
+
+#[test]
+fn test_float_parsing() {
+    let mut p = Polar::new();
+    qvar(&mut p, "x=1+1", "x", values![2]);
+    qvar(&mut p, "x=1+1.5", "x", values![2.5]);
+    qvar(&mut p, "x=1.e+5", "x", values![1e5]);
+    qvar(&mut p, "x=1e+5", "x", values![1e5]);
+    qvar(&mut p, "x=1e5", "x", values![1e5]);
+    qvar(&mut p, "x=1e-5", "x", values![1e-5]);
+    qvar(&mut p, "x=1.e-5", "x", values![1e-5]);
+    qvar(&mut p, "x=1.0e+15", "x", values![1e15]);
+    qvar(&mut p, "x=1.0E+15", "x", values![1e15]);
+    qvar(&mut p, "x=1.0e-15", "x", values![1e-15]);
+}
+
+#[test]
+fn test_assignment() {
+    let mut p = Polar::new();
+    qeval(&mut p, "x := 5 and x == 5");
+    qruntime!(
+        "x := 5 and x := 6",
+        RuntimeError::TypeError { msg: s, .. },
+        s == "Can only assign to unbound variables, x is not unbound."
+    );
+    qnull(&mut p, "x := 5 and x > 6");
+    qeval(&mut p, "x := y and y = 6 and x = 6");
+
+    // confirm old syntax -> parse error
+    qparse!("f(x) := g(x);", ParseError::UnrecognizedToken { .. });
+}
+
+#[test]
+fn test_rule_index() -> TestResult {
+    let mut p = Polar::new();
+    p.load_str(
+        r#"f(1, 1, "x");
+           f(1, 1, "y");
+           f(1, x, "y") if x = 2;
+           f(1, 2, {b: "y"});
+           f(1, 3, {c: "z"});"#,
+    )?;
+    // Exercise the index.
+    qeval(&mut p, r#"f(1, 1, "x")"#);
+    qeval(&mut p, r#"f(1, 1, "y")"#);
+    qvar(&mut p, r#"f(1, x, "y")"#, "x", values![1, 2]);
+    qnull(&mut p, r#"f(1, 1, "z")"#);
+    qnull(&mut p, r#"f(1, 2, "x")"#);
+    qeval(&mut p, r#"f(1, 2, {b: "y"})"#);
+    qeval(&mut p, r#"f(1, 3, {c: "z"})"#);
+    Ok(())
+}
+
+#[test]
+fn test_fib() -> TestResult {
+    let mut p = Polar::new();
+    p.load_str(
+        r#"fib(0, 1) if cut;
+           fib(1, 1) if cut;
+           fib(n, a+b) if fib(n-1, a) and fib(n-2, b);"#,
+    )?;
+    qvar(&mut p, r#"fib(0, x)"#, "x", values![1]);
+    qvar(&mut p, r#"fib(1, x)"#, "x", values![1]);
+    qvar(&mut p, r#"fib(2, x)"#, "x", values![2]);
+    qvar(&mut p, r#"fib(3, x)"#, "x", values![3]);
+    qvar(&mut p, r#"fib(4, x)"#, "x", values![5]);
+    qvar(&mut p, r#"fib(5, x)"#, "x", values![8]);
+    Ok(())
+}
+
+#[test]
+fn test_duplicated_rule() -> TestResult {
+    let mut p = Polar::new();
+    p.load_str(
+        r#"f(1);
+           f(1);"#,
+    )?;
+    qvar(&mut p, "f(x)", "x", values![1, 1]);
+    Ok(())
+}
+
+#[test]
+fn test_numeric_applicability() -> TestResult {
+    let mut p = Polar::new();
+    let eps = f64::EPSILON;
+    let nan1 = f64::NAN;
+    let nan2 = f64::from_bits(f64::NAN.to_bits() | 1);
+    assert!(eps.is_normal() && nan1.is_nan() && nan2.is_nan());
+    p.register_constant(sym!("eps"), term!(eps));
+    p.register_constant(sym!("nan1"), term!(nan1));
+    p.register_constant(sym!("nan2"), term!(nan2));
+    p.load_str(
+        r#"f(0);
+           f(1);
+           f(9007199254740991); # (1 << 53) - 1
+           f(9007199254740992); # (1 << 53)
+           f(9223372036854775807); # i64::MAX
+           f(-9223372036854775807); # i64::MIN + 1
+           f(9223372036854776000.0); # i64::MAX as f64
+           f(nan1); # NaN"#,
+    )?;
+    qeval(&mut p, "f(0)");
+    qeval(&mut p, "f(0.0)");
+    qnull(&mut p, "f(eps)");
+    qeval(&mut p, "f(1)");
+    qeval(&mut p, "f(1.0)");
+    qnull(&mut p, "f(1.0000000000000002)");
+    qnull(&mut p, "f(9007199254740990)");
+    qnull(&mut p, "f(9007199254740990.0)");
+    qeval(&mut p, "f(9007199254740991)");
+    qeval(&mut p, "f(9007199254740991.0)");
+    qeval(&mut p, "f(9007199254740992)");
+    qeval(&mut p, "f(9007199254740992.0)");
+    qeval(&mut p, "f(9223372036854775807)");
+    qeval(&mut p, "f(-9223372036854775807)");
+    qeval(&mut p, "f(9223372036854776000.0)");
+    qnull(&mut p, "f(nan1)");
+    qnull(&mut p, "f(nan2)");
+    Ok(())
+}
+
+#[test]
+fn test_external_unify() -> TestResult {
+    let p = Polar::new();
+    p.load_str(
+        r#"selfEq(x) if eq(x, x);
+           eq(x, x);"#,
+    )?;
+
+    let q = p.new_query("selfEq(new Foo())", false)?;
+    let (results, _externals) = query_results_with_externals(q);
+    assert_eq!(results.len(), 1);
+
+    let q = p.new_query("eq(new Foo(), new Foo())", false)?;
+    let (results, _externals) = query_results_with_externals(q);
+    assert!(results.is_empty());
+    Ok(())
+}
+
+#[test]
+fn test_list_results() -> TestResult {
+    let mut p = Polar::new();
+    p.load_str(
+        r#"delete([x, *xs], x, ys) if delete(xs, x, ys);
+           delete([x, *xs], z, [x, *ys]) if
+               x != z and delete(xs, z, ys);
+           delete([], _, []);"#,
+    )?;
+    qeval(&mut p, "delete([1,2,3,2,1],2,[1,3,1])");
+    qvar(
+        &mut p,
+        "delete([1,2,3,2,1],2,result)",
+        "result",
+        vec![value!([1, 3, 1])],
+    );
+
+    qvar(&mut p, "[1,2] = [1,*ys]", "ys", vec![value!([2])]);
+    qvar(
+        &mut p,
+        "[1,2,*xs] = [1,*ys] and [1,2,3] = [1,*ys]",
+        "xs",
+        vec![value!([3])],
+    );
+    qvar(
+        &mut p,
+        "[1,2,*xs] = [1,*ys] and [1,2,3] = [1,*ys]",
+        "ys",
+        vec![value!([2, 3])],
+    );
+    qvar(
+        &mut p,
+        "[1,2,*xs] = [1,*ys] and [1,2,3] = [1,*ys]",
+        "ys",
+        vec![value!([2, 3])],
+    );
+    qeval(&mut p, "xs = [2] and [1,2] = [1, *xs]");
+    qnull(&mut p, "[1, 2] = [2, *ys]");
+    Ok(())
+}
+
+#[test]
+fn test_expressions_in_lists() -> TestResult {
+    let mut p = Polar::new();
+    p.load_str(
+        r#"scope(actor: Dictionary, "read", "Person", filters) if
+               filters = ["id", "=", actor.id];"#,
+    )?;
+    qeval(
+        &mut p,
+        r#"scope({id: 1}, "read", "Person", ["id", "=", 1])"#,
+    );
+    qnull(
+        &mut p,
+        r#"scope({id: 2}, "read", "Person", ["id", "=", 1])"#,
+    );
+    qnull(
+        &mut p,
+        r#"scope({id: 1}, "read", "Person", ["not_id", "=", 1])"#,
+    );
+    qeval(&mut p, r#"d = {x: 1} and [d.x, 1+1] = [1, 2]"#);
+    qvar(
+        &mut p,
+        r#"d = {x: 1} and [d.x, 1+1] = [1, *rest]"#,
+        "rest",
+        vec![value!([2])],
+    );
+    Ok(())
+}
+
+#[test]
+fn test_list_matches() {
+    let mut p = Polar::new();
+    qeval(&mut p, "[] matches []");
+    qnull(&mut p, "[1] matches []");
+    qnull(&mut p, "[] matches [1]");
+    qnull(&mut p, "[1, 2] matches [1, 2, 3]");
+    qnull(&mut p, "[2, 1] matches [1, 2]");
+    qeval(&mut p, "[1, 2, 3] matches [1, 2, 3]");
+    qnull(&mut p, "[1, 2, 3] matches [1, 2]");
+
+    qnull(&mut p, "[x] matches []");
+    qnull(&mut p, "[] matches [x]");
+    qnull(&mut p, "[1, 2, x] matches [1, 2]");
+    qnull(&mut p, "[1, x] matches [1, 2, 3]");
+    qnull(&mut p, "[2, x] matches [1, 2]");
+    qvar(&mut p, "[1, 2, x] matches [1, 2, 3]", "x", values![3]);
+    qnull(&mut p, "[1, 2, 3] matches [1, x]");
+
+    qvar(&mut p, "[] matches [*ys]", "ys", vec![value!([])]);
+    qvar(&mut p, "[*xs] matches []", "xs", vec![value!([])]);
+    qvar(&mut p, "[*xs] matches [1]", "xs", vec![value!([1])]);
+    qvar(&mut p, "[1] matches [*ys]", "ys", vec![value!([1])]);
+    qeval(&mut p, "[xs] matches [*ys]");
+    qeval(&mut p, "[*xs] matches [ys]");
+    qeval(&mut p, "[*xs] matches [*ys]");
+    qvar(&mut p, "[1,2,3] matches [1,2,*xs]", "xs", vec![value!([3])]);
+    qvar(
+        &mut p,
+        "[1,2,*xs] matches [1,2,3,*ys]",
+        "xs",
+        vec![value!([3, Value::RestVariable(Symbol::new("ys"))])],
+    );
+}
+
+#[test]
+#[allow(clippy::unnecessary_wraps)]
+fn error_on_binding_expressions_and_patterns_to_variables() -> TestResult {
+    qruntime!(
+        "x matches y",
+        RuntimeError::TypeError { msg: m, .. },
+        m == "cannot unify patterns directly `x` = `y{}`"
+    );
+    Ok(())
+}
+
+#[test]
+fn test_builtin_iterables() {
+    let mut p = Polar::new();
+
+    qnull(&mut p, r#"x in """#);
+    qvar(
+        &mut p,
+        "x in \"abc\"",
+        "x",
+        vec![value!("a"), value!("b"), value!("c")],
+    );
+    qeval(&mut p, r#""b" in "abc""#);
+    qnull(&mut p, r#""d" in "abc""#);
+    // Strings are iterated by character; `in` is not a substring test.
+    qnull(&mut p, r#""bc" in "abc""#);
+    qnull(&mut p, r#"1 in "abc""#);
+    qvar(&mut p, r#"x in "né""#, "x", vec![value!("n"), value!("é")]);
+    qnull(&mut p, "x in {}");
+    qvar(
+        &mut p,
+        "x in {a: 1, b: 2}",
+        "x",
+        vec![value!(["a", 1]), value!(["b", 2])],
+    );
+    qeval(&mut p, r#"["a", 1] in {a: 1, b: 2}"#);
+    qvar(
+        &mut p,
+        "[x, _] in {a: 1, b: 2}",
+        "x",
+        vec![value!("a"), value!("b")],
+    );
+    qeval(&mut p, r#"["a", 1] in {a: 1, b: 2}"#);
+    qvar(
+        &mut p,
+        "[_, x] in {a: 1, b: 2}",
+        "x",
+        vec![value!(1), value!(2)],
+    );
+
+    qeval(&mut p, r#""b" in "abc""#);
+    qnull(&mut p, r#""d" in "abc""#);
+    qeval(&mut p, r#"forall(x in "abc", x in "abacus")"#);
+    qnull(&mut p, r#"forall(x in "abcd", x in "abacus")"#);
+}
+
+#[test]
+fn test_dry_run() -> TestResult {
+    let p = Polar::new();
+    p.load_str(
+        r#"allow(actor, "read", resource) if resource.owner.name = actor.name;
+           allow(actor, "write", resource) if actor.is_admin(resource.org) = true;"#,
+    )?;
+
+    let instance = || {
+        term!(Value::ExternalInstance(ExternalInstance {
+            instance_id: p.get_external_id(),
+            constructor: None,
+            repr: None,
+            class_tag: None,
+        }))
+    };
+    let (actor, resource) = (instance(), instance());
+    let mut q = p.new_query("allow(actor, _action, resource)", false)?;
+    q.bind(sym!("actor"), actor.clone())?;
+    q.bind(sym!("resource"), resource.clone())?;
+    q.enable_dry_run();
+
+    let mut results = 0;
+    loop {
+        match q.next_event()? {
+            QueryEvent::Done { .. } => break,
+            QueryEvent::Result { .. } => results += 1,
+            event => panic!("unexpected event {:?}", event),
+        }
+    }
+    assert_eq!(results, 2);
+
+    let lookups: Vec<_> = q
+        .required_lookups()
+        .unwrap()
+        .into_iter()
+        .map(|lookup| {
+            let path: Vec<_> = lookup.path.iter().map(|s| s.0.as_str()).collect();
+            (
+                lookup.receiver,
+                path.join("."),
+                lookup.attribute.0,
+                lookup.args.map(|args| args.len()),
+            )
+        })
+        .collect();
+    assert_eq!(
+        lookups,
+        vec![
+            (resource.clone(), "".to_string(), "owner".to_string(), None),
+            (
+                resource.clone(),
+                "owner".to_string(),
+                "name".to_string(),
+                None
+            ),
+            (actor.clone(), "".to_string(), "name".to_string(), None),
+            (resource, "".to_string(), "org".to_string(), None),
+            (actor, "".to_string(), "is_admin".to_string(), Some(1)),
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+fn test_external_call_cache() -> TestResult {
+    let p = Polar::new();
+    p.load_str("f(x, n) if n in [1, 2, 3] and x.roll(6) = n;")?;
+
+    let run = |uncached| -> Result<(usize, usize), PolarError> {
+        let mut q = p.new_query("f(x, n)", false)?;
+        q.bind(
+            sym!("x"),
+            term!(Value::ExternalInstance(ExternalInstance {
+                instance_id: 1,
+                constructor: None,
+                repr: None,
+                class_tag: None,
+            })),
+        )?;
+        let (mut calls, mut results) = (0, 0);
+        loop {
+            match q.next_event()? {
+                QueryEvent::Done { .. } => break,
+                QueryEvent::Result { .. } => results += 1,
+                QueryEvent::ExternalCall {
+                    call_id, attribute, ..
+                } => {
+                    assert_eq!(attribute, sym!("roll"));
+                    calls += 1;
+                    if uncached {
+                        q.uncache_call(call_id);
+                    }
+                    q.call_result(call_id, Some(term!(calls as i64)))?;
+                }
+                event => panic!("unexpected event {:?}", event),
+            }
+        }
+        Ok((calls, results))
+    };
+
+    // The host is asked once, and the answer is reused on backtracking.
+    assert_eq!(run(false)?, (1, 1));
+    // Uncached calls are made again, here returning a new value each time.
+    assert_eq!(run(true)?, (3, 3));
+    Ok(())
+}
+
+#[test]
+fn test_external_call_cache_skips_instances() -> TestResult {
+    let p = Polar::new();
+    p.load_str("f(x, n, y) if n in [1, 2, 3] and y = x.items();")?;
+    let instance = |instance_id| {
+        term!(Value::ExternalInstance(ExternalInstance {
+            instance_id,
+            constructor: None,
+            repr: None,
+            class_tag: None,
+        }))
+    };
+
+    let mut q = p.new_query("f(x, n, y)", false)?;
+    q.bind(sym!("x"), instance(1))?;
+    let mut calls = 0;
+    loop {
+        match q.next_event()? {
+            QueryEvent::Done { .. } => break,
+            QueryEvent::Result { .. } => (),
+            QueryEvent::ExternalCall { call_id, .. } => {
+                calls += 1;
+                q.call_result(call_id, Some(instance(100 + calls)))?;
+            }
+            event => panic!("unexpected event {:?}", event),
+        }
+    }
+
+    // Each lookup returns a new instance, e.g., an iterator, so none are reused.
+    assert_eq!(calls, 3);
+    Ok(())
+}
+
+#[test]
+fn test_suspend_query() -> TestResult {
+    let policy = r#"f(x, n) if n in [1, 2, 3] and x.roll(6) = n;
+                    g(x) if not x.roll(6) = 1;"#;
+    let p = Polar::new();
+    p.load_str(policy)?;
+    let instance = term!(Value::ExternalInstance(ExternalInstance {
+        instance_id: 1,
+        constructor: None,
+        repr: None,
+        class_tag: None,
+    }));
+    let mut q = p.new_query("f(x, n)", false)?;
+    q.bind(sym!("x"), instance.clone())?;
+    let call_id = match q.next_event()? {
+        QueryEvent::ExternalCall { call_id, .. } => call_id,
+        event => panic!("unexpected event {:?}", event),
+    };
+    let suspended = q.suspend()?;
+    drop(q);
+
+    // Resume the query against another instance with the same policy.
+    let p = Polar::new();
+    p.load_str(policy)?;
+    let mut q = p.resume_query(&suspended)?;
+    assert!(p.get_external_id() > call_id);
+    q.call_result(call_id, Some(term!(2)))?;
+    let mut results = vec![];
+    loop {
+        match q.next_event()? {
+            QueryEvent::Done { .. } => break,
+            QueryEvent::Result { bindings, .. } => results.push(bindings[&sym!("n")].clone()),
+            event => panic!("unexpected event {:?}", event),
+        }
+    }
+    // The answer is cached for the choices left after resuming, too.
+    assert_eq!(results, vec![term!(2)]);
+
+    // Negations run in a sub-query, which can't be suspended.
+    let mut q = p.new_query("g(x)", false)?;
+    q.bind(sym!("x"), instance)?;
+    assert!(matches!(q.next_event()?, QueryEvent::ExternalCall { .. }));
+    assert!(matches!(
+        q.suspend().unwrap_err().kind,
+        ErrorKind::Operational(OperationalError::Unimplemented(_))
+    ));
+    Ok(())
+}
+
+#[test]
+fn test_record_and_replay() -> TestResult {
+    let instance = |instance_id| {
+        term!(Value::ExternalInstance(ExternalInstance {
+            instance_id,
+            constructor: None,
+            repr: None,
+            class_tag: None,
+        }))
+    };
+    let query_with = |p: &Polar, actor, resource| -> Result<Query, PolarError> {
+        let mut q = p.new_query("allow(actor, resource)", false)?;
+        q.bind(sym!("actor"), instance(actor))?;
+        q.bind(sym!("resource"), instance(resource))?;
+        Ok(q)
+    };
+    let query = |p: &Polar| query_with(p, 1, 2);
+    let p = Polar::new();
+    p.load_str(
+        r#"allow(actor, resource) if actor.role = "admin";
+           allow(_actor, resource) if resource.public = true;"#,
+    )?;
+
+    let mut q = query(&p)?;
+    q.start_recording();
+    let mut results = 0;
+    loop {
+        match q.next_event()? {
+            QueryEvent::Done { .. } => break,
+            QueryEvent::Result { .. } => results += 1,
+            QueryEvent::ExternalCall {
+                call_id, attribute, ..
+            } => match attribute.0.as_str() {
+                "role" => q.call_result(call_id, Some(term!("guest")))?,
+                "public" => q.call_result(call_id, Some(term!(true)))?,
+                _ => panic!("unexpected attribute {}", attribute),
+            },
+            event => panic!("unexpected event {:?}", event),
+        }
+    }
+    assert_eq!(results, 1);
+    let recording = q.recording().unwrap();
+    assert_eq!(recording.interactions.len(), 2);
+    // Recordings can be saved and loaded.
+    let recording: Recording =
+        serde_json::from_str(&serde_json::to_string(recording).unwrap()).unwrap();
+
+    // The replayed query doesn't ask the host anything.
+    let mut q = query(&p)?;
+    q.replay(recording.clone());
+    assert!(matches!(q.next_event()?, QueryEvent::Result { .. }));
+    assert!(matches!(q.next_event()?, QueryEvent::Done { .. }));
+
+    // Even if the host's instances have different IDs this time.
+    let mut q = query_with(&p, 3, 4)?;
+    q.replay(recording.clone());
+    assert!(matches!(q.next_event()?, QueryEvent::Result { .. }));
+    assert!(matches!(q.next_event()?, QueryEvent::Done { .. }));
+
+    // It fails if the policy has changed so that it asks something else.
+    let p = Polar::new();
+    p.load_str(r#"allow(actor, _resource) if actor.name = "alice";"#)?;
+    let mut q = query(&p)?;
+    q.replay(recording);
+    assert!(matches!(
+        q.next_event().unwrap_err().kind,
+        ErrorKind::Runtime(RuntimeError::ReplayDiverged { .. })
+    ));
+    Ok(())
+}
+
+#[test]
+fn test_query_stops_after_each_result() -> TestResult {
+    let p = Polar::new();
+    p.load_str("f(x, n) if n in [1, 2, 3] and x.check(n) = true;")?;
+    let mut q = p.new_query("f(x, n)", false)?;
+    q.bind(
+        sym!("x"),
+        term!(Value::ExternalInstance(ExternalInstance {
+            instance_id: 1,
+            constructor: None,
+            repr: None,
+            class_tag: None,
+        })),
+    )?;
+
+    // The query doesn't look for the next result until the host asks for it.
+    let mut events = vec![];
+    loop {
+        match q.next_event()? {
+            QueryEvent::Done { .. } => break,
+            QueryEvent::Result { bindings, .. } => {
+                events.push(format!("result {}", bindings[&sym!("n")]))
+            }
+            QueryEvent::ExternalCall { call_id, args, .. } => {
+                events.push(format!("call {}", args.unwrap()[0]));
+                q.call_result(call_id, Some(term!(true)))?;
+            }
+            event => panic!("unexpected event {:?}", event),
+        }
+    }
+    assert_eq!(
+        events,
+        vec!["call 1", "result 1", "call 2", "result 2", "call 3", "result 3"]
+    );
+    Ok(())
+}
+
+#[test]
+fn test_external_lookup_many() -> TestResult {
+    let p = Polar::new();
+    p.load_str("f(_: {a: 1, b: 2, c: 3});")?;
+
+    // Answers the bulk lookup with `answered` and reports each event.
+    let run = |bulk, answered: &[&str]| -> Result<(Vec<String>, usize), PolarError> {
+        let mut q = p.new_query("f(x)", false)?;
+        if bulk {
+            q.enable_bulk_lookups();
+        }
+        q.bind(
+            sym!("x"),
+            term!(Value::ExternalInstance(ExternalInstance {
+                instance_id: 1,
+                constructor: None,
+                repr: None,
+                class_tag: None,
+            })),
+        )?;
+        let value = |attribute: &Symbol| match attribute.0.as_str() {
+            "a" => term!(1),
+            "b" => term!(2),
+            _ => term!(3),
+        };
+        let (mut events, mut results) = (vec![], 0);
+        loop {
+            match q.next_event()? {
+                QueryEvent::Done { .. } => break,
+                QueryEvent::Result { .. } => results += 1,
+                QueryEvent::ExternalCall {
+                    call_id, attribute, ..
+                } => {
+                    events.push(attribute.0.clone());
+                    q.call_result(call_id, Some(value(&attribute)))?;
+                }
+                QueryEvent::ExternalLookupMany {
+                    call_id,
+                    instance_id,
+                    attributes,
+                } => {
+                    assert_eq!(instance_id, 1);
+                    let names: Vec<&str> = attributes.iter().map(|a| a.0.as_str()).collect();
+                    events.push(format!("{{{}}}", names.join(", ")));
+                    let results = attributes
+                        .iter()
+                        .filter(|a| answered.contains(&a.0.as_str()))
+                        .map(|a| (a.clone(), value(a)))
+                        .collect();
+                    q.lookup_many_result(call_id, results)?;
+                }
+                event => panic!("unexpected event {:?}", event),
+            }
+        }
+        Ok((events, results))
+    };
+
+    // Off by default.
+    assert_eq!(
+        run(false, &[])?,
+        (vec!["c".into(), "b".into(), "a".into()], 1)
+    );
+    // Attributes the host answers in bulk aren't looked up again.
+    assert_eq!(
+        run(true, &["a", "b", "c"])?,
+        (vec!["{a, b, c}".to_string()], 1)
+    );
+    // Ones it leaves out are looked up individually.
+    assert_eq!(
+        run(true, &["a", "c"])?,
+        (vec!["{a, b, c}".to_string(), "b".to_string()], 1)
+    );
+    Ok(())
+}
+
+#[test]
+fn test_freeze_and_thaw() -> TestResult {
+    let p = Polar::new();
+    p.load_str("f(1);")?;
+    let frozen = p.freeze();
+    qvar(&mut frozen.thaw(), "f(x)", "x", values![1]);
+
+    // Later changes to the instance don't affect what was frozen.
+    p.load_str("f(2);")?;
+    let results = query_results!(frozen.new_query("f(x)", false)?);
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].0[&sym!("x")], value!(1));
+
+    // Nor do changes to a thawed copy.
+    let mut thawed = frozen.thaw();
+    thawed.load_str("f(3);")?;
+    qvar(&mut thawed, "f(x)", "x", values![1, 3]);
+    assert_eq!(query_results!(frozen.new_query("f(x)", false)?).len(), 1);
+
+    // Clones share it across threads.
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let frozen = frozen.clone();
+            std::thread::spawn(move || {
+                let query = frozen.new_query("f(1)", false).unwrap();
+                query_results!(query).len()
+            })
+        })
+        .collect();
+    for handle in handles {
+        assert_eq!(handle.join().unwrap(), 1);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_rule_metrics() -> TestResult {
+    let mut p = Polar::new();
+    p.load_str(
+        r#"f(x) if g(x);
+           g(1);
+           g(2);
+           h(_x) if false;"#,
+    )?;
+    // Nothing is counted until enabled.
+    qvar(&mut p, "f(x)", "x", values![1, 2]);
+    assert!(p.rule_metrics().is_empty());
+
+    p.set_rule_metrics(true);
+    let counts = |p: &Polar| -> Vec<(String, u64, u64)> {
+        p.rule_metrics()
+            .into_iter()
+            .map(|counts| (counts.rule, counts.hits, counts.successes))
+            .collect()
+    };
+    qvar(&mut p, "f(x)", "x", values![1, 2]);
+    qeval(&mut p, "g(1)");
+    assert_eq!(
+        counts(&p),
+        vec![
+            ("f(x) if g(x);".to_string(), 1, 2),
+            ("g(1);".to_string(), 2, 2),
+            ("g(2);".to_string(), 1, 1),
+            ("h(_x) if false;".to_string(), 0, 0),
+        ]
+    );
+
+    // Frozen instances keep counting, across threads.
+    let frozen = p.freeze();
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let frozen = frozen.clone();
+            std::thread::spawn(move || {
+                query_results!(frozen.new_query("h(1)", false).unwrap()).len()
+            })
+        })
+        .collect();
+    for handle in handles {
+        assert_eq!(handle.join().unwrap(), 0);
+    }
+    assert_eq!(frozen.rule_metrics()[3].hits, 4);
+
+    frozen.reset_rule_metrics();
+    assert!(counts(&p).iter().all(|(_, hits, _)| *hits == 0));
+    p.set_rule_metrics(false);
+    assert!(p.rule_metrics().is_empty());
+    Ok(())
+}
+
+#[test]
+fn test_concurrent_calls() -> TestResult {
+    let p = Polar::new();
+    p.load_str("f(x) if x.a = 1 and x.b(2) = 2 and x.c(x.a) = 3;")?;
+
+    // Leaves the concurrent calls to `unanswered` unanswered, answers the
+    // rest in reverse order, and reports each event.
+    let query = |concurrent| -> Result<Query, PolarError> {
+        let mut q = p.new_query("f(x)", false)?;
+        if concurrent {
+            q.enable_concurrent_calls();
+        }
+        q.bind(
+            sym!("x"),
+            term!(Value::ExternalInstance(ExternalInstance {
+                instance_id: 1,
+                constructor: None,
+                repr: None,
+                class_tag: None,
+            })),
+        )?;
+        Ok(q)
+    };
+    let run = |q: &mut Query, unanswered: &str| -> Result<(Vec<String>, usize), PolarError> {
+        let value = |attribute: &Symbol| match attribute.0.as_str() {
+            "a" => term!(1),
+            "b" => term!(2),
+            _ => term!(3),
+        };
+        let (mut events, mut results) = (vec![], 0);
+        loop {
+            match q.next_event()? {
+                QueryEvent::Done { .. } => break,
+                QueryEvent::Result { .. } => results += 1,
+                QueryEvent::ExternalCall {
+                    call_id, attribute, ..
+                } => {
+                    events.push(attribute.0.clone());
+                    q.call_result(call_id, Some(value(&attribute)))?;
+                }
+                QueryEvent::ExternalCalls { calls } => {
+                    let names: Vec<&str> = calls.iter().map(|c| c.attribute.0.as_str()).collect();
+                    events.push(format!("{{{}}}", names.join(", ")));
+                    for call in calls.iter().rev() {
+                        if call.attribute.0 != unanswered {
+                            q.call_result(call.call_id, Some(value(&call.attribute)))?;
+                        }
+                    }
+                }
+                event => panic!("unexpected event {:?}", event),
+            }
+        }
+        Ok((events, results))
+    };
+
+    // Off by default.
+    assert_eq!(
+        run(&mut query(false)?, "")?,
+        (vec!["a".into(), "b".into(), "c".into()], 1)
+    );
+    // Calls with ground arguments are made together; `x.c(x.a)` waits for
+    // the answer to `x.a`.
+    assert_eq!(
+        run(&mut query(true)?, "")?,
+        (vec!["{a, b}".to_string(), "c".to_string()], 1)
+    );
+    // Calls left unanswered are made individually.
+    assert_eq!(
+        run(&mut query(true)?, "b")?,
+        (
+            vec!["{a, b}".to_string(), "b".to_string(), "c".to_string()],
+            1
+        )
+    );
+
+    // Answers to concurrent calls are replayed to the calls they answered.
+    let mut q = query(true)?;
+    q.start_recording();
+    run(&mut q, "")?;
+    let recording = q.recording().unwrap().clone();
+    let mut q = query(true)?;
+    q.replay(recording);
+    assert_eq!(run(&mut q, "")?, (vec![], 1));
+    Ok(())
+}
+
+#[test]
+fn test_instance_snapshots() -> TestResult {
+    let polar = || -> Result<Polar, PolarError> {
+        let p = Polar::new();
+        p.load_str(
+            r#"f(x) if x.a = 1 and x.b = 2 and x.name() = "n";
+               g(x) if x = new Foo() and f(x);"#,
+        )?;
+        Ok(p)
+    };
+    let p = polar()?;
+
+    // Answers every lookup, snapshotting constructed instances with `a` and
+    // `b`, and reports each lookup asked of the host.
+    let run = |q: &mut Query| -> Result<(Vec<String>, usize), PolarError> {
+        let (mut lookups, mut results) = (vec![], 0);
+        loop {
+            match q.next_event()? {
+                QueryEvent::Done { .. } => break,
+                QueryEvent::Result { .. } => results += 1,
+                QueryEvent::MakeExternal { instance_id, .. } => q.snapshot_instance(
+                    instance_id,
+                    btreemap! { sym!("a") => term!(1), sym!("b") => term!(2) },
+                )?,
+                QueryEvent::ExternalCall {
+                    call_id, attribute, ..
+                } => {
+                    lookups.push(attribute.0.clone());
+                    let value = match attribute.0.as_str() {
+                        "a" => term!(1),
+                        "b" => term!(2),
+                        _ => term!("n"),
+                    };
+                    q.call_result(call_id, Some(value))?;
+                }
+                event => panic!("unexpected event {:?}", event),
+            }
+        }
+        Ok((lookups, results))
+    };
+    let instance = term!(Value::ExternalInstance(ExternalInstance {
+        instance_id: 1,
+        constructor: None,
+        repr: None,
+        class_tag: None,
+    }));
+    let query = |p: &Polar| -> Result<Query, PolarError> {
+        let mut q = p.new_query("f(x)", false)?;
+        q.bind(sym!("x"), instance.clone())?;
+        Ok(q)
+    };
+
+    assert_eq!(
+        run(&mut query(&p)?)?,
+        (vec!["a".into(), "b".into(), "name".into()], 1)
+    );
+
+    // Snapshots on the instance answer attribute lookups, not method calls.
+    p.set_instance_snapshot(1, Some(btreemap! { sym!("a") => term!(1) }));
+    assert_eq!(run(&mut query(&p)?)?, (vec!["b".into(), "name".into()], 1));
+    p.set_instance_snapshot(1, None);
+    assert_eq!(run(&mut query(&p)?)?.0.len(), 3);
+
+    // Hosts can snapshot instances they construct.
+    let mut q = polar()?.new_query("g(x)", false)?;
+    q.start_recording();
+    assert_eq!(run(&mut q)?, (vec!["name".into()], 1));
+
+    // Snapshots are replayed along with other answers.
+    let recording = q.recording().unwrap().clone();
+    let mut q = polar()?.new_query("g(x)", false)?;
+    q.replay(recording);
+    assert!(matches!(q.next_event()?, QueryEvent::Result { .. }));
+    assert!(matches!(q.next_event()?, QueryEvent::Done { .. }));
+    Ok(())
+}
+
+#[test]
+fn test_dispatch_cache() -> TestResult {
+    let p = Polar::new();
+    p.load_str(
+        r#"f(_x: A, r) if r = "a";
+           f(_x: B, r) if r = "b";
+           f(_x: C, r) if r = "c";"#,
+    )?;
+
+    // Queries `f(x, r)` for an instance of `class`, answering whether it's
+    // an instance of each class it's asked about; returns the results and
+    // how many classes it was asked about.
+    let run = |instance_id, class: &str| -> Result<(Vec<Term>, usize), PolarError> {
+        let mut q = p.new_query("f(x, r)", false)?;
+        q.bind(
+            sym!("x"),
+            term!(Value::ExternalInstance(ExternalInstance {
+                instance_id,
+                constructor: None,
+                repr: None,
+                class_tag: Some(sym!(class)),
+            })),
+        )?;
+        let (mut results, mut isas) = (vec![], 0);
+        loop {
+            match q.next_event()? {
+                QueryEvent::Done { .. } => break,
+                QueryEvent::Result { bindings, .. } => results.push(bindings[&sym!("r")].clone()),
+                QueryEvent::ExternalIsa {
+                    call_id, class_tag, ..
+                } => {
+                    isas += 1;
+                    q.question_result(call_id, class_tag.0 == class)?;
+                }
+                event => panic!("unexpected event {:?}", event),
+            }
+        }
+        Ok((results, isas))
+    };
+
+    // Each rule is checked for applicability, then the applicable one is run.
+    assert_eq!(run(1, "A")?, (vec![term!("a")], 4));
+    // Other instances of the class reuse the applicable rules.
+    assert_eq!(run(2, "A")?, (vec![term!("a")], 1));
+    assert_eq!(run(3, "B")?, (vec![term!("b")], 4));
+    assert_eq!(run(4, "B")?, (vec![term!("b")], 1));
+
+    // Adding rules clears the cache.
+    p.load_str(r#"f(_x: D, r) if r = "d";"#)?;
+    assert_eq!(run(5, "A")?, (vec![term!("a")], 5));
+    assert_eq!(run(6, "A")?, (vec![term!("a")], 1));
+
+    // Rules that depend on more than classes aren't cached.
+    p.load_str(r#"f(_x: A, "z");"#)?;
+    assert_eq!(run(7, "A")?.1, run(8, "A")?.1);
+    Ok(())
+}
+
+#[test]
+fn test_frozen_dispatch() -> TestResult {
+    let load = || -> PolarResult<Polar> {
+        let p = Polar::new();
+        p.register_constant(sym!("A"), term!(true));
+        p.load_str(
+            r#"f(_x: A, _y, r) if r = "x";
+               f(_x, _y: A, r) if r = "y";
+               f(_x: A, _y: A, r) if r = "both";"#,
+        )?;
+        Ok(p)
+    };
+    let (p, frozen) = (load()?, load()?.freeze());
+
+    // Runs `f(x, y, r)` for two instances of `A`; returns the results and
+    // how many classes it was asked about.
+    let run = |mut q: Query| -> Result<(Vec<Value>, usize), PolarError> {
+        for (var, instance_id) in [("x", 1), ("y", 2)] {
+            q.bind(
+                sym!(var),
+                term!(Value::ExternalInstance(ExternalInstance {
+                    instance_id,
+                    constructor: None,
+                    repr: None,
+                    class_tag: Some(sym!("A")),
+                })),
+            )?;
+        }
+        let (mut results, mut isas) = (vec![], 0);
+        loop {
+            match q.next_event()? {
+                QueryEvent::Done { .. } => break,
+                QueryEvent::Result { bindings, .. } => {
+                    results.push(bindings[&sym!("r")].value().clone())
+                }
+                QueryEvent::ExternalIsa {
+                    call_id, class_tag, ..
+                } => {
+                    isas += 1;
+                    q.question_result(call_id, class_tag.0 == "A")?;
+                }
+                event => panic!("unexpected event {:?}", event),
+            }
+        }
+        Ok((results, isas))
+    };
+    let results = vec![value!("both"), value!("x"), value!("y")];
+
+    // The first call checks each rule for applicability before running the
+    // applicable ones.
+    assert_eq!(
+        run(p.new_query("f(x, y, r)", false)?)?,
+        (results.clone(), 8)
+    );
+    assert_eq!(
+        run(p.new_query("f(x, y, r)", false)?)?,
+        (results.clone(), 4)
+    );
+    // A frozen instance sorted them for the classes they specialize when
+    // it was frozen.
+    assert_eq!(run(frozen.new_query("f(x, y, r)", false)?)?, (results, 4));
+    Ok(())
+}
+
+#[test]
+fn test_explain() -> TestResult {
+    let p = Polar::new();
+    p.load_str(
+        r#"f(1, x) if g(x);
+           f(2, x) if g(x) or h(x);
+           f(n, 3) if n > 0;
+           g(1);"#,
+    )?;
+
+    let plan = p.explain("f(1, y) and (g(y) or missing(y)) and sort([], z)")?;
+    assert_eq!(
+        plan.goals,
+        vec![
+            GoalPlan {
+                goal: "f(1, y)".to_string(),
+                candidates: vec![
+                    "f(1, x) if g(x);".to_string(),
+                    "f(n, 3) if n > 0;".to_string()
+                ],
+                rules_defined: 3,
+                indexed_args: vec![0],
+                builtin: false,
+            },
+            GoalPlan {
+                goal: "g(y)".to_string(),
+                candidates: vec!["g(1);".to_string()],
+                rules_defined: 1,
+                indexed_args: vec![],
+                builtin: false,
+            },
+            GoalPlan {
+                goal: "missing(y)".to_string(),
+                candidates: vec![],
+                rules_defined: 0,
+                indexed_args: vec![],
+                builtin: false,
+            },
+            GoalPlan {
+                goal: "sort([], z)".to_string(),
+                candidates: vec![],
+                rules_defined: 0,
+                indexed_args: vec![],
+                builtin: true,
+            },
+        ]
+    );
+    // One for the two candidates of `f`, one for the `or`.
+    assert_eq!(plan.choice_points, 2);
+
+    // Explaining doesn't run the query.
+    assert!(p.next_message().is_none());
+    Ok(())
+}
+
+#[test]
+fn test_goal_sink() -> TestResult {
+    let p = Polar::new();
+    p.load_str("f(x) if x = 1 or x = 2;")?;
+    let mut q = p.new_query("f(x) and x > 1", false)?;
+
+    let records = Rc::new(RefCell::new(vec![]));
+    let sink = records.clone();
+    q.set_goal_sink(Rc::new(RefCell::new(move |record: GoalRecord| {
+        sink.borrow_mut().push(record)
+    })));
+    let mut results = 0;
+    while let QueryEvent::Result { .. } = q.next_event()? {
+        results += 1;
+    }
+    assert_eq!(results, 1);
+
+    let records = records.borrow();
+    assert_eq!(records[0].kind, "Query");
+    assert_eq!(records[0].summary, "Query(f(x) and x > 1)");
+    assert_eq!(records[0].depth, 0);
+    assert!(records.iter().any(|r| r.kind == "Backtrack"));
+    assert!(records.iter().any(|r| r.kind == "Unify" && r.depth > 1));
+    assert!(records
+        .windows(2)
+        .all(|w| w[0].timestamp_us <= w[1].timestamp_us));
+    Ok(())
+}
+
+#[test]
+fn test_instrumentation() -> TestResult {
+    let mut p = Polar::new();
+    p.load_str(
+        r#"f(x) if g(x) and x > 0;
+           g(x) if x = 1;"#,
+    )?;
+    let goals: Arc<Mutex<Vec<(String, Option<Symbol>, usize)>>> = Arc::default();
+    let hook = goals.clone();
+    p.set_instrumentation(move |goal: &GoalSummary| {
+        hook.lock()
+            .unwrap()
+            .push((goal.kind.to_owned(), goal.rule.cloned(), goal.depth))
+    });
+    qeval(&mut p, "f(1)");
+    {
+        let goals = goals.lock().unwrap();
+        assert_eq!(goals[0], ("Query".to_owned(), None, 0));
+        assert!(goals
+            .iter()
+            .any(|(kind, rule, depth)| kind == "Unify" && rule == &Some(sym!("g")) && *depth > 1));
+        assert!(goals
+            .iter()
+            .any(|(kind, rule, _)| kind == "Query" && rule == &Some(sym!("f"))));
+    }
+
+    p.clear_instrumentation();
+    let count = goals.lock().unwrap().len();
+    qeval(&mut p, "f(1)");
+    assert_eq!(goals.lock().unwrap().len(), count);
+    Ok(())
+}
+
+#[test]
+fn test_decision_logger() -> TestResult {
+    let p = Polar::new();
+    p.load_versioned("f(1); f(2);", None, "v1".to_string())?;
+    let decisions: Arc<Mutex<Vec<Decision>>> = Arc::default();
+    let log = decisions.clone();
+    p.set_decision_logger(move |decision: &Decision| log.lock().unwrap().push(decision.clone()));
+
+    // Logged once the query is done.
+    let mut q = p.new_query("f(x)", false)?;
+    while let QueryEvent::Result { .. } = q.next_event()? {}
+    assert!(q.next_event().is_ok());
+    drop(q);
+    {
+        let decisions = decisions.lock().unwrap();
+        assert_eq!(decisions.len(), 1);
+        let decision = &decisions[0];
+        assert_eq!(decision.query, "f(x)");
+        assert_eq!(decision.results, 2);
+        assert!(decision.completed);
+        assert_eq!(decision.error, None);
+        assert_eq!(decision.policy_version, Some("v1".to_string()));
+        assert_eq!(decision.proof.as_ref().unwrap().to_string(), "f(2)\n");
+        assert_eq!(decision.trace, None);
+    }
+
+    // Logged when dropped after the first result, or on an error.
+    let mut q = p.new_query("f(x)", false)?;
+    assert!(matches!(q.next_event()?, QueryEvent::Result { .. }));
+    drop(q);
+    let mut q = p.new_query("1 + \"a\" = x", false)?;
+    assert!(q.next_event().is_err());
+    drop(q);
+    {
+        let decisions = decisions.lock().unwrap();
+        assert_eq!(decisions.len(), 3);
+        assert_eq!((decisions[1].results, decisions[1].completed), (1, false));
+        assert_eq!(decisions[1].proof.as_ref().unwrap().to_string(), "f(1)\n");
+        assert!(decisions[2].completed);
+        assert!(decisions[2].error.as_ref().unwrap().contains("R0003"));
+    }
+
+    struct Prover(Arc<Mutex<Vec<Decision>>>);
+    impl DecisionLogger for Prover {
+        fn log(&self, decision: &Decision) {
+            self.0.lock().unwrap().push(decision.clone())
+        }
+        fn wants_traces(&self) -> bool {
+            true
+        }
+    }
+    p.set_decision_logger(Prover(decisions.clone()));
+    let mut q = p.new_query("f(2)", false)?;
+    assert!(matches!(q.next_event()?, QueryEvent::Result { .. }));
+    drop(q);
+    assert!(decisions.lock().unwrap()[3]
+        .trace
+        .as_ref()
+        .unwrap()
+        .contains("f(2)"));
+
+    p.clear_decision_logger();
+    let mut q = p.new_query("f(x)", false)?;
+    assert!(q.next_event().is_ok());
+    drop(q);
+    assert_eq!(decisions.lock().unwrap().len(), 4);
+    Ok(())
+}
+
+#[test]
+fn test_query_constants() -> TestResult {
+    let p = Polar::new();
+    p.load_str(
+        r#"allow(user) if user.expires > now;
+           flagged(name) if name in flags;"#,
+    )?;
+    while p.next_message().is_some() {}
+
+    let run =
+        |src, constants: Vec<(&str, Term)>| -> Result<Vec<HashMap<Symbol, Term>>, PolarError> {
+            let mut q = p.new_query(src, false)?;
+            for (name, value) in constants {
+                q.bind_constant(sym!(name), value)?;
+            }
+            let mut results = vec![];
+            while let QueryEvent::Result { bindings, .. } = q.next_event()? {
+                results.push(bindings);
+            }
+            Ok(results)
+        };
+
+    // Each query sees only its own constants.
+    let query = "allow({expires: 100})";
+    assert_eq!(run(query, vec![("now", term!(50))])?.len(), 1);
+    assert!(run(query, vec![("now", term!(150))])?.is_empty());
+    assert_eq!(run(query, vec![("now", term!(99))])?.len(), 1);
+
+    // Constants aren't reported in results.
+    let flags = term!(["a", "b"]);
+    let results = run("flagged(x)", vec![("flags", flags)])?;
+    assert_eq!(
+        results
+            .iter()
+            .map(|bindings| bindings.keys().cloned().collect::<Vec<_>>())
+            .collect::<Vec<_>>(),
+        vec![vec![sym!("x")], vec![sym!("x")]]
+    );
+
+    let mut q = p.new_query("x = 1", false)?;
+    q.bind(sym!("y"), term!(1))?;
+    assert!(q.bind_constant(sym!("now"), term!(1)).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_overlay() -> TestResult {
+    let base = Polar::new();
+    base.load_str(
+        r#"allow(user, "read") if role(user, "reader");
+           role("alice", "reader");"#,
+    )?;
+    let tenant = Polar::new();
+    tenant.load_str(
+        r#"allow(user, "write") if role(user, "writer");
+           role("bob", "reader");
+           role("bob", "writer");"#,
+    )?;
+
+    let results = |q: Query| -> Result<Vec<String>, PolarError> {
+        let mut results = vec![];
+        for event in q {
+            if let QueryEvent::Result { bindings, .. } = event? {
+                results.push(format!(
+                    "{} {}",
+                    bindings[&sym!("user")].to_polar(),
+                    bindings[&sym!("action")].to_polar()
+                ));
+            }
+        }
+        Ok(results)
+    };
+
+    let src = "allow(user, action)";
+    assert_eq!(
+        results(base.new_query(src, false)?)?,
+        vec![r#""alice" "read""#]
+    );
+    // Overlay rules are tried first.
+    assert_eq!(
+        results(base.new_query_with_overlay(src, &tenant.kb, false)?)?,
+        vec![r#""bob" "write""#, r#""bob" "read""#, r#""alice" "read""#]
+    );
+    // The base knowledge base is unchanged.
+    assert_eq!(
+        results(base.new_query(src, false)?)?,
+        vec![r#""alice" "read""#]
+    );
+
+    // Errors in overlay rules point into the overlay's sources.
+    tenant.load(
+        "\n\nbroken() if 1 + \"a\" = 2;",
+        Some("tenant.polar".to_string()),
+    )?;
+    let err = base
+        .new_query_with_overlay("broken()", &tenant.kb, false)?
+        .next_event()
+        .unwrap_err();
+    let context = err.context.expect("context");
+    assert_eq!(context.source.filename.as_deref(), Some("tenant.polar"));
+    assert_eq!(context.row, 2);
+    Ok(())
+}
+
+#[test]
+fn test_add_fact() -> TestResult {
+    let mut p = Polar::new();
+    p.load_str(r#"allow(actor) if has_role(actor, "admin");"#)?;
+    p.add_fact(sym!("has_role"), vec![term!("alice"), term!("admin")])?;
+    p.add_fact(
+        sym!("has_role"),
+        vec![
+            term!("bob"),
+            term!(btreemap! {sym!("team") => term!([1, 2])}),
+        ],
+    )?;
+    qeval(&mut p, r#"allow("alice")"#);
+    qnull(&mut p, r#"allow("bob")"#);
+    qvar(
+        &mut p,
+        r#"has_role("bob", {team: t})"#,
+        "t",
+        vec![value!([1, 2])],
+    );
+
+    // A running query sees facts added before the calls it makes.
+    let mut q = p.new_query(r#"name = x.name and has_role(name, role)"#, false)?;
+    q.bind(
+        sym!("x"),
+        term!(Value::ExternalInstance(ExternalInstance {
+            instance_id: 1,
+            constructor: None,
+            repr: None,
+            class_tag: None,
+        })),
+    )?;
+    match q.next_event()? {
+        QueryEvent::ExternalCall { call_id, .. } => {
+            p.add_fact(sym!("has_role"), vec![term!("carol"), term!("viewer")])?;
+            q.call_result(call_id, Some(term!("carol")))?;
+        }
+        event => panic!("unexpected event {:?}", event),
+    }
+    match q.next_event()? {
+        QueryEvent::Result { bindings, .. } => assert_eq!(bindings[&sym!("role")], term!("viewer")),
+        event => panic!("unexpected event {:?}", event),
+    }
+
+    // Facts hold data, not variables or expressions.
+    let err = p
+        .add_fact(sym!("has_role"), vec![term!(sym!("x")), term!("admin")])
+        .unwrap_err();
+    assert!(matches!(err.kind, ErrorKind::Parameter(_)));
+    Ok(())
+}
+
+#[test]
+fn test_load_facts() -> TestResult {
+    let mut p = Polar::new();
+    p.load_str(r#"member(user, group) if in_group(user, group);"#)?;
+    let facts = (0..1000).map(|i| vec![Value::String(format!("user{}", i)), value!(i % 10)]);
+    assert_eq!(p.load_facts(sym!("in_group"), facts)?, 1000);
+    qeval(&mut p, r#"member("user42", 2)"#);
+    qnull(&mut p, r#"member("user42", 3)"#);
+    qvar(&mut p, r#"in_group("user999", g)"#, "g", vec![value!(9)]);
+
+    // An invalid fact fails the whole load.
+    let facts = vec![
+        vec![value!("ann"), value!(1)],
+        vec![value!(sym!("x")), value!(2)],
+    ];
+    let err = p
+        .load_facts(sym!("in_group"), facts.into_iter())
+        .unwrap_err();
+    assert!(matches!(err.kind, ErrorKind::Parameter(_)));
+    qnull(&mut p, r#"in_group("ann", _)"#);
+    Ok(())
+}
+
+#[test]
+fn test_fact_source() -> TestResult {
+    let mut p = Polar::new();
+    p.load_str(
+        r#"allow(user, org) if has_role(user, "admin", org);
+           has_role("root", _role, _org);"#,
+    )?;
+    let roles = vec![("alice", "admin", "acme"), ("bob", "viewer", "acme")];
+    let calls = Arc::new(Mutex::new(0));
+    let source_calls = calls.clone();
+    let source = move |args: &[Term]| -> PolarResult<Vec<Vec<Term>>> {
+        *source_calls.lock().unwrap() += 1;
+        // Narrow by user when it's bound, as an index would.
+        Ok(roles
+            .iter()
+            .filter(|(user, ..)| match args[0].value() {
+                Value::String(bound) => bound == user,
+                _ => true,
+            })
+            .map(|(user, role, org)| vec![term!(*user), term!(*role), term!(*org)])
+            .collect())
+    };
+    p.set_fact_source(sym!("has_role"), 3, Some(Arc::new(source)));
+
+    qeval(&mut p, r#"allow("alice", "acme")"#);
+    qnull(&mut p, r#"allow("bob", "acme")"#);
+    // Rules for the predicate still apply, after the source's facts.
+    qeval(&mut p, r#"allow("root", "acme")"#);
+    qvar(
+        &mut p,
+        r#"has_role(user, _, "acme")"#,
+        "user",
+        vec![value!("alice"), value!("bob"), value!("root")],
+    );
+    assert_eq!(*calls.lock().unwrap(), 4);
+
+    // Other arities aren't answered by the source.
+    qnull(&mut p, r#"has_role("alice", "admin")"#);
+
+    p.set_fact_source(sym!("has_role"), 3, None);
+    qnull(&mut p, r#"allow("alice", "acme")"#);
+    Ok(())
+}
+
+#[test]
+fn test_remove_facts() -> TestResult {
+    let mut p = Polar::new();
+    p.load_str(
+        r#"has_role("alice", "admin", "org1");
+           has_role("alice", "viewer", "org2");
+           has_role(user, "viewer", "org3") if user = "bob";"#,
+    )?;
+    let fact = |user, role, org| vec![term!(user), term!(role), term!(org)];
+    p.add_fact(sym!("has_role"), fact("bob", "admin", "org1"))?;
+    p.add_fact(sym!("has_role"), fact("bob", "admin", "org2"))?;
+    p.add_fact(sym!("has_role"), fact("carol", "carol", "org1"))?;
+
+    assert_eq!(
+        p.remove_fact(sym!("has_role"), fact("alice", "admin", "org1"))?,
+        1
+    );
+    assert_eq!(
+        p.remove_fact(sym!("has_role"), fact("alice", "admin", "org1"))?,
+        0
+    );
+    qnull(&mut p, r#"has_role("alice", "admin", _)"#);
+    qeval(&mut p, r#"has_role("alice", "viewer", "org2")"#);
+
+    // Variables in patterns match any value, but the same one throughout.
+    let var = |name| term!(sym!(name));
+    let removed = p.remove_facts_matching(
+        sym!("has_role"),
+        vec![term!("bob"), term!("admin"), var("_org")],
+    )?;
+    assert_eq!(removed, 2);
+    let removed =
+        p.remove_facts_matching(sym!("has_role"), vec![var("x"), var("x"), var("_org")])?;
+    assert_eq!(removed, 1);
+    qnull(&mut p, r#"has_role("bob", "admin", _)"#);
+    qnull(&mut p, r#"has_role("carol", _, _)"#);
+
+    // Only facts are removed, not rules.
+    let removed = p.remove_facts_matching(sym!("has_role"), vec![var("u"), var("r"), var("o")])?;
+    assert_eq!(removed, 1);
+    qeval(&mut p, r#"has_role("bob", "viewer", "org3")"#);
+    Ok(())
+}
+
+#[test]
+fn test_load_package() -> TestResult {
+    let dir = std::env::temp_dir().join(format!("polar-package-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let write = |name: &str, contents: &str| std::fs::write(dir.join(name), contents).unwrap();
+    write(
+        "polar-package.json",
+        r#"{"name": "rbac", "version": "1.0.0",
+            "files": ["roles.polar", "rbac.polar"], "exports": ["allow"]}"#,
+    );
+    write("roles.polar", r#"role("alice", "admin");"#);
+    write(
+        "rbac.polar",
+        r#"allow(actor, action) if role(actor, role) and permits(role, action);
+           permits("admin", _action);"#,
+    );
+
+    let mut p = Polar::new();
+    let manifest = p.load_package(&dir)?;
+    assert_eq!(manifest.version, "1.0.0");
+    // A rule outside the package with the name of a private rule is distinct.
+    p.load_str(r#"role("bob", "admin");"#)?;
+    qeval(&mut p, r#"allow("alice", "read")"#);
+    qnull(&mut p, r#"allow("bob", "read")"#);
+    qnull(&mut p, r#"role("alice", "admin")"#);
+
+    write(
+        "polar-package.json",
+        r#"{"name": "rbac", "version": "1.0.1",
+            "files": ["rbac.polar"], "exports": ["allow", "deny"]}"#,
+    );
+    let err = Polar::new().load_package(&dir).unwrap_err();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert!(matches!(
+        err.kind,
+        ErrorKind::Runtime(RuntimeError::FileLoading { msg })
+            if msg == "Package rbac exports deny, which it doesn't define"
+    ));
+    Ok(())
+}
+
+#[test]
+fn test_package_dependencies() -> TestResult {
+    let root = std::env::temp_dir().join(format!("polar-packages-{}", std::process::id()));
+    let package = |dir: &str, manifest: &str, src: &str| {
+        let dir = root.join(dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("polar-package.json"), manifest).unwrap();
+        std::fs::write(dir.join("main.polar"), src).unwrap();
+        dir
+    };
+    package(
+        "roles-1",
+        r#"{"name": "roles", "version": "1.2.0", "files": ["main.polar"], "exports": ["has_role"]}"#,
+        r#"has_role(_actor, "guest");"#,
+    );
+    package(
+        "roles-2",
+        r#"{"name": "roles", "version": "2.1.0", "files": ["main.polar"], "exports": ["has_role"]}"#,
+        r#"has_role("alice", "admin");"#,
+    );
+    let rbac = package(
+        "rbac",
+        r#"{"name": "rbac", "version": "1.0.0", "files": ["main.polar"], "exports": ["allow"],
+            "dependencies": {"roles": "^2.0"}}"#,
+        r#"allow(actor) if has_role(actor, "admin");"#,
+    );
+    package(
+        "audit",
+        r#"{"name": "audit", "version": "1.0.0", "files": ["main.polar"], "exports": ["audited"],
+            "dependencies": {"roles": "^1.0"}}"#,
+        r#"audited(actor) if has_role(actor, "guest");"#,
+    );
+    let app = package(
+        "app",
+        r#"{"name": "app", "version": "0.1.0", "files": ["main.polar"], "exports": ["ok"],
+            "dependencies": {"rbac": "1", "audit": "1"}}"#,
+        r#"ok(actor) if allow(actor) and audited(actor);"#,
+    );
+    let cyclic = package(
+        "cyclic",
+        r#"{"name": "cyclic", "version": "1.0.0", "files": ["main.polar"], "exports": ["f"],
+            "dependencies": {"loop": "1"}}"#,
+        "f(1);",
+    );
+    package(
+        "loop",
+        r#"{"name": "loop", "version": "1.0.0", "files": ["main.polar"], "exports": ["g"],
+            "dependencies": {"cyclic": "1"}}"#,
+        "g(1);",
+    );
+    let loading_error = |err: PolarError| match err.kind {
+        ErrorKind::Runtime(RuntimeError::FileLoading { msg }) => msg,
+        kind => panic!("unexpected error {:?}", kind),
+    };
+
+    // The newest matching version of a dependency is loaded first.
+    let mut p = Polar::new();
+    p.load_package(&rbac)?;
+    assert_eq!(p.loaded_packages()["roles"].version, "2.1.0");
+    qeval(&mut p, r#"allow("alice")"#);
+
+    // Dependencies must agree on the versions of shared dependencies.
+    assert_eq!(
+        loading_error(Polar::new().load_package(&app).unwrap_err()),
+        "Conflicting requirements on package roles: rbac requires ^2.0, \
+         but audit requires ^1.0, which resolved to version 1.2.0"
+    );
+    assert_eq!(
+        loading_error(Polar::new().load_package(&cyclic).unwrap_err()),
+        "Packages depend on each other in a cycle: cyclic -> loop -> cyclic"
+    );
+    // Including with packages loaded before.
+    let p = Polar::new();
+    p.load_package(root.join("roles-1"))?;
+    let err = p.load_package(&rbac).unwrap_err();
+    std::fs::remove_dir_all(&root).unwrap();
+    assert_eq!(
+        loading_error(err),
+        "Package rbac requires roles ^2.0, but version 1.2.0 is already loaded"
+    );
+    Ok(())
+}
+
+#[test]
+fn test_policy_version() -> TestResult {
+    let p = Polar::new();
+    p.load_str("f(1);")?;
+    assert_eq!(p.policy_version(), None);
+
+    let versions = |q: Query| -> Result<Vec<(&str, Option<String>)>, PolarError> {
+        let mut versions = vec![];
+        for event in q {
+            match event? {
+                QueryEvent::Result { policy_version, .. } => {
+                    versions.push(("result", policy_version))
+                }
+                QueryEvent::Done { policy_version, .. } => versions.push(("done", policy_version)),
+                _ => (),
+            }
+        }
+        Ok(versions)
+    };
+    assert_eq!(
+        versions(p.new_query("f(x)", false)?)?,
+        vec![("result", None), ("done", None)]
+    );
+
+    p.load_versioned("f(2);", None, "v2".to_string())?;
+    assert_eq!(p.policy_version(), Some("v2".to_string()));
+    let v2 = Some("v2".to_string());
+    assert_eq!(
+        versions(p.new_query("f(x)", false)?)?,
+        vec![("result", v2.clone()), ("result", v2.clone()), ("done", v2)]
+    );
+
+    // A query with no results still reports its version.
+    p.clear_rules();
+    p.load_versioned("f(3);", None, "v3".to_string())?;
+    assert_eq!(
+        versions(p.new_query("f(1)", false)?)?,
+        vec![("done", Some("v3".to_string()))]
+    );
+
+    // A query keeps the version it started with across reloads.
+    let q = p.new_query("f(x)", false)?;
+    p.load_versioned("f(4);", None, "v4".to_string())?;
+    let v3 = Some("v3".to_string());
+    for event in q {
+        match event? {
+            QueryEvent::Result { policy_version, .. } | QueryEvent::Done { policy_version, .. } => {
+                assert_eq!(policy_version, v3)
+            }
+            _ => (),
+        }
+    }
+
+    p.clear_rules();
+    assert_eq!(p.policy_version(), None);
+    Ok(())
+}
+
+#[test]
+fn test_heartbeat() -> TestResult {
+    let p = Polar::new();
+    p.load_str(
+        r#"count(n, n);
+           count(i, n) if i < n and count(i + 1, n);"#,
+    )?;
+
+    let run = |interval| -> Result<(Vec<u64>, usize), PolarError> {
+        let mut q = p.new_query("count(0, 100)", false)?;
+        q.set_heartbeat_interval(interval);
+        let (mut heartbeats, mut results) = (vec![], 0);
+        loop {
+            match q.next_event()? {
+                QueryEvent::Done { .. } => break,
+                QueryEvent::Result { .. } => results += 1,
+                QueryEvent::Heartbeat { goals_executed } => heartbeats.push(goals_executed),
+                event => panic!("unexpected event {:?}", event),
+            }
+        }
+        Ok((heartbeats, results))
+    };
+
+    // Heartbeats are off by default.
+    assert_eq!(run(0)?, (vec![], 1));
+
+    let (heartbeats, results) = run(50)?;
+    assert_eq!(results, 1);
+    assert!(heartbeats.len() > 2);
+    assert!(heartbeats.iter().all(|n| n % 50 == 0));
+    assert!(heartbeats.windows(2).all(|w| w[0] < w[1]));
+    Ok(())
+}
+
+#[test]
+fn test_progress() -> TestResult {
+    let p = Polar::new();
+    p.load_str(
+        r#"count(n, n);
+           count(i, n) if i < n and count(i + 1, n);"#,
+    )?;
+
+    let run = |interval| -> Result<(Vec<(u64, u64)>, usize), PolarError> {
+        let mut q = p.new_query("count(0, 100)", false)?;
+        q.set_progress_interval(interval);
+        let (mut progress, mut results) = (vec![], 0);
+        loop {
+            match q.next_event()? {
+                QueryEvent::Done { .. } => break,
+                QueryEvent::Result { .. } => results += 1,
+                QueryEvent::Progress {
+                    goals_executed,
+                    choice_points,
+                    ..
+                } => progress.push((goals_executed, choice_points)),
+                event => panic!("unexpected event {:?}", event),
+            }
+        }
+        Ok((progress, results))
+    };
+
+    // Progress events are off by default.
+    assert_eq!(run(0)?, (vec![], 1));
+
+    let (progress, results) = run(50)?;
+    assert_eq!(results, 1);
+    assert!(progress.len() > 2);
+    assert!(progress.iter().all(|(goals, _)| goals % 50 == 0));
+    // Both statistics are cumulative.
+    assert!(progress
+        .windows(2)
+        .all(|w| w[0].0 < w[1].0 && w[0].1 <= w[1].1 && w[1].1 > 0));
+    Ok(())
+}
+
+#[test]
+fn test_datetimes() -> TestResult {
+    let mut p = Polar::new();
+    p.load_str(
+        r#"active(grant) if
+               grant.starts_at <= @2024-05-01T12:00:00Z and
+               @2024-05-01T12:00:00Z < grant.expires_at;
+           launch(@2024-05-01);"#,
+    )?;
+    qeval(
+        &mut p,
+        "active({starts_at: @2024-05-01, expires_at: @2024-05-02})",
+    );
+    qnull(
+        &mut p,
+        "active({starts_at: @2024-05-01, expires_at: @2024-05-01T12:00:00Z})",
+    );
+
+    qeval(&mut p, "@2024-01-01 < @2024-06-01T00:00:00Z");
+    qeval(
+        &mut p,
+        "@2024-05-01T09:30:00+02:00 == @2024-05-01T07:30:00Z",
+    );
+    qeval(&mut p, "launch(@2024-05-01T02:00:00+02:00)");
+    qnull(&mut p, "launch(@2024-05-01T00:00:00.001Z)");
+    qruntime!("@2024-05-01 < 1", RuntimeError::Unsupported { .. });
+    qparse!(
+        "expired(x) if x < @2024-13-01;",
+        ParseError::InvalidDateTime { .. }
+    );
+    Ok(())
+}
+
+#[test]
+fn test_nil() -> TestResult {
+    let mut p = Polar::new();
+    p.load_str(
+        r#"managed(x) if x.manager != nil;
+           manager_name(x, name) if name = x.manager.name;
+           missing(nil);"#,
+    )?;
+    let instance = |instance_id| {
+        term!(Value::ExternalInstance(ExternalInstance {
+            instance_id,
+            constructor: None,
+            repr: None,
+            class_tag: None,
+        }))
+    };
+
+    // Only instance 1 has a manager; the host has no value for the others.
+    let run = |query: &str, id: u64| -> PolarResult<Vec<Term>> {
+        let mut q = p.new_query(query, false)?;
+        q.bind(sym!("x"), instance(id))?;
+        let mut results = vec![];
+        loop {
+            match q.next_event()? {
+                QueryEvent::Done { .. } => break,
+                QueryEvent::Result { bindings, .. } => {
+                    results.push(bindings.get(&sym!("name")).cloned().unwrap_or(term!(true)))
+                }
+                QueryEvent::ExternalCall {
+                    call_id,
+                    instance: object,
+                    attribute,
+                    ..
+                } => {
+                    let value = match (object.value(), attribute.0.as_str()) {
+                        (
+                            Value::ExternalInstance(ExternalInstance { instance_id: 1, .. }),
+                            "manager",
+                        ) => Some(instance(2)),
+                        (_, "name") => Some(term!("bob")),
+                        _ => None,
+                    };
+                    q.call_result(call_id, value)?;
+                }
+                event => panic!("unexpected event {:?}", event),
+            }
+        }
+        Ok(results)
+    };
+    assert_eq!(run("managed(x)", 1)?.len(), 1);
+    assert!(run("managed(x)", 2)?.is_empty());
+    assert_eq!(run("manager_name(x, name)", 1)?, vec![term!("bob")]);
+    assert_eq!(run("manager_name(x, name)", 2)?, vec![term!(Value::Nil)]);
+    assert_eq!(run("x.manager = nil", 3)?.len(), 1);
+
+    qeval(&mut p, "nil = nil");
+    qeval(&mut p, "nil == nil");
+    qeval(&mut p, "nil != 1 and 0 != nil and nil != false");
+    qeval(&mut p, "missing(nil)");
+    qnull(&mut p, "missing(false)");
+    qnull(&mut p, "nil");
+    qnull(&mut p, "x in nil");
+    qeval(&mut p, "not nil");
+    qvar(&mut p, "x = nil.y", "x", vec![Value::Nil]);
+    qruntime!("nil < 1", RuntimeError::Unsupported { .. });
+    Ok(())
+}
+
+#[test]
+fn test_cached_predicates() -> TestResult {
+    let mut p = Polar::new();
+    p.load_str(
+        r#"@cached
+           reachable(x, y) if edge(x, y);
+           reachable(x, y) if edge(x, z) and reachable(z, y);
+           edge(1, 2); edge(2, 3); edge(1, 3);"#,
+    )?;
+
+    let goals_executed = |p: &Polar, query: &str| -> PolarResult<(usize, u64)> {
+        let mut q = p.new_query(query, false)?;
+        q.enable_stats();
+        loop {
+            if let QueryEvent::Done { stats, .. } = q.next_event()? {
+                let stats = stats.expect("stats");
+                return Ok((stats.results, stats.goals_executed));
+            }
+        }
+    };
+
+    // Ground calls succeed at most once, and are answered from the memo
+    // when they're made again.
+    let (results, first) = goals_executed(&p, "reachable(1, 3)")?;
+    assert_eq!(results, 1);
+    let (results, again) = goals_executed(&p, "reachable(1, 3)")?;
+    assert_eq!(results, 1);
+    assert!(again < first, "{} < {}", again, first);
+    assert_eq!(goals_executed(&p, "reachable(3, 4)")?.0, 0);
+    assert_eq!(goals_executed(&p, "reachable(3, 4)")?.0, 0);
+
+    // Calls that aren't ground aren't memoized.
+    qvar(&mut p, "reachable(1, y)", "y", values![2, 3, 3]);
+
+    // Loading rules and adding facts forget what was remembered.
+    p.load_str("edge(3, 4);")?;
+    qeval(&mut p, "reachable(1, 4)");
+    p.add_fact(sym!("edge"), vec![term!(4), term!(5)])?;
+    qeval(&mut p, "reachable(1, 5)");
+    p.remove_fact(sym!("edge"), vec![term!(4), term!(5)])?;
+    qnull(&mut p, "reachable(1, 5)");
+    p.add_fact(sym!("edge"), vec![term!(4), term!(5)])?;
+    qeval(&mut p, "reachable(1, 5)");
+    p.load_facts(sym!("edge"), vec![vec![value!(5), value!(6)]].into_iter())?;
+    qeval(&mut p, "reachable(1, 6)");
+    p.clear_rules();
+    p.load_str("@cached f(x) if g(x); g(1);")?;
+    qnull(&mut p, "f(2)");
+    p.add_fact(sym!("g"), vec![term!(2)])?;
+    qeval(&mut p, "f(2)");
+
+    qparse!("@cached(1) f(x);", ParseError::InvalidAnnotation { .. });
+    Ok(())
+}
+
+#[test]
+fn test_sandbox() -> TestResult {
+    let p = Polar::new();
+    p.load_str(
+        r#"count(0);
+           count(n) if n > 0 and count(n - 1);
+           f(x) if x in [1, 2, 3];"#,
+    )?;
+    let run = |src: &str| -> Result<Vec<QueryEvent>, PolarError> {
+        p.new_query(src, false)?
+            .filter(|event| !matches!(event, Ok(QueryEvent::Done { .. })))
+            .collect()
+    };
+    let sandbox_error = |src: &str| match run(src).unwrap_err().kind {
+        ErrorKind::Runtime(error) => error,
+        kind => panic!("unexpected error {:?}", kind),
+    };
+
+    let config = SandboxConfig {
+        max_goals: Some(1_000),
+        max_bindings: None,
+        max_results: Some(2),
+        max_stack: 10_000,
+        ..SandboxConfig::default()
+    };
+    p.set_sandbox(Some(config.clone()));
+    assert_eq!(run("count(10)")?.len(), 1);
+    assert!(matches!(
+        sandbox_error("count(1000)"),
+        RuntimeError::SandboxLimitExceeded { max: 1_000, .. }
+    ));
+    p.set_sandbox(Some(SandboxConfig {
+        max_bindings: Some(50),
+        ..config
+    }));
+    let vars = (0..60)
+        .map(|i| format!("x{} = {}", i, i))
+        .collect::<Vec<_>>();
+    assert!(matches!(
+        sandbox_error(&vars.join(" and ")),
+        RuntimeError::SandboxLimitExceeded { max: 50, .. }
+    ));
+    assert!(matches!(
+        sandbox_error("f(x)"),
+        RuntimeError::TooManyResults { max: 2 }
+    ));
+
+    // `debug()` does nothing, and the host can't be asked anything.
+    assert!(matches!(
+        &run("debug() and x = 1")?[..],
+        [QueryEvent::Result { .. }]
+    ));
+    assert!(matches!(
+        sandbox_error("x = new Foo()"),
+        RuntimeError::Unsupported { .. }
+    ));
+
+    // Parser limits apply too.
+    assert!(matches!(
+        p.load_str(&"#".repeat(64 * 1024 + 1)).unwrap_err().kind,
+        ErrorKind::Parse(ParseError::LimitExceeded { .. })
+    ));
+
+    p.set_sandbox(None);
+    assert_eq!(run("count(1000)")?.len(), 1);
+    assert_eq!(run("f(x)")?.len(), 3);
+    assert!(matches!(
+        &run("debug()")?[..],
+        [QueryEvent::Debug { .. }, ..]
+    ));
+    Ok(())
+}
+
+#[test]
+fn test_variable_names() -> TestResult {
+    let p = Polar::new();
+    p.load_str("f(x) if x = [y, _, z];")?;
+    let mut q = p.new_query("f(y)", false)?;
+    let bindings = match q.next_event()? {
+        QueryEvent::Result { bindings, .. } => bindings,
+        event => panic!("unexpected event {:?}", event),
+    };
+    assert_eq!(bindings[&sym!("y")].to_polar(), "[y_2, _1, z]");
+    let mut names: Vec<&str> = q.variable_names().values().map(|v| v.0.as_str()).collect();
+    names.sort_unstable();
+    assert_eq!(names, vec!["_1", "y_2", "z"]);
+    assert!(q
+        .variable_names()
+        .keys()
+        .all(|var| var.0.starts_with('_') && var.0 != "_1"));
+    Ok(())
 }
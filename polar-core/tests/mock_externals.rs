@@ -45,6 +45,13 @@ impl MockExternal {
         }
     }
 
+    /// The constructor of instance `instance_id`.
+    pub fn constructor(&self, instance_id: u64) -> &Term {
+        self.externals
+            .get(&instance_id)
+            .expect("Instance not constructed")
+    }
+
     pub fn make_external(&mut self, instance_id: u64, constructor: Term) {
         assert!(self.externals.insert(instance_id, constructor).is_none());
     }
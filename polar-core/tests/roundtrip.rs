@@ -0,0 +1,23 @@
+#![cfg(feature = "testing")]
+
+use proptest::prelude::*;
+
+use polar_core::formatting::ToPolarString;
+use polar_core::parser::{parse_rules, parse_term};
+use polar_core::testing::{arb_program, arb_term};
+
+proptest! {
+    #[test]
+    fn term_roundtrip(term in arb_term()) {
+        let printed = term.to_polar();
+        let parsed = parse_term(&printed).unwrap();
+        prop_assert_eq!(parsed, term, "{}", printed);
+    }
+
+    #[test]
+    fn program_roundtrip(rules in arb_program(5)) {
+        let printed = rules.iter().map(|r| r.to_polar()).collect::<Vec<_>>().join("\n");
+        let parsed = parse_rules(0, &printed).unwrap();
+        prop_assert_eq!(parsed, rules, "{}", printed);
+    }
+}
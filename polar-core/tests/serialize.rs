@@ -53,10 +53,11 @@ mod tests {
             instance_id: 12345,
             constructor: None,
             repr: None,
+            class_tag: None,
         }));
         let list_of = Term::new_from_test(Value::List(vec![external]));
         eprintln!("{}", serde_json::to_string(&list_of).unwrap());
-        let mut fields = BTreeMap::new();
+        let mut fields = Fields::new();
         fields.insert(Symbol::new("foo"), list_of);
         let dict = Term::new_from_test(Value::Dictionary(Dictionary { fields }));
         eprintln!("{}", serde_json::to_string(&dict).unwrap());
@@ -70,10 +71,12 @@ mod tests {
         let rule = Rule {
             name: Symbol::new("foo"),
             params: vec![],
+            guard: None,
             body: Term::new_temporary(Value::Expression(Operation {
                 operator: Operator::And,
                 args: vec![dict.clone(), dict.clone(), dict],
             })),
+            annotations: vec![],
         };
         eprintln!("{}", rule);
     }